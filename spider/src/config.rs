@@ -1,6 +1,9 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{fs, net::Ipv4Addr, path::{Path, PathBuf}};
 
 use serde::{Serialize, Deserialize};
+use spider_link::link::DEFAULT_MAX_FRAME_LEN;
+use tracing::error;
+use tracing_appender::rolling::Rotation;
 
 
 
@@ -10,16 +13,79 @@ use serde::{Serialize, Deserialize};
 pub struct SpiderConfig{
     #[serde(default = "default_listen_addr")]
     pub listen_addr: String,
+    // Addresses to also listen on, such as "[::]:1930" for IPv6 clients.
+    // Where IPv6 sockets also accept IPv4, that address may conflict with
+    // listen_addr, a failed bind is logged and the other addresses are kept.
+    #[serde(default)]
+    pub extra_listen_addrs: Vec<String>,
     #[serde(default = "default_pub_addr")]
     pub pub_addr: String,
     #[serde(default = "default_log_path")]
     pub log_path: String,
+    #[serde(default)]
+    pub log_rotation: LogRotation,
+    #[serde(default = "default_true")]
+    pub log_spider_only: bool,
+    #[serde(default = "default_true")]
+    pub console_enabled: bool,
     #[serde(default = "default_state_data_path")]
     pub state_data_path: String,
 
     #[serde(default)]
     pub keyfile_path: Option<String>,
 
+    // Beacon configuration
+    #[serde(default = "default_beacon_port")]
+    pub beacon_port: u16,
+    #[serde(default)]
+    pub beacon_group: Option<Ipv4Addr>,
+
+    // Chord configuration, chords listen on the first free port in
+    // chord_port_base..chord_port_base + chord_port_count
+    #[serde(default = "default_chord_port_base")]
+    pub chord_port_base: u16,
+    #[serde(default = "default_chord_port_count")]
+    pub chord_port_count: u16,
+
+    // Rate limit for messages received over each link. A link may send
+    // link_burst messages at once, then link_rate messages per second.
+    // A link that has link_abuse_limit messages dropped without slowing
+    // down is terminated and blocked.
+    #[serde(default = "default_link_rate")]
+    pub link_rate: u32,
+    #[serde(default = "default_link_burst")]
+    pub link_burst: u32,
+    #[serde(default = "default_link_abuse_limit")]
+    pub link_abuse_limit: u32,
+
+    // How long a message may wait to be accepted by a link before the link
+    // is considered stuck and closed, so it cannot hold up routing.
+    #[serde(default = "default_link_send_timeout_ms")]
+    pub link_send_timeout_ms: u64,
+
+    // The most messages buffered for a peer that is not connected, the
+    // oldest are dropped once this is reached.
+    #[serde(default = "default_pending_message_limit")]
+    pub pending_message_limit: usize,
+
+    // The largest frame read from a link, a link sending a larger frame
+    // is closed
+    #[serde(default = "default_max_frame_len")]
+    pub max_frame_len: usize,
+
+    // Approval codes let a pending link approve itself, shorter codes are
+    // rejected when set. Single use codes are forgotten once they approve
+    // a link.
+    #[serde(default = "default_approval_code_min_length")]
+    pub approval_code_min_length: usize,
+    #[serde(default = "default_true")]
+    pub approval_code_single_use: bool,
+
+    // UI peripherals that may subscribe to the private datasets of every
+    // peripheral, such as an owner's dashboard, by the base64 of their ids.
+    #[serde(default)]
+    pub owner_uis: Vec<String>,
+
     // No peripheral configurations
     #[serde(default)]
     peripheral_path: Option<String>,
@@ -30,9 +96,35 @@ pub struct SpiderConfig{
     // Dataset configuration
     #[serde(default)]
     dataset_path: Option<String>,
+    // Subscribers are sent each change to a dataset, after this many changes
+    // they are sent the whole dataset instead, to correct any drift.
+    #[serde(default = "default_dataset_resync_interval")]
+    pub dataset_resync_interval: usize,
 }
 
 
+/// How often the log file is replaced with a new one.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl LogRotation {
+    pub fn rotation(&self) -> Rotation {
+        match self {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
 impl SpiderConfig {
     pub fn from_file(path: &Path) -> Self {
         let data = match fs::read_to_string(&path){
@@ -40,10 +132,22 @@ impl SpiderConfig {
             Err(_) => String::from("{}"),
         };
         // let data = fs::read_to_string(&path).expect(&format!("Failed to read config file: {:?}", path));
-		let config = serde_json::from_str(&data).expect("Failed to deserialize config");
+		let mut config: Self = serde_json::from_str(&data).expect("Failed to deserialize config");
+        config.validate();
         config
     }
 
+    /// Disable settings that cannot work, logging why, rather than fail
+    /// later where the problem is harder to see.
+    fn validate(&mut self) {
+        if let Some(group) = self.beacon_group {
+            if !group.is_multicast() {
+                error!("beacon_group {} is not a multicast address, only answering direct probes", group);
+                self.beacon_group = None;
+            }
+        }
+    }
+
     pub fn peripheral_path(&self)-> PathBuf{
         let s = self.peripheral_path.clone().unwrap_or(String::from("peripherals"));
         PathBuf::from(s)
@@ -67,6 +171,54 @@ fn default_pub_addr() -> String {
     "0.0.0.0:1930".into()
 }
 
+fn default_beacon_port() -> u16 {
+    1930
+}
+
+fn default_chord_port_base() -> u16 {
+    1932
+}
+
+fn default_chord_port_count() -> u16 {
+    18
+}
+
+fn default_link_rate() -> u32 {
+    50
+}
+
+fn default_link_burst() -> u32 {
+    200
+}
+
+fn default_link_abuse_limit() -> u32 {
+    1000
+}
+
+fn default_link_send_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_pending_message_limit() -> usize {
+    100
+}
+
+fn default_max_frame_len() -> usize {
+    DEFAULT_MAX_FRAME_LEN
+}
+
+fn default_approval_code_min_length() -> usize {
+    8
+}
+
+fn default_dataset_resync_interval() -> usize {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
 fn default_log_path() -> String {
     "spider.log".into()
 }
@@ -36,11 +36,11 @@
 //! some type of low power probe or sensor.
 
 /// included libraries
-use std::{env, io::{self, ErrorKind}, path::{Path, PathBuf}, time::Duration};
+use std::{env, io, path::{Path, PathBuf}, time::Duration};
 
 use tracing::{info, debug, error};
-use tracing_appender::rolling::{Rotation, RollingFileAppender};
-use tracing_subscriber::{filter::filter_fn, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt};
+use tracing_appender::{non_blocking::WorkerGuard, rolling::RollingFileAppender};
+use tracing_subscriber::{filter::filter_fn, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, Layer};
 
 /// Module structure
 mod config;
@@ -50,7 +50,7 @@ mod state_data;
 use state_data::StateData;
 
 mod processor;
-use crate::processor::ProcessorBuilder;
+use crate::processor::{ProcessorBuilder, ProcessorMessage};
 
 
 #[tokio::main]
@@ -58,30 +58,11 @@ async fn main() -> Result<(), io::Error> {
 	// command line arguments: <filename>
 	// filename is name of config file, defaults to config.json
 
-	// setup tokio debugger
-	console_subscriber::ConsoleLayer::builder()
-        .retention(Duration::from_secs(600))
-        .server_addr(([127, 0, 0, 1], 6669))
-        .init();
-
 	// load config file
 	let config = load_config();
 
-	
-
-	// Setup tracing
-	// let filter = filter_fn(|metadata|{
-	// 	metadata.target() == "spider"
-	// });
-	
-	// let log_path = config.log_path.clone();
-	// let file_appender = RollingFileAppender::new(Rotation::NEVER, "", log_path);
-	// let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-	// let subscriber = tracing_subscriber::fmt()
-	// 	.pretty().with_ansi(false)
-	// 	.with_writer(non_blocking)
-	// 	.finish();
-	// subscriber.with(filter).init();
+	// Setup tracing, the guard must be held until exit to flush the log
+	let _guard = setup_tracing(&config);
 
 	info!("Starting!");
 	info!("Loaded config: {:?}", config);
@@ -103,10 +84,75 @@ async fn main() -> Result<(), io::Error> {
 	// start processor 
 	let processor_handle = pb.start_processor().expect("processor was able to start");
 
+	// stop the processor cleanly when the process is asked to exit
+	let sender = processor_handle.sender();
+	tokio::spawn(async move {
+		shutdown_signal().await;
+		info!("Received shutdown signal");
+		let _ = sender.send(ProcessorMessage::Shutdown).await;
+	});
+
 	processor_handle.join().await;
 	Ok(())
 }
 
+fn setup_tracing(config: &SpiderConfig) -> WorkerGuard {
+	// tokio debugger
+	let console_layer = if config.console_enabled {
+		let layer = console_subscriber::ConsoleLayer::builder()
+			.retention(Duration::from_secs(600))
+			.server_addr(([127, 0, 0, 1], 6669))
+			.spawn();
+		Some(layer)
+	} else {
+		None
+	};
+
+	// log file
+	let log_path = Path::new(&config.log_path);
+	let directory = match log_path.parent() {
+		Some(parent) => parent.to_path_buf(),
+		None => PathBuf::new(),
+	};
+	let file_name = log_path.file_name().unwrap_or("spider.log".as_ref());
+	let file_appender = RollingFileAppender::new(config.log_rotation.rotation(), directory, file_name);
+	let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+
+	let spider_only = config.log_spider_only;
+	let filter = filter_fn(move |metadata| {
+		!spider_only || metadata.target() == "spider" || metadata.target().starts_with("spider::")
+	});
+	let file_layer = tracing_subscriber::fmt::layer()
+		.pretty()
+		.with_ansi(false)
+		.with_writer(non_blocking)
+		.with_filter(filter);
+
+	tracing_subscriber::registry()
+		.with(console_layer)
+		.with(file_layer)
+		.init();
+
+	guard
+}
+
+/// Wait for a SIGINT, or a SIGTERM on unix systems.
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+		let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => {},
+			_ = terminate.recv() => {},
+		}
+	}
+	#[cfg(not(unix))]
+	{
+		let _ = tokio::signal::ctrl_c().await;
+	}
+}
+
 fn load_config() -> SpiderConfig {
 	let mut args = env::args().skip(1);
 	let path_str = args.next().unwrap_or("spider_config.json".to_string());
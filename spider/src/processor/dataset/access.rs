@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use spider_link::{message::DatasetAccess, Relation, Role, SpiderId2048};
+
+/// The access set on a public dataset, and the relation that set it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DatasetAcl {
+    pub owner: Relation,
+    pub access: DatasetAccess,
+}
+
+impl DatasetAcl {
+    /// Returns true if the relation may modify the dataset.
+    pub fn permits(&self, rel: &Relation) -> bool {
+        match &self.access {
+            DatasetAccess::Open => true,
+            DatasetAccess::OwnerOnly => self.is_owner(rel),
+            DatasetAccess::Allowed(allowed) => {
+                self.is_owner(rel) || allowed.iter().any(|allowed| allowed.id == rel.id)
+            }
+        }
+    }
+
+    /// An acl that no relation other than the base satisfies, used when the
+    /// stored acl cannot be read. The owner's id is all zeros, which is not
+    /// a valid key.
+    pub fn deny_all() -> Self {
+        Self {
+            owner: Relation {
+                id: SpiderId2048::from_bytes([0; _]),
+                role: Role::Peripheral,
+            },
+            access: DatasetAccess::OwnerOnly,
+        }
+    }
+
+    /// Returns true if the relation owns the dataset. Ownership follows the
+    /// id, not the role that the relation connected with.
+    pub fn is_owner(&self, rel: &Relation) -> bool {
+        self.owner.id == rel.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use spider_link::{message::DatasetAccess, Relation, Role, SelfRelation};
+
+    use super::DatasetAcl;
+
+    fn relation() -> Relation {
+        SelfRelation::generate_key(Role::Peripheral).relation
+    }
+
+    #[test]
+    fn permits() {
+        let owner = relation();
+        let allowed = relation();
+        let other = relation();
+
+        let mut acl = DatasetAcl {
+            owner: owner.clone(),
+            access: DatasetAccess::Open,
+        };
+        assert!(acl.permits(&other));
+
+        acl.access = DatasetAccess::OwnerOnly;
+        assert!(acl.permits(&owner));
+        assert!(!acl.permits(&allowed));
+
+        acl.access = DatasetAccess::Allowed(vec![allowed.clone()]);
+        assert!(acl.permits(&owner));
+        assert!(acl.permits(&allowed));
+        assert!(!acl.permits(&other));
+
+        // the same id connecting with another role is still the owner
+        let owner_as_peer = Relation {
+            id: owner.id.clone(),
+            role: Role::Peer,
+        };
+        assert!(acl.permits(&owner_as_peer));
+    }
+}
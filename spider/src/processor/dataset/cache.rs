@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use spider_link::message::{AbsoluteDatasetPath, DatasetData, DatasetSchema};
+use tokio::time::Instant;
+
+use super::{access::DatasetAcl, DatasetProcessorState};
+
+/// Datasets that have not been accessed for this long are removed from the
+/// cache.
+const EVICT_AFTER: Duration = Duration::from_secs(300);
+
+pub(crate) struct CachedDataset {
+    data: Vec<DatasetData>,
+    schema: Option<DatasetSchema>,
+    access: Option<DatasetAcl>,
+    last_access: Instant,
+}
+
+impl DatasetProcessorState {
+    /// Get a dataset, reading it into the cache if it is not already present.
+    pub(crate) async fn get_dataset(&mut self, path: &AbsoluteDatasetPath) -> &Vec<DatasetData> {
+        &self.load_cached(path).await.data
+    }
+
+    /// Get a dataset to modify, reading it into the cache if it is not
+    /// already present. Changes must also be made to the storage.
+    pub(crate) async fn get_dataset_mut(
+        &mut self,
+        path: &AbsoluteDatasetPath,
+    ) -> &mut Vec<DatasetData> {
+        &mut self.load_cached(path).await.data
+    }
+
+    /// Returns true if all of the items conform to the dataset's schema, or
+    /// if the dataset has no schema.
+    pub(crate) async fn conforms(&mut self, path: &AbsoluteDatasetPath, items: &[DatasetData]) -> bool {
+        match &self.load_cached(path).await.schema {
+            Some(schema) => items.iter().all(|item| schema.validate(item)),
+            None => true,
+        }
+    }
+
+    /// Set the schema of a dataset, the schema is written immediately.
+    pub(crate) async fn set_schema(&mut self, path: &AbsoluteDatasetPath, schema: DatasetSchema) -> rusqlite::Result<()> {
+        self.storage.save_schema(path, &schema)?;
+        self.load_cached(path).await.schema = Some(schema);
+        Ok(())
+    }
+
+    /// Get the access set on a dataset, if any.
+    pub(crate) async fn get_access(&mut self, path: &AbsoluteDatasetPath) -> Option<DatasetAcl> {
+        self.load_cached(path).await.access.clone()
+    }
+
+    /// Set or remove the access of a dataset, the access is written
+    /// immediately.
+    pub(crate) async fn set_access(&mut self, path: &AbsoluteDatasetPath, acl: Option<DatasetAcl>) -> rusqlite::Result<()> {
+        self.storage.save_access(path, acl.as_ref())?;
+        self.load_cached(path).await.access = acl;
+        Ok(())
+    }
+
+    async fn load_cached(&mut self, path: &AbsoluteDatasetPath) -> &mut CachedDataset {
+        if !self.cache.contains_key(path) {
+            let entry = CachedDataset {
+                data: self.storage.load(path),
+                schema: self.storage.load_schema(path),
+                access: self.storage.load_access(path),
+                last_access: Instant::now(),
+            };
+            self.cache.insert(path.clone(), entry);
+        }
+        let entry = self.cache.get_mut(path).expect("dataset was just cached");
+        entry.last_access = Instant::now();
+        entry
+    }
+
+    /// Evict datasets that have not been used recently.
+    pub(crate) async fn evict_datasets(&mut self) {
+        self.cache
+            .retain(|_, entry| entry.last_access.elapsed() <= EVICT_AFTER);
+    }
+}
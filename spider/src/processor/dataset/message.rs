@@ -6,6 +6,11 @@ pub enum DatasetProcessorMessage {
     PublicMessage(Relation, DatasetMessage),
     UiSubscribe(AbsoluteDatasetPath),
     UiUnsubscribe(AbsoluteDatasetPath),
+    // A UI peripheral subscribing directly to a dataset, the ui processor
+    // has already checked that it may.
+    UiPeripheralSubscribe(Relation, AbsoluteDatasetPath),
+    UiPeripheralUnsubscribe(Relation, AbsoluteDatasetPath),
     ToUi(Relation, AbsoluteDatasetPath),
     Upkeep,
+    Shutdown,
 }
\ No newline at end of file
@@ -1,23 +1,24 @@
-use std::{
-    collections::{HashMap, HashSet},
-    path::{Path, PathBuf},
-    str::FromStr,
-};
+use std::collections::{HashMap, HashSet};
 
 use crate::{config::SpiderConfig, state_data::StateData};
 
 use super::{sender::ProcessorSender, ui::UiProcessorMessage};
 
+mod access;
+use access::DatasetAcl;
+mod cache;
+use cache::CachedDataset;
 mod message;
 pub use message::DatasetProcessorMessage;
+mod storage;
+use storage::DatasetStorage;
 
 use spider_link::{
-    message::{AbsoluteDatasetPath, AbsoluteDatasetScope, DatasetData, DatasetMessage, Message, UiMessage},
+    message::{AbsoluteDatasetPath, AbsoluteDatasetScope, DatasetAccess, DatasetData, DatasetMessage, DatasetOp, DatasetPath, ErrorCode, ErrorMessage, Message, UiMessage},
     Relation, SpiderId2048,
 };
+use tracing::warn;
 use tokio::{
-    fs::{File, OpenOptions, create_dir_all},
-    io::{AsyncReadExt, AsyncWriteExt},
     sync::mpsc::{channel, error::SendError, Receiver, Sender},
     task::{JoinError, JoinHandle},
 };
@@ -26,6 +27,8 @@ use tokio::{
 enum DatasetSubscriber {
     Ui,
     Peripheral(SpiderId2048),
+    /// A UI peripheral subscribed directly, which is sent the absolute path
+    UiPeripheral(SpiderId2048),
 }
 
 pub(crate) struct DatasetProcessor {
@@ -63,6 +66,10 @@ pub(crate) struct DatasetProcessorState {
     receiver: Receiver<DatasetProcessorMessage>,
 
     subscriptions: HashMap<AbsoluteDatasetPath, HashSet<DatasetSubscriber>>,
+    // deltas sent to peripherals since each dataset was last sent in full
+    deltas: HashMap<AbsoluteDatasetPath, usize>,
+    storage: DatasetStorage,
+    cache: HashMap<AbsoluteDatasetPath, CachedDataset>,
 }
 
 impl DatasetProcessorState {
@@ -72,6 +79,7 @@ impl DatasetProcessorState {
         sender: ProcessorSender,
         receiver: Receiver<DatasetProcessorMessage>,
     ) -> Self {
+        let storage = DatasetStorage::open(&config.dataset_path());
         Self {
             config,
             state,
@@ -79,6 +87,9 @@ impl DatasetProcessorState {
             receiver,
 
             subscriptions: HashMap::new(),
+            deltas: HashMap::new(),
+            storage,
+            cache: HashMap::new(),
         }
     }
 
@@ -95,45 +106,39 @@ impl DatasetProcessorState {
                         self.handle_public_message(rel, msg).await
                     }
                     DatasetProcessorMessage::UiSubscribe(k) => {
-                        let is_new = match self.subscriptions.get_mut(&k) {
-                            Some(h_set) => h_set.insert(DatasetSubscriber::Ui),
-                            None => {
-                                let mut h_set = HashSet::new();
-                                h_set.insert(DatasetSubscriber::Ui);
-                                self.subscriptions.insert(k.clone(), h_set);
-                                true
-                            }
-                        };
-                        if is_new {
-                            let file_path = self.get_file_path(&k);
-                            let dataset = parse_dataset(&file_path).await;
+                        if self.subscribe(&k, DatasetSubscriber::Ui) {
+                            let dataset = self.get_dataset(&k).await.clone();
                             self.sender
                                 .send_ui(UiProcessorMessage::DatasetUpdate(k, dataset))
                                 .await;
                         }
                     }
                     DatasetProcessorMessage::UiUnsubscribe(k) => {
-                        match self.subscriptions.get_mut(&k){
-                            Some(h_set) => {
-                                h_set.remove(&DatasetSubscriber::Ui);
-                                // if the set is empty, remove it from the map
-                                if h_set.is_empty(){
-                                    self.subscriptions.remove(&k);
-                                }
-                            },
-                            None => {
-                                // if no set, there was no subscription after all!
-                            },
-                        }
+                        self.unsubscribe(&k, &DatasetSubscriber::Ui);
+                    }
+                    DatasetProcessorMessage::UiPeripheralSubscribe(relation, path) => {
+                        self.subscribe(&path, DatasetSubscriber::UiPeripheral(relation.id.clone()));
+                        let dataset = self.get_dataset(&path).await.clone();
+                        let msg = Message::Ui(UiMessage::Dataset(path, dataset));
+                        self.sender.send_message(relation, msg).await;
+                    }
+                    DatasetProcessorMessage::UiPeripheralUnsubscribe(relation, path) => {
+                        self.unsubscribe(&path, &DatasetSubscriber::UiPeripheral(relation.id));
                     }
                     DatasetProcessorMessage::ToUi(relation, path) => {
                         // send dataset on behalf of the ui processor as a ui update
-                        let file_path = self.get_file_path(&path);
-                        let dataset = parse_dataset(&file_path).await;
+                        let dataset = self.get_dataset(&path).await.clone();
                         let msg = Message::Ui(UiMessage::Dataset(path, dataset));
                         self.sender.send_message(relation, msg).await;
                     }
-                    DatasetProcessorMessage::Upkeep => {}
+                    DatasetProcessorMessage::Upkeep => {
+                        self.evict_datasets().await;
+                    }
+                    DatasetProcessorMessage::Shutdown => {
+                        // changes are written to storage as they are made,
+                        // so the cache does not need to be flushed
+                        break;
+                    }
                 }
             }
         });
@@ -141,6 +146,13 @@ impl DatasetProcessorState {
     }
 
     async fn handle_public_message(&mut self, rel: Relation, msg: DatasetMessage) {
+        if let Some(path) = modified_path(&msg) {
+            let path = path.clone().resolve(rel.id.clone());
+            if !self.may_write(&rel, &path).await {
+                self.access_error(rel, &path, "Not permitted to modify the dataset").await;
+                return;
+            }
+        }
         match msg {
             DatasetMessage::Subscribe { path } => {
                 let path = path.resolve(rel.id.clone());
@@ -158,108 +170,355 @@ impl DatasetProcessorState {
                     }
                 }
                 // Reply with dataset
-                let file_path = self.get_file_path(&path);
-                let dataset = parse_dataset(&file_path).await;
+                let dataset = self.get_dataset(&path).await.clone();
                 let msg = Message::Dataset(DatasetMessage::Dataset {
                     path: path.specialize(),
                     data: dataset,
                 });
                 self.sender.send_message(rel, msg).await;
             }
-            DatasetMessage::Append { path, data } => {
-                let path = path.resolve(rel.id);
-                let file_path = self.get_file_path(&path);
-                // open/parse file
-                let mut dataset = parse_dataset(&file_path).await;
+            DatasetMessage::Append { path, data, token } => {
+                let abs_path = path.clone().resolve(rel.id.clone());
+                if !self.conforms(&abs_path, std::slice::from_ref(&data)).await {
+                    self.schema_error(rel, &abs_path).await;
+                    return;
+                }
+                let op = DatasetOp::Append(data);
+                let dataset = self.get_dataset_mut(&abs_path).await;
                 // make change
-                dataset.push(data);
-                // write file
-                write_dataset(&file_path, dataset.clone()).await;
-                self.message_subscribed(path, &dataset).await;
+                let id = dataset.len();
+                op.apply(dataset);
+                let dataset = dataset.clone();
+                let result = self.storage.set_range(&abs_path, id, &dataset[id..]);
+                if !self.check_write(&rel, &abs_path, result).await {
+                    return;
+                }
+                // inform appender of the new element's index
+                let msg = Message::Dataset(DatasetMessage::AppendResult { path, id, token });
+                self.sender.send_message(rel, msg).await;
+                self.message_subscribed(abs_path, &dataset, vec![op]).await;
             }
-            DatasetMessage::Extend { path, mut data } => {
-                let path = path.resolve(rel.id);
-                let file_path = self.get_file_path(&path);
-                // open/parse file
-                let mut dataset = parse_dataset(&file_path).await;
+            DatasetMessage::Extend { path, data } => {
+                let path = path.resolve(rel.id.clone());
+                if !self.conforms(&path, &data).await {
+                    self.schema_error(rel, &path).await;
+                    return;
+                }
+                let op = DatasetOp::Extend(data);
+                let dataset = self.get_dataset_mut(&path).await;
                 // make change
-                dataset.append(&mut data);
-                // write file
-                write_dataset(&file_path, dataset.clone()).await;
-                self.message_subscribed(path, &dataset).await;
+                let start = dataset.len();
+                op.apply(dataset);
+                let dataset = dataset.clone();
+                let result = self.storage.set_range(&path, start, &dataset[start..]);
+                if !self.check_write(&rel, &path, result).await {
+                    return;
+                }
+                self.message_subscribed(path, &dataset, vec![op]).await;
             }
             DatasetMessage::SetElement { path, data, id } => {
-                let path = path.resolve(rel.id);
-                let file_path = self.get_file_path(&path);
-                // open/parse file
-                let mut dataset = parse_dataset(&file_path).await;
-                // make change
-                // pad
-                for _ in dataset.len()..=id {
-                    dataset.push(DatasetData::Null);
+                let path = path.resolve(rel.id.clone());
+                if !self.conforms(&path, std::slice::from_ref(&data)).await {
+                    self.schema_error(rel, &path).await;
+                    return;
+                }
+                let op = DatasetOp::SetElement { id, data };
+                let dataset = self.get_dataset_mut(&path).await;
+                // make change, padding the dataset up to id
+                let start = dataset.len().min(id);
+                if !op.apply(dataset) {
+                    self.index_error(rel, &path).await;
+                    return;
+                }
+                let dataset = dataset.clone();
+                let result = self.storage.set_range(&path, start, &dataset[start..=id]);
+                if !self.check_write(&rel, &path, result).await {
+                    return;
                 }
-                // set elem
-                let elem = dataset
-                    .get_mut(id)
-                    .expect("dataset should have been extended to length");
-                *elem = data;
-                // write file
-                write_dataset(&file_path, dataset.clone()).await;
-                self.message_subscribed(path, &dataset).await;
+                self.message_subscribed(path, &dataset, vec![op]).await;
             }
             DatasetMessage::SetElements { path, data, id } => {
-                let path = path.resolve(rel.id);
-                let file_path = self.get_file_path(&path);
-                // open/parse file
-                let mut dataset = parse_dataset(&file_path).await;
-                // make change
-                // pad
-                for _ in dataset.len()..(id + data.len()) {
-                    dataset.push(DatasetData::Null);
+                let path = path.resolve(rel.id.clone());
+                if !self.conforms(&path, &data).await {
+                    self.schema_error(rel, &path).await;
+                    return;
                 }
-                // set elems
-                for (i, new_elem) in data.into_iter().enumerate() {
-                    let elem = dataset
-                        .get_mut(id + i)
-                        .expect("dataset should have been extended to length");
-                    *elem = new_elem;
+                // exact once the op is known to fit
+                let end = id.saturating_add(data.len());
+                let op = DatasetOp::SetElements { id, data };
+                let dataset = self.get_dataset_mut(&path).await;
+                // make change, padding the dataset up to id
+                let start = dataset.len().min(id);
+                if !op.apply(dataset) {
+                    self.index_error(rel, &path).await;
+                    return;
                 }
-
-                // write file
-                write_dataset(&file_path, dataset.clone()).await;
-                self.message_subscribed(path, &dataset).await;
+                let dataset = dataset.clone();
+                let result = self.storage.set_range(&path, start, &dataset[start..end]);
+                if !self.check_write(&rel, &path, result).await {
+                    return;
+                }
+                self.message_subscribed(path, &dataset, vec![op]).await;
             }
             DatasetMessage::DeleteElement { path, id } => {
-                let path = path.resolve(rel.id);
-                let file_path = self.get_file_path(&path);
-                // open/parse file
-                let mut dataset = parse_dataset(&file_path).await;
+                let path = path.resolve(rel.id.clone());
+                let op = DatasetOp::DeleteElement { id };
+                let dataset = self.get_dataset_mut(&path).await;
                 // make change
-                if id < dataset.len() {
-                    dataset.remove(id);
+                op.apply(dataset);
+                let dataset = dataset.clone();
+                let result = self.storage.delete(&path, id);
+                if !self.check_write(&rel, &path, result).await {
+                    return;
                 }
-                // write file
-                write_dataset(&file_path, dataset.clone()).await;
-                self.message_subscribed(path, &dataset).await;
+                self.message_subscribed(path, &dataset, vec![op]).await;
             }
             DatasetMessage::Empty { path } => {
-                let path = path.resolve(rel.id);
-                let file_path = self.get_file_path(&path);
+                let path = path.resolve(rel.id.clone());
                 // create empty dataset
-                let dataset = vec![];
-                // write to file
-                write_dataset(&file_path, dataset.clone()).await;
-                self.message_subscribed(path, &dataset).await;
+                let dataset = self.get_dataset_mut(&path).await;
+                DatasetOp::Empty.apply(dataset);
+                let dataset = dataset.clone();
+                let result = self.storage.clear(&path);
+                if !self.check_write(&rel, &path, result).await {
+                    return;
+                }
+                self.message_subscribed(path, &dataset, vec![DatasetOp::Empty]).await;
 
             }
+            DatasetMessage::Batch(msgs) => {
+                self.handle_batch(rel, msgs).await;
+            }
+            DatasetMessage::SetSchema { path, schema } => {
+                let path = path.resolve(rel.id.clone());
+                let result = self.set_schema(&path, schema).await;
+                self.check_write(&rel, &path, result).await;
+            }
+            DatasetMessage::SetAccess { path, access } => {
+                let path = path.resolve(rel.id.clone());
+                if let AbsoluteDatasetScope::Peripheral(_) = path.scope() {
+                    let msg = ErrorMessage::new(ErrorCode::InvalidRequest, "Access can only be set on public datasets")
+                        .with_context(format!("{:?}", path.parts()));
+                    self.sender.send_message(rel, Message::Error(msg)).await;
+                    return;
+                }
+                // the owner keeps ownership when changing the access, only
+                // the base or an owner ui may claim a dataset without one
+                let owner = match self.get_access(&path).await {
+                    Some(acl) => {
+                        if !acl.is_owner(&rel) && !self.is_base(&rel).await {
+                            self.access_error(rel, &path, "Only the owner may set the access of the dataset").await;
+                            return;
+                        }
+                        acl.owner
+                    }
+                    None => {
+                        if !self.may_claim(&rel).await {
+                            self.access_error(rel, &path, "Only the base or an owner UI may claim the dataset").await;
+                            return;
+                        }
+                        rel.clone()
+                    }
+                };
+                let acl = match access {
+                    DatasetAccess::Open => None,
+                    access => Some(DatasetAcl { owner, access }),
+                };
+                let result = self.set_access(&path, acl).await;
+                self.check_write(&rel, &path, result).await;
+            }
+            DatasetMessage::Query { path, offset, limit, filter } => {
+                let abs_path = path.clone().resolve(rel.id.clone());
+                let dataset = self.get_dataset(&abs_path).await;
+                // select matching elements
+                let data = dataset
+                    .iter()
+                    .filter(|elem| match &filter {
+                        Some(filter) => filter.matches(elem),
+                        None => true,
+                    })
+                    .skip(offset)
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                let msg = Message::Dataset(DatasetMessage::QueryResult { path, offset, data });
+                self.sender.send_message(rel, msg).await;
+            }
             DatasetMessage::Dataset { .. } => {} //base sends this, not recieve (Could use as an assignment operation)
+            DatasetMessage::Delta { .. } => {} // base sends this, not recieve
+            DatasetMessage::AppendResult { .. } => {} // base sends this, not recieve
+            DatasetMessage::QueryResult { .. } => {} // base sends this, not recieve
+        }
+    }
+
+    /// Apply a batch of changes to a single dataset. The batch is checked
+    /// in full before any change is made, then the dataset is written in a
+    /// single transaction and subscribers are informed once.
+    async fn handle_batch(&mut self, rel: Relation, msgs: Vec<DatasetMessage>) {
+        let (path, ops, tokens) = match batch_ops(msgs) {
+            Ok(Some(batch)) => batch,
+            Ok(None) => return, // empty batch, nothing to do
+            Err(detail) => {
+                let msg = ErrorMessage::new(ErrorCode::InvalidRequest, detail);
+                self.sender.send_message(rel, Message::Error(msg)).await;
+                return;
+            }
+        };
+        let abs_path = path.clone().resolve(rel.id.clone());
+        if !self.may_write(&rel, &abs_path).await {
+            self.access_error(rel, &abs_path, "Not permitted to modify the dataset").await;
+            return;
+        }
+        let items: Vec<DatasetData> = ops.iter().flat_map(op_data).cloned().collect();
+        if !self.conforms(&abs_path, &items).await {
+            self.schema_error(rel, &abs_path).await;
+            return;
+        }
+
+        // make changes to a copy, so the cache is untouched if writing fails
+        let old = self.get_dataset(&abs_path).await;
+        let mut dataset = old.clone();
+        let mut append_ids = Vec::new();
+        let mut fits = true;
+        for op in &ops {
+            if let DatasetOp::Append(_) = op {
+                append_ids.push(dataset.len());
+            }
+            if !op.apply(&mut dataset) {
+                fits = false;
+                break;
+            }
+        }
+        // only rewrite from the first element that changed
+        let start = old
+            .iter()
+            .zip(dataset.iter())
+            .position(|(old, new)| old != new)
+            .unwrap_or(old.len().min(dataset.len()));
+        if !fits {
+            self.index_error(rel, &abs_path).await;
+            return;
+        }
+        let result = self.storage.replace_from(&abs_path, start, &dataset[start..]);
+        if !self.check_write(&rel, &abs_path, result).await {
+            return;
+        }
+        *self.get_dataset_mut(&abs_path).await = dataset.clone();
+
+        // inform appenders of the new elements' indices
+        for (id, token) in append_ids.into_iter().zip(tokens) {
+            let msg = Message::Dataset(DatasetMessage::AppendResult { path: path.clone(), id, token });
+            self.sender.send_message(rel.clone(), msg).await;
+        }
+        self.message_subscribed(abs_path, &dataset, ops).await;
+    }
+
+    /// Check the result of writing a dataset to storage. If it failed, the
+    /// cached copy is dropped so that it is read again from storage, and the
+    /// peripheral that made the change is sent an error. Returns true if
+    /// the write succeeded.
+    async fn check_write(&mut self, rel: &Relation, path: &AbsoluteDatasetPath, result: rusqlite::Result<()>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to write dataset {:?}: {}", path.parts(), e);
+                self.cache.remove(path);
+                let msg = ErrorMessage::new(ErrorCode::StorageFailure, "Failed to write dataset")
+                    .with_context(format!("{:?}", path.parts()));
+                let msg = Message::Error(msg);
+                self.sender.send_message(rel.clone(), msg).await;
+                false
+            }
+        }
+    }
+
+    // takes &mut self so that the processor's future stays Send, as the
+    // storage connection is not Sync
+    async fn is_base(&mut self, rel: &Relation) -> bool {
+        rel.id == self.state.self_id().await
+    }
+
+    /// Returns true if the relation may modify the dataset. Peripherals may
+    /// always modify their own private datasets, and the base may modify
+    /// any dataset. Public datasets without an access set are open.
+    async fn may_write(&mut self, rel: &Relation, path: &AbsoluteDatasetPath) -> bool {
+        if let AbsoluteDatasetScope::Peripheral(_) = path.scope() {
+            return true;
+        }
+        if self.is_base(rel).await {
+            return true;
+        }
+        match self.get_access(path).await {
+            Some(acl) => acl.permits(rel),
+            None => true,
+        }
+    }
+
+    /// Returns true if the relation may claim a public dataset that has no
+    /// owner yet.
+    async fn may_claim(&mut self, rel: &Relation) -> bool {
+        self.is_base(rel).await || self.config.owner_uis.contains(&rel.id.to_base64())
+    }
+
+    async fn access_error(&mut self, rel: Relation, path: &AbsoluteDatasetPath, detail: &str) {
+        warn!("Relation {} denied access to dataset {:?}", rel.id.to_base64(), path.parts());
+        let msg = ErrorMessage::new(ErrorCode::Unauthorized, detail)
+            .with_context(format!("{:?}", path.parts()));
+        self.sender.send_message(rel, Message::Error(msg)).await;
+    }
+
+    async fn index_error(&mut self, rel: Relation, path: &AbsoluteDatasetPath) {
+        let msg = ErrorMessage::new(ErrorCode::InvalidRequest, "Index is too far past the end of the dataset")
+            .with_context(format!("{:?}", path.parts()));
+        self.sender.send_message(rel, Message::Error(msg)).await;
+    }
+
+    async fn schema_error(&mut self, rel: Relation, path: &AbsoluteDatasetPath) {
+        let msg = ErrorMessage::new(ErrorCode::SchemaViolation, "Data does not conform to the schema of the dataset")
+            .with_context(format!("{:?}", path.parts()));
+        let msg = Message::Error(msg);
+        self.sender.send_message(rel, msg).await;
+    }
+
+    /// Add a subscriber to the dataset, returns true if it was not
+    /// already subscribed.
+    fn subscribe(&mut self, path: &AbsoluteDatasetPath, subscriber: DatasetSubscriber) -> bool {
+        self.subscriptions.entry(path.clone()).or_default().insert(subscriber)
+    }
+
+    fn unsubscribe(&mut self, path: &AbsoluteDatasetPath, subscriber: &DatasetSubscriber) {
+        if let Some(subscribers) = self.subscriptions.get_mut(path) {
+            subscribers.remove(subscriber);
+            // if the set is empty, remove it from the map
+            if subscribers.is_empty() {
+                self.subscriptions.remove(path);
+                self.deltas.remove(path);
+            }
+        }
+    }
+
+    /// Count a change to the dataset, returns true if enough changes have
+    /// been sent as deltas that the whole dataset should be sent instead.
+    fn resync_due(&mut self, path: &AbsoluteDatasetPath) -> bool {
+        let count = self.deltas.entry(path.clone()).or_default();
+        *count += 1;
+        if *count >= self.config.dataset_resync_interval {
+            *count = 0;
+            true
+        } else {
+            false
         }
     }
 
-    async fn message_subscribed(&mut self, path: AbsoluteDatasetPath, dataset: &Vec<DatasetData>) {
+    /// Inform the subscribers of a dataset that it has changed. Peripherals
+    /// are sent the ops as a [DatasetMessage::Delta], or the whole dataset
+    /// once every dataset_resync_interval changes. The ui processor and UI
+    /// peripherals are always sent the whole dataset.
+    async fn message_subscribed(&mut self, path: AbsoluteDatasetPath, dataset: &Vec<DatasetData>, ops: Vec<DatasetOp>) {
         match self.subscriptions.get(&path) {
             Some(subscribers) => {
                 let mut peripheral_list = Vec::new();
+                let mut ui_peripheral_list = Vec::new();
                 for subscriber in subscribers {
                     match subscriber {
                         DatasetSubscriber::Ui => {
@@ -276,11 +535,33 @@ impl DatasetProcessorState {
                                 role: spider_link::Role::Peripheral,
                             });
                         }
+                        DatasetSubscriber::UiPeripheral(id) => {
+                            ui_peripheral_list.push(Relation {
+                                id: id.clone(),
+                                role: spider_link::Role::Peripheral,
+                            });
+                        }
                     }
                 }
-                let message = DatasetMessage::Dataset {
-                    path: path.specialize(),
-                    data: dataset.to_vec(),
+                if !ui_peripheral_list.is_empty() {
+                    let message = Message::Ui(UiMessage::Dataset(path.clone(), dataset.clone()));
+                    self.sender
+                        .multicast_message(ui_peripheral_list, message)
+                        .await;
+                }
+                if peripheral_list.is_empty() {
+                    return;
+                }
+                let message = if self.resync_due(&path) {
+                    DatasetMessage::Dataset {
+                        path: path.specialize(),
+                        data: dataset.to_vec(),
+                    }
+                } else {
+                    DatasetMessage::Delta {
+                        path: path.specialize(),
+                        ops,
+                    }
                 };
                 let message = spider_link::message::Message::Dataset(message);
                 self.sender
@@ -292,54 +573,214 @@ impl DatasetProcessorState {
             }
         }
     }
+}
+
+/// Get the path of the dataset that a message modifies, if it modifies one.
+fn modified_path(msg: &DatasetMessage) -> Option<&DatasetPath> {
+    match msg {
+        DatasetMessage::Append { path, .. }
+        | DatasetMessage::Extend { path, .. }
+        | DatasetMessage::SetElement { path, .. }
+        | DatasetMessage::SetElements { path, .. }
+        | DatasetMessage::DeleteElement { path, .. }
+        | DatasetMessage::Empty { path }
+        | DatasetMessage::SetSchema { path, .. } => Some(path),
+        // setting the access is checked against the owner instead
+        DatasetMessage::SetAccess { .. } => None,
+        // checked once the batch has been validated
+        DatasetMessage::Batch(_) => None,
+        DatasetMessage::Subscribe { .. }
+        | DatasetMessage::Query { .. }
+        | DatasetMessage::Dataset { .. }
+        | DatasetMessage::Delta { .. }
+        | DatasetMessage::AppendResult { .. }
+        | DatasetMessage::QueryResult { .. } => None,
+    }
+}
+
+/// The path, ops, and append tokens of a batch.
+type Batch = (DatasetPath, Vec<DatasetOp>, Vec<Option<String>>);
 
-    fn get_file_path(&self, path: &AbsoluteDatasetPath) -> PathBuf {
-        let base = &self.config.dataset_path();
-        let x = match path.scope() {
-            AbsoluteDatasetScope::Peripheral(id) => PathBuf::from_str(&id.sha256()).unwrap(),
-            AbsoluteDatasetScope::Public => PathBuf::from_str("public").unwrap(),
+/// Convert the messages in a batch to the ops they make, checking that each
+/// may be batched and that they all modify the same dataset. Returns None
+/// for an empty batch.
+fn batch_ops(msgs: Vec<DatasetMessage>) -> Result<Option<Batch>, &'static str> {
+    let mut batch_path = None;
+    let mut ops = Vec::with_capacity(msgs.len());
+    let mut tokens = Vec::new();
+    for msg in msgs {
+        let (path, op) = match msg {
+            DatasetMessage::Append { path, data, token } => {
+                tokens.push(token);
+                (path, DatasetOp::Append(data))
+            }
+            DatasetMessage::Extend { path, data } => (path, DatasetOp::Extend(data)),
+            DatasetMessage::SetElement { path, data, id } => (path, DatasetOp::SetElement { id, data }),
+            DatasetMessage::SetElements { path, data, id } => (path, DatasetOp::SetElements { id, data }),
+            DatasetMessage::DeleteElement { path, id } => (path, DatasetOp::DeleteElement { id }),
+            DatasetMessage::Empty { path } => (path, DatasetOp::Empty),
+            DatasetMessage::Batch(_) => return Err("Batches may not be nested"),
+            _ => return Err("Only changes to elements may be batched"),
         };
-        let mut p = base.join(x);
-        for item in path.parts() {
-            p.push(item);
+        match &batch_path {
+            Some(batch_path) if *batch_path != path => {
+                return Err("All messages in a batch must modify the same dataset");
+            }
+            Some(_) => {}
+            None => batch_path = Some(path),
         }
-        p.set_extension("dat");
-        p
+        ops.push(op);
     }
+    Ok(batch_path.map(|path| (path, ops, tokens)))
 }
 
-async fn parse_dataset(path: &Path) -> Vec<DatasetData> {
-    println!("Path: {}", path.display());
-    // create directories above file
-    create_dir_all(path.parent().unwrap()).await.unwrap();
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)
-        .await
-        .unwrap();
-    let mut data: String = String::new();
-    file.read_to_string(&mut data).await;
-    if data.len() == 0{
-        data = String::from("[]");
+/// The data that an op writes, to be checked against the schema.
+fn op_data(op: &DatasetOp) -> &[DatasetData] {
+    match op {
+        DatasetOp::Append(data) | DatasetOp::SetElement { data, .. } => std::slice::from_ref(data),
+        DatasetOp::Extend(data) | DatasetOp::SetElements { data, .. } => data,
+        DatasetOp::DeleteElement { .. } | DatasetOp::Empty => &[],
     }
-    serde_json::from_str(&data).unwrap()
 }
 
-async fn write_dataset(path: &Path, data: Vec<DatasetData>) {
-    // create directories above file
-    create_dir_all(path.parent().unwrap()).await.unwrap();
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)
-        .await
-        .unwrap();
-
-    let data = serde_json::to_string(&data).unwrap();
-
-    file.write_all(data.as_bytes()).await;
-    file.set_len(data.len().try_into().unwrap()).await;
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs, path::PathBuf};
+
+    use spider_link::{
+        message::{DatasetAccess, DatasetData, DatasetMessage, DatasetOp, DatasetPath, ErrorCode, Message},
+        Relation, Role, SelfRelation,
+    };
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use crate::{
+        config::SpiderConfig,
+        processor::{message::ProcessorMessage, router::RouterProcessorMessage, sender::ProcessorSender},
+        state_data::StateData,
+    };
+
+    use super::{batch_ops, DatasetProcessorState};
+
+    fn processor(name: &str, owner_uis: &[&Relation]) -> (DatasetProcessorState, Receiver<ProcessorMessage>, PathBuf) {
+        let dir = temp_dir().join(format!("spider_dataset_{}_test", name));
+        let _ = fs::remove_dir_all(&dir);
+        let owner_uis: Vec<String> = owner_uis.iter().map(|rel| rel.id.to_base64()).collect();
+        let config = serde_json::json!({"dataset_path": dir, "owner_uis": owner_uis});
+        let config: SpiderConfig = serde_json::from_value(config).unwrap();
+        let state = StateData::with_generated_key(&dir.join("state"));
+        let (sender, receiver) = channel(50);
+        let (_dataset_sender, dataset_receiver) = channel(50);
+        let processor = DatasetProcessorState::new(config, state, ProcessorSender::new(sender), dataset_receiver);
+        (processor, receiver, dir)
+    }
+
+    fn expect_error(receiver: &mut Receiver<ProcessorMessage>, rel: &Relation, code: ErrorCode) {
+        match receiver.try_recv().unwrap() {
+            ProcessorMessage::RouterMessage(RouterProcessorMessage::SendMessage(to, Message::Error(error))) => {
+                assert_eq!(&to, rel);
+                assert_eq!(error.code(), code);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_range_index() {
+        let (mut processor, mut receiver, dir) = processor("index", &[]);
+
+        let rel: Relation = SelfRelation::generate_key(Role::Peripheral).relation;
+        let path = DatasetPath::new_public(vec!["test".into()]);
+        let two = vec![DatasetData::Null, DatasetData::Null];
+        let msgs = vec![
+            DatasetMessage::SetElement { path: path.clone(), data: DatasetData::Null, id: usize::MAX },
+            // overflows when the length is added
+            DatasetMessage::SetElements { path: path.clone(), data: two.clone(), id: usize::MAX - 1 },
+            // does not overflow, but would pad far too much
+            DatasetMessage::SetElements { path: path.clone(), data: two.clone(), id: usize::MAX / 2 },
+            DatasetMessage::Batch(vec![
+                DatasetMessage::Append { path: path.clone(), data: DatasetData::Null, token: None },
+                DatasetMessage::SetElement { path: path.clone(), data: DatasetData::Null, id: usize::MAX },
+            ]),
+        ];
+        for msg in msgs {
+            processor.handle_public_message(rel.clone(), msg).await;
+            expect_error(&mut receiver, &rel, ErrorCode::InvalidRequest);
+        }
+        let abs_path = path.clone().resolve(rel.id.clone());
+        assert!(processor.get_dataset(&abs_path).await.is_empty());
+
+        // a small amount of padding is still allowed
+        let msg = DatasetMessage::SetElement { path, data: DatasetData::Byte(1), id: 3 };
+        processor.handle_public_message(rel.clone(), msg).await;
+        assert_eq!(processor.get_dataset(&abs_path).await.len(), 4);
+
+        drop(processor);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hostile_claim() {
+        let hostile: Relation = SelfRelation::generate_key(Role::Peripheral).relation;
+        let owner_ui: Relation = SelfRelation::generate_key(Role::Peripheral).relation;
+        let (mut processor, mut receiver, dir) = processor("claim", &[&owner_ui]);
+        let path = DatasetPath::new_public(vec!["test".into()]);
+        let abs_path = path.clone().resolve(hostile.id.clone());
+        let set_access = DatasetMessage::SetAccess { path: path.clone(), access: DatasetAccess::OwnerOnly };
+        let append = DatasetMessage::Append { path: path.clone(), data: DatasetData::Null, token: None };
+
+        // a relation that is not an owner ui cannot claim the open dataset
+        processor.handle_public_message(hostile.clone(), set_access.clone()).await;
+        expect_error(&mut receiver, &hostile, ErrorCode::Unauthorized);
+        assert!(processor.get_access(&abs_path).await.is_none());
+        processor.handle_public_message(hostile.clone(), append.clone()).await;
+        match receiver.try_recv().unwrap() {
+            ProcessorMessage::RouterMessage(RouterProcessorMessage::SendMessage(to, Message::Dataset(_))) => {
+                assert_eq!(to, hostile);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert_eq!(processor.get_dataset(&abs_path).await.len(), 1);
+
+        // an owner ui can, after which the hostile relation is locked out
+        processor.handle_public_message(owner_ui.clone(), set_access.clone()).await;
+        assert!(receiver.try_recv().is_err());
+        assert!(processor.get_access(&abs_path).await.unwrap().is_owner(&owner_ui));
+        processor.handle_public_message(hostile.clone(), set_access).await;
+        expect_error(&mut receiver, &hostile, ErrorCode::Unauthorized);
+        processor.handle_public_message(hostile.clone(), append).await;
+        expect_error(&mut receiver, &hostile, ErrorCode::Unauthorized);
+        assert_eq!(processor.get_dataset(&abs_path).await.len(), 1);
+
+        drop(processor);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_validation() {
+        let path = DatasetPath::new_public(vec!["test".into()]);
+        let other = DatasetPath::new_private(vec!["test".into()]);
+        let append = |path: &DatasetPath| DatasetMessage::Append {
+            path: path.clone(),
+            data: DatasetData::Null,
+            token: Some("token".into()),
+        };
+
+        assert_eq!(batch_ops(vec![]), Ok(None));
+
+        let msgs = vec![
+            append(&path),
+            DatasetMessage::DeleteElement { path: path.clone(), id: 0 },
+        ];
+        let (batch_path, ops, tokens) = batch_ops(msgs).unwrap().unwrap();
+        assert_eq!(batch_path, path);
+        assert_eq!(ops, vec![DatasetOp::Append(DatasetData::Null), DatasetOp::DeleteElement { id: 0 }]);
+        assert_eq!(tokens, vec![Some("token".to_string())]);
+
+        // every message must modify the same dataset
+        assert!(batch_ops(vec![append(&path), append(&other)]).is_err());
+        // batches cannot be nested
+        assert!(batch_ops(vec![DatasetMessage::Batch(vec![append(&path)])]).is_err());
+        // only element changes may be batched
+        assert!(batch_ops(vec![DatasetMessage::Subscribe { path }]).is_err());
+    }
 }
@@ -0,0 +1,480 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use spider_link::message::{AbsoluteDatasetPath, AbsoluteDatasetScope, DatasetData, DatasetSchema};
+use tracing::warn;
+
+use super::access::DatasetAcl;
+
+/// The name of the database file within the dataset directory.
+const DATABASE_NAME: &str = "datasets.db";
+
+/// Stores datasets in a sqlite database, with one row per element.
+/// Each dataset is keyed by its scope (the sha256 of the owning
+/// peripheral's id, or "public") and its name (the path's parts as
+/// a json array).
+pub(crate) struct DatasetStorage {
+    conn: Connection,
+}
+
+impl DatasetStorage {
+    /// Open the database in the dataset directory, importing any datasets
+    /// from the older file based storage the first time it is opened.
+    pub fn open(dataset_path: &Path) -> Self {
+        fs::create_dir_all(dataset_path).expect("failed to create dataset directory");
+        let conn = Connection::open(dataset_path.join(DATABASE_NAME))
+            .expect("failed to open dataset database");
+        let mut storage = Self::from_connection(conn);
+        storage.migrate(dataset_path);
+        storage
+    }
+
+    fn from_connection(conn: Connection) -> Self {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS data (
+                scope TEXT NOT NULL,
+                name TEXT NOT NULL,
+                idx INTEGER NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (scope, name, idx)
+            );
+            CREATE TABLE IF NOT EXISTS schemas (
+                scope TEXT NOT NULL,
+                name TEXT NOT NULL,
+                schema TEXT NOT NULL,
+                PRIMARY KEY (scope, name)
+            );
+            CREATE TABLE IF NOT EXISTS access (
+                scope TEXT NOT NULL,
+                name TEXT NOT NULL,
+                acl TEXT NOT NULL,
+                PRIMARY KEY (scope, name)
+            );",
+        )
+        .expect("failed to create dataset tables");
+        Self { conn }
+    }
+
+    /// Read an entire dataset.
+    pub fn load(&self, path: &AbsoluteDatasetPath) -> Vec<DatasetData> {
+        let (scope, name) = dataset_key(path);
+        let result = self
+            .conn
+            .prepare_cached("SELECT idx, value FROM data WHERE scope = ?1 AND name = ?2 ORDER BY idx")
+            .and_then(|mut stmt| {
+                stmt.query_map(params![scope, name], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            });
+        let rows = match result {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("Failed to read dataset {:?}, using empty dataset: {}", path.parts(), e);
+                return Vec::new();
+            }
+        };
+
+        let mut dataset = Vec::with_capacity(rows.len());
+        for (idx, value) in rows {
+            // missing indices are filled with null
+            while (dataset.len() as i64) < idx {
+                dataset.push(DatasetData::Null);
+            }
+            match serde_json::from_str(&value) {
+                Ok(elem) => dataset.push(elem),
+                Err(e) => {
+                    warn!("Element {} of dataset {:?} is malformed: {}", idx, path.parts(), e);
+                    dataset.push(DatasetData::Null);
+                }
+            }
+        }
+        dataset
+    }
+
+    /// Write the items into the dataset, starting at the given index.
+    /// Existing elements are replaced.
+    pub fn set_range(&mut self, path: &AbsoluteDatasetPath, start: usize, items: &[DatasetData]) -> rusqlite::Result<()> {
+        let (scope, name) = dataset_key(path);
+        self.set_rows(&scope, &name, start, items)
+    }
+
+    fn set_rows(&mut self, scope: &str, name: &str, start: usize, items: &[DatasetData]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        insert_rows(&tx, scope, name, start, items)?;
+        tx.commit()
+    }
+
+    /// Replace every element from the given index onwards with the items,
+    /// truncating the dataset if there are fewer items. The change is made
+    /// in a single transaction.
+    pub fn replace_from(&mut self, path: &AbsoluteDatasetPath, start: usize, items: &[DatasetData]) -> rusqlite::Result<()> {
+        let (scope, name) = dataset_key(path);
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM data WHERE scope = ?1 AND name = ?2 AND idx >= ?3",
+            params![scope, name, start as i64],
+        )?;
+        insert_rows(&tx, &scope, &name, start, items)?;
+        tx.commit()
+    }
+
+    /// Remove the element at the index, shifting all succeeding elements back
+    /// by one.
+    pub fn delete(&mut self, path: &AbsoluteDatasetPath, idx: usize) -> rusqlite::Result<()> {
+        let (scope, name) = dataset_key(path);
+        let idx = idx as i64;
+        self.conn.transaction().and_then(|tx| {
+            tx.execute(
+                "DELETE FROM data WHERE scope = ?1 AND name = ?2 AND idx = ?3",
+                params![scope, name, idx],
+            )?;
+            // shift in two steps, through negative indices, so that no two
+            // rows share an index part way through the update
+            tx.execute(
+                "UPDATE data SET idx = -idx WHERE scope = ?1 AND name = ?2 AND idx > ?3",
+                params![scope, name, idx],
+            )?;
+            tx.execute(
+                "UPDATE data SET idx = -idx - 1 WHERE scope = ?1 AND name = ?2 AND idx < 0",
+                params![scope, name],
+            )?;
+            tx.commit()
+        })
+    }
+
+    /// Remove all elements from the dataset.
+    pub fn clear(&mut self, path: &AbsoluteDatasetPath) -> rusqlite::Result<()> {
+        let (scope, name) = dataset_key(path);
+        self.conn
+            .execute("DELETE FROM data WHERE scope = ?1 AND name = ?2", params![scope, name])
+            .map(|_| ())
+    }
+
+    /// Read the schema of the dataset, if it has one.
+    pub fn load_schema(&self, path: &AbsoluteDatasetPath) -> Option<DatasetSchema> {
+        let (scope, name) = dataset_key(path);
+        let value: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT schema FROM schemas WHERE scope = ?1 AND name = ?2",
+                params![scope, name],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                warn!("Failed to read schema of dataset {:?}: {}", path.parts(), e);
+                None
+            });
+        match serde_json::from_str(&value?) {
+            Ok(schema) => Some(schema),
+            Err(e) => {
+                warn!("Schema of dataset {:?} is malformed, ignoring: {}", path.parts(), e);
+                None
+            }
+        }
+    }
+
+    /// Write the schema of the dataset.
+    pub fn save_schema(&mut self, path: &AbsoluteDatasetPath, schema: &DatasetSchema) -> rusqlite::Result<()> {
+        let (scope, name) = dataset_key(path);
+        let value = serde_json::to_string(schema).expect("schema should serialize");
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO schemas (scope, name, schema) VALUES (?1, ?2, ?3)",
+                params![scope, name, value],
+            )
+            .map(|_| ())
+    }
+
+    /// Read the access set on the dataset, if any.
+    pub fn load_access(&self, path: &AbsoluteDatasetPath) -> Option<DatasetAcl> {
+        let (scope, name) = dataset_key(path);
+        let value: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT acl FROM access WHERE scope = ?1 AND name = ?2",
+                params![scope, name],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                warn!("Failed to read access of dataset {:?}: {}", path.parts(), e);
+                None
+            });
+        match serde_json::from_str(&value?) {
+            Ok(acl) => Some(acl),
+            Err(e) => {
+                // fail closed, so a damaged entry does not open the dataset
+                warn!("Access of dataset {:?} is malformed, denying writes: {}", path.parts(), e);
+                Some(DatasetAcl::deny_all())
+            }
+        }
+    }
+
+    /// Write the access of the dataset, or remove it if there is none.
+    pub fn save_access(&mut self, path: &AbsoluteDatasetPath, acl: Option<&DatasetAcl>) -> rusqlite::Result<()> {
+        let (scope, name) = dataset_key(path);
+        match acl {
+            Some(acl) => {
+                let value = serde_json::to_string(acl).expect("access should serialize");
+                self.conn.execute(
+                    "INSERT OR REPLACE INTO access (scope, name, acl) VALUES (?1, ?2, ?3)",
+                    params![scope, name, value],
+                )
+            }
+            None => self.conn.execute(
+                "DELETE FROM access WHERE scope = ?1 AND name = ?2",
+                params![scope, name],
+            ),
+        }
+        .map(|_| ())
+    }
+
+    /// Import the datasets and schemas stored as json files by older versions,
+    /// this only happens once. The files are left in place.
+    fn migrate(&mut self, dataset_path: &Path) {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .expect("failed to read database version");
+        if version >= 1 {
+            return;
+        }
+
+        let mut files = Vec::new();
+        find_files(dataset_path, &mut files);
+        for file in files {
+            // files are stored as <scope>/<parts...>.<extension>
+            let relative = match file.strip_prefix(dataset_path) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            let mut parts: Vec<String> = relative
+                .with_extension("")
+                .iter()
+                .map(|part| part.to_string_lossy().to_string())
+                .collect();
+            if parts.len() < 2 {
+                continue; // not a dataset file
+            }
+            let scope = parts.remove(0);
+            let name = name_key(&parts);
+
+            match file.extension().and_then(|ext| ext.to_str()) {
+                Some("dat") => {
+                    let dataset = parse_dataset(&file);
+                    if let Err(e) = self.set_rows(&scope, &name, 0, &dataset) {
+                        warn!("Failed to import dataset {}: {}", file.display(), e);
+                    }
+                }
+                Some("schema") => {
+                    let schema = fs::read_to_string(&file)
+                        .ok()
+                        .and_then(|data| serde_json::from_str::<DatasetSchema>(&data).ok());
+                    if let Some(schema) = schema {
+                        let value = serde_json::to_string(&schema).expect("schema should serialize");
+                        let result = self.conn.execute(
+                            "INSERT OR REPLACE INTO schemas (scope, name, schema) VALUES (?1, ?2, ?3)",
+                            params![scope, name, value],
+                        );
+                        if let Err(e) = result {
+                            warn!("Failed to import schema {}: {}", file.display(), e);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.conn
+            .execute_batch("PRAGMA user_version = 1")
+            .expect("failed to write database version");
+    }
+}
+
+fn insert_rows(tx: &Transaction, scope: &str, name: &str, start: usize, items: &[DatasetData]) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT OR REPLACE INTO data (scope, name, idx, value) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (i, item) in items.iter().enumerate() {
+        let value = serde_json::to_string(item).expect("dataset data should serialize");
+        stmt.execute(params![scope, name, (start + i) as i64, value])?;
+    }
+    Ok(())
+}
+
+/// Get the scope and name used to store a dataset.
+fn dataset_key(path: &AbsoluteDatasetPath) -> (String, String) {
+    let scope = match path.scope() {
+        AbsoluteDatasetScope::Peripheral(id) => id.sha256(),
+        AbsoluteDatasetScope::Public => String::from("public"),
+    };
+    (scope, name_key(path.parts()))
+}
+
+fn name_key(parts: &[String]) -> String {
+    serde_json::to_string(parts).expect("path should serialize")
+}
+
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Read a dataset stored as a json file, a malformed file (such as one
+/// that was only partially written) is read as an empty dataset.
+fn parse_dataset(path: &Path) -> Vec<DatasetData> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Failed to read dataset {}, using empty dataset: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    if data.is_empty() {
+        return Vec::new();
+    }
+    match serde_json::from_str(&data) {
+        Ok(dataset) => dataset,
+        Err(e) => {
+            warn!("Dataset {} is malformed, using empty dataset: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs, time::Instant};
+
+    use rusqlite::Connection;
+    use spider_link::{message::{AbsoluteDatasetPath, DatasetAccess, DatasetData}, Role, SelfRelation};
+
+    use super::{DatasetAcl, DatasetStorage};
+
+    fn memory_storage() -> DatasetStorage {
+        DatasetStorage::from_connection(Connection::open_in_memory().unwrap())
+    }
+
+    #[test]
+    fn element_operations() {
+        let mut storage = memory_storage();
+        let path = AbsoluteDatasetPath::new_public(vec!["test".into()]);
+        let items: Vec<DatasetData> = (0..5).map(DatasetData::Int).collect();
+        storage.set_range(&path, 0, &items).unwrap();
+        storage.delete(&path, 1).unwrap();
+        storage.set_range(&path, 4, &[DatasetData::Int(10)]).unwrap();
+        let expected: Vec<DatasetData> = [0, 2, 3, 4, 10].into_iter().map(DatasetData::Int).collect();
+        assert_eq!(storage.load(&path), expected);
+
+        storage.clear(&path).unwrap();
+        assert!(storage.load(&path).is_empty());
+    }
+
+    #[test]
+    fn replace_from() {
+        let mut storage = memory_storage();
+        let path = AbsoluteDatasetPath::new_public(vec!["test".into()]);
+        let items: Vec<DatasetData> = (0..5).map(DatasetData::Int).collect();
+        storage.set_range(&path, 0, &items).unwrap();
+
+        // fewer items truncate the dataset
+        storage.replace_from(&path, 2, &[DatasetData::Int(10)]).unwrap();
+        let expected: Vec<DatasetData> = [0, 1, 10].into_iter().map(DatasetData::Int).collect();
+        assert_eq!(storage.load(&path), expected);
+
+        // more items extend it
+        storage.replace_from(&path, 3, &[DatasetData::Int(11), DatasetData::Int(12)]).unwrap();
+        let expected: Vec<DatasetData> = [0, 1, 10, 11, 12].into_iter().map(DatasetData::Int).collect();
+        assert_eq!(storage.load(&path), expected);
+    }
+
+    #[test]
+    fn access() {
+        let mut storage = memory_storage();
+        let path = AbsoluteDatasetPath::new_public(vec!["test".into()]);
+        assert_eq!(storage.load_access(&path), None);
+
+        let acl = DatasetAcl {
+            owner: SelfRelation::generate_key(Role::Peripheral).relation,
+            access: DatasetAccess::OwnerOnly,
+        };
+        storage.save_access(&path, Some(&acl)).unwrap();
+        assert_eq!(storage.load_access(&path), Some(acl));
+
+        storage.save_access(&path, None).unwrap();
+        assert_eq!(storage.load_access(&path), None);
+    }
+
+    #[test]
+    fn migrate_json_files() {
+        let dir = temp_dir().join("spider_dataset_migration");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("public").join("nested")).unwrap();
+        fs::write(dir.join("public").join("nested").join("good.dat"), br#"[{"Int":1},{"Int":2}]"#).unwrap();
+        // simulate a crash partway through writing the file
+        fs::write(dir.join("public").join("partial.dat"), br#"[{"Int":1},{"In"#).unwrap();
+
+        let storage = DatasetStorage::open(&dir);
+        let good = AbsoluteDatasetPath::new_public(vec!["nested".into(), "good".into()]);
+        assert_eq!(storage.load(&good), vec![DatasetData::Int(1), DatasetData::Int(2)]);
+        let partial = AbsoluteDatasetPath::new_public(vec!["partial".into()]);
+        assert!(storage.load(&partial).is_empty());
+        drop(storage);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Compares appending to a 100k element dataset with the previous json
+    /// file storage and with sqlite. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_large_dataset() {
+        let dir = temp_dir().join("spider_dataset_bench");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut dataset: Vec<DatasetData> = (0..100_000).map(DatasetData::Int).collect();
+        let appends = 100;
+
+        // json: every append rewrites the whole file
+        let file = dir.join("bench.dat");
+        fs::write(&file, serde_json::to_string(&dataset).unwrap()).unwrap();
+        let start = Instant::now();
+        for i in 0..appends {
+            let mut data: Vec<DatasetData> = serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+            data.push(DatasetData::Int(i));
+            fs::write(&file, serde_json::to_string(&data).unwrap()).unwrap();
+        }
+        let json_time = start.elapsed();
+
+        // sqlite: every append writes a single row
+        let mut storage = DatasetStorage::open(&dir);
+        let path = AbsoluteDatasetPath::new_public(vec!["bench_sqlite".into()]);
+        storage.set_range(&path, 0, &dataset).unwrap();
+        let start = Instant::now();
+        for i in 0..appends {
+            storage.set_range(&path, dataset.len(), &[DatasetData::Int(i)]).unwrap();
+            dataset.push(DatasetData::Int(i));
+        }
+        let sqlite_time = start.elapsed();
+        drop(storage);
+
+        println!("{} appends to 100k elements: json {:?}, sqlite {:?}", appends, json_time, sqlite_time);
+        assert!(sqlite_time < json_time);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
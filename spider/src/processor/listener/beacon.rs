@@ -1,23 +1,45 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::{Ipv4Addr, SocketAddr}, str::FromStr};
 
+use spider_link::beacon::beacon_reply_format;
 use tokio::{net::UdpSocket, task::JoinHandle};
+use tracing::{error, warn};
 
-use crate::config::SpiderConfig;
+use crate::{config::SpiderConfig, state_data::StateData};
 
-pub(crate) fn start_beacon(config: &SpiderConfig) -> JoinHandle<()> {
+pub(crate) fn start_beacon(config: &SpiderConfig, state: StateData) -> JoinHandle<()> {
     let listen_addr = config.listen_addr.clone();
     let port = match SocketAddr::from_str(&listen_addr) {
         Ok(addr) => addr.port(),
         Err(_) => 1930u16,
     };
+    let beacon_port = config.beacon_port;
+    let beacon_group = config.beacon_group;
     tokio::spawn(async move {
         let mut buf = [0; 1024];
+        let id = state.self_id().await;
 
-        let socket = UdpSocket::bind("0.0.0.0:1930").await.unwrap();
-        // socket.
+        let socket = match UdpSocket::bind(("0.0.0.0", beacon_port)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Beacon failed to bind port {}: {}", beacon_port, e);
+                return;
+            }
+        };
+        if let Some(group) = beacon_group {
+            // probes sent directly to the beacon are still answered
+            if let Err(e) = socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED) {
+                error!("Beacon failed to join multicast group {}: {}", group, e);
+            }
+        }
         loop {
             println!("probe looping");
-            let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+            let (size, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Beacon failed to receive a probe: {}", e);
+                    continue;
+                }
+            };
 
             println!("probe recieved: {} bytes from {}", size, from);
             let msg = &mut buf[..size];
@@ -30,12 +52,13 @@ pub(crate) fn start_beacon(config: &SpiderConfig) -> JoinHandle<()> {
                 // it isnt always clear what the address of this device is,
                 // if it is listening on 0.0.0.0.
                 // let the other side get the address from the reply, but send
-                // the port number to connect to.
-                let reply = format!("SPIDER_REPLY:{}", port);
-                socket
-                    .send_to(&reply.as_bytes().to_vec(), addr)
-                    .await
-                    .unwrap();
+                // the port number to connect to, along with this base's
+                // id and name so that the other side can tell bases apart.
+                let name = state.name().await.clone();
+                let reply = beacon_reply_format(port, &id, &name);
+                if let Err(e) = socket.send_to(reply.as_bytes(), addr).await {
+                    warn!("Beacon failed to reply to {}: {}", addr, e);
+                }
             }
         }
     })
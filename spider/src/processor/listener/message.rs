@@ -1,6 +1,9 @@
 #[derive(Debug)]
 pub enum ListenProcessorMessage {
     SetKeyRequest(Option<String>),
+    /// Stop listening on the listen address, and listen on this one instead.
+    Rebind(String),
 
     Upkeep,
+    Shutdown,
 }
@@ -1,13 +1,18 @@
+use std::{io, sync::Arc};
+
 use tokio::select;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
 use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, info, warn};
 
 use crate::processor::ProcessorMessage;
 use crate::{config::SpiderConfig, state_data::StateData};
-use spider_link::link::Link;
+use spider_link::{link::Link, message::UiInput};
 
 use super::router::RouterProcessorMessage;
+use super::ui::UiProcessorMessage;
 use super::sender::ProcessorSender;
 
 mod beacon;
@@ -23,7 +28,7 @@ pub struct ListenerProcessor {
 impl ListenerProcessor {
     pub fn new(config: SpiderConfig, state: StateData, sender: ProcessorSender) -> Self {
         // start beacon
-        let beacon = beacon::start_beacon(&config);
+        let beacon = beacon::start_beacon(&config, state.clone());
 
         let (listen_sender, listen_receiver) = channel(50);
         let processor = ListenProcessorState::new(config, state, sender, listen_receiver);
@@ -43,6 +48,7 @@ impl ListenerProcessor {
     }
 
     pub async fn join(self) -> Result<(), JoinError> {
+        self.beacon.abort();
         self.handle.await
     }
 }
@@ -52,6 +58,15 @@ struct ListenProcessorState {
     state: StateData,
     sender: ProcessorSender,
     receiver: Receiver<ListenProcessorMessage>,
+
+    // every listener sends its links through the same channel
+    links_tx: Sender<Link>,
+    links_rx: Receiver<Link>,
+    key_request: Arc<Mutex<Option<String>>>,
+    // the listener on config.listen_addr, which can be rebound
+    listen_addr: String,
+    primary: Option<JoinHandle<()>>,
+    extras: Vec<JoinHandle<()>>,
 }
 
 impl ListenProcessorState {
@@ -61,21 +76,44 @@ impl ListenProcessorState {
         sender: ProcessorSender,
         receiver: Receiver<ListenProcessorMessage>,
     ) -> Self {
+        let (links_tx, links_rx) = channel(50);
+        let listen_addr = config.listen_addr.clone();
         Self {
             config,
             state,
             sender,
             receiver,
+
+            links_tx,
+            links_rx,
+            key_request: Arc::new(Mutex::new(None)),
+            listen_addr,
+            primary: None,
+            extras: Vec::new(),
         }
     }
 
     pub fn start(mut self) -> JoinHandle<()> {
-        let listen_addr = self.config.listen_addr.clone();
         tokio::spawn(async move {
-            let self_relation = self.state.self_relation().await;
-            let broadcast_name = self.state.name().await.clone();
-            let (mut listener, broadcast_setting) = Link::listen(self_relation, listen_addr);
-            *broadcast_setting.lock().await = Some(broadcast_name);
+            *self.key_request.lock().await = Some(self.state.name().await.clone());
+            let listen_addr = self.listen_addr.clone();
+            match self.bind(&listen_addr).await {
+                Ok(handle) => {
+                    self.primary = Some(handle);
+                    self.listen_status(format!("Listening on {}", listen_addr)).await;
+                }
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", listen_addr, e);
+                    self.listen_status(format!("Could not listen on {}: {}", listen_addr, e)).await;
+                }
+            }
+            for addr in self.config.extra_listen_addrs.clone() {
+                match self.bind(&addr).await {
+                    Ok(handle) => self.extras.push(handle),
+                    Err(e) => error!("Failed to listen on {}: {}", addr, e),
+                }
+            }
+
             loop {
                 select! {
                     // Process Channel
@@ -84,10 +122,16 @@ impl ListenProcessorState {
                             Some(msg) => {
                                 match msg{
                                     ListenProcessorMessage::SetKeyRequest(key_request) => {
-                                        *broadcast_setting.lock().await = key_request;
+                                        *self.key_request.lock().await = key_request;
+                                    },
+                                    ListenProcessorMessage::Rebind(addr) => {
+                                        self.rebind(addr).await;
                                     },
                                     ListenProcessorMessage::Upkeep => {
                                     },
+                                    ListenProcessorMessage::Shutdown => {
+                                        break; // stop accepting new links
+                                    },
                                 }
                             },
                             None => {
@@ -96,7 +140,7 @@ impl ListenProcessorState {
                         }
                     },
                     // Process Listener
-                    link = listener.recv() => {
+                    link = self.links_rx.recv() => {
                         match link {
                             None => {
                                 break; // no new link, listener is closed
@@ -115,6 +159,137 @@ impl ListenProcessorState {
                     }
                 }
             }
+
+            // stop accepting new links
+            for handle in self.primary.take().into_iter().chain(self.extras.drain(..)) {
+                handle.abort();
+                handle.await.ok();
+            }
         })
     }
+
+    /// Start a listener on the address, sending its links to this processor.
+    async fn bind(&self, addr: &str) -> io::Result<JoinHandle<()>> {
+        let self_relation = self.state.self_relation().await;
+        Link::listen_on(
+            self_relation,
+            addr,
+            self.links_tx.clone(),
+            self.key_request.clone(),
+            self.config.max_frame_len,
+        )
+        .await
+    }
+
+    /// Replace the listener on the listen address with one on a new
+    /// address. If the new address cannot be bound, the previous address
+    /// is bound again.
+    async fn rebind(&mut self, addr: String) {
+        // the old listener must be closed first, in case the new address
+        // overlaps it
+        if let Some(handle) = self.primary.take() {
+            handle.abort();
+            handle.await.ok();
+        }
+        match self.bind(&addr).await {
+            Ok(handle) => {
+                info!("Listening on {}", addr);
+                self.primary = Some(handle);
+                self.listen_addr = addr.clone();
+                self.listen_status(format!("Listening on {}", addr)).await;
+            }
+            Err(e) => {
+                warn!("Failed to listen on {}: {}", addr, e);
+                let old_addr = self.listen_addr.clone();
+                let status = match self.bind(&old_addr).await {
+                    Ok(handle) => {
+                        self.primary = Some(handle);
+                        format!("Could not listen on {}: {}, still listening on {}", addr, e, old_addr)
+                    }
+                    Err(old_e) => {
+                        error!("Failed to listen on {}: {}", old_addr, old_e);
+                        format!("Could not listen on {}: {}, not listening", addr, e)
+                    }
+                };
+                self.listen_status(status).await;
+            }
+        }
+    }
+
+    /// Show the listener status in the settings, with an entry to change
+    /// the listen address.
+    async fn listen_status(&mut self, status: String) {
+        let msg = UiProcessorMessage::SetSetting {
+            header: String::from("System"),
+            title: String::from("Listen Address:"),
+            inputs: vec![
+                ("text".to_string(), status),
+                ("textentry".to_string(), "New Address".to_string()),
+            ],
+            cb: |_, _, input, _| {
+                match input {
+                    UiInput::Text(addr) => {
+                        let msg = ListenProcessorMessage::Rebind(addr);
+                        Some(ProcessorMessage::ListenerMessage(msg))
+                    }
+                    _ => None,
+                }
+            },
+            data: String::new(),
+        };
+        self.sender.send_ui(msg).await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use tokio::{net::TcpStream, sync::mpsc::{channel, Receiver}};
+
+    use crate::{
+        config::SpiderConfig,
+        processor::{sender::ProcessorSender, ui::UiProcessorMessage, ProcessorMessage},
+        state_data::StateData,
+    };
+
+    use super::{ListenProcessorMessage, ListenProcessorState};
+
+    /// Wait for the next listener status shown in the settings.
+    async fn status(receiver: &mut Receiver<ProcessorMessage>) -> String {
+        loop {
+            match receiver.recv().await.expect("processor closed") {
+                ProcessorMessage::UiMessage(UiProcessorMessage::SetSetting { title, mut inputs, .. })
+                    if title == "Listen Address:" => return inputs.remove(0).1,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rebind() {
+        let config: SpiderConfig = serde_json::from_str(r#"{"listen_addr": "127.0.0.1:1937"}"#).unwrap();
+        let state = StateData::with_generated_key(&temp_dir().join("spider_listener_test_state"));
+        let (sender, mut receiver) = channel(50);
+        let (control, control_receiver) = channel(50);
+        let handle = ListenProcessorState::new(config, state, ProcessorSender::new(sender), control_receiver).start();
+        assert_eq!(status(&mut receiver).await, "Listening on 127.0.0.1:1937");
+
+        // the old address is closed and the new one is open
+        control.send(ListenProcessorMessage::Rebind(String::from("127.0.0.1:1938"))).await.unwrap();
+        assert_eq!(status(&mut receiver).await, "Listening on 127.0.0.1:1938");
+        assert!(TcpStream::connect("127.0.0.1:1937").await.is_err());
+        assert!(TcpStream::connect("127.0.0.1:1938").await.is_ok());
+
+        // a failed rebind keeps the current address
+        control.send(ListenProcessorMessage::Rebind(String::from("not an address"))).await.unwrap();
+        let failed = status(&mut receiver).await;
+        assert!(failed.starts_with("Could not listen on not an address"));
+        assert!(failed.ends_with("still listening on 127.0.0.1:1938"));
+        assert!(TcpStream::connect("127.0.0.1:1938").await.is_ok());
+
+        control.send(ListenProcessorMessage::Shutdown).await.unwrap();
+        handle.await.unwrap();
+        assert!(TcpStream::connect("127.0.0.1:1938").await.is_err());
+    }
 }
@@ -17,4 +17,5 @@ pub enum ProcessorMessage {
     DatasetMessage(DatasetProcessorMessage),
     PeripheralMessage(PeripheralProcessorMessage),
     Upkeep,
+    Shutdown,
 }
@@ -4,7 +4,9 @@ use std::{path::Path, time::Duration};
 
 use spider_link::Keyfile;
 use spider_link::message::Message;
+use tracing::{info, warn};
 use tokio::{
+    select,
     sync::mpsc::{channel, Receiver},
     task::{JoinError, JoinHandle},
     time::interval,
@@ -23,7 +25,7 @@ use router::RouterProcessor;
 pub use router::ChordState;
 
 mod message;
-use message::ProcessorMessage;
+pub(crate) use message::ProcessorMessage;
 
 mod ui;
 use ui::{UiProcessor, UiProcessorMessage};
@@ -193,8 +195,8 @@ impl Processor {
                 header: String::from("System"),
                 title: String::from("Exit!"),
                 inputs: vec![("button".to_string(), "Exit".to_string())],
-                cb: |idx, title, input, _|{
-                    std::process::exit(0);
+                cb: |_, _, _, _|{
+                    Some(ProcessorMessage::Shutdown)
                 },
                 data: String::new(),
             };
@@ -264,16 +266,59 @@ impl Processor {
                         self.dataset_processor.send(DatasetProcessorMessage::Upkeep).await;
                         self.router.send(RouterProcessorMessage::Upkeep).await;
                         self.peripherals.send(PeripheralProcessorMessage::Upkeep).await;
-                        self.state.save_file().await;
+                        // a failed save is tried again at the next upkeep
+                        if let Err(e) = self.state.save_file().await {
+                            warn!("Failed to save state: {}", e);
+                        }
                     }
+                    ProcessorMessage::Shutdown => break,
                 }
             }
+
+            self.shutdown().await;
         });
 
         ProcessorHandle { sender, handle }
     }
 }
 
+impl Processor {
+    /// Stop each of the sub-processors, waiting for them to save their
+    /// state, then save the state file.
+    async fn shutdown(mut self) {
+        info!("Shutting down");
+        self.upkeep_interval_handle.abort();
+
+        self.listener.send(ListenProcessorMessage::Shutdown).await;
+        self.peripherals.send(PeripheralProcessorMessage::Shutdown).await;
+        self.router.send(RouterProcessorMessage::Shutdown).await;
+        self.ui.send(UiProcessorMessage::Shutdown).await;
+        self.dataset_processor.send(DatasetProcessorMessage::Shutdown).await;
+
+        let Processor { state, mut receiver, listener, router, peripherals, ui, dataset_processor, .. } = self;
+        let joined = async move {
+            let _ = listener.join().await;
+            let _ = peripherals.join().await;
+            let _ = router.join().await;
+            let _ = ui.join().await;
+            let _ = dataset_processor.join().await;
+        };
+        tokio::pin!(joined);
+        // keep receiving while the sub-processors finish, so that none of
+        // them block sending to a full channel
+        loop {
+            select! {
+                _ = &mut joined => break,
+                _ = receiver.recv() => {}
+            }
+        }
+
+        if let Err(e) = state.save_file().await {
+            warn!("Failed to save state: {}", e);
+        }
+    }
+}
+
 pub struct ProcessorHandle {
     sender: ProcessorSender,
     handle: JoinHandle<()>,
@@ -284,6 +329,12 @@ impl ProcessorHandle {
         self.sender.send(message).await;
     }
 
+    /// Get a sender for the processor, which can be used while waiting for
+    /// it to finish.
+    pub(crate) fn sender(&self) -> ProcessorSender {
+        self.sender.clone()
+    }
+
     pub async fn join(self) -> Result<(), JoinError> {
         self.handle.await
     }
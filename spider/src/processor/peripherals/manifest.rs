@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 
 use serde::Deserialize;
 use tokio::fs;
@@ -21,11 +21,32 @@ pub enum LaunchConfig{
     Cargo,
 }
 
+/// Limits on the resources a service may use. These are best effort: they
+/// are only applied on unix, a nice level lower than the base's requires
+/// privileges and is skipped if it cannot be set, and the memory cap limits
+/// the service's address space, which may be larger than its actual use.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct ResourceLimits{
+    /// The nice level to run the service at, from -20 (highest priority)
+    /// to 19 (lowest).
+    pub nice: Option<i32>,
+    /// The most memory the service may use, in megabytes.
+    pub memory_mb: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PeripheralManifest{
     #[serde(default)]
     build: Option<BuildConfig>,
     launch: LaunchConfig,
+    /// Environment variables set for the service.
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Arguments passed to the service.
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    limits: ResourceLimits,
 }
 
 
@@ -40,55 +61,100 @@ impl PeripheralManifest{
         
 
         match data{
-            Ok(data) => {
-                match toml::from_str(&data){
-                    Ok(val) => {
-                        let val: Value = val;
-
-
-                        let build = match val.get("build"){
-                            Some(build_val) => {
-                                match build_val{
-                                    Value::String(s) if s == "cargo" => {
-                                        Some(BuildConfig::Cargo)
-                                    },
-                                    _ => None
+            Ok(data) => Self::parse(&data),
+            Err(_) => None,
+        }
+    }
+
+    /// Parse the contents of a manifest. The launch table may also contain
+    /// `args`, a list of arguments, and `env`, a table of environment
+    /// variables, both of which default to empty. An optional limits table
+    /// sets the service's [ResourceLimits].
+    pub fn parse(data: &str) -> Option<Self>{
+        match toml::from_str(data){
+            Ok(val) => {
+                let val: Value = val;
+
+
+                let build = match val.get("build"){
+                    Some(build_val) => {
+                        match build_val{
+                            Value::String(s) if s == "cargo" => {
+                                Some(BuildConfig::Cargo)
+                            },
+                            _ => None
+                        }
+                    },
+                    None => None,
+                };
+                let launch = match val.get("launch")?{
+                    Value::Table(launch_table) => {
+                        match launch_table.get("method")? {
+                            Value::String(method) if method == "exe" => {
+                                if let Value::String(path) = launch_table.get("path")?{
+                                    LaunchConfig::Exe(path.to_string())
+                                }else{
+                                    return None;
                                 }
                             },
-                            None => None,
-                        };
-                        let launch = match val.get("launch")?{
-                            Value::Table(launch_table) => {
-                                match launch_table.get("method")? {
-                                    Value::String(method) if method == "exe" => {
-                                        if let Value::String(path) = launch_table.get("path")?{
-                                            LaunchConfig::Exe(path.to_string())
-                                        }else{
-                                            return None;
-                                        }
-                                    },
-                                    Value::String(method) if method == "python" => {
-                                        if let Value::String(path) = launch_table.get("path")?{
-                                            LaunchConfig::Python(path.to_string())
-                                        }else{
-                                            return None;
-                                        }
-                                    },
-                                    Value::String(method) if method == "cargo" => {
-                                        LaunchConfig::Cargo
-                                    },
-                                    _ => return None,
+                            Value::String(method) if method == "python" => {
+                                if let Value::String(path) = launch_table.get("path")?{
+                                    LaunchConfig::Python(path.to_string())
+                                }else{
+                                    return None;
                                 }
                             },
+                            Value::String(method) if method == "cargo" => {
+                                LaunchConfig::Cargo
+                            },
                             _ => return None,
+                        }
+                    },
+                    _ => return None,
+                };
+                let launch_table = val.get("launch")?.as_table()?;
+                let args = match launch_table.get("args"){
+                    Some(Value::Array(args)) => {
+                        args.iter()
+                            .map(|arg| arg.as_str().map(String::from))
+                            .collect::<Option<Vec<_>>>()?
+                    },
+                    Some(_) => return None,
+                    None => Vec::new(),
+                };
+                let env = match launch_table.get("env"){
+                    Some(Value::Table(env)) => {
+                        env.iter()
+                            .map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                            .collect::<Option<HashMap<_, _>>>()?
+                    },
+                    Some(_) => return None,
+                    None => HashMap::new(),
+                };
+                let limits = match val.get("limits"){
+                    Some(Value::Table(limits)) => {
+                        let nice = match limits.get("nice"){
+                            Some(Value::Integer(nice)) => Some((*nice).clamp(-20, 19) as i32),
+                            Some(_) => return None,
+                            None => None,
+                        };
+                        let memory_mb = match limits.get("memory_mb"){
+                            Some(Value::Integer(memory)) => Some(u64::try_from(*memory).ok()?),
+                            Some(_) => return None,
+                            None => None,
                         };
-                        Some(PeripheralManifest{
-                            build,
-                            launch,
-                        })
+                        ResourceLimits{ nice, memory_mb }
                     },
-                    Err(_) => None,
-                }
+                    Some(_) => return None,
+                    None => ResourceLimits::default(),
+                };
+                Some(PeripheralManifest{
+                    build,
+                    launch,
+                    env,
+                    args,
+                    limits,
+                })
             },
             Err(_) => None,
         }
@@ -102,4 +168,75 @@ impl PeripheralManifest{
     pub fn launch(&self) -> &LaunchConfig{
         &self.launch
     }
+
+    pub fn env(&self) -> &HashMap<String, String>{
+        &self.env
+    }
+
+    pub fn args(&self) -> &Vec<String>{
+        &self.args
+    }
+
+    pub fn limits(&self) -> &ResourceLimits{
+        &self.limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LaunchConfig, PeripheralManifest, ResourceLimits};
+
+    #[test]
+    fn env_and_args() {
+        let data = r#"
+            [launch]
+            method = "exe"
+            path = "service"
+            args = ["--verbose", "--port", "2000"]
+
+            [launch.env]
+            RUST_LOG = "debug"
+            SERVICE_MODE = "peripheral"
+        "#;
+        let manifest = PeripheralManifest::parse(data).unwrap();
+        assert!(matches!(manifest.launch(), LaunchConfig::Exe(path) if path == "service"));
+        assert_eq!(manifest.args(), &vec!["--verbose", "--port", "2000"]);
+        assert_eq!(manifest.env().get("RUST_LOG").map(String::as_str), Some("debug"));
+        assert_eq!(manifest.env().len(), 2);
+    }
+
+    #[test]
+    fn defaults() {
+        let data = r#"
+            [launch]
+            method = "cargo"
+        "#;
+        let manifest = PeripheralManifest::parse(data).unwrap();
+        assert!(manifest.args().is_empty());
+        assert!(manifest.env().is_empty());
+        assert_eq!(manifest.limits(), &ResourceLimits::default());
+
+        // non-string arguments make the manifest invalid
+        let data = r#"
+            [launch]
+            method = "cargo"
+            args = [1]
+        "#;
+        assert!(PeripheralManifest::parse(data).is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let data = r#"
+            [launch]
+            method = "cargo"
+
+            [limits]
+            nice = 30
+            memory_mb = 256
+        "#;
+        let manifest = PeripheralManifest::parse(data).unwrap();
+        // nice levels are clamped to the valid range
+        assert_eq!(manifest.limits(), &ResourceLimits { nice: Some(19), memory_mb: Some(256) });
+    }
 }
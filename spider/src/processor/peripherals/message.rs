@@ -6,5 +6,11 @@ pub enum PeripheralProcessorMessage {
     Start(String),
     Stop(String),
     Remove(String),
+    /// Fetch the latest version of a service's ref, and relaunch it.
+    Update(String),
+    /// Load the last lines of a service's stdout and stderr into the
+    /// private dataset ["logs", name], and show them on the settings page.
+    TailLogs(String, usize),
     Upkeep,
+    Shutdown,
 }
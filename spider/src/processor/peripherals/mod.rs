@@ -1,9 +1,10 @@
 
 use std::collections::HashMap;
 
-use crate::{config::SpiderConfig, state_data::StateData};
+use crate::{config::SpiderConfig, state_data::{PeripheralService, StateData}};
 
-use super::{sender::ProcessorSender, ui::UiProcessorMessage, message::ProcessorMessage};
+use super::{sender::ProcessorSender, ui::UiProcessorMessage, message::ProcessorMessage, dataset::DatasetProcessorMessage};
+use spider_link::message::{DatasetData, DatasetMessage, DatasetPath};
 
 mod message;
 pub use message::PeripheralProcessorMessage;
@@ -11,8 +12,9 @@ pub use message::PeripheralProcessorMessage;
 mod manifest;
 
 mod utils;
+use utils::{split_git_ref, tail_file, validate_git_address, MAX_LOG_TAIL_LINES};
 
-use regex::Regex;
+use tracing::{info, warn};
 
 use tokio::{
     sync::mpsc::{channel, error::SendError, Receiver, Sender},
@@ -97,8 +99,14 @@ impl PeripheralProcessorState{
                     PeripheralProcessorMessage::Start(name) => self.start_service(name).await,
                     PeripheralProcessorMessage::Stop(name) => self.stop_service(name).await,
                     PeripheralProcessorMessage::Remove(name) => self.uninstall_service(name).await,
-
-                    PeripheralProcessorMessage::Upkeep => {}
+                    PeripheralProcessorMessage::Update(name) => self.update_service(name).await,
+                    PeripheralProcessorMessage::TailLogs(name, lines) => self.tail_logs(name, lines).await,
+
+                    PeripheralProcessorMessage::Upkeep => self.reap_children().await,
+                    PeripheralProcessorMessage::Shutdown => {
+                        self.stop_children().await;
+                        break;
+                    }
                 }
             }
         });
@@ -114,6 +122,8 @@ impl PeripheralProcessorState{
             cb: |idx, name, input, _|{
                 match input{
                     spider_link::message::UiInput::Click => None,
+                    spider_link::message::UiInput::Bool(_) => None,
+                    spider_link::message::UiInput::Number(_) => None,
                     spider_link::message::UiInput::Text(addr) => {
                         let peripheral_msg = PeripheralProcessorMessage::Install(addr);
                         let msg = ProcessorMessage::PeripheralMessage(peripheral_msg);
@@ -128,8 +138,8 @@ impl PeripheralProcessorState{
         // iterate and launch
         let ps = self.state.peripheral_services().await;
         let mut x = Vec::new();
-        for (name, status) in ps.iter(){
-            x.push((name.clone(), *status));
+        for (name, service) in ps.iter(){
+            x.push((name.clone(), service.running));
         }
         drop(ps);
         for (name, status) in x{
@@ -152,28 +162,42 @@ impl PeripheralProcessorState{
     async fn install_service(&mut self, addr: String){
         println!("========== Installing! ============\n{}", addr);
         // parse addr
-        let re = Regex::new(r"/([^/]*?)(\.git)?$").unwrap();
-        let name = match re.captures(&addr){
-            Some(captures) => {
-                match captures.get(1){
-                    Some(g1) => {
-                        g1.as_str().to_string()
-                    },
-                    None => return,
-                }
+        let (addr, git_ref) = match split_git_ref(&addr){
+            Ok((addr, git_ref)) => (addr.to_string(), git_ref),
+            Err(reason) => {
+                self.install_error(reason).await;
+                return;
+            },
+        };
+        let name = match validate_git_address(&addr){
+            Ok(name) => name,
+            Err(reason) => {
+                self.install_error(reason).await;
+                return;
             },
-            None => return ,
         };
         println!("package name: {}", name);
 
+        if self.state.peripheral_services().await.contains_key(&name) {
+            self.install_error(format!("{} is already installed", name)).await;
+            return;
+        }
+
         let path = self.get_service_directory(&name);
         println!("Produced path: {}", path.display());
         // create directory
-        create_dir_all(path.clone()).await.unwrap();
+        if let Err(e) = create_dir_all(path.clone()).await {
+            self.install_error(format!("could not create directory: {}", e)).await;
+            return;
+        }
 
         // launch git in directory
         println!("launching git...");
-        self.download_with_git(&path, &addr).await;
+        if !self.download_with_git(&path, &addr, git_ref.as_deref()).await {
+            let _ = remove_dir_all(&path).await;
+            self.install_error(format!("could not clone {}", addr)).await;
+            return;
+        }
 
         // copy keyfile into directory
         println!("writing keyfile...");
@@ -181,7 +205,12 @@ impl PeripheralProcessorState{
 
         // list process in state file
         let mut ps = self.state.peripheral_services().await;
-        ps.insert(name.clone(), true);
+        let service = PeripheralService {
+            running: true,
+            addr: Some(addr),
+            git_ref,
+        };
+        ps.insert(name.clone(), service);
         drop(ps);
 
         // launch peripheral as sub-process
@@ -199,9 +228,9 @@ impl PeripheralProcessorState{
 
     async fn start_service(&mut self, name: String){
         match self.state.peripheral_services().await.get_mut(&name){
-            Some(running) if *running == false => {
+            Some(service) if !service.running => {
                 // set to running
-                *running = true;
+                service.running = true;
             },
             _ => {
                 return; // not installed, or already running
@@ -217,11 +246,84 @@ impl PeripheralProcessorState{
         }
     }
 
+    /// Stop every running peripheral service without marking them as
+    /// stopped, so they are started again with the base.
+    async fn stop_children(&mut self){
+        for (name, mut child) in self.children.drain() {
+            if let Err(e) = child.kill().await {
+                warn!("Failed to stop peripheral service {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Replace the contents of the private dataset ["logs", name] with the
+    /// last lines of the service's stdout and stderr. Each element is a map
+    /// with the "stream" the line came from and the "line" itself. The logs
+    /// are then shown on the settings page.
+    async fn tail_logs(&mut self, name: String, lines: usize){
+        if !self.state.peripheral_services().await.contains_key(&name) {
+            return; // not installed
+        }
+        let lines = lines.min(MAX_LOG_TAIL_LINES);
+        let path = self.get_service_directory(&name);
+
+        let mut data = Vec::new();
+        for stream in ["stdout", "stderr"] {
+            let stream_lines = match tail_file(&path.join(stream), lines).await {
+                Ok(stream_lines) => stream_lines,
+                Err(e) => {
+                    warn!("Failed to read {} of peripheral service {}: {}", stream, name, e);
+                    continue;
+                }
+            };
+            for line in stream_lines {
+                let mut map = HashMap::new();
+                map.insert("stream".to_string(), DatasetData::String(stream.to_string()));
+                map.insert("line".to_string(), DatasetData::String(line));
+                data.push(DatasetData::Map(map));
+            }
+        }
+
+        // replace the lines in one batch, so the logs are never seen empty
+        let rel = self.state.self_relation().await.relation;
+        let path = DatasetPath::new_private(vec!["logs".to_string(), name.clone()]);
+        let msg = DatasetMessage::Batch(vec![
+            DatasetMessage::Empty { path: path.clone() },
+            DatasetMessage::Extend { path, data },
+        ]);
+        self.sender.send_dataset(DatasetProcessorMessage::PublicMessage(rel, msg)).await;
+        self.sender.send_ui(UiProcessorMessage::ShowLogs { name }).await;
+    }
+
+    /// Find peripheral services that have exited on their own, and mark
+    /// them as stopped so that they can be started again from the settings.
+    async fn reap_children(&mut self){
+        let mut exited = Vec::new();
+        for (name, child) in self.children.iter_mut() {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    info!("Peripheral service {} exited: {}", name, status);
+                    exited.push(name.clone());
+                }
+                Ok(None) => {} // still running
+                Err(e) => warn!("Failed to check peripheral service {}: {}", name, e),
+            }
+        }
+
+        for name in exited {
+            self.children.remove(&name);
+            if let Some(service) = self.state.peripheral_services().await.get_mut(&name) {
+                service.running = false;
+            }
+            self.make_setting_entry(name, false).await;
+        }
+    }
+
     async fn stop_service(&mut self, name: String){
         match self.state.peripheral_services().await.get_mut(&name){
-            Some(running) if *running == true => {
+            Some(service) if service.running => {
                 // set to stopped
-                *running = false;
+                service.running = false;
             },
             _ => {
                 return; // not installed, or already stopped
@@ -229,12 +331,50 @@ impl PeripheralProcessorState{
         }
 
         if let Some(mut child) = self.children.remove(&name){
-            child.kill().await;
+            if let Err(e) = child.kill().await {
+                warn!("Failed to stop peripheral service {}: {}", name, e);
+            }
         }
 
         self.make_setting_entry(name, false).await;
     }
 
+    async fn update_service(&mut self, name: String){
+        let git_ref = match self.state.peripheral_services().await.get(&name){
+            Some(service) => service.git_ref.clone(),
+            None => return, // not installed
+        };
+
+        // stop child while it is updated
+        let was_running = match self.children.remove(&name) {
+            Some(mut child) => {
+                if let Err(e) = child.kill().await {
+                    warn!("Failed to stop peripheral service {} for update: {}", name, e);
+                }
+                true
+            },
+            None => false,
+        };
+
+        let path = self.get_service_directory(&name);
+        if !self.update_with_git(&path, git_ref.as_deref()).await {
+            self.install_error(format!("could not update {}", name)).await;
+        }
+
+        if was_running {
+            let child = self.launch_peripheral_service(name.clone()).await;
+            if let Some(child) = child {
+                self.children.insert(name.clone(), child);
+                return;
+            }
+            // failed to relaunch, show as stopped
+            if let Some(service) = self.state.peripheral_services().await.get_mut(&name) {
+                service.running = false;
+            }
+            self.make_setting_entry(name, false).await;
+        }
+    }
+
     async fn uninstall_service(&mut self, name: String){
         println!("========== Uninstalling! ============");
         println!("package name: {}", name);
@@ -246,11 +386,15 @@ impl PeripheralProcessorState{
         let mut ps = self.state.peripheral_services().await;
         ps.remove(&name);
         drop(ps);
-        self.state.save_file().await;
+        if let Err(e) = self.state.save_file().await {
+            warn!("Failed to save state after uninstalling {}: {}", name, e);
+        }
 
         // stop child if started
         if let Some(mut child) = self.children.remove(&name) {
-            child.kill().await;
+            if let Err(e) = child.kill().await {
+                warn!("Failed to stop peripheral service {}: {}", name, e);
+            }
         }
 
         // remove folder
@@ -3,11 +3,19 @@ use std::{path::{PathBuf, Path}, str::FromStr, process::Stdio, io::SeekFrom, env
 use rand::distributions::{Alphanumeric, DistString};
 use regex::Regex;
 use spider_link::{message::{UiInput, Message}, Keyfile};
+use tracing::{debug, warn};
 use tokio::{process::{Command, Child}, fs::{File, self, OpenOptions}, io::{AsyncReadExt, AsyncWriteExt, AsyncSeekExt}};
 
 use crate::processor::{message::ProcessorMessage, ui::UiProcessorMessage, router::RouterProcessorMessage};
 
-use super::{PeripheralProcessorState, PeripheralProcessorMessage, manifest::PeripheralManifest};
+use super::{PeripheralProcessorState, PeripheralProcessorMessage, manifest::{PeripheralManifest, ResourceLimits}};
+
+/// The number of log lines loaded by the View Logs button.
+const LOG_TAIL_LINES: usize = 100;
+/// The most log lines that will be loaded from each file.
+pub(crate) const MAX_LOG_TAIL_LINES: usize = 1000;
+/// Only this many bytes from the end of a log file are searched for lines.
+const MAX_LOG_TAIL_BYTES: u64 = 256000;
 
 
 
@@ -20,33 +28,113 @@ impl PeripheralProcessorState{
         path
     }
 
-    pub(crate) async fn download_with_git(&self, path: &PathBuf, addr: &String){
-        let x = Command::new("git")
-            .current_dir(path.clone())
-            .arg("clone")
-            .arg(addr)
-            .arg(".")
-            .output().await;
-        println!("{:?}", x);
-        
-        // Fix for nexted crates while developing
-        // if let Ok(val) = env::var("CARGO"){
-            let path = path.join("Cargo.toml");
-            if let Ok(s) = fs::read_to_string(path.clone()).await {
-                let re = Regex::new(r"^[workspace]$").unwrap();
-                if !re.is_match(&s){
-                    // if there is no [workspace] in the cargo manifest,
-                    // tag one at the bottom
-                    let mut file = OpenOptions::new();
-                    file.write(true);
-                    file.append(true);
-                    let mut file = file.open(path).await.unwrap();
-                    let buf = String::from("\n[workspace]\n");
-                    file.write_all_buf(&mut buf.as_bytes()).await.unwrap();
-                }
+    /// Clone the repository into the path and check out the ref, if there
+    /// is one. Returns false if either failed.
+    pub(crate) async fn download_with_git(&self, path: &Path, addr: &str, git_ref: Option<&str>) -> bool{
+        if !run_git(path, &["clone", "--", addr, "."]).await {
+            return false;
+        }
+        if let Some(git_ref) = git_ref {
+            if !run_git(path, &["checkout", "--detach", git_ref]).await {
+                return false;
+            }
+        }
+        fix_cargo_workspace(path).await;
+        true
+    }
+
+    /// Bring an installed service up to date with its ref, or with the
+    /// default branch if it has none. Returns false if this failed.
+    pub(crate) async fn update_with_git(&self, path: &Path, git_ref: Option<&str>) -> bool{
+        // discard the workspace fix so that it does not conflict
+        if !run_git(path, &["checkout", "--", "."]).await {
+            return false;
+        }
+        if !run_git(path, &["fetch", "--tags", "origin"]).await {
+            return false;
+        }
+        let updated = match git_ref {
+            Some(git_ref) => {
+                // a branch should follow the remote, tags and commits are used as is
+                let remote_branch = format!("origin/{}", git_ref);
+                let target = match run_git(path, &["rev-parse", "--verify", "--quiet", &remote_branch]).await {
+                    true => remote_branch.as_str(),
+                    false => git_ref,
+                };
+                run_git(path, &["checkout", "--detach", target]).await
             }
-        // }
+            None => run_git(path, &["pull", "--ff-only"]).await,
+        };
+        fix_cargo_workspace(path).await;
+        updated
+    }
+}
+
+/// Run git in the directory, returns true if it succeeded.
+async fn run_git(path: &Path, args: &[&str]) -> bool{
+    let x = Command::new("git")
+        .current_dir(path)
+        .args(args)
+        .output().await;
+    debug!("git {:?}: {:?}", args, x);
+    matches!(x, Ok(output) if output.status.success())
+}
+
+/// Build the cargo project in the path, returning the path of the built
+/// executable. If the project has several, the last one built is used.
+async fn cargo_build(path: &Path) -> Option<PathBuf>{
+    let output = Command::new("cargo")
+        .current_dir(path)
+        .args(["build", "--message-format=json-render-diagnostics"])
+        .stdin(Stdio::null())
+        .output().await;
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to run cargo for {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        warn!("Failed to build {}: {}", path.display(), String::from_utf8_lossy(&output.stderr));
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let executable = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|msg| msg["reason"] == "compiler-artifact")
+        .filter_map(|msg| msg["executable"].as_str().map(PathBuf::from))
+        .next_back();
+    if executable.is_none() {
+        warn!("Cargo did not build an executable for {}", path.display());
     }
+    executable
+}
+
+/// Add an empty [workspace] to the service's cargo manifest, so that it is
+/// not built as part of any workspace it is nested in.
+async fn fix_cargo_workspace(path: &Path){
+    // Fix for nexted crates while developing
+    // if let Ok(val) = env::var("CARGO"){
+        let path = path.join("Cargo.toml");
+        if let Ok(s) = fs::read_to_string(path.clone()).await {
+            let re = Regex::new(r"(?m)^\[workspace\]\s*$").unwrap();
+            if !re.is_match(&s){
+                // if there is no [workspace] in the cargo manifest,
+                // tag one at the bottom
+                let mut file = OpenOptions::new();
+                file.write(true);
+                file.append(true);
+                let mut file = file.open(path).await.unwrap();
+                let buf = String::from("\n[workspace]\n");
+                file.write_all_buf(&mut buf.as_bytes()).await.unwrap();
+            }
+        }
+    // }
+}
+
+impl PeripheralProcessorState{
 
     pub(crate) async fn write_keyfile(&self, mut path: PathBuf){
         path.push("spider_keyfile.json");
@@ -80,17 +168,33 @@ impl PeripheralProcessorState{
                 cmd
             },
             crate::processor::peripherals::manifest::LaunchConfig::Cargo => {
-                let mut cmd = Command::new("cargo");
-                cmd.arg("run");
-                cmd
+                // build without the limits, only the service runs under them
+                let exe_path = cargo_build(&path).await?;
+                Command::new(exe_path)
             },
         };
         command.current_dir(path.clone());
+        command.args(manifest.args());
+        command.envs(manifest.env());
 
         println!("launching child: {}", path.display());
+        apply_limits(&mut command, manifest.limits());
         launch_child(command, Some(&path)).await
     }
 
+    /// Show why an install failed in the settings, until it is dismissed.
+    pub(crate) async fn install_error(&mut self, reason: String){
+        println!("Install failed: {}", reason);
+        let msg = UiProcessorMessage::SetSetting {
+            header: String::from("Peripheral Services"),
+            title: format!("Install failed: {}", reason),
+            inputs: vec![("button".to_string(), "Dismiss".to_string())],
+            cb: cb_dismiss,
+            data: String::new(),
+        };
+        self.sender.send_ui(msg).await;
+    }
+
     pub(crate) async fn make_setting_entry(&mut self, name: String, running: bool){
         let (start_stop, cb) = match running {
             true => ("Stop".to_string(), cb_with_stop as fn(u32, &String, UiInput, &mut String) -> Option<ProcessorMessage>),
@@ -101,7 +205,9 @@ impl PeripheralProcessorState{
             title: name,
             inputs: vec![
                 ("button".to_string(), start_stop),
-                ("button".to_string(), "Remove".to_string())
+                ("button".to_string(), "Remove".to_string()),
+                ("button".to_string(), "View Logs".to_string()),
+                ("button".to_string(), "Update".to_string()),
             ],
             cb,
             data: String::new(),
@@ -122,6 +228,8 @@ fn cb_with_stop(idx: u32, name: &String, input: UiInput, data: &mut String) -> O
                     Some(msg)
                 },
                 UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
             }
         }
         1 => {
@@ -132,6 +240,32 @@ fn cb_with_stop(idx: u32, name: &String, input: UiInput, data: &mut String) -> O
                     Some(msg)
                 },
                 UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
+            }
+        }
+        2 => {
+            match input{
+                UiInput::Click => {
+                    let peripheral_msg = PeripheralProcessorMessage::TailLogs(name.clone(), LOG_TAIL_LINES);
+                    let msg = ProcessorMessage::PeripheralMessage(peripheral_msg);
+                    Some(msg)
+                },
+                UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
+            }
+        }
+        3 => {
+            match input{
+                UiInput::Click => {
+                    let peripheral_msg = PeripheralProcessorMessage::Update(name.clone());
+                    let msg = ProcessorMessage::PeripheralMessage(peripheral_msg);
+                    Some(msg)
+                },
+                UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
             }
         }
         _ => None
@@ -148,6 +282,8 @@ fn cb_with_start(idx: u32, name: &String, input: UiInput, data: &mut String) ->
                     Some(msg)
                 },
                 UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
             }
         }
         1 => {
@@ -158,14 +294,132 @@ fn cb_with_start(idx: u32, name: &String, input: UiInput, data: &mut String) ->
                     Some(msg)
                 },
                 UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
+            }
+        }
+        2 => {
+            match input{
+                UiInput::Click => {
+                    let peripheral_msg = PeripheralProcessorMessage::TailLogs(name.clone(), LOG_TAIL_LINES);
+                    let msg = ProcessorMessage::PeripheralMessage(peripheral_msg);
+                    Some(msg)
+                },
+                UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
+            }
+        }
+        3 => {
+            match input{
+                UiInput::Click => {
+                    let peripheral_msg = PeripheralProcessorMessage::Update(name.clone());
+                    let msg = ProcessorMessage::PeripheralMessage(peripheral_msg);
+                    Some(msg)
+                },
+                UiInput::Text(_) => None,
+                UiInput::Bool(_) => None,
+                UiInput::Number(_) => None,
             }
         }
         _ => None
     }
 }
 
+fn cb_dismiss(_: u32, title: &String, input: UiInput, _: &mut String) -> Option<ProcessorMessage>{
+    match input{
+        UiInput::Click => {
+            let ui_msg = UiProcessorMessage::RemoveSetting {
+                header: String::from("Peripheral Services"),
+                title: title.clone(),
+            };
+            Some(ProcessorMessage::UiMessage(ui_msg))
+        },
+        _ => None,
+    }
+}
+
+// ===== Install Functions =====
+
+/// Check that an address is a plausible git url before it is given to git,
+/// and get the package name from it. Only https, ssh and scp style
+/// (user@host:path) addresses are accepted, since git also accepts options
+/// and remote helpers (e.g. ext::) that can run commands.
+pub(crate) fn validate_git_address(addr: &str) -> Result<String, String>{
+    if addr.is_empty() {
+        return Err("the address is empty".to_string());
+    }
+    if let Some(c) = addr.chars().find(|c| c.is_whitespace() || c.is_control() || "`$;|&<>(){}[]\\'\"*?!".contains(*c)) {
+        return Err(format!("the address contains {:?}", c));
+    }
+    let scp_like = Regex::new(r"^[A-Za-z0-9_.-]+@[A-Za-z0-9.-]+:[^/]").unwrap();
+    if !(addr.starts_with("https://") || addr.starts_with("ssh://") || scp_like.is_match(addr)) {
+        return Err("the address must be an https or ssh git url".to_string());
+    }
+
+    let re = Regex::new(r"/([^/]*?)(\.git)?$").unwrap();
+    let name = match re.captures(addr).and_then(|captures| captures.get(1)) {
+        Some(name) => name.as_str(),
+        None => return Err("the address has no repository name".to_string()),
+    };
+    let safe_name = Regex::new(r"^[A-Za-z0-9_][A-Za-z0-9_.-]*$").unwrap();
+    if !safe_name.is_match(name) {
+        return Err(format!("{:?} is not a valid package name", name));
+    }
+    Ok(name.to_string())
+}
+
+/// Split the ref from an address given as url#ref, and check that the ref
+/// cannot be mistaken for an option.
+pub(crate) fn split_git_ref(addr: &str) -> Result<(&str, Option<String>), String>{
+    let (url, git_ref) = match addr.rsplit_once('#') {
+        Some((url, git_ref)) => (url, git_ref),
+        None => return Ok((addr, None)),
+    };
+    let safe_ref = Regex::new(r"^[A-Za-z0-9_][A-Za-z0-9_./-]*$").unwrap();
+    if !safe_ref.is_match(git_ref) || git_ref.contains("..") {
+        return Err(format!("{:?} is not a valid git ref", git_ref));
+    }
+    Ok((url, Some(git_ref.to_string())))
+}
+
 // ===== Child Functions =====
 
+/// Apply the limits to the child when it is spawned, see [ResourceLimits]
+/// for what is supported.
+#[cfg(unix)]
+fn apply_limits(command: &mut Command, limits: &ResourceLimits){
+    let nice = limits.nice;
+    let memory = limits.memory_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+    if nice.is_none() && memory.is_none() {
+        return;
+    }
+    // Safety: only async-signal-safe functions are called between fork and
+    // exec, and failures are ignored rather than allocating an error.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                libc::setpriority(libc::PRIO_PROCESS as _, 0, nice);
+            }
+            if let Some(memory) = memory {
+                let limit = libc::rlimit {
+                    rlim_cur: memory as libc::rlim_t,
+                    rlim_max: memory as libc::rlim_t,
+                };
+                libc::setrlimit(libc::RLIMIT_AS, &limit);
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_limits(_command: &mut Command, limits: &ResourceLimits){
+    if limits.nice.is_some() || limits.memory_mb.is_some() {
+        println!("Resource limits are not supported on this platform, ignoring");
+    }
+}
+
 pub(crate) async fn launch_child(mut command: Command, stdio_dir: Option<&Path> ) -> Option<Child>{
     command.stdin(Stdio::null()); // dont pass inputs to child
     match stdio_dir{
@@ -198,6 +452,25 @@ pub(crate) async fn launch_child(mut command: Command, stdio_dir: Option<&Path>
     }
 }
 
+/// Read up to the last `lines` lines of a file. Only the end of the file
+/// is read, so this is bounded even for large log files.
+pub(crate) async fn tail_file(path: &Path, lines: usize) -> std::io::Result<Vec<String>> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let start = len.saturating_sub(MAX_LOG_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).await?;
+    let text = String::from_utf8_lossy(&buf);
+
+    let mut all_lines: Vec<&str> = text.lines().collect();
+    if start > 0 && !all_lines.is_empty() {
+        all_lines.remove(0); // the first line is likely partial
+    }
+    let skip = all_lines.len().saturating_sub(lines);
+    Ok(all_lines[skip..].iter().map(|line| line.to_string()).collect())
+}
+
 async fn wrap_child_stdio(child: &mut Child, stdio_dir: &Path) {
     let mut stdout = child.stdout.take().expect("should have handle");
     let mut stdout_file = File::create(stdio_dir.join("stdout")).await.expect("file can be created");
@@ -269,3 +542,70 @@ async fn wrap_child_stdio(child: &mut Child, stdio_dir: &Path) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::{cargo_build, split_git_ref, tail_file, validate_git_address};
+
+    #[test]
+    fn git_refs() {
+        assert_eq!(split_git_ref("https://host.com/repo.git"), Ok(("https://host.com/repo.git", None)));
+        assert_eq!(split_git_ref("https://host.com/repo.git#v1.2"), Ok(("https://host.com/repo.git", Some("v1.2".to_string()))));
+        assert_eq!(split_git_ref("https://host.com/repo#release/1"), Ok(("https://host.com/repo", Some("release/1".to_string()))));
+        assert!(split_git_ref("https://host.com/repo#").is_err());
+        assert!(split_git_ref("https://host.com/repo#--force").is_err());
+        assert!(split_git_ref("https://host.com/repo#a..b").is_err());
+    }
+
+    #[test]
+    fn git_addresses() {
+        assert_eq!(validate_git_address("https://github.com/Ocelmot/spider.git"), Ok("spider".to_string()));
+        assert_eq!(validate_git_address("ssh://git@host.com/user/repo"), Ok("repo".to_string()));
+        assert_eq!(validate_git_address("git@github.com:Ocelmot/spider.git"), Ok("spider".to_string()));
+
+        assert!(validate_git_address("").is_err());
+        assert!(validate_git_address("--upload-pack=touch /tmp/x").is_err());
+        assert!(validate_git_address("ext::sh -c touch% /tmp/x").is_err());
+        assert!(validate_git_address("file:///etc/repo").is_err());
+        assert!(validate_git_address("https://host.com/repo;rm").is_err());
+        assert!(validate_git_address("https://host.com/..").is_err());
+        assert!(validate_git_address("https://host.com/").is_err());
+    }
+
+    #[tokio::test]
+    async fn tail_log_file() {
+        let path = temp_dir().join("spider_tail_log_file");
+        let contents: String = (0..50).map(|i| format!("line {}\n", i)).collect();
+        tokio::fs::write(&path, contents).await.unwrap();
+
+        let lines = tail_file(&path, 3).await.unwrap();
+        assert_eq!(lines, vec!["line 47", "line 48", "line 49"]);
+        let lines = tail_file(&path, 100).await.unwrap();
+        assert_eq!(lines.len(), 50);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cargo_build_executable() {
+        let path = temp_dir().join("spider_cargo_build");
+        let _ = tokio::fs::remove_dir_all(&path).await;
+        tokio::fs::create_dir_all(path.join("src")).await.unwrap();
+        let manifest = "[package]\nname = \"service\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n";
+        tokio::fs::write(path.join("Cargo.toml"), manifest).await.unwrap();
+        tokio::fs::write(path.join("src/main.rs"), "fn main() {}\n").await.unwrap();
+
+        let exe = cargo_build(&path).await.unwrap();
+        assert!(exe.starts_with(path.join("target")));
+        assert!(exe.file_stem().unwrap() == "service");
+        assert!(exe.exists());
+
+        // a project that does not build has no executable
+        tokio::fs::write(path.join("src/main.rs"), "fn main() {\n").await.unwrap();
+        assert!(cargo_build(&path).await.is_none());
+
+        tokio::fs::remove_dir_all(&path).await.unwrap();
+    }
+}
@@ -4,6 +4,7 @@ use spider_link::{
     message::{Message, RouterMessage, UiMessage},
     Link,
 };
+use subtle::{Choice, ConstantTimeEq};
 use tokio::{
     select, spawn,
     sync::{
@@ -13,6 +14,8 @@ use tokio::{
     time::Instant,
 };
 
+use tracing::warn;
+
 use crate::processor::{
     message::ProcessorMessage, sender::ProcessorSender, ui::UiProcessorMessage,
 };
@@ -116,22 +119,60 @@ impl RouterProcessorState {
     }
 
     pub(super) async fn set_approval_code_handler(&mut self, code: String) {
+        // short codes are too easy to guess
+        if code.chars().count() < self.config.approval_code_min_length {
+            warn!("Rejected approval code shorter than {} characters", self.config.approval_code_min_length);
+            return;
+        }
         // add code to valid set, send code to each pending link processor
         // for approval
         self.approval_codes
             .insert(code.clone(), Instant::now() + Duration::from_secs(300));
-        for (_, pending_link) in &self.incoming_links {
+        for pending_link in self.incoming_links.values() {
             pending_link
                 .send(PendingLinkControl::AddCode(code.clone()))
                 .await;
         }
     }
+
+    /// Forget approval codes that expired before the given time.
+    pub(super) fn clean_approval_codes(&mut self, now: Instant) {
+        self.approval_codes.retain(|_, expiry| *expiry > now);
+    }
+
+    pub(super) async fn redeem_approval_code_handler(&mut self, relation: String, code: String) {
+        let now = Instant::now();
+        let valid: bool = self
+            .approval_codes
+            .iter()
+            .filter(|(_, expiry)| **expiry > now)
+            .fold(Choice::from(0), |found, (c, _)| found | c.as_bytes().ct_eq(code.as_bytes()))
+            .into();
+        if !valid {
+            // expired or already used, stop the pending link matching it
+            if let Some(ctrl) = self.incoming_links.get(&relation) {
+                ctrl.send(PendingLinkControl::RemoveCode(code)).await;
+            }
+            return;
+        }
+
+        if self.config.approval_code_single_use {
+            self.approval_codes.remove(&code);
+            for pending_link in self.incoming_links.values() {
+                pending_link
+                    .send(PendingLinkControl::RemoveCode(code.clone()))
+                    .await;
+            }
+        }
+        self.approve_link_handler(relation).await;
+    }
 }
 
 pub enum PendingLinkControl {
     Approve,
     Deny,
     AddCode(String),
+    RemoveCode(String),
 }
 
 fn pending_link_processor(
@@ -141,6 +182,7 @@ fn pending_link_processor(
     mut should_approve_ui: Arc<watch::Sender<bool>>,
 ) -> Sender<PendingLinkControl> {
     let (tx, mut rx) = channel(50);
+    let key = link.other_relation().to_base64();
     spawn(async move {
         link.send(Message::Router(RouterMessage::Pending)).await;
 
@@ -168,19 +210,13 @@ fn pending_link_processor(
                             break;
                         }
                         PendingLinkControl::AddCode(new_code) => {
-                            match &code {
-                                Some(code) => {
-                                    if code == &new_code {
-                                        approve_link(sender, link, backlog).await;
-                                        break;
-                                    } else {
-                                        codes.insert(new_code);
-                                    }
-                                },
-                                None => {
-                                    codes.insert(new_code);
-                                },
+                            if code.as_ref().is_some_and(|code| codes_match(code, &new_code)) {
+                                redeem_code(&sender, key.clone(), new_code.clone()).await;
                             }
+                            codes.insert(new_code);
+                        }
+                        PendingLinkControl::RemoveCode(old_code) => {
+                            codes.remove(&old_code);
                         }
                         PendingLinkControl::Deny => {
                             // cancel this pending link
@@ -195,9 +231,9 @@ fn pending_link_processor(
 
                             // check incoming message for approval code
                             if let Message::Router(RouterMessage::ApprovalCode(new_code)) = &msg {
-                                if codes.contains(new_code) {
-                                    approve_link(sender, link, backlog).await;
-                                    break;
+                                if contains_code(&codes, new_code) {
+                                    redeem_code(&sender, key.clone(), new_code.clone()).await;
+                                    continue;
                                 } else {
                                     code = Some(new_code.clone());
                                     code_attempts += 1;
@@ -251,6 +287,27 @@ fn pending_link_processor(
     tx
 }
 
+/// Compare two codes in constant time, so the time taken does not reveal
+/// how much of a guessed code is correct.
+fn codes_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Check if the code is in the set, comparing it against every code in
+/// constant time rather than stopping at the first match.
+fn contains_code(codes: &HashSet<String>, code: &str) -> bool {
+    codes
+        .iter()
+        .fold(Choice::from(0), |found, c| found | c.as_bytes().ct_eq(code.as_bytes()))
+        .into()
+}
+
+async fn redeem_code(sender: &ProcessorSender, key: String, code: String) {
+    let msg = RouterProcessorMessage::RedeemApprovalCode(key, code);
+    let msg = ProcessorMessage::RouterMessage(msg);
+    sender.send(msg).await;
+}
+
 async fn approve_link(mut sender: ProcessorSender, link: Link, backlog: Vec<Message>) {
     // Send approved link to link
     link.send(Message::Router(RouterMessage::Approved)).await;
@@ -292,3 +349,80 @@ async fn deny_link(mut sender: ProcessorSender, link: Link) {
     };
     sender.send_ui(msg).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use tokio::{sync::mpsc::{channel, Receiver}, time::Instant};
+
+    use crate::processor::router::tests::router;
+
+    use super::{codes_match, contains_code, PendingLinkControl};
+
+    fn controls(receiver: &mut Receiver<PendingLinkControl>) -> Vec<String> {
+        let mut controls = Vec::new();
+        while let Ok(ctrl) = receiver.try_recv() {
+            controls.push(match ctrl {
+                PendingLinkControl::Approve => String::from("approve"),
+                PendingLinkControl::Deny => String::from("deny"),
+                PendingLinkControl::AddCode(code) => format!("add {}", code),
+                PendingLinkControl::RemoveCode(code) => format!("remove {}", code),
+            });
+        }
+        controls
+    }
+
+    #[test]
+    fn code_comparison() {
+        assert!(codes_match("abcdefgh", "abcdefgh"));
+        assert!(!codes_match("abcdefgh", "abcdefgx"));
+        assert!(!codes_match("abcdefgh", "abcdefg"));
+        assert!(!codes_match("", "abcdefgh"));
+
+        let codes = HashSet::from([String::from("abcdefgh"), String::from("12345678")]);
+        assert!(contains_code(&codes, "12345678"));
+        assert!(!contains_code(&codes, "1234567"));
+        assert!(!contains_code(&HashSet::new(), ""));
+    }
+
+    #[tokio::test]
+    async fn single_use_codes() {
+        let (mut router, _receiver) = router();
+
+        // short codes are rejected
+        router.set_approval_code_handler(String::from("short")).await;
+        assert!(router.approval_codes.is_empty());
+
+        let (first, mut first_rx) = channel(50);
+        let (second, mut second_rx) = channel(50);
+        router.incoming_links.insert(String::from("first"), first);
+        router.incoming_links.insert(String::from("second"), second);
+
+        let code = String::from("abcdefghij");
+        router.set_approval_code_handler(code.clone()).await;
+        assert_eq!(controls(&mut first_rx), vec!["add abcdefghij"]);
+        assert_eq!(controls(&mut second_rx), vec!["add abcdefghij"]);
+
+        router.redeem_approval_code_handler(String::from("first"), code.clone()).await;
+        assert_eq!(controls(&mut first_rx), vec!["remove abcdefghij", "approve"]);
+        assert_eq!(controls(&mut second_rx), vec!["remove abcdefghij"]);
+
+        // the code cannot approve a second link
+        router.redeem_approval_code_handler(String::from("second"), code).await;
+        assert_eq!(controls(&mut second_rx), vec!["remove abcdefghij"]);
+        assert!(router.incoming_links.contains_key("second"));
+    }
+
+    #[tokio::test]
+    async fn expired_codes_cleaned() {
+        let (mut router, _receiver) = router();
+        let now = Instant::now();
+        router.approval_codes.insert(String::from("expired"), now - Duration::from_secs(1));
+        router.approval_codes.insert(String::from("future"), now + Duration::from_secs(300));
+
+        router.clean_approval_codes(now);
+        assert!(!router.approval_codes.contains_key("expired"));
+        assert!(router.approval_codes.contains_key("future"));
+    }
+}
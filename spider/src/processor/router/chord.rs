@@ -23,9 +23,12 @@ impl RouterProcessorState {
                 let id = self.state.self_id().await;
                 let join_or_host = true;
 
-                if let Some(chord_entry) = ChordEntry::start_chord(processor_sender, id, state, join_or_host).await{
-                    let name = self.get_next_name();
-                    self.install_chord(name, chord_entry).await;
+                match ChordEntry::start_chord(processor_sender, id, state, join_or_host).await{
+                    Ok(chord_entry) => {
+                        let name = self.get_next_name();
+                        self.install_chord(name, chord_entry).await;
+                    },
+                    Err(e) => self.chord_error(e).await,
                 }
 
             }else{
@@ -35,12 +38,14 @@ impl RouterProcessorState {
     }
 
     pub(crate) async fn handle_join_chord(&mut self, addr: String) {
+        if let Err(e) = self.join_chord(addr).await {
+            self.chord_error(e).await;
+        }
+    }
 
+    async fn join_chord(&mut self, addr: String) -> Result<(), ChordError> {
         // listen addr needs to get next available port number
-        let listen_port: u16 = match self.get_next_port(){
-            Some(port) => port,
-            None => return, // No more available ports
-        };
+        let listen_port: u16 = self.get_next_port()?;
         let listen_addr = format!("0.0.0.0:{}", listen_port);
 
         // get pub addr from new peer, but use port from base listen addr
@@ -56,10 +61,10 @@ impl RouterProcessorState {
                         let pub_addr = addr.to_string();
                         (pub_addr, advert_addr)
                     },
-                    Err(_) => return, // cant get public addr
+                    Err(_) => return Err(ChordError::NoPublicAddress(addr)), // cant get public addr
                 }
             },
-            None => return, // cant get public addr
+            None => return Err(ChordError::NoPublicAddress(addr)), // cant get public addr
         };
 
         // make chord state
@@ -72,19 +77,21 @@ impl RouterProcessorState {
         let processor_sender = self.sender.clone();
         let join_or_host = false;
 
-        if let Some(chord_entry) = ChordEntry::start_chord(processor_sender, id, state, join_or_host).await{
-            let name = self.get_next_name();
-            self.install_chord(name, chord_entry).await;
-        }
+        let chord_entry = ChordEntry::start_chord(processor_sender, id, state, join_or_host).await?;
+        let name = self.get_next_name();
+        self.install_chord(name, chord_entry).await;
+        Ok(())
     }
 
     pub(crate) async fn handle_host_chord(&mut self, advert_addr: String) {
-        
+        if let Err(e) = self.host_chord(advert_addr).await {
+            self.chord_error(e).await;
+        }
+    }
+
+    async fn host_chord(&mut self, advert_addr: String) -> Result<(), ChordError> {
         // listen addr needs to get next available port number
-        let listen_port: u16 = match self.get_next_port(){
-            Some(port) => port,
-            None => return, // No more available ports
-        };
+        let listen_port: u16 = self.get_next_port()?;
 
         // pub addr should be from input, but filling in the port from the listen address if missing
         let (pub_addr, advert_addr) = match advert_addr.parse::<SocketAddr>(){
@@ -104,14 +111,14 @@ impl RouterProcessorState {
                         (pub_addr.to_string(), advert_addr.to_string())
                     },
                     Err(_) => {
-                        return; // cant create address
+                        return Err(ChordError::InvalidAddress(advert_addr)); // cant create address
                     },
                 }
             },
-        };        
+        };
 
         let id = self.state.self_id().await;
-        
+
         let processor_sender = self.sender.clone();
         let listen_addr = format!("0.0.0.0:{}", listen_port);
         let join_addrs = vec![];
@@ -120,10 +127,10 @@ impl RouterProcessorState {
         let mut state = ChordState::new(listen_addr, pub_addr, advert_addr);
         state.add_addrs(join_addrs);
 
-        if let Some(chord_entry) = ChordEntry::start_chord(processor_sender, id, state, join_or_host).await{
-            let name = self.get_next_name();
-            self.install_chord(name, chord_entry).await;
-        }
+        let chord_entry = ChordEntry::start_chord(processor_sender, id, state, join_or_host).await?;
+        let name = self.get_next_name();
+        self.install_chord(name, chord_entry).await;
+        Ok(())
     }
 
     pub(crate) async fn handle_leave_chord(&mut self, name: String){
@@ -155,10 +162,12 @@ impl RouterProcessorState {
         return String::from("default name");
     }
 
-    fn get_next_port(&self) -> Option<u16> {
+    fn get_next_port(&self) -> Result<u16, ChordError> {
         // search through connected chords to find the next available port
         // starting at a configurable base port
-        let mut choices: HashSet<u16> = (1932..1950).collect();
+        let base = self.config.chord_port_base;
+        let end = base.saturating_add(self.config.chord_port_count);
+        let mut choices: HashSet<u16> = (base..end).collect();
         for (_, entry) in &self.chords{
             match entry.state.listen_addr.parse::<SocketAddr>(){
                 Ok(addr) => {
@@ -169,7 +178,36 @@ impl RouterProcessorState {
             }
         }
 
-        choices.iter().next().copied()
+        choices.into_iter().min().ok_or(ChordError::NoPorts { base, end })
+    }
+
+    /// Show why a chord could not be started in the settings, until it is
+    /// dismissed.
+    async fn chord_error(&mut self, error: ChordError){
+        let reason = error.to_string();
+        println!("Could not start chord: {}", reason);
+        let msg = UiProcessorMessage::SetSetting {
+            header: String::from("Connected Chords"),
+            title: String::from("Could not start chord"),
+            inputs: vec![
+                ("text".to_string(), reason),
+                ("button".to_string(), "Dismiss".to_string()),
+            ],
+            cb: |idx, title, input, _|{
+                match (idx, input){
+                    (1, spider_link::message::UiInput::Click) => {
+                        let ui_msg = UiProcessorMessage::RemoveSetting {
+                            header: String::from("Connected Chords"),
+                            title: title.to_string(),
+                        };
+                        Some(ProcessorMessage::UiMessage(ui_msg))
+                    },
+                    _ => None,
+                }
+            },
+            data: String::new(),
+        };
+        self.sender.send_ui(msg).await;
     }
 
     async fn install_chord(&mut self, name: String, chord_entry: ChordEntry){
@@ -192,6 +230,8 @@ impl RouterProcessorState {
                         Some(msg)
                     },
                     spider_link::message::UiInput::Text(_) => None,
+                    spider_link::message::UiInput::Bool(_) => None,
+                    spider_link::message::UiInput::Number(_) => None,
                 }
             },
             data: String::new(),
@@ -206,6 +246,34 @@ impl RouterProcessorState {
 
 
 
+/// The reasons a chord could not be joined or hosted.
+#[derive(Debug)]
+pub enum ChordError{
+    /// Every port from base up to (but not including) end is used by
+    /// another chord.
+    NoPorts{ base: u16, end: u16 },
+    /// The address to host the chord at could not be parsed.
+    InvalidAddress(String),
+    /// The peer at this address could not tell us our public address.
+    NoPublicAddress(String),
+    /// The chord could not be started at this listen address, for example
+    /// because it could not be bound or no peer could be joined.
+    StartFailed(String),
+}
+
+impl std::fmt::Display for ChordError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChordError::NoPorts { base, end } => {
+                write!(f, "All chord ports from {} to {} are in use", base, end.saturating_sub(1))
+            },
+            ChordError::InvalidAddress(addr) => write!(f, "{:?} is not a valid address", addr),
+            ChordError::NoPublicAddress(addr) => write!(f, "Could not get a public address from {}", addr),
+            ChordError::StartFailed(addr) => write!(f, "Could not start the chord listening on {}", addr),
+        }
+    }
+}
+
 pub struct ChordEntry{
     handle: ChordHandle<String, SpiderId2048>,
     associate: AssociateChannel<String, SpiderId2048>,
@@ -230,14 +298,14 @@ impl ChordEntry{
         id: SpiderId2048,
         state: ChordState,
         join_or_host: bool,
-    ) -> Option<Self> {
+    ) -> Result<Self, ChordError> {
         let listen_addr = state.listen_addr.clone();
         let pub_addr = state.pub_addr.clone();
         let advert_addr = state.advert_addr.clone();
         let join_addrs: Vec<String> = state.get_addrs().map(|x|{x.0.clone()}).collect();
 
         let mut chord = TCPChord::new(listen_addr.clone(), id.clone());
-        chord.set_listen_addr(listen_addr);
+        chord.set_listen_addr(listen_addr.clone());
         chord.set_self_addr(pub_addr);
         chord.set_advert(Some(advert_addr.as_bytes().to_vec()));
         if join_addrs.len() != 0{
@@ -256,14 +324,14 @@ impl ChordEntry{
                 let addr_sender = Self::create_addr_sender(sender_associate, processor_sender);
 
                 // return chord
-                Some(Self{
+                Ok(Self{
                     handle,
                     associate,
                     addr_sender,
                     state,
                 })
             },
-            None => None,
+            None => Err(ChordError::StartFailed(listen_addr)),
         }
     }
     
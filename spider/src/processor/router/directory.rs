@@ -1,6 +1,6 @@
 
 use phf::{Set, phf_set};
-use spider_link::{message::{RouterMessage, Message, DirectoryEntry}, Relation};
+use spider_link::{message::{RouterMessage, Message, DirectoryEntry, GoodbyeReason}, Relation};
 
 use crate::processor::{ui::UiProcessorMessage, message::ProcessorMessage};
 
@@ -16,6 +16,9 @@ static SELF_PROPERTIES: Set<&'static str> = phf_set! {
     "name"
 };
 
+/// Properties set by the owner of the base are stored under this prefix
+const USER_PROPERTY_PREFIX: &str = "user.";
+
 
 // Directory Functionality
 impl RouterProcessorState{
@@ -48,13 +51,54 @@ impl RouterProcessorState{
         self.directory_subscribers.remove(&rel);
     }
 
+    pub(crate) async fn handle_search_directory(&mut self, rel: Relation, query: String){
+        let query = query.to_lowercase();
+        let entries = self.directory.values()
+            .filter(|entry| entry_matches(entry, &query))
+            .cloned()
+            .collect();
+        let msg = RouterMessage::SearchDirResult(entries);
+        let msg = Message::Router(msg);
+        self.sender.send_message(rel, msg).await;
+    }
+
     pub(crate) async fn clear_directory_entry_handler(&mut self, rel: Relation) {
         // remove from directory
         self.remove_identity(&rel).await;
 
+        // it can no longer unsubscribe, so drop its subscriptions now
+        self.remove_event_subscriptions(&rel);
+        self.state.save_event_subscriptions(&self.event_subscribers, &self.event_pattern_subscribers).await;
+
         // cancel existing connection
-        if let Some(link) = self.links.remove(&rel){
-            link.terminate().await;
+        self.terminate_link(&rel).await;
+    }
+
+    pub(crate) async fn toggle_blocked_handler(&mut self, rel: Relation) {
+        let blocked = match self.directory.get(&rel) {
+            Some(entry) => entry.get("blocked").is_some_and(|blocked| blocked == "true"),
+            None => false,
+        };
+        let blocked = !blocked;
+        self.set_identity_system(rel.clone(), "blocked".into(), blocked.to_string()).await;
+        self.state.save_directory(&self.directory).await;
+
+        // a blocked relation may not remain connected
+        if blocked {
+            self.terminate_link(&rel).await;
+        }
+    }
+
+    pub(crate) async fn link_flooding_handler(&mut self, rel: Relation) {
+        println!("Blocking relation for flooding its link");
+        self.set_identity_system(rel.clone(), "blocked".into(), "true".into()).await;
+        self.state.save_directory(&self.directory).await;
+        self.terminate_link(&rel).await;
+    }
+
+    async fn terminate_link(&mut self, rel: &Relation) {
+        if let Some(link) = self.links.remove(rel){
+            link.terminate_with(GoodbyeReason::Unpaired).await;
         }
     }
 }
@@ -79,35 +123,25 @@ impl RouterProcessorState{
         if !SELF_PROPERTIES.contains(&key) {
             return; // only specified keys are allowed to be set by clients
         }
-
-        let ident = match self.directory.get_mut(&rel){
-            Some(entry) => {
-                if Some(&value) == entry.get(&key){
-                    return; // value is already set
-                }
-                entry
-            },
-            None => {
-                self.directory.insert(rel.clone(), DirectoryEntry::new(rel.clone()));
-                self.directory.get_mut(&rel).expect("entry should still exist")
-            },
-        };
-
-        ident.set(key, value);
-        let updated_entry = ident.clone();
-
-        let msg = RouterMessage::AddIdentity(ident.clone());
-        self.message_dir_subscribers(msg).await;
-
-        // set/update setting entry
-        self.set_directory_setting(updated_entry).await;
+        self.set_identity(rel, key, value).await;
     }
 
     pub(crate) async fn set_identity_system(&mut self, rel: Relation, key: String, value: String){
         if !SYSTEM_PROPERTIES.contains(&key) {
             return; // only specified keys are allowed to be set by the system
         }
+        self.set_identity(rel, key, value).await;
+    }
 
+    pub(crate) async fn set_identity_user(&mut self, rel: Relation, key: String, value: String){
+        // user properties are namespaced, so they can not collide with the
+        // allowed properties
+        let key = format!("{USER_PROPERTY_PREFIX}{key}");
+        self.set_identity(rel, key, value).await;
+        self.state.save_directory(&self.directory).await;
+    }
+
+    async fn set_identity(&mut self, rel: Relation, key: String, value: String){
         let ident = match self.directory.get_mut(&rel){
             Some(entry) => {
                 if Some(&value) == entry.get(&key){
@@ -152,6 +186,17 @@ impl RouterProcessorState{
     }
 }
 
+/// Returns true if the nickname or name of the entry contains the query.
+/// The query must already be lowercase.
+fn entry_matches(entry: &DirectoryEntry, query: &str) -> bool{
+    if query.is_empty() {
+        return true;
+    }
+    ["nickname", "name"].iter().any(|key|{
+        entry.get(key).is_some_and(|value| value.to_lowercase().contains(query))
+    })
+}
+
 // Utility functions
 impl RouterProcessorState{
     pub(crate) async fn message_dir_subscribers(&mut self, msg: RouterMessage){
@@ -180,6 +225,14 @@ impl RouterProcessorState{
             None => String::new(),
         };
         let label = format!("{} {}", nickname, name);
+        let block = match entry.get("blocked").map(|blocked| blocked.as_str()) {
+            Some("true") => String::from("Unblock"),
+            _ => String::from("Block"),
+        };
+        let note = match entry.get(&format!("{USER_PROPERTY_PREFIX}note")){
+            Some(note) => note.clone(),
+            None => String::from("Note"),
+        };
         
 
         let msg = UiProcessorMessage::SetSetting {
@@ -188,23 +241,34 @@ impl RouterProcessorState{
             inputs: vec![
                 ("text".into(), label),
                 ("textentry".into(), "Rename".into()),
+                ("textentry".into(), note),
+                ("button".into(), block),
                 ("button".into(), "Remove".into()),
             ],
             cb: |idx, name, input, data|{
                 let rel = serde_json::from_str(data).unwrap();
-                match input{
-                    spider_link::message::UiInput::Click => {
-                        // only button will send click
-                        let router_msg = RouterProcessorMessage::ClearDirectoryEntry(rel);
+                match (idx, input){
+                    (1, spider_link::message::UiInput::Text(name)) => {
+                        let router_msg = RouterProcessorMessage::SetNickname(rel, name);
                         let msg = ProcessorMessage::RouterMessage(router_msg);
                         Some(msg)
                     },
-                    spider_link::message::UiInput::Text(name) => {
-                        // only textentry will send text
-                        let router_msg = RouterProcessorMessage::SetNickname(rel, name);
+                    (2, spider_link::message::UiInput::Text(note)) => {
+                        let router_msg = RouterProcessorMessage::SetDirectoryProperty(rel, "note".into(), note);
                         let msg = ProcessorMessage::RouterMessage(router_msg);
                         Some(msg)
                     },
+                    (3, spider_link::message::UiInput::Click) => {
+                        let router_msg = RouterProcessorMessage::ToggleBlocked(rel);
+                        let msg = ProcessorMessage::RouterMessage(router_msg);
+                        Some(msg)
+                    },
+                    (4, spider_link::message::UiInput::Click) => {
+                        let router_msg = RouterProcessorMessage::ClearDirectoryEntry(rel);
+                        let msg = ProcessorMessage::RouterMessage(router_msg);
+                        Some(msg)
+                    },
+                    _ => None,
                 }
             },
             data: serde_json::to_string(rel).unwrap(),
@@ -212,3 +276,81 @@ impl RouterProcessorState{
         self.sender.send_ui(msg).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use spider_link::{id::SpiderId, message::{DirectoryEntry, Message, RouterMessage}, Relation, Role};
+    use tokio::sync::mpsc::Receiver;
+
+    use crate::processor::{message::ProcessorMessage, router::{tests::router, RouterProcessorMessage}};
+
+    use super::entry_matches;
+
+    fn broadcast_blocked(receiver: &mut Receiver<ProcessorMessage>, subscriber: &Relation) -> Option<String> {
+        let mut blocked = None;
+        while let Ok(msg) = receiver.try_recv() {
+            if let ProcessorMessage::RouterMessage(RouterProcessorMessage::SendMessage(rel, Message::Router(RouterMessage::AddIdentity(entry)))) = msg {
+                if &rel == subscriber {
+                    blocked = entry.get("blocked").cloned();
+                }
+            }
+        }
+        blocked
+    }
+
+    #[test]
+    fn search_matches() {
+        let mut entry = DirectoryEntry::new(Relation { role: Role::Peer, id: SpiderId::from_bytes([1; 294]) });
+        assert!(entry_matches(&entry, ""));
+        assert!(!entry_matches(&entry, "kit"));
+
+        entry.set("nickname".into(), "Kitchen Lights".into());
+        entry.set("name".into(), "Hue Bridge".into());
+        assert!(entry_matches(&entry, "kit"));
+        assert!(entry_matches(&entry, "bridge"));
+        assert!(!entry_matches(&entry, "garage"));
+    }
+
+    #[tokio::test]
+    async fn toggle_blocked_broadcasts() {
+        let (mut router, mut receiver) = router();
+
+        let subscriber = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([1; 294]) };
+        let peer = Relation { role: Role::Peer, id: SpiderId::from_bytes([2; 294]) };
+        router.handle_subscribe_directory(subscriber.clone()).await;
+        router.add_identity(peer.clone()).await;
+        assert_eq!(broadcast_blocked(&mut receiver, &subscriber), None);
+
+        router.toggle_blocked_handler(peer.clone()).await;
+        assert_eq!(broadcast_blocked(&mut receiver, &subscriber), Some("true".into()));
+        router.toggle_blocked_handler(peer.clone()).await;
+        assert_eq!(broadcast_blocked(&mut receiver, &subscriber), Some("false".into()));
+    }
+
+    #[tokio::test]
+    async fn clear_entry_removes_subscriptions() {
+        let (mut router, _receiver) = router();
+
+        let removed = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([1; 294]) };
+        let kept = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([2; 294]) };
+        for rel in [&removed, &kept] {
+            router.add_identity(rel.clone()).await;
+            router.process_remote_message(rel.clone(), RouterMessage::Subscribe("reading".into())).await;
+            router.process_remote_message(rel.clone(), RouterMessage::SubscribePattern("sensor.*".into())).await;
+        }
+        router.process_remote_message(removed.clone(), RouterMessage::Subscribe("only".into())).await;
+
+        router.clear_directory_entry_handler(removed.clone()).await;
+        assert!(!router.event_subscribers.contains_key("only"));
+        assert!(router.event_subscribers.values().all(|set| !set.contains(&removed)));
+        assert!(router.event_pattern_subscribers.iter().all(|(_, set)| !set.contains(&removed)));
+
+        // they are not restored from the state either
+        let (exact, patterns) = router.state.clone().load_event_subscriptions().await;
+        assert_eq!(exact.get("reading"), Some(&HashSet::from([kept.clone()])));
+        assert!(!exact.contains_key("only"));
+        assert_eq!(patterns, vec![("sensor.*".to_string(), HashSet::from([kept]))]);
+    }
+}
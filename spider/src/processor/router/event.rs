@@ -1,10 +1,28 @@
-use std::{time::Duration, collections::HashSet};
+use std::{time::Duration, collections::{HashMap, HashSet}};
 
 use spider_link::{Relation, message::{DatasetData, RouterMessage, Message}};
 use tokio::time::Instant;
 
 use super::RouterProcessorState;
 
+/// The most event types a single peer may subscribe to
+const MAX_PEER_SUBSCRIPTIONS: usize = 64;
+/// The most events that will be sent to a single peer in each window
+const PEER_EVENT_LIMIT: u32 = 50;
+/// The length of the window in which events to a peer are counted
+const PEER_EVENT_WINDOW: Duration = Duration::from_secs(1);
+
+// The event history holds at most EVENT_HISTORY_LEN events for each of at
+// most EVENT_HISTORY_NAMES event names, so its size is bounded by the size
+// of the events themselves. Events older than EVENT_HISTORY_AGE are removed
+// during upkeep.
+/// The most recent events kept for each event name
+const EVENT_HISTORY_LEN: usize = 16;
+/// The most event names that will have their history kept
+const EVENT_HISTORY_NAMES: usize = 256;
+/// How long an event is kept in the history
+const EVENT_HISTORY_AGE: Duration = Duration::from_secs(600);
+
 
 // event handling functions
 impl RouterProcessorState{
@@ -18,40 +36,17 @@ impl RouterProcessorState{
                 continue; // this recipient already recieved message via subscription
             }
             println!("Sending message to external...");
-            match self.links.get_mut(&external){
-                Some(link) => {
-                    // send to already-connected link
-                    println!("Link is connected");
-                    let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
-                    let msg = Message::Router(router_msg);
-                    link.send(msg).await;
-                    println!("Sent");
-                },
-                None => {
-                    // insert into pending links
-                    println!("Link is pending");
-                    match self.pending_links.get_mut(&external) {
-                        Some((_, tries, pending_msgs)) => {
-                            println!("adding message to entry");
-                            let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
-                            let msg = Message::Router(router_msg);
-                            pending_msgs.push(msg);
-                            *tries = 0;
-                        },
-                        None => {
-                            // not already in, need to init connection requests
-                            println!("new pending entry");
-                            let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
-                            let msg = Message::Router(router_msg);
-                            let pending_msgs = vec![msg];
-                            let mut t = Instant::now();
-                            t = t - Duration::from_secs(600);
-                            self.pending_links.insert(external.clone(), (t, 0u8, pending_msgs));
-                            // start connection process
-                            self.process_pending_link(external).await;
-                        },
-                    }
-                },
+            let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
+            let msg = Message::Router(router_msg);
+            if self.links.contains_key(&external){
+                // send to already-connected link
+                println!("Link is connected");
+                self.send_to_connected(&external, msg).await;
+                println!("Sent");
+            }else{
+                // insert into pending links
+                println!("Link is pending");
+                self.queue_pending(external, msg).await;
             }
         }
     }
@@ -61,27 +56,228 @@ impl RouterProcessorState{
         self.event_to_subscribers(&name, &from, &data).await;
     }
 
+    pub(crate) async fn replay_events(&mut self, rel: Relation, name: String, count: usize){
+        let history = match self.event_history.get(&name){
+            Some(history) => history,
+            None => return, // no events to replay
+        };
+        let skip = history.len().saturating_sub(count);
+        let msgs: Vec<Message> = history.iter().skip(skip).map(|(_, from, data)|{
+            let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
+            Message::Router(router_msg)
+        }).collect();
+        for msg in msgs{
+            self.send_msg(rel.clone(), msg).await;
+        }
+    }
+
+    pub(crate) fn expire_event_history(&mut self){
+        let now = Instant::now();
+        self.event_history.retain(|_, history|{
+            while let Some((time, _, _)) = history.front(){
+                if now.duration_since(*time) < EVENT_HISTORY_AGE{
+                    break;
+                }
+                history.pop_front();
+            }
+            !history.is_empty()
+        });
+    }
+
+    pub(crate) async fn handle_subscribe_pattern(&mut self, rel: Relation, pattern: String){
+        match self.event_pattern_subscribers.iter_mut().find(|(p, _)| *p == pattern){
+            Some((_, subscriber_set)) => {
+                subscriber_set.insert(rel);
+            },
+            None => {
+                self.event_pattern_subscribers.push((pattern, HashSet::from([rel])));
+            },
+        }
+    }
+
+    pub(crate) async fn handle_unsubscribe_pattern(&mut self, rel: Relation, pattern: String){
+        for (p, subscriber_set) in self.event_pattern_subscribers.iter_mut(){
+            if *p == pattern{
+                subscriber_set.remove(&rel);
+            }
+        }
+        self.event_pattern_subscribers.retain(|(_, subscriber_set)| !subscriber_set.is_empty());
+    }
+
+    /// Remove every event subscription held by the relation, for when it
+    /// is removed from the directory and can no longer unsubscribe.
+    pub(crate) fn remove_event_subscriptions(&mut self, rel: &Relation){
+        for subscribers in [&mut self.event_subscribers, &mut self.peer_event_subscribers]{
+            subscribers.retain(|_, subscriber_set| {
+                subscriber_set.remove(rel);
+                !subscriber_set.is_empty()
+            });
+        }
+        self.event_pattern_subscribers.retain_mut(|(_, subscriber_set)| {
+            subscriber_set.remove(rel);
+            !subscriber_set.is_empty()
+        });
+    }
+
+    pub(crate) async fn handle_peer_subscribe(&mut self, from: Relation, name: String){
+        if !from.is_peer(){
+            return; // peripherals subscribe with RouterMessage::Subscribe
+        }
+        let count = self.peer_event_subscribers.values().filter(|set| set.contains(&from)).count();
+        if count >= MAX_PEER_SUBSCRIPTIONS{
+            println!("Peer has too many event subscriptions, ignoring: {}", name);
+            return;
+        }
+        self.peer_event_subscribers.entry(name).or_default().insert(from);
+    }
+
+    pub(crate) async fn handle_peer_unsubscribe(&mut self, from: Relation, name: String){
+        if let Some(subscriber_set) = self.peer_event_subscribers.get_mut(&name){
+            subscriber_set.remove(&from);
+            if subscriber_set.is_empty() {
+                self.peer_event_subscribers.remove(&name);
+            }
+        }
+    }
+
 
 }
 
 // Helper functions
 impl RouterProcessorState{
     async fn event_to_subscribers(&mut self, name: &String, from: &Relation, data: &DatasetData) -> HashSet<Relation>{
+        self.record_event(name, from, data);
         let mut recipients = HashSet::new();
-        if let Some(subscriber_set) = self.event_subscribers.get(name){
-            for subscriber in subscriber_set{
-                // Check if source is external and dest is external, skip
-                if from.is_peer() && subscriber.is_peer(){
-                    continue;
-                }
-                if let Some(link) = self.links.get_mut(subscriber){
-                    recipients.insert(subscriber.clone());
-                    let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
-                    let msg = Message::Router(router_msg);
-                    link.send(msg).await;
+        let subscribers = subscribers_for(&self.event_subscribers, &self.event_pattern_subscribers, name);
+        for subscriber in subscribers{
+            // Check if source is external and dest is external, skip
+            if from.is_peer() && subscriber.is_peer(){
+                continue;
+            }
+            if self.links.contains_key(subscriber){
+                recipients.insert(subscriber.clone());
+            }
+        }
+        // Send to peer bases, events from other peers are not forwarded
+        if !from.is_peer(){
+            if let Some(subscriber_set) = self.peer_event_subscribers.get(name){
+                for subscriber in subscriber_set{
+                    if recipients.contains(subscriber){
+                        continue;
+                    }
+                    if !Self::peer_event_allowed(&mut self.peer_event_windows, subscriber){
+                        continue; // peer has recieved too many events recently
+                    }
+                    if self.links.contains_key(subscriber){
+                        recipients.insert(subscriber.clone());
+                    }
                 }
             }
         }
+        // every recipient is connected, so none are left pending
+        let router_msg = RouterMessage::Event(name.clone(), from.clone(), data.clone());
+        self.multicast_msg(recipients.iter().cloned().collect(), Message::Router(router_msg)).await;
         recipients
     }
-}
\ No newline at end of file
+
+    fn record_event(&mut self, name: &String, from: &Relation, data: &DatasetData){
+        if !self.event_history.contains_key(name) && self.event_history.len() >= EVENT_HISTORY_NAMES{
+            return; // history is full
+        }
+        let history = self.event_history.entry(name.clone()).or_default();
+        if history.len() >= EVENT_HISTORY_LEN{
+            history.pop_front();
+        }
+        history.push_back((Instant::now(), from.clone(), data.clone()));
+    }
+
+    /// Count an event towards the peer's limit, returning false if the
+    /// peer has reached the limit for the current window.
+    fn peer_event_allowed(windows: &mut HashMap<Relation, (Instant, u32)>, peer: &Relation) -> bool{
+        let now = Instant::now();
+        let (start, count) = windows.entry(peer.clone()).or_insert((now, 0));
+        if now.duration_since(*start) >= PEER_EVENT_WINDOW{
+            *start = now;
+            *count = 0;
+        }
+        if *count >= PEER_EVENT_LIMIT{
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+/// Returns true if the event name matches the pattern. A pattern ending in
+/// `*` matches any name beginning with the rest of the pattern.
+fn pattern_matches(pattern: &str, name: &str) -> bool{
+    match pattern.strip_suffix('*'){
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// Collect the subscribers to an event, both exact and by pattern. Each
+/// subscriber is included only once, even if it has several matching
+/// subscriptions.
+fn subscribers_for<'a>(
+    exact: &'a HashMap<String, HashSet<Relation>>,
+    patterns: &'a [(String, HashSet<Relation>)],
+    name: &str,
+) -> HashSet<&'a Relation>{
+    let mut subscribers: HashSet<&Relation> = HashSet::new();
+    if let Some(subscriber_set) = exact.get(name){
+        subscribers.extend(subscriber_set);
+    }
+    for (pattern, subscriber_set) in patterns{
+        if pattern_matches(pattern, name){
+            subscribers.extend(subscriber_set);
+        }
+    }
+    subscribers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use spider_link::{id::SpiderId, Relation, Role};
+
+    use super::{pattern_matches, subscribers_for};
+
+    fn relation(byte: u8) -> Relation {
+        Relation { role: Role::Peripheral, id: SpiderId::from_bytes([byte; 294]) }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("sensor.*", "sensor.temperature"));
+        assert!(pattern_matches("sensor.*", "sensor."));
+        assert!(!pattern_matches("sensor.*", "sensors"));
+        assert!(pattern_matches("*", "anything"));
+        assert!(pattern_matches("sensor", "sensor"));
+        assert!(!pattern_matches("sensor", "sensor.temperature"));
+    }
+
+    #[test]
+    fn overlapping_subscriptions() {
+        let both = relation(1);
+        let exact_only = relation(2);
+        let pattern_only = relation(3);
+
+        let mut exact = HashMap::new();
+        exact.insert("sensor.temperature".to_string(), HashSet::from([both.clone(), exact_only.clone()]));
+        let patterns = vec![
+            ("sensor.*".to_string(), HashSet::from([both.clone(), pattern_only.clone()])),
+            ("*".to_string(), HashSet::from([both.clone()])),
+        ];
+
+        let subscribers = subscribers_for(&exact, &patterns, "sensor.temperature");
+        assert_eq!(subscribers, HashSet::from([&both, &exact_only, &pattern_only]));
+
+        let subscribers = subscribers_for(&exact, &patterns, "sensor.humidity");
+        assert_eq!(subscribers, HashSet::from([&both, &pattern_only]));
+
+        let subscribers = subscribers_for(&exact, &patterns, "light");
+        assert_eq!(subscribers, HashSet::from([&both]));
+    }
+}
@@ -10,8 +10,14 @@ pub enum RouterProcessorMessage {
     NewLink(Link),
     SetApprovalCode(String),
     ApproveLink(String),
+    /// A pending link has sent an approval code that it was given, the
+    /// router checks that the code is still valid before approving it.
+    RedeemApprovalCode(String, String),
     DenyLink(String),
     ApprovedLink(Link),
+    LinkClosed(Relation),
+    /// A link has kept sending messages faster than the configured rate.
+    LinkFlooding(Relation),
 
     SendMessage(Relation, Message),
     MulticastMessage(Vec<Relation>, Message),
@@ -21,10 +27,16 @@ pub enum RouterProcessorMessage {
     LeaveChord(String),
 
     AddrUpdate(SpiderId2048, String),
+    /// A connection attempt started by an AddrUpdate has finished, with the
+    /// new link if it succeeded.
+    ConnectFinished(Relation, Option<Link>),
 
     SetName(String),
     SetNickname(Relation, String),
     ClearDirectoryEntry(Relation),
+    ToggleBlocked(Relation),
+    SetDirectoryProperty(Relation, String, String),
 
     Upkeep,
+    Shutdown,
 }
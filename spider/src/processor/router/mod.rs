@@ -1,19 +1,22 @@
-use std::{collections::{HashMap, HashSet}, time::Duration, sync::Arc};
+use std::{collections::{HashMap, HashSet, VecDeque}, time::Duration, sync::{Arc, atomic::{AtomicU64, Ordering}}};
 
 use dht_chord::associate::{AssociateRequest, AssociateResponse};
+use futures::future::join_all;
 use lru::LruCache;
 use spider_link::{
-    message::{Message, RouterMessage, DirectoryEntry},
+    message::{Message, RouterMessage, DirectoryEntry, DatasetData, ConnectionInfo, ErrorCode, ErrorMessage},
     Link, Relation, Role, SpiderId2048,
 };
 use tokio::{
     sync::{mpsc::{channel, error::SendError, Receiver, Sender}, watch},
-    task::{JoinError, JoinHandle}, time::Instant, select,
+    task::{JoinError, JoinHandle}, time::{timeout, Instant}, select,
 };
 
+use tracing::{debug, warn};
+
 use crate::{config::SpiderConfig, state_data::StateData};
 
-use self::{chord::ChordEntry, authorization::PendingLinkControl};
+use self::{chord::ChordEntry, authorization::PendingLinkControl, rate_limit::{RateDecision, RateLimiter}};
 
 use super::{message::ProcessorMessage, sender::ProcessorSender, ui::UiProcessorMessage, listener::ListenProcessorMessage};
 
@@ -22,6 +25,7 @@ mod event;
 mod chord;
 pub use chord::ChordState;
 mod directory;
+mod rate_limit;
 
 mod message;
 pub use message::RouterProcessorMessage;
@@ -54,6 +58,18 @@ impl RouterProcessor {
     }
 }
 
+/// The number of recent chord addresses kept, which is also the most that
+/// a chord subscriber can request.
+const CHORD_ADDRS_CAPACITY: usize = 500;
+
+/// The number of messages sent and received over a link. Received messages
+/// are counted by the task reading from the link, so the counts are shared.
+#[derive(Default)]
+struct LinkCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
 pub(crate) struct RouterProcessorState {
     config: SpiderConfig,
     state: StateData,
@@ -65,11 +81,18 @@ pub(crate) struct RouterProcessorState {
     approval_codes: HashMap<String, Instant>,
     incoming_links: HashMap<String, Sender<PendingLinkControl>>,
     links: HashMap<Relation, Link>,
+    link_counters: HashMap<Relation, Arc<LinkCounters>>,
     
     pending_links: HashMap<Relation, (Instant, u8, Vec<Message>)>,
+    /// Relations with a connection attempt in flight
+    connecting: HashSet<Relation>,
 
     // Event items
     event_subscribers: HashMap<String, HashSet<Relation>>,
+    event_pattern_subscribers: Vec<(String, HashSet<Relation>)>,
+    peer_event_subscribers: HashMap<String, HashSet<Relation>>,
+    peer_event_windows: HashMap<Relation, (Instant, u32)>,
+    event_history: HashMap<String, VecDeque<(Instant, Relation, DatasetData)>>,
 
     // Chord items
     chords: HashMap<String, ChordEntry>,
@@ -101,15 +124,21 @@ impl RouterProcessorState {
             approval_codes: HashMap::new(),
             incoming_links: HashMap::new(),
             links: HashMap::new(),
+            link_counters: HashMap::new(),
             pending_links: HashMap::new(),
+            connecting: HashSet::new(),
 
             // Event items
             event_subscribers: HashMap::new(),
+            event_pattern_subscribers: Vec::new(),
+            peer_event_subscribers: HashMap::new(),
+            peer_event_windows: HashMap::new(),
+            event_history: HashMap::new(),
 
             // Chord items
             chords: HashMap::new(),
             chord_subscribers: HashMap::new(),
-            chord_addrs: LruCache::new(500),
+            chord_addrs: LruCache::new(CHORD_ADDRS_CAPACITY),
 
             // Directory items
             directory_subscribers: HashSet::new(),
@@ -136,6 +165,9 @@ impl RouterProcessorState {
                     RouterProcessorMessage::ApproveLink(relation) => {
                         self.approve_link_handler(relation).await;
                     }
+                    RouterProcessorMessage::RedeemApprovalCode(relation, code) => {
+                        self.redeem_approval_code_handler(relation, code).await;
+                    }
                     RouterProcessorMessage::DenyLink(relation) => {
                         self.deny_link_handler(relation).await;
                     }
@@ -145,6 +177,12 @@ impl RouterProcessorState {
                     RouterProcessorMessage::ApprovedLink(link) => {
                         self.approved_link_handler(link).await;
                     }
+                    RouterProcessorMessage::LinkClosed(relation) => {
+                        self.link_closed_handler(relation).await;
+                    }
+                    RouterProcessorMessage::LinkFlooding(relation) => {
+                        self.link_flooding_handler(relation).await;
+                    }
 
                     RouterProcessorMessage::SendMessage(rel, msg) => {
                         self.send_msg(rel, msg).await;
@@ -164,21 +202,10 @@ impl RouterProcessorState {
                     },
 
                     RouterProcessorMessage::AddrUpdate(id, addr) => {
-                        // if there is already a link for this id, ignore. Otherwise:
-                        // create a new link to this address
-                        println!("Got addr update");
-                        let relation = Relation{role: Role::Peer, id};
-                        if !self.links.contains_key(&relation){
-                            println!("Creating new link");
-                            let self_relation = self.state.self_relation().await;
-                            let new_link = Link::connect(self_relation, addr, relation).await;
-                            if let Some(new_link) = new_link {
-                                println!("New link connected");
-                                self.approved_link_handler(new_link).await;
-                            }else{
-                                println!("Link failed to connect");
-                            }
-                        }
+                        self.addr_update_handler(id, addr).await;
+                    },
+                    RouterProcessorMessage::ConnectFinished(relation, link) => {
+                        self.connect_finished_handler(relation, link).await;
                     },
 
                     RouterProcessorMessage::SetName(name) => {
@@ -201,6 +228,8 @@ impl RouterProcessorState {
                             cb: |idx, name, input, _|{
                                 match input{
                                     spider_link::message::UiInput::Click => None,
+                                    spider_link::message::UiInput::Bool(_) => None,
+                                    spider_link::message::UiInput::Number(_) => None,
                                     spider_link::message::UiInput::Text(name) => {
                                         let router_msg = RouterProcessorMessage::SetName(name);
                                         let msg = ProcessorMessage::RouterMessage(router_msg);
@@ -212,11 +241,9 @@ impl RouterProcessorState {
                         };
                         self.sender.send_ui(msg).await;
                         // message name on existing channels
-                        for (_, link) in &self.links{
-                            let msg = RouterMessage::SetIdentityProperty("name".into(), name.clone());
-                            let msg = Message::Router(msg);
-                            link.send(msg).await;
-                        }
+                        let relations = self.links.keys().cloned().collect();
+                        let msg = RouterMessage::SetIdentityProperty("name".into(), name.clone());
+                        self.multicast_msg(relations, Message::Router(msg)).await;
                     }
                     RouterProcessorMessage::SetNickname(rel, name) => {
                         self.set_identity_system(rel, "nickname".into(), name).await;
@@ -224,13 +251,23 @@ impl RouterProcessorState {
                     RouterProcessorMessage::ClearDirectoryEntry(rel) => {
                         self.clear_directory_entry_handler(rel).await;
                     }
+                    RouterProcessorMessage::ToggleBlocked(rel) => {
+                        self.toggle_blocked_handler(rel).await;
+                    }
+                    RouterProcessorMessage::SetDirectoryProperty(rel, key, value) => {
+                        self.set_identity_user(rel, key, value).await;
+                    }
 
                     RouterProcessorMessage::Upkeep => {
-                        // should check for disconnected peers, and clean them up
+                        // Clean up disconnected links
+                        self.links.retain(|_, link| link.is_connected());
 
                         // Process pending links
                         self.process_pending_links().await;
 
+                        // Expire old events
+                        self.expire_event_history();
+
                         // Save chord state
                         for (name, chord_entry) in self.chords.iter_mut() {
                             let associate = chord_entry.get_associate();
@@ -257,7 +294,8 @@ impl RouterProcessorState {
                         // Handle chord address subscriptions
                         let mut messages = Vec::with_capacity(self.chord_subscribers.len());
                         for (rel, limit) in &self.chord_subscribers{
-                            let x: Vec<String> = self.chord_addrs.iter().take(*limit).map(|(x, _)|{x.clone()}).collect();
+                            let limit = (*limit).min(CHORD_ADDRS_CAPACITY);
+                            let x: Vec<String> = self.chord_addrs.iter().take(limit).map(|(x, _)|{x.clone()}).collect();
                             println!("Sending Chord Subscription: {:?}", x);
                             let msg = Message::Router(RouterMessage::ChordAddrs(x));
                             messages.push((rel.clone(), msg));
@@ -269,10 +307,19 @@ impl RouterProcessorState {
                         // Save Directory state
                         self.state.save_directory(&self.directory).await;
 
+                        // Save event subscriptions
+                        self.state.save_event_subscriptions(&self.event_subscribers, &self.event_pattern_subscribers).await;
+
                         // Clean approval codes
-                        self.approval_codes.retain(|_, v|{
-                            v < &mut Instant::now()
-                        });
+                        self.clean_approval_codes(Instant::now());
+                    }
+                    RouterProcessorMessage::Shutdown => {
+                        // Save Directory state
+                        self.state.save_directory(&self.directory).await;
+
+                        // Save event subscriptions
+                        self.state.save_event_subscriptions(&self.event_subscribers, &self.event_pattern_subscribers).await;
+                        break;
                     }
                 }
             }
@@ -294,6 +341,8 @@ impl RouterProcessorState {
             cb: |idx, name, input, _|{
                 match input{
                     spider_link::message::UiInput::Click => None,
+                    spider_link::message::UiInput::Bool(_) => None,
+                    spider_link::message::UiInput::Number(_) => None,
                     spider_link::message::UiInput::Text(name) => {
                         let router_msg = RouterProcessorMessage::SetName(name);
                         let msg = ProcessorMessage::RouterMessage(router_msg);
@@ -314,6 +363,8 @@ impl RouterProcessorState {
             cb: |idx, name, input, _|{
                 match input{
                     spider_link::message::UiInput::Click => None,
+                    spider_link::message::UiInput::Bool(_) => None,
+                    spider_link::message::UiInput::Number(_) => None,
                     spider_link::message::UiInput::Text(addr) => {
                         let router_msg = RouterProcessorMessage::JoinChord(addr);
                         let msg = ProcessorMessage::RouterMessage(router_msg);
@@ -333,6 +384,8 @@ impl RouterProcessorState {
             cb: |idx, name, input, _|{
                 match input{
                     spider_link::message::UiInput::Click => None,
+                    spider_link::message::UiInput::Bool(_) => None,
+                    spider_link::message::UiInput::Number(_) => None,
                     spider_link::message::UiInput::Text(addr) => {
                         let router_msg = RouterProcessorMessage::HostChord(addr);
                         let msg = ProcessorMessage::RouterMessage(router_msg);
@@ -350,6 +403,11 @@ impl RouterProcessorState {
         // Initialize directory functions
         self.init_directory_functions().await;
 
+        // Restore event subscriptions
+        let (exact, patterns) = self.state.load_event_subscriptions().await;
+        self.event_subscribers = exact;
+        self.event_pattern_subscribers = patterns;
+
     }
 
     async fn process_remote_message(&mut self, rel: Relation, msg: RouterMessage) {
@@ -380,6 +438,15 @@ impl RouterProcessorState {
                 let subscriber_set = entry.or_default();
                 subscriber_set.insert(rel);
             },
+            RouterMessage::SubscribeReplay(name, count) => {
+                if rel.is_peer(){
+                    return; // dont allow subscriptions from peers (at least for now)
+                }
+                let entry = self.event_subscribers.entry(name.clone());
+                let subscriber_set = entry.or_default();
+                subscriber_set.insert(rel.clone());
+                self.replay_events(rel, name, count).await;
+            },
             RouterMessage::Unsubscribe(name) => {
                 if rel.is_peer(){
                     return; // dont allow subscriptions from peers (at least for now)
@@ -394,6 +461,35 @@ impl RouterProcessorState {
                     None => {}, // there were no subscribers to this message type
                 }
             },
+            RouterMessage::SubscribePattern(pattern) => {
+                if rel.is_peer(){
+                    return; // dont allow subscriptions from peers (at least for now)
+                }
+                self.handle_subscribe_pattern(rel, pattern).await;
+            },
+            RouterMessage::UnsubscribePattern(pattern) => {
+                self.handle_unsubscribe_pattern(rel, pattern).await;
+            },
+            RouterMessage::SubscribePeer(peer, name) => {
+                if rel.is_peer() || !peer.is_peer(){
+                    return; // only peripherals may subscribe the base to a peer
+                }
+                let msg = Message::Router(RouterMessage::PeerSubscribe(name));
+                self.send_msg(peer, msg).await;
+            },
+            RouterMessage::UnsubscribePeer(peer, name) => {
+                if rel.is_peer() || !peer.is_peer(){
+                    return; // only peripherals may subscribe the base to a peer
+                }
+                let msg = Message::Router(RouterMessage::PeerUnsubscribe(name));
+                self.send_msg(peer, msg).await;
+            },
+            RouterMessage::PeerSubscribe(name) => {
+                self.handle_peer_subscribe(rel, name).await;
+            },
+            RouterMessage::PeerUnsubscribe(name) => {
+                self.handle_peer_unsubscribe(rel, name).await;
+            },
 
             // Directory Messages
             RouterMessage::SubscribeDir => {
@@ -411,10 +507,25 @@ impl RouterProcessorState {
             RouterMessage::SetIdentityProperty(key, value) => {
                 self.set_identity_self(rel, key, value).await;
             }
+            RouterMessage::SearchDir { query } => {
+                self.handle_search_directory(rel, query).await;
+            }
+            RouterMessage::SearchDirResult(_) => {
+                // base send this, doesnt recieve
+            }
 
             // Chord Connected Messages
             RouterMessage::SubscribeChord(limit) => {
                 println!("===== Subscribing to chord!");
+                // the limit comes from the subscriber, so keep it within
+                // the number of addresses there could be
+                if limit == 0 {
+                    let msg = ErrorMessage::new(ErrorCode::InvalidRequest, "Chord subscription limit must be at least 1");
+                    let msg = Message::Error(msg);
+                    self.send_msg(rel, msg).await;
+                    return;
+                }
+                let limit = limit.min(CHORD_ADDRS_CAPACITY);
                 self.chord_subscribers.insert(rel.clone(), limit);
                 let x: Vec<String> = self.chord_addrs.iter().take(limit).map(|(x, _)|{x.clone()}).collect();
                 let msg = Message::Router(RouterMessage::ChordAddrs(x));
@@ -426,6 +537,19 @@ impl RouterProcessorState {
             RouterMessage::ChordAddrs(..) => {
                 // base sends this, doesnt recieve
             }
+
+            // Connection Messages
+            RouterMessage::ListConnections => {
+                // peers should not learn who else is connected
+                if let Role::Peer = rel.role {
+                    return;
+                }
+                let msg = Message::Router(RouterMessage::Connections(self.connection_list()));
+                self.send_msg(rel, msg).await;
+            }
+            RouterMessage::Connections(_) => {
+                // base sends this, doesnt recieve
+            }
         }
     }
 
@@ -438,12 +562,16 @@ impl RouterProcessorState {
         };
         let relation = link.other_relation().clone();
 
+        // add link to structures, counts start over for each new link
+        let counters = Arc::new(LinkCounters::default());
+        self.links.insert(relation.clone(), link);
+        self.link_counters.insert(relation.clone(), counters.clone());
+
         // Send name
         let name = self.state.name().await;
         let msg = RouterMessage::SetIdentityProperty("name".into(), name.clone());
         drop(name);
-        let msg = Message::Router(msg);
-        link.send(msg).await;
+        self.send_to_connected(&relation, Message::Router(msg)).await;
 
         // add link relation to directory
         self.add_identity(relation.clone()).await;
@@ -452,19 +580,30 @@ impl RouterProcessorState {
         if let Some((_, _, msgs)) = self.pending_links.remove(&relation){
             for msg in msgs{
                 println!("Adding message to new link");
-                link.send(msg).await;
+                if !self.send_to_connected(&relation, msg).await{
+                    break; // the link has closed
+                }
             }
         }
-        
-        // add link to structures
-        self.links.insert(relation.clone(), link);
 
         // start link processor
         let channel = self.sender.clone();
+        let mut limiter = RateLimiter::from_config(&self.config);
         tokio::spawn(async move {
             loop {
                 match rx.recv().await {
                     Some(msg) => {
+                        counters.received.fetch_add(1, Ordering::Relaxed);
+                        match limiter.check() {
+                            RateDecision::Allow => {}
+                            RateDecision::Drop => continue,
+                            RateDecision::Abuse => {
+                                warn!("Link is flooding messages, closing");
+                                let router_msg = RouterProcessorMessage::LinkFlooding(relation.clone());
+                                channel.send(ProcessorMessage::RouterMessage(router_msg)).await;
+                                break;
+                            }
+                        }
                         match channel
                             .send(ProcessorMessage::RemoteMessage(relation.clone(), msg))
                             .await
@@ -476,24 +615,196 @@ impl RouterProcessorState {
                     None => break, // connection is finished
                 }
             }
+            // inform the router so it can clean up after the link
+            let router_msg = RouterProcessorMessage::LinkClosed(relation);
+            channel.send(ProcessorMessage::RouterMessage(router_msg)).await;
         });
     }
 
+    async fn addr_update_handler(&mut self, id: SpiderId2048, addr: String) {
+        // if there is already a link for this id, or a connection attempt in
+        // flight, ignore. Otherwise: create a new link to this address
+        debug!("Got addr update");
+        let relation = Relation{role: Role::Peer, id};
+        if self.links.contains_key(&relation) || !self.connecting.insert(relation.clone()) {
+            return;
+        }
+        println!("Creating new link");
+        let self_relation = self.state.self_relation().await;
+        let max_frame_len = self.config.max_frame_len;
+        let channel = self.sender.clone();
+        // connect in a task, so the router is not blocked while it waits
+        tokio::spawn(async move {
+            let new_link = match Link::connect_with_max_frame_len(self_relation, addr, relation.clone(), max_frame_len).await {
+                Ok(new_link) => {
+                    println!("New link connected");
+                    Some(new_link)
+                }
+                Err(e) => {
+                    println!("Link failed to connect: {}", e);
+                    None
+                }
+            };
+            let router_msg = RouterProcessorMessage::ConnectFinished(relation, new_link);
+            channel.send(ProcessorMessage::RouterMessage(router_msg)).await;
+        });
+    }
+
+    async fn connect_finished_handler(&mut self, relation: Relation, link: Option<Link>) {
+        self.connecting.remove(&relation);
+        if let Some(link) = link {
+            self.approved_link_handler(link).await;
+        }
+    }
+
+    async fn link_closed_handler(&mut self, relation: Relation) {
+        // the relation may have reconnected with a new link in the meantime
+        if let Some(link) = self.links.get(&relation) {
+            if link.is_connected() {
+                return;
+            }
+        }
+        self.links.remove(&relation);
+        self.link_counters.remove(&relation);
+        self.peer_event_windows.remove(&relation);
+
+        // remove the page of a disconnected peripheral
+        if let Role::Peripheral = relation.role {
+            let msg = UiProcessorMessage::PeripheralGone(relation.id);
+            self.sender.send_ui(msg).await;
+        }
+    }
+
     async fn send_msg(&mut self, relation: Relation, msg: Message) {
         // println!("Sending message: {:?}", msg);
-        match self.links.get_mut(&relation) {
-            Some(link) => {
-                link.send(msg).await;
+        if self.links.contains_key(&relation) {
+            self.send_to_connected(&relation, msg).await;
+        } else {
+            // buffer messages for peers until they connect, peripherals
+            // connect to the base so they cannot be looked up
+            if let Role::Peer = relation.role {
+                self.queue_pending(relation, msg).await;
+            }
+        }
+    }
+
+    /// Send a message over the relation's link, if it is connected.
+    /// Returns false if the message could not be sent.
+    async fn send_to_connected(&mut self, relation: &Relation, msg: Message) -> bool {
+        let limit = self.link_send_timeout();
+        let link = match self.links.get(relation) {
+            Some(link) => link,
+            None => return false,
+        };
+        let outcome = send_to_link(link, msg, limit).await;
+        self.record_send(relation, outcome)
+    }
+
+    /// Count a message sent over a link, or close the link if it stalled.
+    /// Returns false if the message was not sent.
+    fn record_send(&mut self, relation: &Relation, outcome: SendOutcome) -> bool {
+        match outcome {
+            SendOutcome::Sent => {
+                if let Some(counters) = self.link_counters.get(relation) {
+                    counters.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                true
+            }
+            SendOutcome::Closed => false, // cleaned up when the link reports it closed
+            SendOutcome::TimedOut => {
+                self.close_stalled_link(relation);
+                false
             }
-            None => { // no link, no send at the moment (should buffer messages and start a new connection)
-                //
-            } 
         }
     }
 
+    /// Buffer a message for a relation that is not connected, and start
+    /// looking up its address if this is the first message. Up to the
+    /// configured pending_message_limit are kept, after which the oldest
+    /// message is dropped for each new one, so the most recent messages are
+    /// sent once the relation connects.
+    pub(crate) async fn queue_pending(&mut self, relation: Relation, msg: Message) {
+        let limit = self.config.pending_message_limit.max(1);
+        match self.pending_links.get_mut(&relation) {
+            Some((_, tries, pending_msgs)) => {
+                debug!("adding message to entry");
+                if pending_msgs.len() >= limit {
+                    let excess = pending_msgs.len() + 1 - limit;
+                    warn!("Pending messages are full, dropping {} oldest", excess);
+                    pending_msgs.drain(..excess);
+                }
+                pending_msgs.push(msg);
+                *tries = 0;
+            },
+            None => {
+                // not already in, need to init connection requests
+                println!("new pending entry");
+                let pending_msgs = vec![msg];
+                let mut t = Instant::now();
+                t -= Duration::from_secs(600);
+                self.pending_links.insert(relation.clone(), (t, 0u8, pending_msgs));
+                // start connection process
+                self.process_pending_link(relation).await;
+            },
+        }
+    }
+
+    /// Describe each relation with a live link, with its nickname from the
+    /// directory and the number of messages sent over its link.
+    fn connection_list(&self) -> Vec<ConnectionInfo> {
+        self.links
+            .iter()
+            .filter(|(_, link)| link.is_connected())
+            .map(|(relation, _)| {
+                let nickname = self.directory.get(relation).and_then(|entry| entry.get("nickname")).cloned();
+                let (sent, received) = match self.link_counters.get(relation) {
+                    Some(counters) => (counters.sent.load(Ordering::Relaxed), counters.received.load(Ordering::Relaxed)),
+                    None => (0, 0),
+                };
+                ConnectionInfo::new(relation.clone(), nickname, sent, received)
+            })
+            .collect()
+    }
+
+    fn link_send_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.link_send_timeout_ms)
+    }
+
+    /// Close a link that has stopped accepting messages, so that it cannot
+    /// hold up the router. The link is terminated in its own task, as its
+    /// processor may be too stuck to stop promptly.
+    fn close_stalled_link(&mut self, relation: &Relation) {
+        if let Some(link) = self.links.remove(relation) {
+            warn!("Closing a {:?} link that stopped accepting messages", relation.role);
+            tokio::spawn(link.terminate());
+        }
+    }
+
+    /// Send a message to each of the relations. Connected links are sent
+    /// to concurrently, so a slow link only delays its own message, up to
+    /// the link send timeout. Messages to each relation arrive in the order
+    /// they were sent, but there is no order between relations.
     async fn multicast_msg(&mut self, relations: Vec<Relation>, msg: Message) {
+        let limit = self.link_send_timeout();
+        let mut sends = Vec::new();
+        let mut offline = Vec::new();
         for relation in relations {
-            self.send_msg(relation, msg.clone()).await
+            match self.links.get(&relation) {
+                Some(link) => {
+                    let msg = msg.clone();
+                    sends.push(async move {
+                        let outcome = send_to_link(link, msg, limit).await;
+                        (relation, outcome)
+                    });
+                }
+                None => offline.push(relation),
+            }
+        }
+        for (relation, outcome) in join_all(sends).await {
+            self.record_send(&relation, outcome);
+        }
+        for relation in offline {
+            self.send_msg(relation, msg.clone()).await;
         }
     }
 
@@ -501,12 +812,20 @@ impl RouterProcessorState {
         if let Some((start, tries, msgs)) = self.pending_links.get_mut(&relation){
             println!("Processing pending");
             // check if pending link has connected
-            if let Some(link) = self.links.get_mut(&relation){
+            if self.links.contains_key(&relation){
                 println!("Found link, inserting messages");
+                let msgs = std::mem::take(msgs);
+                self.pending_links.remove(&relation);
                 for msg in msgs{
-                    link.send(msg.clone()).await;
+                    if !self.send_to_connected(&relation, msg).await{
+                        break; // the link has closed
+                    }
                 }
-                self.pending_links.remove(&relation);
+                return;
+            }
+
+            // wait for a connection attempt that is already in flight
+            if self.connecting.contains(&relation){
                 return;
             }
 
@@ -542,3 +861,312 @@ impl RouterProcessorState {
     }
 
 }
+
+/// The result of sending a message over a link.
+enum SendOutcome {
+    Sent,
+    /// The link has closed.
+    Closed,
+    /// The link did not accept the message in time.
+    TimedOut,
+}
+
+/// Send a message over a link, giving up if the link does not accept it
+/// within the limit, such as when its processor is stuck and its channel
+/// is full.
+async fn send_to_link(link: &Link, msg: Message, limit: Duration) -> SendOutcome {
+    match timeout(limit, link.send(msg)).await {
+        Ok(Ok(())) => SendOutcome::Sent,
+        Ok(Err(_)) => SendOutcome::Closed,
+        Err(_) => SendOutcome::TimedOut,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, sync::Arc, time::Duration};
+
+    use spider_link::{id::SpiderId, message::{ConnectionInfo, DatasetData, Message, RouterMessage}, Link, Relation, Role, SelfRelation, SpiderId2048};
+    use tokio::{sync::{mpsc::{channel, Receiver}, Mutex}, time::{sleep, timeout}};
+
+    use crate::{
+        config::SpiderConfig,
+        processor::{message::ProcessorMessage, sender::ProcessorSender, ui::UiProcessorMessage},
+        state_data::StateData,
+    };
+
+    use super::{RouterProcessorMessage, RouterProcessorState, CHORD_ADDRS_CAPACITY};
+
+    /// A router with an empty config and a fresh key, along with
+    /// the receiver for the messages it sends to other processors.
+    pub(super) fn router() -> (RouterProcessorState, Receiver<ProcessorMessage>) {
+        let config: SpiderConfig = serde_json::from_str("{}").unwrap();
+        let state = StateData::with_generated_key(&temp_dir().join("spider_router_test_state"));
+        let (sender, receiver) = channel(50);
+        let (_router_sender, router_receiver) = channel(50);
+        (RouterProcessorState::new(config, state, ProcessorSender::new(sender), router_receiver), receiver)
+    }
+
+    #[tokio::test]
+    async fn one_connection_per_relation() {
+        let (mut router, mut receiver) = router();
+
+        // nothing is listening at this address, so both attempts would fail
+        let id = SpiderId::from_bytes([3; 294]);
+        router.addr_update_handler(id.clone(), "127.0.0.1:1953".into()).await;
+        router.addr_update_handler(id.clone(), "127.0.0.1:1953".into()).await;
+        assert_eq!(router.connecting.len(), 1);
+
+        let msg = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        let (relation, link) = match msg {
+            ProcessorMessage::RouterMessage(RouterProcessorMessage::ConnectFinished(relation, link)) => (relation, link),
+            other => panic!("unexpected message: {:?}", other),
+        };
+        assert!(link.is_none());
+        // with the clock paused, the timeout only elapses once nothing
+        // else is left to run
+        tokio::time::pause();
+        assert!(timeout(Duration::from_millis(200), receiver.recv()).await.is_err());
+
+        // once finished, another attempt can be made
+        router.connect_finished_handler(relation, link).await;
+        assert!(router.connecting.is_empty());
+        router.addr_update_handler(id.clone(), "127.0.0.1:1953".into()).await;
+        assert!(router.connecting.contains(&Relation { role: Role::Peer, id }));
+    }
+
+    #[tokio::test]
+    async fn buffer_offline_peer_messages() {
+        let (mut router, _receiver) = router();
+
+        let peer = Relation { role: Role::Peer, id: SpiderId::from_bytes([4; 294]) };
+        let limit = router.config.pending_message_limit;
+        for i in 0..limit + 50 {
+            router.send_msg(peer.clone(), Message::Error(i.to_string().into())).await;
+        }
+        // the oldest messages are dropped
+        let (_, _, msgs) = router.pending_links.get(&peer).unwrap();
+        assert_eq!(msgs.len(), limit);
+        assert!(matches!(&msgs[0], Message::Error(i) if i.detail() == "50"));
+
+        // messages to peripherals are not buffered
+        let peripheral = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([5; 294]) };
+        router.send_msg(peripheral.clone(), Message::Error("lost".into())).await;
+        assert!(!router.pending_links.contains_key(&peripheral));
+    }
+
+    #[tokio::test]
+    async fn chord_subscription_limit() {
+        let (mut router, _receiver) = router();
+
+        let peripheral = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([6; 294]) };
+        router.process_remote_message(peripheral.clone(), RouterMessage::SubscribeChord(usize::MAX)).await;
+        assert_eq!(router.chord_subscribers.get(&peripheral), Some(&CHORD_ADDRS_CAPACITY));
+
+        let other = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([7; 294]) };
+        router.process_remote_message(other.clone(), RouterMessage::SubscribeChord(0)).await;
+        assert!(!router.chord_subscribers.contains_key(&other));
+    }
+
+    #[tokio::test]
+    async fn list_connections_peripheral_only() {
+        let (mut router, _receiver) = router();
+        assert!(router.connection_list().is_empty());
+
+        // a reply to a peer would be buffered until it connects
+        let peer = Relation { role: Role::Peer, id: SpiderId::from_bytes([8; 294]) };
+        router.process_remote_message(peer.clone(), RouterMessage::ListConnections).await;
+        assert!(!router.pending_links.contains_key(&peer));
+
+        // a peripheral is sent the list, and the events it was sent count
+        let base = SelfRelation::generate_key(Role::Peer);
+        let peripheral = SelfRelation::generate_key(Role::Peripheral);
+        let rel = peripheral.relation.clone();
+        let (mut link, accepted) = connect_pair("127.0.0.1:1945", base, peripheral).await;
+        router.approved_link_handler(accepted).await;
+        router.event_subscribers.entry("lamp".into()).or_default().insert(rel.clone());
+        router.handle_event("lamp".into(), peer.clone(), DatasetData::Null).await;
+        router.handle_event("lamp".into(), peer, DatasetData::Null).await;
+
+        let connections = list_connections(&mut router, &rel, &mut link).await;
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].relation(), &rel);
+        assert_eq!(connections[0].messages_received(), 0);
+        // the base's name and both events
+        assert_eq!(connections[0].messages_sent(), 3);
+    }
+
+    /// Have the peripheral send messages to the base, waiting until the
+    /// router has received all of them.
+    async fn send_from(link: &Link, receiver: &mut Receiver<ProcessorMessage>, count: usize) {
+        for i in 0..count {
+            link.send(Message::Error(i.to_string().into())).await.unwrap();
+        }
+        let mut received = 0;
+        while received < count {
+            let msg = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+            if let ProcessorMessage::RemoteMessage(..) = msg {
+                received += 1;
+            }
+        }
+    }
+
+    /// Request the list of connections over the link, returning the reply.
+    async fn list_connections(router: &mut RouterProcessorState, rel: &Relation, link: &mut Link) -> Vec<ConnectionInfo> {
+        router.process_remote_message(rel.clone(), RouterMessage::ListConnections).await;
+        loop {
+            let msg = timeout(Duration::from_secs(5), link.recv()).await.unwrap().unwrap();
+            if let Message::Router(RouterMessage::Connections(connections)) = msg {
+                return connections;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_connections_reply() {
+        let (mut router, mut receiver) = router();
+        let base = SelfRelation::generate_key(Role::Peer);
+        let peripheral = SelfRelation::generate_key(Role::Peripheral);
+        let rel = peripheral.relation.clone();
+
+        let (mut link, accepted) = connect_pair("127.0.0.1:1944", base, peripheral).await;
+        router.approved_link_handler(accepted).await;
+        router.set_identity_system(rel.clone(), "nickname".into(), "Lamp".into()).await;
+
+        send_from(&link, &mut receiver, 3).await;
+        let connections = list_connections(&mut router, &rel, &mut link).await;
+        assert_eq!(connections.len(), 1);
+        let info = &connections[0];
+        assert_eq!(info.relation(), &rel);
+        assert_eq!(info.relation().role, Role::Peripheral);
+        assert_eq!(info.nickname(), Some(&"Lamp".to_string()));
+        assert_eq!(info.messages_received(), 3);
+        // the base's name is sent when the link is approved
+        assert_eq!(info.messages_sent(), 1);
+
+        // the counters go up as messages are exchanged, including the
+        // previous reply
+        router.send_msg(rel.clone(), Message::Error("one".into())).await;
+        router.send_msg(rel.clone(), Message::Error("two".into())).await;
+        send_from(&link, &mut receiver, 2).await;
+        let connections = list_connections(&mut router, &rel, &mut link).await;
+        assert_eq!(connections[0].messages_received(), 5);
+        assert_eq!(connections[0].messages_sent(), 4);
+    }
+
+    /// Connect two links over the address, returning the relation of the
+    /// listening side, the link to it, and the link it accepted.
+    async fn link_pair(addr: &'static str) -> (Relation, Link, Link) {
+        let base = SelfRelation::generate_key(Role::Peer);
+        let peripheral = SelfRelation::generate_key(Role::Peripheral);
+        let (link, accepted) = connect_pair(addr, base.clone(), peripheral).await;
+        (base.relation, link, accepted)
+    }
+
+    /// Connect the peripheral to the base over the address, returning the
+    /// peripheral's link and the link the base accepted.
+    async fn connect_pair(addr: &'static str, base: SelfRelation, peripheral: SelfRelation) -> (Link, Link) {
+        let (links, mut listener) = channel(1);
+        let key_request = Arc::new(Mutex::new(None));
+        let accept = Link::listen_on(base.clone(), addr, links, key_request, 1 << 20).await.unwrap();
+        let link = Link::connect(peripheral, addr, base.relation).await.unwrap();
+        let accepted = listener.recv().await.unwrap();
+        accept.abort();
+        (link, accepted)
+    }
+
+    /// Receive messages until a link closes, returning its relation.
+    async fn until_closed(receiver: &mut Receiver<ProcessorMessage>) -> Relation {
+        loop {
+            let msg = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+            if let ProcessorMessage::RouterMessage(RouterProcessorMessage::LinkClosed(relation)) = msg {
+                return relation;
+            }
+        }
+    }
+
+    fn peripherals_gone(receiver: &mut Receiver<ProcessorMessage>) -> Vec<SpiderId2048> {
+        let mut gone = Vec::new();
+        while let Ok(msg) = receiver.try_recv() {
+            if let ProcessorMessage::UiMessage(UiProcessorMessage::PeripheralGone(id)) = msg {
+                gone.push(id);
+            }
+        }
+        gone
+    }
+
+    #[tokio::test]
+    async fn dropped_link_removes_peripheral() {
+        let (mut router, mut receiver) = router();
+        let base = SelfRelation::generate_key(Role::Peer);
+        let peripheral = SelfRelation::generate_key(Role::Peripheral);
+
+        let (link, accepted) = connect_pair("127.0.0.1:1942", base.clone(), peripheral.clone()).await;
+        router.approved_link_handler(accepted).await;
+        assert!(router.links.contains_key(&peripheral.relation));
+
+        // the peripheral goes away
+        drop(link);
+        let relation = until_closed(&mut receiver).await;
+        assert_eq!(relation, peripheral.relation);
+        router.link_closed_handler(relation).await;
+        assert!(!router.links.contains_key(&peripheral.relation));
+        assert_eq!(peripherals_gone(&mut receiver), vec![peripheral.relation.id.clone()]);
+
+        // it reconnects before the close of an older link is handled
+        let (_link, accepted) = connect_pair("127.0.0.1:1943", base, peripheral.clone()).await;
+        router.approved_link_handler(accepted).await;
+        router.link_closed_handler(peripheral.relation.clone()).await;
+        assert!(router.links.contains_key(&peripheral.relation));
+        assert!(peripherals_gone(&mut receiver).is_empty());
+    }
+
+    #[tokio::test]
+    async fn multicast_fans_out() {
+        let (mut router, _receiver) = router();
+
+        let (connected, link, mut accepted) = link_pair("127.0.0.1:1941").await;
+        router.links.insert(connected.clone(), link);
+        let offline = Relation { role: Role::Peer, id: SpiderId::from_bytes([9; 294]) };
+
+        let msg = Message::Error("multicast".into());
+        router.multicast_msg(vec![connected, offline.clone()], msg).await;
+        match timeout(Duration::from_secs(5), accepted.recv()).await.unwrap().unwrap() {
+            Message::Error(error) => assert_eq!(error.detail(), "multicast"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        // messages to relations without a link are still buffered
+        assert_eq!(router.pending_links[&offline].2.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stalled_link_closed() {
+        let (mut router, _receiver) = router();
+        router.config.link_send_timeout_ms = 50;
+
+        // nothing reads the messages arriving on the router's link, so once
+        // its incoming channel is full its processor blocks and stops
+        // taking outgoing messages
+        let (base, link, stuck) = link_pair("127.0.0.1:1939").await;
+        router.links.insert(base.clone(), link);
+        for i in 0..60 {
+            stuck.send(Message::Error(i.to_string().into())).await.unwrap();
+        }
+        // the links are connected, so pause the clock. Sleeps and send
+        // timeouts then elapse as soon as nothing else is left to run,
+        // rather than after real time has passed
+        tokio::time::pause();
+        // give them time to arrive
+        sleep(Duration::from_millis(200)).await;
+
+        // the outgoing channel holds 50 messages, the processor may take a
+        // few more before it blocks
+        for _ in 0..100 {
+            router.send_msg(base.clone(), Message::Error("small".into())).await;
+            if !router.links.contains_key(&base) {
+                return;
+            }
+        }
+        panic!("stalled link was not closed");
+    }
+}
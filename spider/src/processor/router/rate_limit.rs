@@ -0,0 +1,114 @@
+use tokio::time::Instant;
+
+use crate::config::SpiderConfig;
+
+/// The result of checking a message against a [RateLimiter].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RateDecision {
+    /// The message is within the rate and should be processed.
+    Allow,
+    /// The message is over the rate and should be dropped.
+    Drop,
+    /// The sender has stayed over the rate for too long.
+    Abuse,
+}
+
+/// A token bucket limiting the messages received over a single link.
+/// The bucket holds up to `burst` tokens and refills at `rate` tokens per
+/// second, each message takes one token. Messages that arrive while the
+/// bucket is empty are dropped, and once `abuse_limit` messages have been
+/// dropped without the bucket refilling completely the sender is
+/// considered abusive.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    abuse_limit: u32,
+    tokens: f64,
+    dropped: u32,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32, abuse_limit: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate: rate as f64,
+            burst,
+            abuse_limit,
+            tokens: burst,
+            dropped: 0,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn from_config(config: &SpiderConfig) -> Self {
+        Self::new(config.link_rate, config.link_burst, config.link_abuse_limit)
+    }
+
+    pub fn check(&mut self) -> RateDecision {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> RateDecision {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+        // a full bucket means the sender slowed down, forgive earlier drops
+        if self.tokens >= self.burst {
+            self.dropped = 0;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return RateDecision::Allow;
+        }
+
+        self.dropped = self.dropped.saturating_add(1);
+        if self.dropped >= self.abuse_limit {
+            RateDecision::Abuse
+        } else {
+            RateDecision::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{RateDecision, RateLimiter};
+
+    #[test]
+    fn burst_then_refill() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10, 5, 100);
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(start), RateDecision::Allow);
+        }
+        assert_eq!(limiter.check_at(start), RateDecision::Drop);
+
+        // a tenth of a second refills one token
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.check_at(later), RateDecision::Allow);
+        assert_eq!(limiter.check_at(later), RateDecision::Drop);
+    }
+
+    #[test]
+    fn sustained_abuse() {
+        let mut now = Instant::now();
+        let mut limiter = RateLimiter::new(10, 1, 3);
+        assert_eq!(limiter.check_at(now), RateDecision::Allow);
+        assert_eq!(limiter.check_at(now), RateDecision::Drop);
+        assert_eq!(limiter.check_at(now), RateDecision::Drop);
+
+        // slowing down until the bucket is full forgives the drops
+        now += Duration::from_secs(1);
+        assert_eq!(limiter.check_at(now), RateDecision::Allow);
+        assert_eq!(limiter.check_at(now), RateDecision::Drop);
+        assert_eq!(limiter.check_at(now), RateDecision::Drop);
+        assert_eq!(limiter.check_at(now), RateDecision::Abuse);
+    }
+}
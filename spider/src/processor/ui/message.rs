@@ -1,12 +1,13 @@
 use spider_link::{
     message::{AbsoluteDatasetPath, DatasetData, UiInput, UiMessage},
-    Relation,
+    Relation, SpiderId2048,
 };
 
 use crate::processor::message::ProcessorMessage;
 
 pub enum UiProcessorMessage {
     RemoteMessage(Relation, UiMessage),
+    PeripheralGone(SpiderId2048),
     DatasetUpdate(AbsoluteDatasetPath, Vec<DatasetData>),
     SetSettingHeader{header: String},
     SetSetting {
@@ -21,7 +22,13 @@ pub enum UiProcessorMessage {
         header: String,
         title: String,
     },
+    /// Show the base's private dataset ["logs", name] on the settings page,
+    /// in place of any logs that were shown before.
+    ShowLogs {
+        name: String,
+    },
     Upkeep,
+    Shutdown,
 }
 
 impl std::fmt::Debug for UiProcessorMessage {
@@ -32,6 +39,10 @@ impl std::fmt::Debug for UiProcessorMessage {
                 .field(arg0)
                 .field(arg1)
                 .finish(),
+            Self::PeripheralGone(id) => f
+                .debug_tuple("PeripheralGone")
+                .field(id)
+                .finish(),
             Self::DatasetUpdate(path, dataset) => f
                 .debug_struct("DatasetUpdate")
                 .field("path", path)
@@ -65,7 +76,12 @@ impl std::fmt::Debug for UiProcessorMessage {
                 .field("header", header)
                 .field("title", title)
                 .finish(),
+            Self::ShowLogs { name } => f
+                .debug_struct("ShowLogs")
+                .field("name", name)
+                .finish(),
             Self::Upkeep => write!(f, "Upkeep"),
+            Self::Shutdown => write!(f, "Shutdown"),
         }
     }
 }
@@ -1,13 +1,14 @@
 use std::collections::{BTreeSet, HashMap, HashSet};
 
 use spider_link::{
-    message::{Message, UiMessage, UiPageList, UiInput, AbsoluteDatasetPath, UiElementUpdate, UiPageManager, UiChildOperations, UpdateSummary, DatasetData},
-    Relation, Role,
+    message::{Message, UiMessage, UiPageList, UiInput, AbsoluteDatasetPath, AbsoluteDatasetScope, UiElementUpdate, UiPageManager, UiChildOperations, UpdateSummary, DatasetData, ErrorCode, ErrorMessage},
+    Relation, Role, SpiderId2048,
 };
 use tokio::{
     sync::mpsc::{channel, error::SendError, Receiver, Sender},
     task::{JoinError, JoinHandle},
 };
+use tracing::warn;
 
 use crate::{config::SpiderConfig, state_data::StateData};
 
@@ -106,6 +107,9 @@ impl UiProcessorState {
                     UiProcessorMessage::RemoteMessage(rel, msg) => {
                         self.process_remote_message(rel, msg).await
                     }
+                    UiProcessorMessage::PeripheralGone(id) => {
+                        self.remove_page(id).await;
+                    }
                     UiProcessorMessage::DatasetUpdate(path, dataset) => {
                         // forward dataset updates to clients
                         let msg = UiMessage::Dataset(path, dataset);
@@ -126,7 +130,11 @@ impl UiProcessorState {
                     UiProcessorMessage::RemoveSetting{header, title} => {
                         self.remove_setting(header, title).await;
                     }
+                    UiProcessorMessage::ShowLogs { name } => {
+                        self.show_logs(name).await;
+                    }
                     UiProcessorMessage::Upkeep => {}
+                    UiProcessorMessage::Shutdown => break,
                 }
             }
         });
@@ -160,8 +168,14 @@ impl UiProcessorState {
             },
             UiMessage::Page(_) => {} // ignore, (base sends this, doesnt process it)
             UiMessage::UpdateElementsFor(_, _) => {} // ignore, (base sends this, doesnt process it)
+            UiMessage::RemovePage(_) => {} // ignore, (base sends this, doesnt process it)
             UiMessage::Dataset(_, _) => {} // ignore, (base sends this, doesnt process it)
             UiMessage::InputFor(peripheral_id, element_id, dataset_ids, input) => {
+                if let UiInput::Number(value) = input {
+                    if !value.is_finite() {
+                        return; // not a value any slider could produce
+                    }
+                }
                 // if this is for the settings page, put it there
                 if self.state.self_id().await == peripheral_id {
                     self.settings_input(&element_id, dataset_ids, input).await;
@@ -177,7 +191,7 @@ impl UiProcessorState {
             }
 
             UiMessage::SetPage(mut page) => {
-                page.set_id(rel.id); // ensure that recieved page uses peripheral's id
+                page.set_id(rel.id.clone()); // ensure that recieved page uses peripheral's id
                 let mut summary = UpdateSummary::new();
                 // add new page
                 summary.add(page.root());
@@ -193,27 +207,54 @@ impl UiProcessorState {
                 self.ui_to_subscribers(msg).await;
 
                 // Handle the summary
-                self.update_dataset_summary(summary).await;
+                self.update_dataset_summary(&rel.id, summary).await;
+            }
+            UiMessage::ClearPage => {
+                self.remove_page(rel.id).await;
             }
-            UiMessage::ClearPage => {}
             UiMessage::UpdateElements(updates) => {
                 // get this manager, apply the updates, forward to clients
                 match self.pages.get_page_mut(&rel.id) {
                     Some(mgr) => {
-                        let summary = mgr.apply_changes(updates.clone());
-                        // send to clients here
-                        let msg = UiMessage::UpdateElementsFor(
-                            rel.id.clone(),
-                            updates.clone(),
-                        );
-                        self.ui_to_subscribers(msg).await;
-                        // handle summary changes
-                        self.update_dataset_summary(summary);
+                        match mgr.apply_changes(updates.clone()) {
+                            Ok(summary) => {
+                                // send to clients here
+                                let msg = UiMessage::UpdateElementsFor(
+                                    rel.id.clone(),
+                                    updates.clone(),
+                                );
+                                self.ui_to_subscribers(msg).await;
+                                // handle summary changes
+                                self.update_dataset_summary(&rel.id, summary).await;
+                            }
+                            Err(err) => {
+                                // the updates only partly applied, so send the
+                                // whole page to the clients to resync them
+                                warn!("Page for {} is out of sync: {}", rel.id.to_base64(), err);
+                                let msg = UiMessage::Page(mgr.get_page().clone());
+                                self.ui_to_subscribers(msg).await;
+                                self.update_dataset_summary(&rel.id, err.into_summary()).await;
+                            }
+                        }
                     }
                     None => {} // no page to update
                 }
             }
             UiMessage::Input(..) => {} // ignore, (base sends this, doesnt process it)
+            UiMessage::SubscribeDataset(path) => {
+                if !self.may_subscribe(&rel, &path) {
+                    let msg = ErrorMessage::new(ErrorCode::Unauthorized, "Not authorized to subscribe to this dataset")
+                        .with_context(format!("{:?}", path.parts()));
+                    self.sender.send_message(rel, Message::Error(msg)).await;
+                    return;
+                }
+                let msg = DatasetProcessorMessage::UiPeripheralSubscribe(rel, path);
+                self.sender.send_dataset(msg).await;
+            }
+            UiMessage::UnsubscribeDataset(path) => {
+                let msg = DatasetProcessorMessage::UiPeripheralUnsubscribe(rel, path);
+                self.sender.send_dataset(msg).await;
+            }
         }
     }
 
@@ -229,9 +270,44 @@ impl UiProcessorState {
         self.sender.multicast_message(subscribers, msg).await;
     }
 
-    async fn update_dataset_summary(&mut self, summary: UpdateSummary){
+    async fn remove_page(&mut self, id: SpiderId2048){
+        if let Some(mgr) = self.pages.remove_page(&id) {
+            // remove the old page's dataset subscriptions
+            let mut summary = UpdateSummary::new();
+            summary.remove(mgr.get_page().root());
+
+            let msg = UiMessage::RemovePage(id.clone());
+            self.ui_to_subscribers(msg).await;
+
+            // Handle the summary
+            self.update_dataset_summary(&id, summary).await;
+        }
+    }
+
+    /// Any UI peripheral may subscribe to public datasets and its own
+    /// private datasets, but only the configured owner UIs may subscribe to
+    /// the private datasets of other peripherals.
+    fn may_subscribe(&self, rel: &Relation, path: &AbsoluteDatasetPath) -> bool {
+        match path.scope() {
+            AbsoluteDatasetScope::Public => true,
+            AbsoluteDatasetScope::Peripheral(id) => {
+                *id == rel.id || self.config.owner_uis.contains(&rel.id.to_base64())
+            }
+        }
+    }
+
+    /// Update the dataset subscriptions for changes to the page of the
+    /// given peripheral. A page may only show public datasets and the
+    /// peripheral's own private datasets, otherwise any peripheral could
+    /// show another's private datasets to every UI.
+    async fn update_dataset_summary(&mut self, owner: &SpiderId2048, summary: UpdateSummary){
         for (path, delta) in summary.dataset_subscriptions() {
-            self.update_dataset_subscriptions(path, *delta).await;
+            match path.scope() {
+                AbsoluteDatasetScope::Peripheral(id) if id != owner => {
+                    warn!("Page for {} refers to another peripheral's dataset", owner.to_base64());
+                }
+                _ => self.update_dataset_subscriptions(path, *delta).await,
+            }
         }
     }
 
@@ -256,3 +332,169 @@ impl UiProcessorState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use spider_link::{
+        id::SpiderId,
+        message::{AbsoluteDatasetPath, DatasetPath, ErrorCode, Message, UiElement, UiElementUpdate, UiMessage, UiPage, UiPageManager, UiPath},
+        Relation, Role,
+    };
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use crate::{
+        config::SpiderConfig,
+        processor::{dataset::DatasetProcessorMessage, message::ProcessorMessage, router::RouterProcessorMessage, sender::ProcessorSender},
+        state_data::StateData,
+    };
+
+    use super::UiProcessorState;
+
+    /// A UI processor for the given owner UIs, along with the receiver
+    /// for the messages it sends to other processors.
+    fn processor(owner_uis: &[&Relation]) -> (UiProcessorState, Receiver<ProcessorMessage>) {
+        let owner_uis: Vec<String> = owner_uis.iter().map(|rel| rel.id.to_base64()).collect();
+        let config: SpiderConfig = serde_json::from_value(serde_json::json!({"owner_uis": owner_uis})).unwrap();
+        let state = StateData::with_generated_key(&temp_dir().join("spider_ui_test_state"));
+        let (sender, receiver) = channel(50);
+        let (_ui_sender, ui_receiver) = channel(50);
+        (UiProcessorState::new(config, state, ProcessorSender::new(sender), ui_receiver), receiver)
+    }
+
+    fn removed_for(receiver: &mut Receiver<ProcessorMessage>, subscriber: &Relation) -> Vec<Relation> {
+        let mut removed = Vec::new();
+        while let Ok(msg) = receiver.try_recv() {
+            if let ProcessorMessage::RouterMessage(RouterProcessorMessage::MulticastMessage(rels, Message::Ui(UiMessage::RemovePage(id)))) = msg {
+                if rels.contains(subscriber) {
+                    removed.push(Relation { role: Role::Peripheral, id });
+                }
+            }
+        }
+        removed
+    }
+
+    #[tokio::test]
+    async fn peripheral_gone_removes_page() {
+        let (mut processor, mut receiver) = processor(&[]);
+
+        let ui = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([1; 294]) };
+        let peripheral = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([2; 294]) };
+        processor.process_remote_message(ui.clone(), UiMessage::Subscribe).await;
+        let page = UiPage::new(peripheral.id.clone(), "Test Page");
+        processor.process_remote_message(peripheral.clone(), UiMessage::SetPage(page)).await;
+        assert!(processor.pages.get_page(&peripheral.id).is_some());
+        assert!(removed_for(&mut receiver, &ui).is_empty());
+
+        // the router reports the peripheral's link has dropped
+        processor.remove_page(peripheral.id.clone()).await;
+        assert!(processor.pages.get_page(&peripheral.id).is_none());
+        assert_eq!(removed_for(&mut receiver, &ui), vec![peripheral]);
+    }
+
+    #[tokio::test]
+    async fn desync_resends_page() {
+        let (mut processor, mut receiver) = processor(&[]);
+
+        let ui = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([1; 294]) };
+        let peripheral = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([2; 294]) };
+        processor.process_remote_message(ui.clone(), UiMessage::Subscribe).await;
+        let page = UiPage::new(peripheral.id.clone(), "Test Page");
+        processor.process_remote_message(peripheral.clone(), UiMessage::SetPage(page)).await;
+        while receiver.try_recv().is_ok() {}
+
+        // an update to an element the base does not have
+        let mut path = UiPath::root();
+        path.append_child(7);
+        let update = UiElementUpdate::update_element(path, UiElement::from_string("missing"));
+        processor.process_remote_message(peripheral.clone(), UiMessage::UpdateElements(vec![update])).await;
+
+        let mut resent = false;
+        while let Ok(msg) = receiver.try_recv() {
+            if let ProcessorMessage::RouterMessage(RouterProcessorMessage::MulticastMessage(rels, msg)) = msg {
+                match msg {
+                    Message::Ui(UiMessage::Page(page)) => {
+                        assert!(rels.contains(&ui));
+                        assert_eq!(page.id(), &peripheral.id);
+                        resent = true;
+                    }
+                    Message::Ui(UiMessage::UpdateElementsFor(..)) => panic!("forwarded updates that did not apply"),
+                    _ => {}
+                }
+            }
+        }
+        assert!(resent);
+    }
+
+    #[tokio::test]
+    async fn dataset_subscription_authorization() {
+        let owner = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([1; 294]) };
+        let ui = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([2; 294]) };
+        let peripheral = Relation { role: Role::Peripheral, id: SpiderId::from_bytes([3; 294]) };
+        let (mut processor, mut receiver) = processor(&[&owner]);
+
+        let private = DatasetPath::new_private(vec![String::from("data")]).resolve(peripheral.id.clone());
+        let public = AbsoluteDatasetPath::new_public(vec![String::from("data")]);
+        let cases = [
+            (&owner, &private, true),
+            (&ui, &private, false),
+            (&ui, &public, true),
+            (&peripheral, &private, true),
+        ];
+        for (rel, path, allowed) in cases {
+            processor.process_remote_message(rel.clone(), UiMessage::SubscribeDataset(path.clone())).await;
+            match receiver.try_recv().unwrap() {
+                ProcessorMessage::DatasetMessage(DatasetProcessorMessage::UiPeripheralSubscribe(sub, sub_path)) => {
+                    assert!(allowed);
+                    assert_eq!((&sub, &sub_path), (rel, path));
+                }
+                ProcessorMessage::RouterMessage(RouterProcessorMessage::SendMessage(to, Message::Error(error))) => {
+                    assert!(!allowed);
+                    assert_eq!(&to, rel);
+                    assert_eq!(error.code(), ErrorCode::Unauthorized);
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        // a page cannot show another peripheral's private dataset
+        let mut mgr = UiPageManager::new(ui.id.clone(), "Test Page");
+        mgr.get_element_raw(&UiPath::root()).unwrap().set_dataset(Some(private.clone()));
+        let page = mgr.get_page().clone();
+        processor.process_remote_message(ui, UiMessage::SetPage(page.clone())).await;
+        assert!(processor.dataset_subscriptions.is_empty());
+        // but the peripheral's own page can
+        processor.process_remote_message(peripheral, UiMessage::SetPage(page)).await;
+        assert!(processor.dataset_subscriptions.contains_key(&private));
+    }
+
+    #[tokio::test]
+    async fn show_logs() {
+        let (mut processor, mut receiver) = processor(&[]);
+        processor.init_settings().await;
+        let id = processor.state.self_id().await;
+        let logs = |name: &str| DatasetPath::new_private(vec!["logs".to_string(), name.to_string()]).resolve(id.clone());
+
+        processor.show_logs("first".into()).await;
+        let page = processor.pages.get_page(&id).unwrap();
+        assert_eq!(page.get_by_id("Service Logs List").unwrap().dataset(), &Some(logs("first")));
+        assert!(processor.dataset_subscriptions.contains_key(&logs("first")));
+
+        // the same section shows the next service's logs instead
+        processor.show_logs("second".into()).await;
+        let page = processor.pages.get_page(&id).unwrap();
+        assert_eq!(page.get_by_id("Service Logs Title").unwrap().text(), "Logs: second");
+        assert_eq!(page.get_by_id("Service Logs List").unwrap().dataset(), &Some(logs("second")));
+        assert_eq!(processor.dataset_subscriptions.keys().collect::<Vec<_>>(), vec![&logs("second")]);
+
+        let mut unsubscribed = false;
+        while let Ok(msg) = receiver.try_recv() {
+            if let ProcessorMessage::DatasetMessage(DatasetProcessorMessage::UiUnsubscribe(path)) = msg {
+                assert_eq!(path, logs("first"));
+                unsubscribed = true;
+            }
+        }
+        assert!(unsubscribed);
+    }
+}
@@ -9,6 +9,13 @@ use crate::processor::{dataset::DatasetProcessorMessage, message::ProcessorMessa
 
 use super::UiProcessorState;
 
+/// The number of inputs available to each setting
+const SETTING_INPUTS: usize = 5;
+/// The ids of the settings section that shows a peripheral service's logs
+const LOGS_ID: &str = "Service Logs";
+const LOGS_TITLE_ID: &str = "Service Logs Title";
+const LOGS_LIST_ID: &str = "Service Logs List";
+
 impl UiProcessorState {
     pub(crate) async fn init_settings(&mut self) {
         let id = self.state.self_id().await;
@@ -47,14 +54,14 @@ impl UiProcessorState {
         self.init_settings_header(&header).await;
 
         // Create Dataset Item
-        let null_inputs = vec![("none".to_string(), String::new()); 3];
+        let null_inputs = vec![("none".to_string(), String::new()); SETTING_INPUTS];
         let mut map = HashMap::new();
         map.insert("header".to_string(), DatasetData::String(header.clone()));
         map.insert("title".to_string(), DatasetData::String(title.clone()));
         for (index, (input_type, input_label)) in inputs
             .into_iter()
             .chain(null_inputs.into_iter())
-            .take(3)
+            .take(SETTING_INPUTS)
             .enumerate()
         {
             map.insert(
@@ -101,6 +108,7 @@ impl UiProcessorState {
                                 DatasetMessage::Append {
                                     path: dataset_path,
                                     data: data_item,
+                                    token: None,
                                 },
                             ))
                             .await;
@@ -121,6 +129,7 @@ impl UiProcessorState {
                         DatasetMessage::Append {
                             path: dataset_path,
                             data: data_item,
+                            token: None,
                         },
                     ))
                     .await;
@@ -165,45 +174,21 @@ impl UiProcessorState {
                             title
                         });
                         row.append_child(UiElement::new(UiElementKind::Spacer));
-                        row.append_child({
-                            let input_type =
-                                UiElementKind::Variable(UiElementContentPart::Data(vec![
-                                    "input_0_type".to_string(),
-                                ]));
-                            let mut input = UiElement::new(input_type);
-                            input.set_id(format!("{}0", header.clone()));
-                            input.set_selectable(true);
-                            input.set_content(UiElementContent::new_data(
-                                "input_0_label".to_string(),
-                            ));
-                            input
-                        });
-                        row.append_child({
-                            let input_type =
-                                UiElementKind::Variable(UiElementContentPart::Data(vec![
-                                    "input_1_type".to_string(),
-                                ]));
-                            let mut input = UiElement::new(input_type);
-                            input.set_id(format!("{}1", header.clone()));
-                            input.set_selectable(true);
-                            input.set_content(UiElementContent::new_data(
-                                "input_1_label".to_string(),
-                            ));
-                            input
-                        });
-                        row.append_child({
-                            let input_type =
-                                UiElementKind::Variable(UiElementContentPart::Data(vec![
-                                    "input_2_type".to_string(),
-                                ]));
-                            let mut input = UiElement::new(input_type);
-                            input.set_id(format!("{}2", header.clone()));
-                            input.set_selectable(true);
-                            input.set_content(UiElementContent::new_data(
-                                "input_2_label".to_string(),
-                            ));
-                            input
-                        });
+                        for index in 0..SETTING_INPUTS {
+                            row.append_child({
+                                let input_type =
+                                    UiElementKind::Variable(UiElementContentPart::Data(vec![
+                                        format!("input_{}_type", index),
+                                    ]));
+                                let mut input = UiElement::new(input_type);
+                                input.set_id(format!("{}{}", header.clone(), index));
+                                input.set_selectable(true);
+                                input.set_content(UiElementContent::new_data(
+                                    format!("input_{}_label", index),
+                                ));
+                                input
+                            });
+                        }
 
                         row
                     });
@@ -240,6 +225,79 @@ impl UiProcessorState {
         };
     }
 
+    /// Show the logs of a peripheral service at the bottom of the settings
+    /// page. Only one service's logs are shown at a time, so the section is
+    /// created the first time and then pointed at the new service's logs.
+    pub(crate) async fn show_logs(&mut self, name: String) {
+        let id = self.state.self_id().await;
+        let path = DatasetPath::new_private(vec!["logs".to_string(), name.clone()]).resolve(id.clone());
+        let title = format!("Logs: {}", name);
+        let mgr = self
+            .pages
+            .get_page_mut(&id)
+            .expect("page should still exist");
+
+        let old_path = match mgr.get_by_id(LOGS_LIST_ID) {
+            Some(list) => {
+                let old_path = list.dataset().clone();
+                if let Some(mut title_element) = mgr.get_by_id_mut(LOGS_TITLE_ID) {
+                    title_element.set_text(title);
+                }
+                if let Some(mut list) = mgr.get_by_id_mut(LOGS_LIST_ID) {
+                    list.set_dataset(Some(path.clone()));
+                }
+                old_path
+            }
+            None => {
+                let mut elem = UiElement::new(UiElementKind::Rows);
+                elem.set_id(LOGS_ID);
+                elem.append_child({
+                    let mut header = UiElement::from_string(title);
+                    header.set_kind(UiElementKind::Header);
+                    header.set_id(LOGS_TITLE_ID);
+                    header
+                });
+                elem.append_child({
+                    // one row for each line, matches to the logs dataset
+                    let mut list = UiElement::new(UiElementKind::Rows);
+                    list.set_id(LOGS_LIST_ID);
+                    list.set_dataset(Some(path.clone()));
+                    list.append_child({
+                        let mut row = UiElement::new(UiElementKind::Columns);
+                        for field in ["stream", "line"] {
+                            row.append_child({
+                                let mut text = UiElement::new(UiElementKind::Text);
+                                text.set_content(UiElementContent::new_data(field.to_string()));
+                                text
+                            });
+                        }
+                        row
+                    });
+                    list
+                });
+
+                let mut root = mgr
+                    .get_element_mut(&UiPath::root())
+                    .expect("all pages have a root");
+                root.append_child(elem);
+                None
+            }
+        };
+
+        // send to clients
+        let updates = mgr.get_changes();
+        let msg = UiMessage::UpdateElementsFor(id.clone(), updates);
+        self.ui_to_subscribers(msg).await;
+
+        // subscribe to the new logs in place of the old ones
+        if old_path.as_ref() != Some(&path) {
+            if let Some(old_path) = old_path {
+                self.update_dataset_subscriptions(&old_path, -1).await;
+            }
+            self.update_dataset_subscriptions(&path, 1).await;
+        }
+    }
+
     pub(crate) async fn remove_setting(&mut self, header: String, title: String) {
         match self.settings_callbacks.get_mut(&header) {
             Some((title_map, list)) => {
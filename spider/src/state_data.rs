@@ -1,8 +1,8 @@
-use std::{fs, path::{Path, PathBuf}, io, sync::Arc, collections::HashMap};
+use std::{fs, path::{Path, PathBuf}, io, sync::{Arc, OnceLock}, collections::{HashMap, HashSet}};
 use spider_link::{SpiderId2048, SelfRelation, Role, Relation, message::DirectoryEntry};
 use serde::{Serialize, Deserialize};
 
-use rsa::{RsaPrivateKey, pkcs8::{DecodePrivateKey, EncodePrivateKey}};
+use rsa::{RsaPrivateKey, pkcs8::EncodePrivateKey};
 
 
 
@@ -18,6 +18,9 @@ pub struct StateData{
     // Aquire locks in struct order.
     filename: Arc<Mutex<PathBuf>>,
     inner: Arc<Mutex<StateDataInner>>,
+    // The key never changes, so the key and the id derived from it
+    // are only calculated once.
+    self_relation: Arc<OnceLock<SelfRelation>>,
 }
 
 
@@ -30,6 +33,7 @@ impl StateData {
         Ok(Self{
             filename: Arc::new(Mutex::new(path.to_path_buf())),
             inner: Arc::new(Mutex::new(inner)),
+            self_relation: Arc::new(OnceLock::new()),
         })
     }
 
@@ -41,36 +45,41 @@ impl StateData {
         StateData{
             filename: Arc::new(Mutex::new(path)),
             inner: Arc::new(Mutex::new(StateDataInner::new(bytes))),
+            self_relation: Arc::new(OnceLock::from(SelfRelation::from_key(priv_key, Role::Peer))),
         }
     }
 
-    pub async fn save_file(&self){
+    /// Write the state to its file. The state is first written to a
+    /// temporary file which then replaces the old one, so a failed write
+    /// leaves the previous state intact.
+    pub async fn save_file(&self) -> io::Result<()>{
         let filename = self.filename.lock().await;
         let inner = self.inner.lock().await;
-        let contents = serde_json::to_string(&*inner).unwrap();
-        tokio::fs::write(&*filename, contents).await;
+        let contents = serde_json::to_string(&*inner)?;
+        drop(inner);
+        let mut temp_name = filename.clone().into_os_string();
+        temp_name.push(".tmp");
+        tokio::fs::write(&temp_name, contents).await?;
+        tokio::fs::rename(&temp_name, &*filename).await
     }
 
 
-    pub async fn priv_key(&self) -> RsaPrivateKey{
-        let inner = self.inner.lock().await;
-        let priv_key = RsaPrivateKey::from_pkcs8_der(&inner.key_der).unwrap();
-        priv_key
-    }
-
     pub async fn self_id(&self) -> SpiderId2048{
-        let priv_key = self.priv_key().await;
-        let pub_key = priv_key.to_public_key();
-        SpiderId2048::from_key(pub_key)
+        self.self_relation().await.relation.id
     }
 
     pub async fn self_relation(&self) -> SelfRelation{
-        let key = self.priv_key().await;
-        SelfRelation::from_key(key, Role::Peer)
+        if let Some(self_relation) = self.self_relation.get() {
+            return self_relation.clone();
+        }
+        let inner = self.inner.lock().await;
+        self.self_relation
+            .get_or_init(|| SelfRelation::from_der(&inner.key_der, Role::Peer))
+            .clone()
     }
 
     // Pheripheral Items
-    pub async fn peripheral_services(&self) -> MappedMutexGuard<'_, HashMap<String, bool>> {
+    pub async fn peripheral_services(&self) -> MappedMutexGuard<'_, HashMap<String, PeripheralService>> {
         let inner = self.inner.lock().await;
         MutexGuard::map(inner, |f| &mut f.peripheral_services)
     }
@@ -136,17 +145,92 @@ impl StateData {
         let mut inner = self.inner.lock().await;
         inner.directory = v;
     }
+
+    pub async fn load_event_subscriptions(&mut self) -> (HashMap<String, HashSet<Relation>>, Vec<(String, HashSet<Relation>)>){
+        let inner = self.inner.lock().await;
+        let mut exact: HashMap<String, HashSet<Relation>> = HashMap::new();
+        let mut patterns: Vec<(String, HashSet<Relation>)> = Vec::new();
+        for (rel, names, rel_patterns) in &inner.event_subscriptions{
+            for name in names{
+                exact.entry(name.clone()).or_default().insert(rel.clone());
+            }
+            for pattern in rel_patterns{
+                match patterns.iter_mut().find(|(p, _)| p == pattern){
+                    Some((_, set)) => {
+                        set.insert(rel.clone());
+                    },
+                    None => patterns.push((pattern.clone(), HashSet::from([rel.clone()]))),
+                }
+            }
+        }
+        (exact, patterns)
+    }
+    pub async fn save_event_subscriptions(&mut self, exact: &HashMap<String, HashSet<Relation>>, patterns: &[(String, HashSet<Relation>)]) {
+        let mut by_relation: HashMap<Relation, (Vec<String>, Vec<String>)> = HashMap::new();
+        for (name, set) in exact{
+            for rel in set{
+                by_relation.entry(rel.clone()).or_default().0.push(name.clone());
+            }
+        }
+        for (pattern, set) in patterns{
+            for rel in set{
+                by_relation.entry(rel.clone()).or_default().1.push(pattern.clone());
+            }
+        }
+        let v = by_relation.into_iter().map(|(rel, (names, patterns))|{
+            (rel, names, patterns)
+        }).collect();
+        let mut inner = self.inner.lock().await;
+        inner.event_subscriptions = v;
+    }
 }
 
 
 
+/// A peripheral service installed from a git repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "PeripheralServiceRepr")]
+pub struct PeripheralService{
+    /// If the service should be running.
+    pub running: bool,
+    /// The address the service was cloned from, unknown for services
+    /// installed by older versions.
+    pub addr: Option<String>,
+    /// The tag, branch, or commit the service is pinned to. The default
+    /// branch is followed if there is none.
+    pub git_ref: Option<String>,
+}
+
+/// Older versions stored only whether each service was running.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeripheralServiceRepr{
+    Running(bool),
+    Service{
+        running: bool,
+        #[serde(default)]
+        addr: Option<String>,
+        #[serde(default)]
+        git_ref: Option<String>,
+    },
+}
+
+impl From<PeripheralServiceRepr> for PeripheralService{
+    fn from(repr: PeripheralServiceRepr) -> Self {
+        match repr {
+            PeripheralServiceRepr::Running(running) => Self { running, addr: None, git_ref: None },
+            PeripheralServiceRepr::Service { running, addr, git_ref } => Self { running, addr, git_ref },
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct StateDataInner{
     pub key_der: Vec<u8>,
     
     // Peripheral Items
     #[serde(default)]
-    pub peripheral_services: HashMap<String, bool>,
+    pub peripheral_services: HashMap<String, PeripheralService>,
 
     // Router Items
     /// Map from chord names to listen_adder, pub_addr, and vectors of recent addresses
@@ -156,6 +240,9 @@ struct StateDataInner{
     chords: HashMap<String, (String, String, String, Vec<String>)>,
     #[serde(default)]
     directory: Vec<DirectoryEntry>,
+    /// List of relations with the event names and patterns they subscribe to
+    #[serde(default)]
+    event_subscriptions: Vec<(Relation, Vec<String>, Vec<String>)>,
 }
 
 
@@ -171,6 +258,27 @@ impl StateDataInner {
             name: None,
             chords: HashMap::new(),
             directory: Vec::new(), 
+            event_subscriptions: Vec::new(),
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::PeripheralService;
+
+    #[test]
+    fn legacy_peripheral_services() {
+        let data = r#"{"old": true, "new": {"running": false, "addr": "https://host.com/new.git", "git_ref": "v1"}}"#;
+        let services: HashMap<String, PeripheralService> = serde_json::from_str(data).unwrap();
+        assert_eq!(services["old"], PeripheralService { running: true, addr: None, git_ref: None });
+        assert_eq!(services["new"].git_ref.as_deref(), Some("v1"));
+
+        let data = serde_json::to_string(&services).unwrap();
+        let round_trip: HashMap<String, PeripheralService> = serde_json::from_str(&data).unwrap();
+        assert_eq!(round_trip, services);
+    }
+}
@@ -1,7 +1,13 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
-use spider_link::{message::Message, SpiderId2048};
-use tokio::sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver};
+use spider_link::{
+    message::{DatasetData, DatasetMessage, DatasetPath, Message, RouterMessage, UiMessage, UiPage},
+    Relation, SpiderId2048,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver},
+    time::{timeout_at, Instant},
+};
 
 use crate::SpiderClientBuilder;
 
@@ -44,6 +50,62 @@ impl ClientChannel {
         self.sender.send(ClientControl::Message(msg)).await;
     }
 
+    /// Append the data to the dataset at the given path.
+    pub async fn dataset_append(&self, path: DatasetPath, data: DatasetData) {
+        let msg = DatasetMessage::Append { path, data, token: None };
+        self.send(Message::Dataset(msg)).await;
+    }
+
+    /// Subscribe to the dataset at the given path. The current contents of
+    /// the dataset and any changes to it will be sent by the base.
+    pub async fn dataset_subscribe(&self, path: DatasetPath) {
+        let msg = DatasetMessage::Subscribe { path };
+        self.send(Message::Dataset(msg)).await;
+    }
+
+    /// Set the [UiPage] for this peripheral.
+    pub async fn set_page(&self, page: UiPage) {
+        let msg = UiMessage::SetPage(page);
+        self.send(Message::Ui(msg)).await;
+    }
+
+    /// Send an event with the given name and data to the base's
+    /// subscribers, as well as to each of the externals.
+    pub async fn send_event<S>(&self, name: S, externals: Vec<Relation>, data: DatasetData)
+    where
+        S: Into<String>,
+    {
+        let msg = RouterMessage::SendEvent(name.into(), externals, data);
+        self.send(Message::Router(msg)).await;
+    }
+
+    /// Send a message through the channel to the base, then wait for the
+    /// first message from the base for which match_fn returns true.
+    /// Returns None if no matching message arrives before the timeout.
+    /// This does not require reception to be enabled on this channel.
+    pub async fn request<F>(&self, msg: Message, match_fn: F, timeout: Duration) -> Option<Message>
+    where
+        F: Fn(&Message) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        // register the receiver before sending, so the response can not be missed
+        let (tx, mut rx) = unbounded_channel();
+        self.sender.send(ClientControl::AddChannel(tx)).await.ok()?;
+        self.sender.send(ClientControl::Message(msg)).await.ok()?;
+        loop {
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(Some(ClientResponse::Message(msg))) => {
+                    if match_fn(&msg) {
+                        return Some(msg);
+                    }
+                }
+                Ok(Some(_)) => {} // not a message
+                Ok(None) => return None, // processor has stopped
+                Err(_) => return None, // timed out
+            }
+        }
+    }
+
     /// Register a function to be called with all subsequent messages.
     pub async fn set_on_message<F>(&self, cb: Option<F>)
     where
@@ -72,6 +134,21 @@ impl ClientChannel {
             .ok();
     }
 
+    /// Register a function to be called when the channel loses its
+    /// connection to the base. The channel will then attempt to reconnect.
+    pub async fn set_on_disconnect<F>(&self, cb: Option<F>)
+    where
+        F: FnMut(&ClientChannel) + Send + 'static,
+    {
+        self.sender
+            .send(ClientControl::SetOnDisconnect(match cb {
+                Some(cb) => Some(Box::new(cb)),
+                None => None,
+            }))
+            .await
+            .ok();
+    }
+
     /// Register a function to be called when the channel becomes disconneted.
     pub async fn set_on_terminate<F>(&self, cb: Option<F>)
     where
@@ -104,6 +181,17 @@ impl ClientChannel {
         }
     }
 
+    /// Create a new receiver for every subsequent response from the base,
+    /// independent of this channel's own reception, so separate parts of a
+    /// peripheral can each consume the responses. Each receiver gets its
+    /// own copy of every response. Dropping the receiver unsubscribes it.
+    pub async fn subscribe(&self) -> UnboundedReceiver<ClientResponse> {
+        let (tx, rx) = unbounded_channel();
+        // if the processor has stopped, the receiver is already closed
+        self.sender.send(ClientControl::AddChannel(tx)).await.ok();
+        rx
+    }
+
     /// Recieve a message from this channel,
     /// waiting if there is none currently.
     pub async fn recv(&mut self) -> Option<ClientResponse> {
@@ -113,6 +201,35 @@ impl ClientChannel {
         }
     }
 
+    /// Recieve a message from this channel if one is waiting, without
+    /// waiting for one to arrive. Returns None if there is no message, or
+    /// if reception is not enabled on this channel.
+    /// This can be called from any thread, with or without a runtime.
+    pub fn try_recv(&mut self) -> Option<ClientResponse> {
+        match &mut self.receiver {
+            Some(receiver) => receiver.try_recv().ok(),
+            None => None,
+        }
+    }
+
+    /// Recieve a message from this channel, blocking the current thread
+    /// until one arrives, for peripherals written without async.
+    /// Returns None immediately if reception is not enabled on this channel,
+    /// or once the connection has been terminated.
+    ///
+    /// The connection is processed by a task on the tokio runtime that
+    /// started it, so that runtime must keep running on another thread,
+    /// such as a multi-threaded runtime, while this thread is blocked.
+    /// This panics if called from within an async context. From async code,
+    /// use [ClientChannel::recv], or call this inside
+    /// [tokio::task::spawn_blocking].
+    pub fn recv_blocking(&mut self) -> Option<ClientResponse> {
+        match &mut self.receiver {
+            Some(receiver) => receiver.blocking_recv(),
+            None => None,
+        }
+    }
+
     /// Request that the connection be terminated.
     pub async fn terminate(&mut self) {
         self.sender.send(ClientControl::Terminate).await;
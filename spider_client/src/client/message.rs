@@ -1,13 +1,18 @@
 use spider_link::message::Message;
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::{ClientChannel, SpiderClientBuilder};
+use crate::{ClientChannel, SpiderClientBuilder, Strategy};
+
+/// A callback that is given the client's channel when the connection
+/// to the base changes.
+pub(crate) type ConnectionCallback = Box<dyn FnMut(&ClientChannel) + Send>;
 
 pub enum ClientControl {
     Message(Message),
     AddChannel(UnboundedSender<ClientResponse>),
     SetOnMessage(Option<Box<dyn FnMut(&ClientChannel, Message) + Send>>),
-    SetOnConnect(Option<Box<dyn FnMut(&ClientChannel) + Send>>),
+    SetOnConnect(Option<ConnectionCallback>),
+    SetOnDisconnect(Option<ConnectionCallback>),
     SetOnTerminate(Option<Box<dyn FnMut(SpiderClientBuilder) + Send>>),
     SetOnDeny(Option<Box<dyn FnMut(SpiderClientBuilder) + Send>>),
     Terminate,
@@ -20,12 +25,21 @@ pub enum ClientControl {
 pub enum ClientResponse {
     /// The peripheral has recieved a message from the base.
     Message(Message),
-    /// The peripheral has connected to the base.
-    Connected,
+    /// The peripheral has connected to the base, using the given strategy
+    /// to find the base at the given address.
+    Connected {
+        /// The strategy that found the base.
+        strategy: Strategy,
+        /// The address the base was found at.
+        addr: String,
+    },
     /// The peripheral has disconnected from the base.
     Disconnected,
     /// The connection was terminated, the current state is returned.
     Terminated(SpiderClientBuilder),
     /// The connection was denied by the base, the current state is returned.
     Denied(SpiderClientBuilder),
+    /// The base has deliberately removed this peripheral, so it will not
+    /// reconnect. The current state is returned.
+    Unpaired(SpiderClientBuilder),
 }
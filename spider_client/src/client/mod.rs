@@ -1,10 +1,12 @@
 use std::{
     fs, io,
+    net::Ipv4Addr,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use crate::SpiderClientState;
-use spider_link::{Relation, Keyfile, Role};
+use spider_link::{Relation, Keyfile, PairingPayload, Role};
 
 use self::processor::SpiderClientProcessor;
 
@@ -15,6 +17,9 @@ pub use channel::ClientChannel;
 mod message;
 pub use message::{ClientControl, ClientResponse};
 
+mod strategy;
+pub use strategy::Strategy;
+
 /// SpiderClientBuilder contains a set of settings that can be loaded
 /// from a file, modified, saved back to a file, or used to connect
 /// to a Spider base.
@@ -75,7 +80,21 @@ impl SpiderClientBuilder {
     /// Use the configuration in the state to create a new connection to a base.
     /// Set enable_recv to true to enable recv for the resulting channel from the start.
     pub fn start(self, enable_recv: bool) -> ClientChannel {
-        let (channel, _) = SpiderClientProcessor::start(self.state_path, self.state, enable_recv);
+        let (channel, _) = SpiderClientProcessor::start(self.state_path, self.state, enable_recv, false);
+        channel
+    }
+
+    /// Use the configuration in the state to create a one-way connection to
+    /// a base, for peripherals that only send messages.
+    /// Unlike [SpiderClientBuilder::start] with enable_recv set to false,
+    /// which still recieves and processes every message from the base,
+    /// a satellite drops the recieving half of the connection and never
+    /// subscribes to anything, such as the chord addresses. Messages from
+    /// the base, including whether it is pending approval, are discarded,
+    /// so any permission code is sent as soon as the satellite connects.
+    /// The satellite still reconnects if the connection is lost.
+    pub fn start_satellite(self) -> ClientChannel {
+        let (channel, _) = SpiderClientProcessor::start(self.state_path, self.state, false, true);
         channel
     }
 
@@ -105,26 +124,83 @@ impl SpiderClientBuilder {
         self.state.host_relation = None;
     }
 
+    /// Set the range of delays between attempts to reconnect to the base.
+    /// After every strategy fails, the delay starts at min and doubles
+    /// with each further failure up to max. A random jitter of up to 20%
+    /// is applied to each delay so that many peripherals do not reconnect
+    /// at once. Defaults to 1 second and 60 seconds. A min below 100
+    /// milliseconds is raised to 100 milliseconds.
+    pub fn set_reconnect_backoff(&mut self, min: Duration, max: Duration) {
+        let min = min.max(processor::MIN_RECONNECT_BACKOFF);
+        self.state.reconnect_backoff_min = min;
+        self.state.reconnect_backoff_max = max.max(min);
+    }
+
     /// If there is a permission code that will allow this peripheral to be
     /// automatically approved, add that code to the state.
     pub fn set_permission_code(&mut self, code: Option<String>) {
         self.state.permission_code = code;
     }
 
+    /// Pair this peripheral with a base it has not connected to before,
+    /// using a permission code from that base instead of a keyfile, such
+    /// as one scanned from a QR code.
+    /// When the base receives the connection it is held as pending, and the
+    /// client responds by sending the code. If the base accepts the code,
+    /// the peripheral is approved and added to the base's directory, so
+    /// the code is no longer needed and is removed from the state.
+    /// If the base denies the connection, the host relation and the code are
+    /// both cleared, and the builder passed to the deny callback can be
+    /// paired again with a new code.
+    pub fn pair_with_code(&mut self, relation: Relation, code: String) {
+        self.set_host_relation(relation);
+        self.set_permission_code(Some(code));
+    }
+
     // Connection strategies
+    /// Set the order that the connection strategies are tried in.
+    /// Strategies left out of the order are never tried, and strategies
+    /// in the order are only tried if they are also enabled.
+    /// Defaults to [Strategy::default_order], which is last address,
+    /// beacon, chord, then fixed addresses.
+    /// Whichever strategy last found the base is remembered and tried
+    /// first, before the rest of the order.
+    pub fn set_strategy_order(&mut self, order: Vec<Strategy>) {
+        let mut deduped = Vec::new();
+        for strategy in order {
+            if !deduped.contains(&strategy) {
+                deduped.push(strategy);
+            }
+        }
+        self.state.strategy_order = deduped;
+    }
+
     /// Enable or disable the use of the last address connection strategy.
     /// Enabled by default.
     /// The last address connection strategy saves the last known address
     /// to use when reconnecting. This should work very well when reconnecting
     /// to the same network, but will fail whenever
     /// first connecting to a new network.
+    /// A local address, such as a private or loopback address, and a global
+    /// address are saved separately. The local address is tried first, so
+    /// a peripheral on the same network as the base connects directly,
+    /// then the global address is tried.
     pub fn enable_last_addr(&mut self, set: bool) {
         self.state.last_addr_enable = set;
     }
-    /// Manually override the last known address
+    /// Manually override the last known address, which is saved as the
+    /// local or global address depending on the address. None clears both.
     pub fn set_last_addr(&mut self, set: Option<String>) {
         self.state.set_last_addr(set);
     }
+    /// Manually override the last known local address.
+    pub fn set_last_addr_local(&mut self, set: Option<String>) {
+        self.state.set_last_addr_local(set);
+    }
+    /// Manually override the last known global address.
+    pub fn set_last_addr_global(&mut self, set: Option<String>) {
+        self.state.set_last_addr_global(set);
+    }
 
     // Beacon
     /// Enable or disable the use of the beacon connection strategy.
@@ -135,6 +211,16 @@ impl SpiderClientBuilder {
     pub fn enable_beacon(&mut self, set: bool) {
         self.state.beacon_enable = set;
     }
+    /// Set the port that the beacon probe is sent to.
+    /// Defaults to 1930, and must match the beacon port of the base.
+    pub fn set_beacon_port(&mut self, port: u16) {
+        self.state.beacon_port = port;
+    }
+    /// Set the multicast group that the beacon probe is sent to.
+    /// If None, which is the default, the probe is broadcast instead.
+    pub fn set_beacon_group(&mut self, group: Option<Ipv4Addr>) {
+        self.state.beacon_group = group;
+    }
 
     // Chord
     /// Enable or disable the use of the chord connection strategy.
@@ -165,18 +251,46 @@ impl SpiderClientBuilder {
     }
 
     // Other Operations
+    /// Pair with the base described by a [PairingPayload], such as one
+    /// scanned from a QR code. The permission code is used as described
+    /// for [SpiderClientBuilder::pair_with_code], and the address hint is
+    /// used as the last known address so the first connection can be
+    /// attempted before any other strategy finds the base.
+    pub fn apply_pairing_payload(&mut self, payload: PairingPayload) {
+        self.set_host_relation(payload.relation);
+        self.set_permission_code(payload.permission_code);
+        if payload.addr_hint.is_some() {
+            self.set_last_addr(payload.addr_hint);
+        }
+        self.save();
+    }
+
     /// Load the base's key and optional permission code from the given file
     /// into the state configuration.
     /// If the file is missing, no error is reported.
+    ///
+    /// If the file lists several bases, the first becomes the host and the
+    /// rest are kept as candidates. Whenever every connection strategy
+    /// fails to reach the host, the client moves on to the next candidate,
+    /// keeping the previous host at the end of the list, so each base is
+    /// tried in turn. If a base denies the connection it is dropped from
+    /// the list instead. The first base to approve the connection becomes
+    /// the only host and the remaining candidates are forgotten.
     pub async fn try_use_keyfile<P>(&mut self, path: P)
     where
         P: AsRef<Path>,
     {
         let keyfile = Keyfile::read_from_file(path).await;
         if let Some(keyfile) = keyfile {
-            let other_relation = Relation {id: keyfile.id, role: Role::Peer};
-            self.set_host_relation(other_relation);
-            self.set_permission_code(keyfile.permission_code);
+            let mut entries = keyfile
+                .entries()
+                .into_iter()
+                .map(|(id, code)| (Relation {id, role: Role::Peer}, code));
+            if let Some((other_relation, code)) = entries.next() {
+                self.set_host_relation(other_relation);
+                self.set_permission_code(code);
+            }
+            self.state.host_candidates = entries.collect();
             self.save();
         }
     }
@@ -6,28 +6,32 @@ use dht_chord::{
     TCPAdaptor,
 };
 use spider_link::{
-    beacon::beacon_lookout_one,
-    message::{Message, RouterMessage},
-    Link, SpiderId2048,
+    beacon::beacon_lookout_base,
+    message::{ErrorCode, ErrorMessage, GoodbyeReason, Message, RouterMessage},
+    Link, LinkError, Relation, SelfRelation, SpiderId2048,
 };
+use rand::Rng;
 use tokio::{
     select, spawn,
     sync::mpsc::{channel, error::SendError, Receiver, UnboundedSender, unbounded_channel},
     task::JoinHandle,
     time::{sleep, timeout},
 };
+use tracing::warn;
 
 use crate::{state::SpiderClientState, SpiderClientBuilder};
 
-use super::{channel::ClientChannel, ClientControl, ClientResponse};
+use super::{channel::ClientChannel, ClientControl, ClientResponse, Strategy};
 
 pub struct SpiderClientProcessor {
     state_path: Option<PathBuf>,
     state: SpiderClientState,
+    satellite: bool,
     terminate: bool,
     client_channel: ClientChannel,
     receiver: Receiver<ClientControl>,
     link: Option<Link>,
+    backoff: Duration,
     on_message: Option<Box<dyn FnMut(&ClientChannel, Message) + Send>>,
     on_connect: Option<Box<dyn FnMut(&ClientChannel) + Send>>,
     on_disconnect: Option<Box<dyn FnMut(&ClientChannel) + Send>>,
@@ -40,7 +44,8 @@ impl SpiderClientProcessor {
     pub(crate) fn start(
         state_path: Option<PathBuf>,
         state: SpiderClientState,
-        enable_recv: bool
+        enable_recv: bool,
+        satellite: bool,
     ) -> (ClientChannel, JoinHandle<()>) {
         if state.host_relation.is_none() {
             panic!("Processor requires a host to be able to connect");
@@ -57,13 +62,16 @@ impl SpiderClientProcessor {
             (client_channel, Vec::new())
         };
 
+        let backoff = state.reconnect_backoff_min.max(MIN_RECONNECT_BACKOFF);
         let mut processor = Self {
             state_path,
             state,
+            satellite,
             terminate: false,
             client_channel: client_channel.clone(),
             receiver,
             link: None,
+            backoff,
             on_message: None,
             on_connect: None,
             on_disconnect: None,
@@ -78,6 +86,7 @@ impl SpiderClientProcessor {
             while !processor.terminate {
                 match processor.link {
                     Some(ref mut link) => {
+                        let disconnected = link.on_disconnect();
                         select! {
                             msg = processor.receiver.recv() => {
                                 // new message from users
@@ -90,7 +99,12 @@ impl SpiderClientProcessor {
                                     None => break,
                                 }
                             },
-                            msg = link.recv() => {
+                            _ = disconnected.notified(), if processor.satellite => {
+                                // satellites do not recieve, so watch the connection instead
+                                processor.link = None;
+                                processor.process_client_response(ClientResponse::Disconnected).await
+                            },
+                            msg = link.recv(), if !processor.satellite => {
                                 // new message from base
                                 match msg {
                                     Some(msg) => {
@@ -102,13 +116,30 @@ impl SpiderClientProcessor {
                                                 link.send(msg).await;
                                             }
                                         }
+                                        if let Message::Router(RouterMessage::Approved) = &msg {
+                                            // once approved, the base remembers this peripheral
+                                            // so the permission code and other candidates are not
+                                            // needed again
+                                            let code = processor.state.permission_code.take();
+                                            let candidates = std::mem::take(&mut processor.state.host_candidates);
+                                            if code.is_some() || !candidates.is_empty() {
+                                                processor.save_state();
+                                            }
+                                        }
                                         if let Message::Router(RouterMessage::Denied) = &msg {
+                                            // try the next candidate, if there is one
+                                            if processor.next_candidate(false) {
+                                                processor.link = None;
+                                                continue;
+                                            }
                                             let mut builder = SpiderClientBuilder {
                                                 state_path: processor.state_path.clone(),
                                                 state: processor.state.clone(),
                                             };
                                             // Since the connection is denied, remove the host relation
+                                            // and the code that was meant for it
                                             builder.state.host_relation = None;
+                                            builder.state.permission_code = None;
                                             processor.process_client_response(ClientResponse::Denied(builder)).await;
                                             return;
                                         }
@@ -121,7 +152,19 @@ impl SpiderClientProcessor {
                                     },
                                     None => {
                                         // became disconected
+                                        let goodbye = link.goodbye();
                                         processor.link = None;
+                                        if let Some(GoodbyeReason::Unpaired) = goodbye {
+                                            let mut builder = SpiderClientBuilder {
+                                                state_path: processor.state_path.clone(),
+                                                state: processor.state.clone(),
+                                            };
+                                            // Since the base removed this peripheral, remove the host relation
+                                            builder.state.host_relation = None;
+                                            builder.state.permission_code = None;
+                                            processor.process_client_response(ClientResponse::Unpaired(builder)).await;
+                                            return;
+                                        }
                                         processor.process_client_response(ClientResponse::Disconnected).await
                                     },
                                 }
@@ -130,11 +173,30 @@ impl SpiderClientProcessor {
                     }
                     None => {
                         // reconnect
-                        if let Some(addr) = processor.connect().await {
-                            if processor.state.last_addr_enable {
-                                processor.state.set_last_addr(Some(addr));
-                                processor.save_state();
+                        let (strategy, addr) = match processor.connect().await {
+                            Some(found) => found,
+                            None => continue, // every strategy failed, try again
+                        };
+                        if processor.state.last_addr_enable {
+                            processor.state.set_last_addr(Some(addr.clone()));
+                            processor.save_state();
+                        }
+                        let connected = ClientResponse::Connected { strategy, addr };
+
+                        if processor.satellite {
+                            // drop the recieving half, and approve without waiting to be asked
+                            if let Some(link) = &mut processor.link {
+                                drop(link.take_recv());
                             }
+                            if let Some(code) = processor.state.permission_code.clone() {
+                                let msg = Message::Router(RouterMessage::ApprovalCode(code));
+                                match processor.link_send(msg).await {
+                                    Ok(_) => {}
+                                    Err(_) => continue, // couldnt send message, need to reconnect
+                                }
+                            }
+                            processor.process_client_response(connected).await;
+                            continue;
                         }
 
                         // establish reconnection subscriptions
@@ -148,7 +210,7 @@ impl SpiderClientProcessor {
                             }
                         }
 
-                        processor.process_client_response(ClientResponse::Connected).await;
+                        processor.process_client_response(connected).await;
                     }
                 }
             }
@@ -184,6 +246,9 @@ impl SpiderClientProcessor {
             ClientControl::SetOnConnect(cb) => {
                 self.on_connect = cb;
             }
+            ClientControl::SetOnDisconnect(cb) => {
+                self.on_disconnect = cb;
+            }
             ClientControl::SetOnTerminate(cb) => {
                 self.on_terminate = cb;
             }
@@ -209,7 +274,8 @@ impl SpiderClientProcessor {
                 }
             }
         } else {
-            Err(SendError(Message::Error(String::from(
+            Err(SendError(Message::Error(ErrorMessage::new(
+                ErrorCode::Other,
                 "No Link established",
             ))))
         }
@@ -224,7 +290,7 @@ impl SpiderClientProcessor {
                     cb(&self.client_channel, msg.clone());
                 }
             }
-            ClientResponse::Connected => {
+            ClientResponse::Connected { .. } => {
                 if let Some(cb) = &mut self.on_connect {
                     cb(&self.client_channel);
                 }
@@ -244,124 +310,231 @@ impl SpiderClientProcessor {
                     cb(builder.clone());
                 }
             }
+            ClientResponse::Unpaired(_) => {}
         }
 
-        // send through channels
+        // send through channels, dropping any whose receiver is gone
         self.channels.retain(|ch| match ch.send(msg.clone()) {
             Ok(_) => true,
             Err(_) => false,
         });
     }
 
-    async fn connect(&mut self) -> Option<String>{
-        // Try each connection method in turn
-
-        // Last known address
-        if self.state.last_addr_enable {
-            if let Some(addr) = &self.state.last_addr_local {
-                let self_relation = self.state.self_relation.clone();
-                let host_relation = self
-                    .state
-                    .host_relation
-                    .clone()
-                    .expect("Host relation should always be set if connected");
-                if let Some(link) = Link::connect(self_relation, addr, host_relation).await {
-                    self.link = Some(link);
-                    return Some(addr.clone());
+    async fn connect(&mut self) -> Option<(Strategy, String)>{
+        let found = self.try_strategies().await;
+        match &found {
+            Some((strategy, _)) => {
+                self.backoff = self.state.reconnect_backoff_min.max(MIN_RECONNECT_BACKOFF);
+                if self.state.last_strategy != Some(*strategy) {
+                    self.state.last_strategy = Some(*strategy);
+                    self.save_state();
                 }
             }
-            if let Some(addr) = &self.state.last_addr_global {
-                let self_relation = self.state.self_relation.clone();
-                let host_relation = self
-                    .state
-                    .host_relation
-                    .clone()
-                    .expect("Host relation should always be set if connected");
-                if let Some(link) = Link::connect(self_relation, addr, host_relation).await {
-                    self.link = Some(link);
-                    return Some(addr.clone());
-                }
+            None => {
+                // If they all fail, move on to the next candidate host and
+                // wait before trying again
+                self.next_candidate(true);
+                sleep(jitter(self.backoff)).await;
+                self.backoff = next_backoff(self.backoff, self.state.reconnect_backoff_max);
             }
         }
+        found
+    }
 
-        // Beacon
-        if self.state.beacon_enable {
-            println!("using beacon...");
-            if let Some(addr) = beacon_lookout_one().await {
-                println!("found beacon addr {:?}", addr);
-                let self_relation = self.state.self_relation.clone();
-                let host_relation = self
-                    .state
-                    .host_relation
-                    .clone()
-                    .expect("Host relation should always be set if connected");
-                if let Some(link) = Link::connect(self_relation, addr.clone(), host_relation).await {
-                    println!("established beacon link");
-                    self.link = Some(link);
-                    return Some(addr);
-                }else{
-                    println!("failed to connect using beacon");
-                }
+    /// Replace the host with the next candidate host, if there is one.
+    /// If keep_current is true, the current host becomes the last candidate,
+    /// otherwise it is forgotten. Returns false if there are no candidates.
+    fn next_candidate(&mut self, keep_current: bool) -> bool {
+        if self.state.host_candidates.is_empty() {
+            return false;
+        }
+        let (relation, code) = self.state.host_candidates.remove(0);
+        let previous = self.state.host_relation.replace(relation);
+        let previous_code = std::mem::replace(&mut self.state.permission_code, code);
+        if keep_current {
+            if let Some(previous) = previous {
+                self.state.host_candidates.push((previous, previous_code));
             }
         }
+        self.save_state();
+        true
+    }
 
-        // Chord
-        if self.state.chord_enable {
-            for addr in &self.state.chord_addrs {
-                let self_relation = self.state.self_relation.clone();
-                let host_relation = self
-                    .state
-                    .host_relation
-                    .clone()
-                    .expect("Host relation should always be set if connected");
-
-                let mut assoc: AssociateClient<String, SpiderId2048> =
-                    TCPAdaptor::associate_client(addr.to_string());
-                assoc
-                    .send_op(dht_chord::associate::AssociateRequest::GetAdvertOf {
-                        id: host_relation.id.clone(),
-                    })
-                    .await;
-                let addr = match timeout(Duration::from_secs(10), assoc.recv_op()).await {
-                    Ok(Some(dht_chord::associate::AssociateResponse::AdvertOf {
-                        data, ..
-                    })) => match data {
-                        Some(data) => match String::from_utf8(data) {
-                            Ok(addr) => addr,
-                            Err(_) => continue,
-                        },
-                        None => continue,
-                    },
-                    _ => {
-                        continue;
-                    }
-                };
+    async fn try_strategies(&mut self) -> Option<(Strategy, String)>{
+        // Try each enabled connection strategy in the configured order,
+        // starting with the one that found the base last time
+        let mut order = self.state.strategy_order.clone();
+        if let Some(last) = self.state.last_strategy {
+            if let Some(idx) = order.iter().position(|strategy| *strategy == last) {
+                let last = order.remove(idx);
+                order.insert(0, last);
+            }
+        }
+        for strategy in order {
+            let addr = match strategy {
+                Strategy::LastAddr if self.state.last_addr_enable => self.try_last_addr().await,
+                Strategy::Beacon if self.state.beacon_enable => self.try_beacon().await,
+                Strategy::Chord if self.state.chord_enable => self.try_chord().await,
+                Strategy::FixedAddr if self.state.fixed_addr_enable => self.try_fixed_addrs().await,
+                _ => None, // disabled
+            };
+            if let Some(addr) = addr {
+                return Some((strategy, addr));
+            }
+        }
+        None
+    }
 
-                if let Some(link) = Link::connect(self_relation, addr.clone(), host_relation).await {
-                    self.link = Some(link);
-                    return Some(addr);
-                }
+    fn host_relation(&self) -> Relation {
+        self.state
+            .host_relation
+            .clone()
+            .expect("Host relation should always be set if connected")
+    }
+
+    async fn try_last_addr(&mut self) -> Option<String>{
+        // the local address only works on the same network as the base,
+        // but is faster when it does, so try it first
+        let addrs = [self.state.last_addr_local.clone(), self.state.last_addr_global.clone()];
+        for addr in addrs.into_iter().flatten() {
+            let self_relation = self.state.self_relation.clone();
+            if let Some(link) = connect_link(self_relation, &addr, self.host_relation()).await {
+                self.link = Some(link);
+                return Some(addr);
             }
         }
+        None
+    }
+
+    async fn try_beacon(&mut self) -> Option<String>{
+        println!("using beacon...");
+        let self_relation = self.state.self_relation.clone();
+        let host_relation = self.host_relation();
+        // only accept a response from the base this peripheral is paired with
+        let lookout = beacon_lookout_base(self.state.beacon_port, self.state.beacon_group, &host_relation.id);
+        let addr = lookout.await?;
+        println!("found beacon addr {:?}", addr);
+        if let Some(link) = connect_link(self_relation, &addr, host_relation).await {
+            println!("established beacon link");
+            self.link = Some(link);
+            return Some(addr);
+        }else{
+            println!("failed to connect using beacon");
+        }
+        None
+    }
 
-        // Fixed address
-        if self.state.fixed_addr_enable {
-            for addr in &self.state.fixed_addrs {
-                let self_relation = self.state.self_relation.clone();
-                let host_relation = self
-                    .state
-                    .host_relation
-                    .clone()
-                    .expect("Host relation should always be set if connected");
-                if let Some(link) = Link::connect(self_relation, addr, host_relation).await {
-                    self.link = Some(link);
-                    return Some(addr.clone());
+    async fn try_chord(&mut self) -> Option<String>{
+        for addr in self.state.chord_addrs.clone() {
+            let self_relation = self.state.self_relation.clone();
+            let host_relation = self.host_relation();
+
+            let mut assoc: AssociateClient<String, SpiderId2048> =
+                TCPAdaptor::associate_client(addr.to_string());
+            assoc
+                .send_op(dht_chord::associate::AssociateRequest::GetAdvertOf {
+                    id: host_relation.id.clone(),
+                })
+                .await;
+            let addr = match timeout(Duration::from_secs(10), assoc.recv_op()).await {
+                Ok(Some(dht_chord::associate::AssociateResponse::AdvertOf {
+                    data, ..
+                })) => match data {
+                    Some(data) => match String::from_utf8(data) {
+                        Ok(addr) => addr,
+                        Err(_) => continue,
+                    },
+                    None => continue,
+                },
+                _ => {
+                    continue;
                 }
+            };
+
+            if let Some(link) = connect_link(self_relation, &addr, host_relation).await {
+                self.link = Some(link);
+                return Some(addr);
             }
         }
+        None
+    }
 
-        // If they all fail sleep for 15s
-        sleep(Duration::from_secs(15)).await;
+    async fn try_fixed_addrs(&mut self) -> Option<String>{
+        for addr in self.state.fixed_addrs.clone() {
+            let self_relation = self.state.self_relation.clone();
+            if let Some(link) = connect_link(self_relation, &addr, self.host_relation()).await {
+                self.link = Some(link);
+                return Some(addr);
+            }
+        }
         None
     }
 }
+
+/// The shortest delay between attempts to reconnect, so that a zero
+/// minimum in the state cannot reconnect in a tight loop.
+pub(crate) const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Double the delay, up to max, and never below MIN_RECONNECT_BACKOFF
+fn next_backoff(backoff: Duration, max: Duration) -> Duration {
+    (backoff * 2).min(max).max(MIN_RECONNECT_BACKOFF)
+}
+
+/// Randomly vary the delay by up to 20% in either direction
+fn jitter(delay: Duration) -> Duration {
+    let factor = rand::thread_rng().gen_range(0.8..=1.2);
+    delay.mul_f64(factor)
+}
+
+/// Attempt to connect to the host, logging the reason for any failure
+async fn connect_link(self_relation: SelfRelation, addr: &str, host_relation: Relation) -> Option<Link> {
+    match Link::connect(self_relation, addr, host_relation).await {
+        Ok(link) => Some(link),
+        Err(LinkError::Connect(e)) => {
+            warn!("could not reach {}: {}", addr, e);
+            None
+        }
+        Err(LinkError::Handshake) => {
+            warn!("handshake with {} failed", addr);
+            None
+        }
+        Err(LinkError::IdentityMismatch) => {
+            warn!("host at {} has a different identity", addr);
+            None
+        }
+        Err(LinkError::Crypto) => {
+            warn!("could not decrypt stream configuration from {}", addr);
+            None
+        }
+        Err(LinkError::IncompatibleVersion(version)) => {
+            warn!("host at {} uses incompatible protocol version {}", addr, version);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{next_backoff, MIN_RECONNECT_BACKOFF};
+
+    #[test]
+    fn backoff_grows_from_zero() {
+        let max = Duration::from_secs(60);
+        for min in [Duration::ZERO, Duration::from_nanos(1)] {
+            let mut backoff = min;
+            let mut delays = Vec::new();
+            for _ in 0..12 {
+                backoff = next_backoff(backoff, max);
+                delays.push(backoff);
+            }
+            assert_eq!(delays[0], MIN_RECONNECT_BACKOFF);
+            assert!(delays.windows(2).all(|w| w[1] >= w[0]));
+            assert!(delays[4] > delays[0]);
+            assert_eq!(*delays.last().unwrap(), max);
+        }
+        // a max below the floor is raised to it
+        assert_eq!(next_backoff(Duration::ZERO, Duration::ZERO), MIN_RECONNECT_BACKOFF);
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+/// A Strategy is one of the ways a client can find the address of its base.
+/// Each strategy must also be enabled in the [SpiderClientBuilder](crate::SpiderClientBuilder)
+/// to be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Strategy {
+    /// Reconnect to the last address the base was found at.
+    LastAddr,
+    /// Broadcast a probe that the base responds to on the same network.
+    Beacon,
+    /// Look up the base's address in its chord.
+    Chord,
+    /// Try each of a fixed list of addresses.
+    FixedAddr,
+}
+
+impl Strategy {
+    /// The order strategies are tried in if no other order is set.
+    pub fn default_order() -> Vec<Strategy> {
+        vec![
+            Strategy::LastAddr,
+            Strategy::Beacon,
+            Strategy::Chord,
+            Strategy::FixedAddr,
+        ]
+    }
+}
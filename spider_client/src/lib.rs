@@ -56,8 +56,26 @@
 //! ```
 //! 
 //! This example shows how a satellite connects to the base.
-//! ```
-//! // TODO
+//! The satellite only sends messages, so it is started with
+//! `start_satellite`, which returns a channel that never recieves.
+//! The satellite is assumed to already know the base's address and key.
+//!
+//! ```no_run
+//! # use std::path::PathBuf;
+//! # use spider_client::{message::DatasetData, SpiderClientBuilder};
+//! #[tokio::main]
+//! async fn main() {
+//!     // Path to saved client state
+//!     let client_path = PathBuf::from("satellite_state.dat");
+//!
+//!     // Load the state, which must already contain the host relation.
+//!     let builder = SpiderClientBuilder::load(&client_path);
+//!
+//!     // The channel is then started, and can only be used to send messages to the base.
+//!     let client_channel = builder.start_satellite();
+//!
+//!     client_channel.send_event("reading", vec![], DatasetData::Null).await;
+//! }
 //! ```
 //! 
 //! //! This example shows how a standalone connects to the base.
@@ -68,12 +86,12 @@
 
 
 pub use spider_link::{
-    beacon::{beacon_lookout_many, beacon_lookout_one},
-    message, Link, Relation, Role, SelfRelation, SpiderId2048,
+    beacon::{beacon_lookout_base, beacon_lookout_many, beacon_lookout_one},
+    message, Link, PairingPayload, Relation, Role, SelfRelation, SpiderId2048,
 };
 
 mod client;
-pub use client::{ClientChannel, ClientResponse, SpiderClientBuilder};
+pub use client::{ClientChannel, ClientResponse, SpiderClientBuilder, Strategy};
 
 mod state;
 use state::SpiderClientState;
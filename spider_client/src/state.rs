@@ -1,10 +1,23 @@
-use std::{fs, path::Path, net::SocketAddr};
+use std::{fs, path::Path, net::{Ipv4Addr, SocketAddr}, time::Duration};
 
 use serde::{Serialize, Deserialize};
-use spider_link::{SelfRelation, Relation, Role};
+use spider_link::{beacon::BEACON_PORT, SelfRelation, Relation, Role};
 
+use crate::Strategy;
 
 
+fn default_reconnect_backoff_min() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_reconnect_backoff_max() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_beacon_port() -> u16 {
+    BEACON_PORT
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct SpiderClientState{
     // Identity
@@ -12,12 +25,25 @@ pub(crate) struct SpiderClientState{
     pub host_relation: Option<Relation>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub permission_code: Option<String>,
+    // Other bases to try, with their permission codes, until one approves
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub host_candidates: Vec<(Relation, Option<String>)>,
 
     // Config
     pub auto_reconnect: bool,
     pub connection_attempts: u8,
+    #[serde(default = "default_reconnect_backoff_min")]
+    pub reconnect_backoff_min: Duration,
+    #[serde(default = "default_reconnect_backoff_max")]
+    pub reconnect_backoff_max: Duration,
 
     // Address finding strategies
+    #[serde(default = "Strategy::default_order")]
+    pub strategy_order: Vec<Strategy>,
+    // The strategy that last found the base, tried first next time
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_strategy: Option<Strategy>,
+
     // Last known address
     pub last_addr_enable: bool,
     pub last_addr_global: Option<String>,
@@ -25,6 +51,10 @@ pub(crate) struct SpiderClientState{
 
     // Beacon 
     pub beacon_enable: bool,
+    #[serde(default = "default_beacon_port")]
+    pub beacon_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub beacon_group: Option<Ipv4Addr>,
 
     // Chord
     pub chord_enable: bool,
@@ -46,12 +76,18 @@ impl SpiderClientState {
             self_relation: SelfRelation::generate_key(Role::Peripheral),
             host_relation: None,
             permission_code: None,
+            host_candidates: Vec::new(),
 
             // Config
             auto_reconnect: false,
             connection_attempts: 0,
+            reconnect_backoff_min: default_reconnect_backoff_min(),
+            reconnect_backoff_max: default_reconnect_backoff_max(),
 
             // Address finding strategies
+            strategy_order: Strategy::default_order(),
+            last_strategy: None,
+
             // Last known address
             last_addr_enable: true,
             last_addr_global: None,
@@ -59,6 +95,8 @@ impl SpiderClientState {
 
             // Beacon 
             beacon_enable: true,
+            beacon_port: default_beacon_port(),
+            beacon_group: None,
 
             // Chord
             chord_enable: true,
@@ -85,25 +123,42 @@ impl SpiderClientState {
         fs::write(&path, data).expect(&format!("Failed to write spider config to file: {:?}", path));
     }
 
+    /// Save the address the base was last found at as either the local or
+    /// global address, depending on the address. None clears both.
     pub fn set_last_addr(&mut self, set: Option<String>){
         match set {
-            Some(addr_str) => {
-                match addr_str.parse::<SocketAddr>(){
-                    Ok(addr) => {
-                        if ip_rfc::global(&addr.ip()){
-                            self.last_addr_global = Some(addr_str);
-                        }else{
-                            self.last_addr_local = Some(addr_str);
-                        }
-                    },
-                    Err(_) => return,
+            Some(addr) => {
+                if is_global_addr(&addr) {
+                    self.set_last_addr_global(Some(addr));
+                } else {
+                    self.set_last_addr_local(Some(addr));
                 }
             },
             None => {
-                self.last_addr_global = None;
-                self.last_addr_local = None;
+                self.set_last_addr_global(None);
+                self.set_last_addr_local(None);
             },
         }
     }
 
+    /// Save the address the base was last found at on the local network.
+    pub fn set_last_addr_local(&mut self, set: Option<String>){
+        self.last_addr_local = set;
+    }
+
+    /// Save the address the base was last found at from anywhere.
+    pub fn set_last_addr_global(&mut self, set: Option<String>){
+        self.last_addr_global = set;
+    }
+
+}
+
+/// Returns false if the address is only reachable from a local network,
+/// such as a private, link local, or loopback address, and true otherwise.
+/// Addresses given by hostname are assumed to be global.
+fn is_global_addr(addr: &str) -> bool {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => ip_rfc::global(&addr.ip()),
+        Err(_) => true,
+    }
 }
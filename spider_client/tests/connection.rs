@@ -1,23 +1,33 @@
-use spider_client::SpiderClientBuilder;
+use std::time::Duration;
+
+use spider_client::{ClientChannel, ClientResponse, SpiderClientBuilder};
 use spider_link::{
-    message::{DatasetData, Message, RouterMessage},
-    Link, Role, SelfRelation,
+    message::{DatasetData, ErrorMessage, Message, RouterMessage},
+    Link, Relation, Role, SelfRelation,
 };
+use tokio::sync::mpsc::Receiver;
 
-#[tokio::test]
-async fn connect() {
+/// Start a base listening on the address, and a client that connects
+/// only to that address. Returns the base's listener, its relation,
+/// and the client.
+fn base_and_client(addr: &str) -> (Receiver<Link>, Relation, ClientChannel) {
     let host_relation = SelfRelation::generate_key(Role::Peer);
     let host_relation_relation = host_relation.relation.clone();
-    let (mut host, _) = Link::listen(host_relation, "127.0.0.1:1950");
+    let (host, _) = Link::listen(host_relation, addr.to_string());
 
     let mut client_builder = SpiderClientBuilder::new();
     client_builder.enable_beacon(false);
     client_builder.enable_chord(false);
     client_builder.enable_last_addr(false);
-    client_builder.set_fixed_addrs(vec![String::from("127.0.0.1:1950")]);
+    client_builder.set_fixed_addrs(vec![String::from(addr)]);
     client_builder.enable_fixed_addrs(true);
     client_builder.set_host_relation(host_relation_relation.clone());
-    let client = client_builder.start(false);
+    (host, host_relation_relation, client_builder.start(false))
+}
+
+#[tokio::test]
+async fn connect() {
+    let (mut host, host_relation_relation, client) = base_and_client("127.0.0.1:1950");
 
     let event = RouterMessage::Event(
         String::from("test"),
@@ -46,3 +56,93 @@ async fn connect() {
         }
     }
 }
+
+#[tokio::test]
+async fn on_disconnect() {
+    let (mut host, _, client) = base_and_client("127.0.0.1:1951");
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    client
+        .set_on_disconnect(Some(move |_: &ClientChannel| {
+            tx.send(()).ok();
+        }))
+        .await;
+    // controls are processed in order, so once this message arrives the
+    // callback has been set
+    client.send(Message::Error(ErrorMessage::from("ready"))).await;
+
+    let mut host_link = host.recv().await.expect("Failed to get Link");
+    match host_link.recv().await {
+        Some(Message::Error(text)) => assert_eq!(text.detail(), "ready"),
+        _ => panic!("Recieved incorrect data"),
+    }
+
+    // drop the base's side of the link
+    drop(host_link);
+    drop(host);
+    tokio::time::timeout(Duration::from_secs(10), rx.recv())
+        .await
+        .expect("on_disconnect was not called")
+        .expect("callback was dropped");
+}
+
+#[tokio::test]
+async fn request_response() {
+    let (mut host, _, client) = base_and_client("127.0.0.1:1952");
+
+    let base = tokio::spawn(async move {
+        let mut host_link = host.recv().await.expect("Failed to get Link");
+        match host_link.recv().await {
+            Some(Message::Error(text)) => assert_eq!(text.detail(), "question"),
+            _ => panic!("Recieved incorrect data"),
+        }
+        // an unrelated message is skipped by the request
+        host_link.send(Message::Error(ErrorMessage::from("noise"))).await.ok();
+        host_link.send(Message::Error(ErrorMessage::from("answer"))).await.ok();
+        host_link
+    });
+
+    let response = client
+        .request(
+            Message::Error(ErrorMessage::from("question")),
+            |msg| matches!(msg, Message::Error(text) if text.detail() == "answer"),
+            Duration::from_secs(30),
+        )
+        .await;
+    assert!(matches!(response, Some(Message::Error(text)) if text.detail() == "answer"));
+    base.await.expect("base should finish");
+}
+
+#[tokio::test]
+async fn subscribers() {
+    let (mut host, _, client) = base_and_client("127.0.0.1:1954");
+
+    let mut first = client.subscribe().await;
+    let mut second = client.subscribe().await;
+    // a dropped subscriber does not stop the others from recieving
+    drop(client.subscribe().await);
+    // controls are processed in order, so once this message arrives the
+    // subscribers have been added
+    client.send(Message::Error(ErrorMessage::from("ready"))).await;
+
+    let mut host_link = host.recv().await.expect("Failed to get Link");
+    match host_link.recv().await {
+        Some(Message::Error(text)) => assert_eq!(text.detail(), "ready"),
+        _ => panic!("Recieved incorrect data"),
+    }
+    host_link.send(Message::Error(ErrorMessage::from("hello"))).await.ok();
+
+    for subscriber in [&mut first, &mut second] {
+        loop {
+            let response = tokio::time::timeout(Duration::from_secs(10), subscriber.recv())
+                .await
+                .expect("subscriber did not recieve the message")
+                .expect("processor stopped");
+            // skip connection events
+            if let ClientResponse::Message(Message::Error(text)) = response {
+                assert_eq!(text.detail(), "hello");
+                break;
+            }
+        }
+    }
+}
@@ -1,91 +1,222 @@
 //! The Beacon functionality allows peripherals to find a base on the
 //! local network by broadcasting a probe. The response allows the
-//! peripheral to find the address of the base.
+//! peripheral to find the address of the base, and tells it the id and
+//! name of the base so that it can tell multiple bases apart.
+//!
+//! The probe is broadcast to the beacon port, which is [BEACON_PORT] by
+//! default. Separate networks on the same LAN can avoid each other by
+//! using a different port, or by sending the probe to a multicast group.
 
-use std::time::Duration;
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
 
 use tokio::{
     net::UdpSocket,
     time::{timeout, Instant},
 };
 
+use crate::SpiderId2048;
+
+/// The default port that the base listens for beacon probes on.
+pub const BEACON_PORT: u16 = 1930;
+
 /// Broadcast a request over the local network for any base that is
-/// listening. The IP address of the first response recieved is returned.
+/// listening on the given port. If a multicast group is given, the request
+/// is sent to that group instead of being broadcast.
+/// The address, id, and name of the first base to respond is returned.
+/// The id and name are None if the base replied in the legacy format,
+/// which only has the port.
+/// This function will timeout after 10 seconds.
+/// Returns None if no base responds, or if the probe could not be sent.
+pub async fn beacon_lookout_one(
+    port: u16,
+    group: Option<Ipv4Addr>,
+) -> Option<(String, Option<SpiderId2048>, Option<String>)> {
+    beacon_lookout_first(port, group, |_| true).await
+}
+
+/// Broadcast a request over the local network for the base with the given
+/// id, such as the base that a peripheral is already paired with.
+/// Responses from other bases are ignored. A base that replies in the
+/// legacy format cannot be told apart, so it is returned as well, and the
+/// Link checks its id when connecting.
+/// The address of the base is returned if it responds.
 /// This function will timeout after 10 seconds.
-pub async fn beacon_lookout_one() -> Option<String> {
-    let socket = UdpSocket::bind("0.0.0.0:1929").await.unwrap();
-    beacon_probe_send(&socket).await;
+pub async fn beacon_lookout_base(
+    port: u16,
+    group: Option<Ipv4Addr>,
+    id: &SpiderId2048,
+) -> Option<String> {
+    beacon_lookout_first(port, group, |base_id| base_id.is_none_or(|base_id| base_id == id))
+        .await
+        .map(|(addr, _, _)| addr)
+}
+
+/// Broadcast a request over the local network for any base that is
+/// listening on the given port. If a multicast group is given, the request
+/// is sent to that group instead of being broadcast.
+/// Returns a Vec of the address, id, and name of each base that responded
+/// during the time limit. The id and name are None for bases that replied
+/// in the legacy format.
+/// This function will timeout after the given Duration.
+/// Returns an empty Vec if the probe could not be sent.
+pub async fn beacon_lookout_many(
+    port: u16,
+    group: Option<Ipv4Addr>,
+    limit: Duration,
+) -> Vec<(String, Option<SpiderId2048>, Option<String>)> {
+    let socket = match UdpSocket::bind("0.0.0.0:1929").await {
+        Ok(socket) => socket,
+        Err(_) => return Vec::new(),
+    };
+    if !beacon_probe_send(&socket, port, group).await {
+        return Vec::new();
+    }
 
     let start = Instant::now();
-    let limit = Duration::from_secs(10);
     let mut remaining = limit.saturating_sub(start.elapsed());
+    let mut bases = Vec::new();
     while remaining > Duration::ZERO {
         let res = beacon_response_recv(&socket, remaining).await;
-        if res.is_some() {
-            return res;
+        if let Some(base) = res {
+            bases.push(base);
         }
         remaining = limit.saturating_sub(start.elapsed());
     }
-    None
+    bases
 }
 
-/// Broadcast a request over the local network for any base that is
-/// listening. Returns a Vec of the IP addresses of the responses
-/// recieved during the time limit.
-/// This function will timeout after the given Duration.
-pub async fn beacon_lookout_many(limit: Duration) -> Vec<String> {
-    let socket = UdpSocket::bind("0.0.0.0:1929").await.unwrap();
-    beacon_probe_send(&socket).await;
+async fn beacon_lookout_first<F>(
+    port: u16,
+    group: Option<Ipv4Addr>,
+    filter: F,
+) -> Option<(String, Option<SpiderId2048>, Option<String>)>
+where
+    F: Fn(Option<&SpiderId2048>) -> bool,
+{
+    let socket = UdpSocket::bind("0.0.0.0:1929").await.ok()?;
+    if !beacon_probe_send(&socket, port, group).await {
+        return None;
+    }
 
     let start = Instant::now();
+    let limit = Duration::from_secs(10);
     let mut remaining = limit.saturating_sub(start.elapsed());
-    let mut addrs = Vec::new();
     while remaining > Duration::ZERO {
         let res = beacon_response_recv(&socket, remaining).await;
-        if let Some(addr) = res {
-            addrs.push(addr);
+        if let Some(base) = res {
+            if filter(base.1.as_ref()) {
+                return Some(base);
+            }
         }
         remaining = limit.saturating_sub(start.elapsed());
     }
-    addrs
+    None
 }
 
-async fn beacon_probe_send(socket: &UdpSocket) {
-    socket.set_broadcast(true);
+async fn beacon_probe_send(socket: &UdpSocket, port: u16, group: Option<Ipv4Addr>) -> bool {
+    let target = match group {
+        Some(group) => group,
+        None => {
+            if socket.set_broadcast(true).is_err() {
+                return false;
+            }
+            Ipv4Addr::BROADCAST
+        }
+    };
     println!("Probing for spiders...");
     socket
-        .send_to(b"SPIDER_PROBE", "255.255.255.255:1930")
-        .await;
+        .send_to(b"SPIDER_PROBE", SocketAddrV4::new(target, port))
+        .await
+        .is_ok()
 }
 
-async fn beacon_response_recv(socket: &UdpSocket, duration: Duration) -> Option<String> {
-    let mut buf = [0; 1024];
+async fn beacon_response_recv(
+    socket: &UdpSocket,
+    duration: Duration,
+) -> Option<(String, Option<SpiderId2048>, Option<String>)> {
+    let mut buf = [0; 2048];
     let x = timeout(duration, async {
         loop {
-            let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+            let (size, from) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(_) => break None,
+            };
 
             println!("probe recieved: {} bytes from {}", size, from);
             let msg = &mut buf[..size];
             let msg_txt = String::from_utf8_lossy(&msg);
             println!("probe recieved: {}", msg_txt);
 
-            let parts = msg_txt.split(':').collect::<Vec<_>>();
-            if parts.len() < 2 {
-                continue;
+            if !msg_txt.starts_with("SPIDER_REPLY:") {
+                break None;
             }
-
-            if parts[0] == "SPIDER_REPLY" {
-                let port = match parts[1..].join(":").parse::<u16>() {
-                    Ok(port) => port,
-                    Err(_) => continue,
-                };
-                let mut to = from.clone();
-                to.set_port(port);
-                break Some(to.to_string());
+            match beacon_reply_parse(&msg_txt, from) {
+                Some(base) => break Some(base),
+                None => continue,
             }
-            break None;
         }
     })
     .await;
     x.ok().flatten()
 }
+
+/// Format the reply that a base sends in response to a beacon probe.
+/// The reply contains the port that the base listens on, since the
+/// address is taken from the reply itself, as well as the id and name
+/// of the base. The name is last since it may contain any character.
+pub fn beacon_reply_format(port: u16, id: &SpiderId2048, name: &str) -> String {
+    format!("SPIDER_REPLY:{}:{}:{}", port, id.to_base64(), name)
+}
+
+/// Parse a reply from [beacon_reply_format]. Bases from before the id and
+/// name were added reply with only `SPIDER_REPLY:<port>`, which is parsed
+/// with no id or name.
+fn beacon_reply_parse(msg: &str, from: SocketAddr) -> Option<(String, Option<SpiderId2048>, Option<String>)> {
+    let mut parts = msg.splitn(4, ':');
+    if parts.next()? != "SPIDER_REPLY" {
+        return None;
+    }
+    let port = parts.next()?.parse::<u16>().ok()?;
+    let (id, name) = match parts.next() {
+        Some(id) => {
+            let id = SpiderId2048::from_base64(id)?;
+            let name = parts.next()?.to_string();
+            (Some(id), Some(name))
+        }
+        None => (None, None),
+    };
+
+    let mut to = from;
+    to.set_port(port);
+    Some((to.to_string(), id, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_round_trip() {
+        let id = SpiderId2048::from_bytes([7; 294]);
+        let reply = beacon_reply_format(1940, &id, "Living:Room");
+        let from: SocketAddr = "192.168.1.5:1930".parse().unwrap();
+
+        let (addr, parsed_id, name) = beacon_reply_parse(&reply, from).unwrap();
+        assert_eq!(addr, "192.168.1.5:1940");
+        assert_eq!(parsed_id, Some(id));
+        assert_eq!(name.as_deref(), Some("Living:Room"));
+
+        assert!(beacon_reply_parse("SPIDER_REPLY:1940:", from).is_none());
+        assert!(beacon_reply_parse("SPIDER_REPLY:port", from).is_none());
+    }
+
+    #[test]
+    fn legacy_reply() {
+        let from: SocketAddr = "192.168.1.5:1930".parse().unwrap();
+        let parsed = beacon_reply_parse("SPIDER_REPLY:1940", from);
+        assert_eq!(parsed, Some(("192.168.1.5:1940".to_string(), None, None)));
+    }
+}
@@ -0,0 +1,110 @@
+//! The Codec module defines how the [Messages](crate::message::Message)
+//! sent through a [Link](crate::Link) are serialized.
+//!
+//! Each side of a Link advertises the codecs it supports during the stream
+//! configuration. If both sides support the binary codec, it is used for
+//! every frame after the introduction. Otherwise, JSON is used, which is
+//! also what peers that predate codec negotiation expect.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A Codec converts values to and from the bytes sent in a frame.
+pub trait Codec {
+    /// The capability flag advertised in the stream configuration when
+    /// this Codec is supported. Zero if it is always supported.
+    const CAPABILITY: u8;
+
+    /// Serialize a value into bytes, or None if it could not be serialized.
+    fn encode<T: Serialize>(value: &T) -> Option<Vec<u8>>;
+
+    /// Deserialize a value from bytes, or None if the bytes are malformed.
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Option<T>;
+}
+
+/// Serializes values as JSON. Used when the other side does not support
+/// any other Codec.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    const CAPABILITY: u8 = 0x00;
+
+    fn encode<T: Serialize>(value: &T) -> Option<Vec<u8>> {
+        serde_json::to_vec(value).ok()
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Option<T> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+/// Serializes values with bincode, which is much more compact than JSON
+/// for byte heavy values such as [SpiderIds](crate::id::SpiderId).
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    const CAPABILITY: u8 = 0x02;
+
+    fn encode<T: Serialize>(value: &T) -> Option<Vec<u8>> {
+        bincode::serialize(value).ok()
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Option<T> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{Message, Protocol, UiMessage, UiPage},
+        Role, SpiderId2048,
+    };
+
+    fn sizes(protocol: &Protocol) -> (usize, usize) {
+        let json = JsonCodec::encode(protocol).unwrap();
+        let binary = BincodeCodec::encode(protocol).unwrap();
+        (json.len(), binary.len())
+    }
+
+    #[test]
+    fn introduction_size() {
+        let intro = Protocol::Introduction {
+            id: SpiderId2048::from_bytes([200; 294]),
+            role: Role::Peripheral,
+            version: 1,
+        };
+        let (json, binary) = sizes(&intro);
+        println!("introduction: json {} bytes, bincode {} bytes", json, binary);
+        assert!(binary < json);
+
+        let bytes = BincodeCodec::encode(&intro).unwrap();
+        match BincodeCodec::decode::<Protocol>(&bytes).unwrap() {
+            Protocol::Introduction { id, role, .. } => {
+                assert_eq!(id, SpiderId2048::from_bytes([200; 294]));
+                assert_eq!(role, Role::Peripheral);
+            }
+            other => panic!("decoded wrong protocol: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pages_size() {
+        let pages = (0..4u8)
+            .map(|n| UiPage::new(SpiderId2048::from_bytes([n; 294]), format!("Page {}", n)))
+            .collect();
+        let msg = Protocol::Message(Message::Ui(UiMessage::Pages(pages)));
+        let (json, binary) = sizes(&msg);
+        println!("pages: json {} bytes, bincode {} bytes", json, binary);
+        assert!(binary < json);
+
+        let bytes = BincodeCodec::encode(&msg).unwrap();
+        match BincodeCodec::decode::<Protocol>(&bytes).unwrap() {
+            Protocol::Message(Message::Ui(UiMessage::Pages(pages))) => {
+                assert_eq!(pages.len(), 4);
+                assert_eq!(pages[2].name(), "Page 2");
+            }
+            other => panic!("decoded wrong protocol: {:?}", other),
+        }
+    }
+}
@@ -2,7 +2,13 @@
 //! have. The id is also the public key of that node which allows the
 //! [Link] between nodes to be encrypted.
 //! 
-//! The current bit length for the key is 2048.
+//! The current bit length for the key is 2048. A SpiderId holds the DER
+//! encoded public key, whose length depends on the bit length of the key,
+//! see [rsa_id_len].
+//!
+//! In human readable formats such as JSON, a SpiderId is serialized as
+//! its base64 string. Binary formats use the raw bytes. Ids that were
+//! stored as an array of bytes can still be deserialized from JSON.
 
 
 use std::fmt;
@@ -15,38 +21,68 @@ use serde::{Serialize, Serializer, Deserialize, Deserializer, de::{Visitor, Erro
 use num_bigint::BigUint;
 
 
+/// The number of bytes in the DER encoded public key of an RSA key with the
+/// given bit length and the default public exponent of 65537, which is the
+/// size of the [SpiderId] for keys of that length.
+///
+/// The key is encoded as a SubjectPublicKeyInfo: a sequence holding the
+/// rsaEncryption algorithm identifier (15 bytes) and a bit string wrapping
+/// the sequence of the modulus and the exponent. The modulus takes one
+/// extra byte, as its top bit is set and a leading zero keeps it positive.
+/// For a 2048 bit key, this is 294 bytes.
+pub const fn rsa_id_len(bits: usize) -> usize {
+    const fn der_len(content: usize) -> usize {
+        let len_of_len = if content < 0x80 {
+            1
+        } else if content < 0x100 {
+            2
+        } else if content < 0x10000 {
+            3
+        } else {
+            4
+        };
+        1 + len_of_len + content
+    }
+    let modulus = der_len(bits.div_ceil(8) + 1);
+    let exponent = der_len(3);
+    let public_key = der_len(modulus + exponent);
+    let bit_string = der_len(1 + public_key);
+    der_len(15 + bit_string)
+}
+
 /// The SpiderId that contains a generic number of bytes
 /// to represent the public key.
 #[derive(Debug, Clone, Eq, Hash)]
 pub struct SpiderId<const BYTE_SIZE: usize>{
-	bytes: [u8; BYTE_SIZE],
+	// boxed, so that the relations and messages holding ids stay small
+	bytes: Box<[u8; BYTE_SIZE]>,
 }
 
 impl<const BYTE_SIZE: usize> SpiderId<BYTE_SIZE>{
     /// Make a SpiderId from an array of bytes
 	pub fn from_bytes(bytes: [u8; BYTE_SIZE])->Self{
 		Self { 
-			bytes
+			bytes: Box::new(bytes)
 		}
 	}
     /// Get the bytes from the SpiderId
     pub fn to_bytes(self) -> [u8; BYTE_SIZE]{
-        self.bytes
+        *self.bytes
     }
 
     /// Interpret this SpiderId as a BigUint
 	pub fn as_big_uint(&self)-> BigUint{
-		BigUint::from_bytes_be(&self.bytes)
+		BigUint::from_bytes_be(self.bytes.as_slice())
 	}
 
     /// Interpret this SpiderId as an RsaPublicKey
 	pub fn as_pub_key(&self) -> Result<RsaPublicKey, spki::Error>{
-        RsaPublicKey::from_public_key_der(&self.bytes)
+        RsaPublicKey::from_public_key_der(self.bytes.as_slice())
 	}
 
     /// Encode this SpiderId as a base64 String
     pub fn to_base64(&self) -> String{
-        general_purpose::URL_SAFE_NO_PAD.encode(self.bytes)
+        general_purpose::URL_SAFE_NO_PAD.encode(self.bytes.as_slice())
     }
 
     /// Make a SpiderId from a base64 String
@@ -70,7 +106,7 @@ impl<const BYTE_SIZE: usize> SpiderId<BYTE_SIZE>{
 
     /// Return the sha256 hash of the SpiderId
     pub fn sha256(&self) -> String{
-        sha256::digest(&self.bytes)
+        sha256::digest(self.bytes.as_slice())
     }
 }
 
@@ -80,7 +116,11 @@ impl<const BYTE_SIZE: usize> Serialize for SpiderId<BYTE_SIZE>{
     where
         S: Serializer,
     {
-		serializer.serialize_bytes(&self.bytes)
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_base64())
+        } else {
+            serializer.serialize_bytes(self.bytes.as_slice())
+        }
     }
 }
 
@@ -90,7 +130,12 @@ impl<'de, const BYTE_SIZE: usize> Deserialize<'de> for SpiderId<BYTE_SIZE> {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(SpiderIdVisitor)
+        if deserializer.is_human_readable() {
+            // either the base64 string, or the older array of bytes
+            deserializer.deserialize_any(SpiderIdVisitor)
+        } else {
+            deserializer.deserialize_bytes(SpiderIdVisitor)
+        }
     }
 }
 
@@ -101,7 +146,11 @@ impl<'de, const BYTE_SIZE: usize> Visitor<'de> for SpiderIdVisitor<BYTE_SIZE>{
     type Value = SpiderId<BYTE_SIZE>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("SpiderId from sequence of bytes")
+        formatter.write_str("SpiderId from base64 string or sequence of bytes")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E> where E: Error{
+        SpiderId::from_base64(s).ok_or_else(|| E::custom(format!("invalid base64 SpiderId of {BYTE_SIZE} bytes")))
     }
 
 	fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E > where E: Error{
@@ -11,12 +11,17 @@ use crate::SpiderId2048;
 /// The connection parameters are the public key used to establish the
 /// encrypted connection to the base, and a permission code to allow the
 /// peripheral to automatically be accepted as an approved connection.
+/// A Keyfile may also list other bases the peripheral could connect to,
+/// each with their own optional permission code.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Keyfile {
     /// The Keyfile's id
     pub id: SpiderId2048,
     /// The Keyfile's permission code
     pub permission_code: Option<String>,
+    /// Other bases, after the first, and their permission codes
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub bases: Vec<(SpiderId2048, Option<String>)>,
 }
 
 impl Keyfile {
@@ -25,16 +30,22 @@ impl Keyfile {
         Self {
             id,
             permission_code,
+            bases: Vec::new(),
         }
     }
 
+    /// Returns every base in this Keyfile with its permission code,
+    /// starting with the first.
+    pub fn entries(&self) -> Vec<(SpiderId2048, Option<String>)> {
+        let mut entries = vec![(self.id.clone(), self.permission_code.clone())];
+        entries.extend(self.bases.iter().cloned());
+        entries
+    }
+
     /// Writes a Keyfile to the given path using the constituant
     /// parts of the keyfile.
     pub async fn write_new(path: PathBuf, id: SpiderId2048, permission_code: Option<String>) {
-        let keyfile = Self {
-            id,
-            permission_code,
-        };
+        let keyfile = Self::new(id, permission_code);
         keyfile.write_to_file(path).await;
     }
 
@@ -59,3 +70,28 @@ impl Keyfile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Keyfile;
+    use crate::SpiderId2048;
+
+    #[test]
+    fn single_and_multiple_bases() {
+        let first = SpiderId2048::from_bytes([1; 294]);
+        let single = format!(r#"{{"id":"{}","permission_code":"abcdefghij"}}"#, first.to_base64());
+        let keyfile: Keyfile = serde_json::from_str(&single).unwrap();
+        let entries = keyfile.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0], (first.clone(), Some(String::from("abcdefghij"))));
+        // a single base is written the same way it was before
+        assert_eq!(serde_json::to_string(&keyfile).unwrap(), single);
+
+        let second = SpiderId2048::from_bytes([2; 294]);
+        let mut keyfile = Keyfile::new(first.clone(), None);
+        keyfile.bases.push((second.clone(), Some(String::from("klmnopqrst"))));
+        let data = serde_json::to_string(&keyfile).unwrap();
+        let keyfile: Keyfile = serde_json::from_str(&data).unwrap();
+        assert_eq!(keyfile.entries(), vec![(first, None), (second, Some(String::from("klmnopqrst")))]);
+    }
+}
@@ -19,7 +19,7 @@
 //! connections are trusted. 
 
 
-use std::sync::Arc;
+use std::{fmt, hash::{Hash, Hasher}, sync::Arc};
 
 use base64::{engine::general_purpose, Engine};
 use rsa::{
@@ -30,19 +30,27 @@ use serde::{Deserialize, Serialize};
 use tokio::{net::ToSocketAddrs, sync::{mpsc::Receiver, Mutex}};
 
 pub mod link;
-pub use link::Link;
+pub use link::{Link, LinkError};
 pub mod message;
 pub mod id;
 use id::SpiderId;
 pub mod beacon;
+pub mod codec;
 mod keyfile;
 pub use keyfile::Keyfile;
+mod pairing;
+pub use pairing::PairingPayload;
 
 // TODO: This should be renamed to SpiderId, and the generic id
 // renamed to something else.
 /// The id used by the spider protocol.
-/// The id is a 2048 bit public key, it requires 294 bytes to represent.
-pub type SpiderId2048 = SpiderId<294>;
+/// The id is a public key of [SPIDER_KEY_BITS] bits, which requires
+/// 294 bytes to represent, as calculated by [id::rsa_id_len].
+pub type SpiderId2048 = SpiderId<{ id::rsa_id_len(SPIDER_KEY_BITS) }>;
+
+/// The bit length of the keys used by the spider protocol.
+/// Keys of any other length do not fit in a [SpiderId2048].
+pub const SPIDER_KEY_BITS: usize = 2048;
 
 /// The type of relationship of one member of the link.
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
@@ -99,25 +107,32 @@ impl Relation{
 
     /// Optionally returns a Relation from a decoded base64 string
     pub fn from_base64(s: String) -> Option<Self> {
-        match general_purpose::URL_SAFE_NO_PAD.decode(s){
-            Ok(mut v) => {
-                let role = match v.pop()? {
-                    0 => Role::Peer,
-                    1 => Role::Peripheral,
-                    _ => return None,
-                };
-                let bytes= match v.try_into() {
-                    Ok(bytes) => bytes,
-                    Err(_) => return None,
-                };
-                let id = SpiderId2048::from_bytes(bytes);
-                Some(Self {
-                    role,
-                    id
-                })
-            },
-            Err(_) => None,
+        Self::try_from_base64(s).ok()
+    }
+
+    /// Returns a Relation from a decoded base64 string, or a
+    /// [RelationParseError] describing why the string is not a Relation.
+    pub fn try_from_base64(s: String) -> Result<Self, RelationParseError> {
+        let mut v = general_purpose::URL_SAFE_NO_PAD
+            .decode(s)
+            .map_err(|_| RelationParseError::InvalidBase64)?;
+        // the id followed by a single role byte
+        if v.len() != id::rsa_id_len(SPIDER_KEY_BITS) + 1 {
+            return Err(RelationParseError::WrongLength(v.len()));
         }
+        let role = match v.pop() {
+            Some(0) => Role::Peer,
+            Some(1) => Role::Peripheral,
+            Some(other) => return Err(RelationParseError::InvalidRole(other)),
+            None => return Err(RelationParseError::WrongLength(0)),
+        };
+        let bytes = v
+            .try_into()
+            .map_err(|v: Vec<u8>| RelationParseError::WrongLength(v.len() + 1))?;
+        Ok(Self {
+            role,
+            id: SpiderId2048::from_bytes(bytes),
+        })
     }
 
     /// Optionally returns a relation from an id from a base64 encoded
@@ -160,27 +175,89 @@ impl Relation{
     }
 }
 
+/// The ways that a string can fail to be parsed as a [Relation].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelationParseError {
+    /// The string is not valid url safe base64.
+    InvalidBase64,
+    /// The decoded string has the wrong number of bytes,
+    /// this is the number it has.
+    WrongLength(usize),
+    /// The last byte is not a known [Role], this is the byte.
+    InvalidRole(u8),
+}
+
+impl fmt::Display for RelationParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelationParseError::InvalidBase64 => write!(f, "not valid base64"),
+            RelationParseError::WrongLength(len) => write!(
+                f,
+                "expected {} bytes, found {}",
+                id::rsa_id_len(SPIDER_KEY_BITS) + 1,
+                len
+            ),
+            RelationParseError::InvalidRole(role) => write!(f, "unknown role {}", role),
+        }
+    }
+}
+
+impl std::error::Error for RelationParseError {}
+
 /// A self relation functions similarly to a [Relation], but it also includes
 /// the private key that corresponds to the id.
 /// Typically represents the local side of a connection.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "SelfRelationDer")]
 pub struct SelfRelation {
     /// The private key of the local node
     pub priv_key_der: Vec<u8>,
     /// The Relation of the local node
     pub relation: Relation,
+    // The decoded private key, shared between clones so the der is only
+    // decoded once. Not serialized, it is decoded again on deserialize.
+    #[serde(skip)]
+    priv_key: Arc<RsaPrivateKey>,
+}
+
+// The serialized form of a SelfRelation, decoded into the full
+// SelfRelation on deserialize.
+#[derive(Deserialize)]
+struct SelfRelationDer {
+    priv_key_der: Vec<u8>,
+    relation: Relation,
+}
+
+impl TryFrom<SelfRelationDer> for SelfRelation {
+    type Error = rsa::pkcs8::Error;
+    fn try_from(value: SelfRelationDer) -> Result<Self, Self::Error> {
+        let key = RsaPrivateKey::from_pkcs8_der(&value.priv_key_der)?;
+        Ok(Self {
+            priv_key_der: value.priv_key_der,
+            relation: value.relation,
+            priv_key: Arc::new(key),
+        })
+    }
 }
 
 impl SelfRelation {
-    /// Create a SelfRelation from a private key and a role
+    /// Create a SelfRelation from a private key and a role.
+    /// Panics if the key is not [SPIDER_KEY_BITS] bits long,
+    /// use [SelfRelation::try_from_key] to handle other keys.
     pub fn from_key(key: RsaPrivateKey, role: Role) -> Self {
-        let priv_bytes = key.to_pkcs8_der().unwrap().as_ref().to_vec();
-        let pub_bytes = key.to_public_key().to_public_key_der().unwrap();
-        let id = SpiderId::from_bytes(pub_bytes.as_ref().try_into().unwrap());
-        Self {
+        Self::try_from_key(key, role).expect("key size does not match the size of SpiderId2048")
+    }
+    /// Create a SelfRelation from a private key and a role, or None if
+    /// the public key does not fit in a [SpiderId2048].
+    pub fn try_from_key(key: RsaPrivateKey, role: Role) -> Option<Self> {
+        let priv_bytes = key.to_pkcs8_der().ok()?.as_ref().to_vec();
+        let pub_bytes = key.to_public_key().to_public_key_der().ok()?;
+        let id = SpiderId::from_bytes(pub_bytes.as_ref().try_into().ok()?);
+        Some(Self {
             priv_key_der: priv_bytes,
             relation: Relation { id, role },
-        }
+            priv_key: Arc::new(key),
+        })
     }
     /// Create a SelfRelation from a der representation
     /// of a private key and a role
@@ -190,21 +267,26 @@ impl SelfRelation {
     }
 
     /// Generate a new SelfRelation with the given Role.
+    /// The key is [SPIDER_KEY_BITS] bits long, so its public key
+    /// fills the 294 bytes of a [SpiderId2048].
     pub fn generate_key(role: Role) -> Self {
         let mut rng = rand::thread_rng();
-        let key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+        let key = RsaPrivateKey::new(&mut rng, SPIDER_KEY_BITS).expect("failed to generate key");
         Self::from_key(key, role)
     }
 
-    /// Get the private key of this SelfRelation
+    /// Get the private key of this SelfRelation.
+    /// The key is decoded from priv_key_der when the SelfRelation is
+    /// created, and shared with any clones of this SelfRelation,
+    /// so priv_key_der should not be modified afterward.
     pub fn private_key(&self) -> RsaPrivateKey {
-        RsaPrivateKey::from_pkcs8_der(&self.priv_key_der).unwrap()
+        (*self.priv_key).clone()
     }
 
     /// Optionally establish a link to an ip address using this
     /// SelfRelation and a provided other Relation
     pub async fn connect_to<A: ToSocketAddrs>(&self, addr: A, relation: Relation) -> Option<Link> {
-        Link::connect(self.clone(), addr, relation).await
+        Link::connect_opt(self.clone(), addr, relation).await
     }
 
     /// Start a listener using this SelfRelation and an ip address to bind
@@ -216,6 +298,19 @@ impl SelfRelation {
     }
 }
 
+/// Two SelfRelations are equal if and only if their [Relations](Relation)
+/// are equal. The private key is not compared, as the id is derived from it.
+impl PartialEq for SelfRelation {
+    fn eq(&self, other: &Self) -> bool {
+        self.relation == other.relation
+    }
+}
+impl Eq for SelfRelation {}
+impl Hash for SelfRelation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.relation.hash(state);
+    }
+}
 
 impl Eq for Relation{}
 impl PartialOrd for Relation{
@@ -231,4 +326,62 @@ impl Ord for Relation{
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.partial_cmp(other).unwrap() // There is no None option in partial cmp
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use rsa::{pkcs8::EncodePublicKey, RsaPrivateKey};
+
+    use super::*;
+
+    #[test]
+    fn key_sizes() {
+        assert_eq!(id::rsa_id_len(SPIDER_KEY_BITS), 294);
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let der = key.to_public_key().to_public_key_der().unwrap();
+        assert_eq!(id::rsa_id_len(1024), der.as_ref().len());
+        assert!(SelfRelation::try_from_key(key, Role::Peer).is_none());
+    }
+
+    #[test]
+    fn relation_parse_errors() {
+        let relation = SelfRelation::generate_key(Role::Peripheral).relation;
+        let encoded = relation.to_base64();
+        assert_eq!(Relation::try_from_base64(encoded.clone()), Ok(relation.clone()));
+        assert_eq!(Relation::from_base64(encoded), Some(relation.clone()));
+
+        assert_eq!(Relation::try_from_base64(String::from("not base64!")), Err(RelationParseError::InvalidBase64));
+        assert_eq!(Relation::try_from_base64(String::new()), Err(RelationParseError::WrongLength(0)));
+        let short = general_purpose::URL_SAFE_NO_PAD.encode([0u8; 100]);
+        assert_eq!(Relation::try_from_base64(short.clone()), Err(RelationParseError::WrongLength(100)));
+        assert_eq!(Relation::from_base64(short), None);
+
+        let mut bytes = relation.id.clone().to_bytes().to_vec();
+        bytes.push(7);
+        let bad_role = general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        assert_eq!(Relation::try_from_base64(bad_role), Err(RelationParseError::InvalidRole(7)));
+    }
+
+    #[test]
+    fn cached_private_key() {
+        let relation = SelfRelation::generate_key(Role::Peer);
+        let json = serde_json::to_string(&relation).unwrap();
+        assert!(!json.contains("priv_key\""));
+        let decoded: SelfRelation = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.private_key(), relation.private_key());
+        assert_eq!(decoded.clone().private_key(), relation.private_key());
+    }
+
+    #[test]
+    fn self_relation_equality() {
+        let relation = SelfRelation::generate_key(Role::Peer);
+        let mut peripheral = relation.clone();
+        peripheral.relation.role = Role::Peripheral;
+        let json = serde_json::to_string(&relation).unwrap();
+        let decoded: SelfRelation = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, relation);
+        assert_ne!(peripheral, relation);
+
+        let set = std::collections::HashSet::from([relation.clone(), decoded, peripheral]);
+        assert_eq!(set.len(), 2);
+    }
+}
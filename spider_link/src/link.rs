@@ -6,12 +6,12 @@
 //! [Messages](Message) that are sent through it.
 
 
-use std::{io::ErrorKind, sync::Arc};
+use std::{fmt, io::{self, ErrorKind, Read, Write}, sync::{Arc, OnceLock, atomic::{AtomicBool, Ordering}}, time::Duration};
 
-use chacha20poly1305::{Key, Nonce, ChaCha20Poly1305, KeyInit, aead::{OsRng, Aead}};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use chacha20poly1305::{Key, Nonce, ChaCha20Poly1305, KeyInit, aead::{self, OsRng, Aead}};
 use rand::RngCore;
 use rsa::PublicKey;
-use serde_json::{Deserializer, error::Category};
 use tokio::{
 	net::{
 		ToSocketAddrs,
@@ -22,13 +22,88 @@ use tokio::{
 		channel,
 		Sender,
 		Receiver, error::SendError
-	}, Mutex, Notify},
+	}, oneshot, Mutex, Notify},
 	select,
-	io::{AsyncReadExt, AsyncWriteExt}, task::JoinHandle
+	io::{AsyncReadExt, AsyncWriteExt}, task::JoinHandle,
+	time::{interval_at, sleep_until, timeout, Instant}
 };
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+
+use crate::{codec::{BincodeCodec, Codec, JsonCodec}, message::{Message, Protocol, KeyRequest, GoodbyeReason}, SelfRelation, Relation};
+
+/// The first byte of every frame, identifying the framing version.
+/// Peers using a different framing will fail to read the frame instead
+/// of waiting for more data.
+const FRAME_VERSION: u8 = 0xF1;
+/// The size of the frame header: the version byte followed by a 4 byte
+/// big-endian payload length.
+const FRAME_HEADER_LEN: usize = 5;
+/// The length of the nonce prepended to each encrypted frame.
+const NONCE_LEN: usize = 12;
+
+/// Capability flag, sent in the stream configuration, advertising that
+/// this side can decompress frames.
+const CAP_COMPRESSION: u8 = 0x01;
+/// The capabilities this side advertises in its stream configuration.
+const CAPABILITIES: u8 = CAP_COMPRESSION | BincodeCodec::CAPABILITY;
+/// Frames with serialized data smaller than this are not compressed, since
+/// the overhead would outweigh the savings.
+const COMPRESSION_THRESHOLD: usize = 1024;
+/// Prefix for the data in a frame once compression has been negotiated.
+const FRAME_PLAIN: u8 = 0x00;
+/// Prefix for the data in a frame that has been compressed.
+const FRAME_COMPRESSED: u8 = 0x01;
+
+/// The version of the protocol spoken by this side of a Link, sent in the
+/// introduction. Increased whenever a change to the messages would prevent
+/// an older peer from understanding them.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The oldest protocol version that this side can still understand.
+const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// The default interval between keepalive pings sent over a Link.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// The default amount of time to wait for a pong before considering
+/// the Link to be dead.
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The default largest frame a Link will read. A frame declaring a larger
+/// length closes the Link before any of it is read.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+/// The default amount of time to wait for a response to a key request.
+pub const DEFAULT_KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest key request response that will be read. A key and a name
+/// are far smaller than this, so anything larger is not a Spider listener.
+const KEY_REQUEST_MAX_LEN: usize = 16 * 1024;
+
+/// The ways that establishing a Link can fail.
+#[derive(Debug)]
+pub enum LinkError{
+	/// The TCP connection could not be made.
+	Connect(io::Error),
+	/// The other side did not complete the handshake.
+	Handshake,
+	/// The other side did not have the expected identity.
+	IdentityMismatch,
+	/// The stream configuration could not be decrypted.
+	Crypto,
+	/// The other side speaks a protocol version too old to understand,
+	/// this is the version it sent.
+	IncompatibleVersion(u16),
+}
 
-use crate::{message::{Frame, Message, Protocol, KeyRequest}, SelfRelation, Relation};
+impl fmt::Display for LinkError{
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			LinkError::Connect(e) => write!(f, "failed to connect: {}", e),
+			LinkError::Handshake => write!(f, "handshake failed"),
+			LinkError::IdentityMismatch => write!(f, "remote identity did not match"),
+			LinkError::Crypto => write!(f, "failed to decrypt stream configuration"),
+			LinkError::IncompatibleVersion(version) => write!(f, "remote protocol version {} is not compatible with version {}", version, PROTOCOL_VERSION),
+		}
+	}
+}
+
+impl std::error::Error for LinkError{}
 
 /// A Link is the connection between two nodes of the network.
 /// It sends and recieves [Messages](Message), and is encrypted.
@@ -40,7 +115,10 @@ pub struct Link{
 	out_tx: Sender<Message>,
 	in_rx: Option<Receiver<Message>>,
 
-	notify_exit: Arc<Notify>,
+	exit_tx: oneshot::Sender<GoodbyeReason>,
+	goodbye: Arc<OnceLock<GoodbyeReason>>,
+	connected: Arc<AtomicBool>,
+	notify_disconnect: Arc<Notify>,
 	handle: JoinHandle<()>,
 }
 
@@ -48,26 +126,52 @@ impl Link{
 	/// Establish a connection between two nodes. This requires the
 	/// SelfRelation of the local node, and the IP Address and
 	/// relation of the remote node.
-	pub async fn connect<A: ToSocketAddrs>(own_relation: SelfRelation, addr: A, relation: Relation) -> Option<Self>{
-		if let Ok(connection) = TcpStream::connect(addr).await {
-			let mut lb = LinkBuilder::from_stream(own_relation, connection);
-			lb.set_other_relation(Some(relation));
-			// println!("connect sending stream config");
-			lb.send_stream_config().await;
-			// println!("connect sent stream config");
-			// println!("connect sending introduction");
-			lb.send_introduction().await;
-			// println!("connect sent introduction");
-			// println!("connect reading stream config");
-			lb.read_stream_config(&None).await;
-			// println!("connect read stream config");
-			// println!("connect reading introduction");
-			lb.read_introduction().await;
-			// println!("connect read introduction");
-			// process stream
-			return Some(lb.process().await);
+	pub async fn connect<A: ToSocketAddrs>(own_relation: SelfRelation, addr: A, relation: Relation) -> Result<Self, LinkError>{
+		Self::connect_with_keepalive(own_relation, addr, relation, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIMEOUT).await
+	}
+
+	/// Establish a connection between two nodes, like [Link::connect],
+	/// discarding the reason for a failure.
+	pub async fn connect_opt<A: ToSocketAddrs>(own_relation: SelfRelation, addr: A, relation: Relation) -> Option<Self>{
+		Self::connect(own_relation, addr, relation).await.ok()
+	}
+
+	/// Establish a connection between two nodes, like [Link::connect],
+	/// but with a custom keepalive. A ping is sent every interval, and
+	/// if no pong is recieved within the timeout, the Link is closed.
+	pub async fn connect_with_keepalive<A: ToSocketAddrs>(own_relation: SelfRelation, addr: A, relation: Relation, interval: Duration, timeout: Duration) -> Result<Self, LinkError>{
+		Self::connect_inner(own_relation, addr, relation, interval, timeout, DEFAULT_MAX_FRAME_LEN).await
+	}
+
+	/// Establish a connection between two nodes, like [Link::connect],
+	/// but closing the Link if the other side sends a frame longer than
+	/// max_frame_len bytes, instead of [DEFAULT_MAX_FRAME_LEN].
+	pub async fn connect_with_max_frame_len<A: ToSocketAddrs>(own_relation: SelfRelation, addr: A, relation: Relation, max_frame_len: usize) -> Result<Self, LinkError>{
+		Self::connect_inner(own_relation, addr, relation, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIMEOUT, max_frame_len).await
+	}
+
+	async fn connect_inner<A: ToSocketAddrs>(own_relation: SelfRelation, addr: A, relation: Relation, interval: Duration, timeout: Duration, max_frame_len: usize) -> Result<Self, LinkError>{
+		let connection = TcpStream::connect(addr).await.map_err(LinkError::Connect)?;
+		let mut lb = LinkBuilder::from_stream(own_relation, connection);
+		lb.set_other_relation(Some(relation));
+		lb.max_frame_len = max_frame_len;
+		// println!("connect sending stream config");
+		lb.send_stream_config().await.map_err(|_| LinkError::Handshake)?;
+		// println!("connect sent stream config");
+		// println!("connect sending introduction");
+		lb.send_introduction().await.map_err(|_| LinkError::Handshake)?;
+		// println!("connect sent introduction");
+		// println!("connect reading stream config");
+		if lb.read_stream_config(&None).await? {
+			// the other side does not respond to key requests on a connect
+			return Err(LinkError::Handshake);
 		}
-		None
+		// println!("connect read stream config");
+		// println!("connect reading introduction");
+		lb.read_introduction().await?;
+		// println!("connect read introduction");
+		// process stream
+		Ok(lb.process(interval, timeout).await)
 	}
 
 	/// Listen for incoming Links with a SelfRelation and a bind address.
@@ -75,65 +179,92 @@ impl Link{
 	/// Mutex to control if this listener will respond to queries of its
 	/// private key.
 	pub fn listen<A: ToSocketAddrs + Send + 'static>(own_relation: SelfRelation, listen_addr: A) -> (Receiver<Link>, Arc<Mutex<Option<String>>>)
+		{
+		Self::listen_with_max_frame_len(own_relation, listen_addr, DEFAULT_MAX_FRAME_LEN)
+	}
+
+	/// Listen for incoming Links, like [Link::listen], but closing each
+	/// Link if the other side sends a frame longer than max_frame_len bytes,
+	/// instead of [DEFAULT_MAX_FRAME_LEN].
+	pub fn listen_with_max_frame_len<A: ToSocketAddrs + Send + 'static>(own_relation: SelfRelation, listen_addr: A, max_frame_len: usize) -> (Receiver<Link>, Arc<Mutex<Option<String>>>)
 		{
 		let (tx, rx) = channel(50);
 		let kr: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
 		let kr_ret = kr.clone();
 		// listen for connections,
 		tokio::spawn(async move{
-			let listener = TcpListener::bind(listen_addr).await.expect("failed to start Link listener");
-			loop{
-				let stream = if let Ok((stream, _)) = listener.accept().await{
-					stream
-				}else{
-					return;
-				};
-
-				let local_tx = tx.clone();
-				let local_own_relation = own_relation.clone();
-				let local_kr = kr.clone();
-				tokio::spawn(async move{
-					let mut lb = LinkBuilder::from_stream(local_own_relation, stream);
-					// println!("listen reading stream config");
-					let done = lb.read_stream_config(&&local_kr.lock().await).await;
-					if done {
-						return;
-					}
-					// println!("listen read stream config");
-					// println!("listen reading introduction");
-					lb.read_introduction().await;
-					// println!("listen read introduction");
-					// println!("listen sending stream config");
-					lb.send_stream_config().await;
-					// println!("listen sent stream config");
-					// println!("listen sending introduction");
-					lb.send_introduction().await;
-					// println!("listen sent introduction");
-					// process stream,
-					let link = lb.process().await;
-					// emit Link on channel,
-					local_tx.send(link).await;
-				});
+			match TcpListener::bind(listen_addr).await {
+				Ok(listener) => accept_links(listener, own_relation, tx, kr, max_frame_len).await,
+				Err(e) => error!("failed to start Link listener: {}", e),
 			}
-			
 		});
 		(rx, kr_ret)
 	}
 
+	/// Listen for incoming Links on each of the given addresses, such as
+	/// both an IPv4 and an IPv6 address, closing each Link if the other
+	/// side sends a frame longer than max_frame_len bytes.
+	/// Links from every address are sent through the same channel, and
+	/// the key request setting applies to every address.
+	/// An address that cannot be bound is logged and skipped, the others
+	/// still accept Links. If none can be bound, the channel is closed.
+	pub fn listen_all(own_relation: SelfRelation, listen_addrs: Vec<String>, max_frame_len: usize) -> (Receiver<Link>, Arc<Mutex<Option<String>>>)
+		{
+		let (tx, rx) = channel(50);
+		let kr: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+		for listen_addr in listen_addrs {
+			let tx = tx.clone();
+			let own_relation = own_relation.clone();
+			let kr = kr.clone();
+			tokio::spawn(async move{
+				match TcpListener::bind(&listen_addr).await {
+					Ok(listener) => {
+						info!("Link listener started on {}", listen_addr);
+						accept_links(listener, own_relation, tx, kr, max_frame_len).await;
+					}
+					Err(e) => error!("failed to start Link listener on {}: {}", listen_addr, e),
+				}
+			});
+		}
+		(rx, kr)
+	}
+
+	/// Bind a listener to the address, then accept incoming Links from it
+	/// in a new task, sending them through the given channel. Returns the
+	/// error if the address could not be bound.
+	/// Unlike [Link::listen], the channel and key request setting are
+	/// provided by the caller, so they can be shared between listeners,
+	/// and the listener is closed when the returned task is aborted, so it
+	/// can be replaced by a listener on another address.
+	pub async fn listen_on<A: ToSocketAddrs>(own_relation: SelfRelation, listen_addr: A, links: Sender<Link>, key_request: Arc<Mutex<Option<String>>>, max_frame_len: usize) -> io::Result<JoinHandle<()>>
+		{
+		let listener = TcpListener::bind(listen_addr).await?;
+		Ok(tokio::spawn(accept_links(listener, own_relation, links, key_request, max_frame_len)))
+	}
+
 	/// Request the private key of a link listener at an IP address.
+	/// Gives up after [DEFAULT_KEY_REQUEST_TIMEOUT].
 	pub async fn key_request<A: ToSocketAddrs + Send + 'static>(addr: A) -> Option<KeyRequest>{
-		let mut sock = match TcpStream::connect(addr).await {
-			Ok(sock) => sock,
-			Err(_) => return None,
+		Self::key_request_with_timeout(addr, DEFAULT_KEY_REQUEST_TIMEOUT).await
+	}
+
+	/// Request the private key of a link listener at an IP address,
+	/// waiting at most the given duration for the whole exchange.
+	/// Returns None if the listener does not respond in time, closes the
+	/// connection without responding, or sends an oversized response.
+	pub async fn key_request_with_timeout<A: ToSocketAddrs + Send + 'static>(addr: A, limit: Duration) -> Option<KeyRequest>{
+		let request = async {
+			let mut sock = match TcpStream::connect(addr).await {
+				Ok(sock) => sock,
+				Err(_) => return None,
+			};
+			sock.write_all(&encode_frame(b"KEY_REQUEST")).await.ok()?;
+			let mut buffer = Vec::new();
+			let data = read_frame_limited(&mut sock, &mut buffer, KEY_REQUEST_MAX_LEN).await?;
+			let key = serde_json::de::from_slice::<KeyRequest>(&data);
+			key.ok()
 		};
-		let frame = Frame{ data: b"KEY_REQUEST".to_vec() };
-		let send_buf = serde_json::to_vec(&frame).expect("frame should serialize");
-		sock.write(&send_buf).await;
-		let mut buf = String::new();
-		sock.read_to_string(&mut buf).await;
-		let frame = serde_json::de::from_str::<Frame>(&buf).ok()?;
-		let key = serde_json::de::from_slice::<KeyRequest>(&frame.data);
-		key.ok()
+		timeout(limit, request).await.ok().flatten()
 	}
 
 	/// Returns the SelfRelation of this Link
@@ -166,9 +297,34 @@ impl Link{
 		self.in_rx.take()
 	}
 
+	/// Returns true if the underlying connection is still alive
+	pub fn is_connected(&self) -> bool{
+		self.connected.load(Ordering::Acquire)
+	}
+
+	/// Returns a Notify that is notified when the Link's connection
+	/// closes for any reason. Since a disconnect may occur before
+	/// waiting on the Notify, check [Link::is_connected] as well.
+	pub fn on_disconnect(&self) -> Arc<Notify>{
+		self.notify_disconnect.clone()
+	}
+
+	/// Returns the reason the other end gave for closing the Link, if
+	/// it closed the Link deliberately.
+	pub fn goodbye(&self) -> Option<GoodbyeReason>{
+		self.goodbye.get().copied()
+	}
+
 	/// Terminates the Link in both directions
 	pub async fn terminate(self){
-		self.notify_exit.notify_waiters();
+		self.terminate_with(GoodbyeReason::Closed).await;
+	}
+
+	/// Terminates the Link in both directions, informing the other end
+	/// of the reason before the connection is closed.
+	pub async fn terminate_with(self, reason: GoodbyeReason){
+		// if the processor has already stopped, there is no one to inform
+		let _ = self.exit_tx.send(reason);
 		self.handle.await;
 	}
 }
@@ -177,17 +333,37 @@ impl Link{
 
 
 
+/// Marks a Link as disconnected when dropped, so that every exit from
+/// the processor task (including panics) is observed.
+struct DisconnectGuard{
+	connected: Arc<AtomicBool>,
+	notify_disconnect: Arc<Notify>,
+}
+
+impl Drop for DisconnectGuard{
+	fn drop(&mut self) {
+		self.connected.store(false, Ordering::Release);
+		self.notify_disconnect.notify_waiters();
+		// store a permit for anyone who begins waiting after the disconnect
+		self.notify_disconnect.notify_one();
+	}
+}
+
 struct LinkBuilder{
 	stream: TcpStream,
 	buffer: Vec<u8>,
 
 	own_relation: SelfRelation,
 	own_key: [u8; 32],
-	own_nonce: [u8; 12],
 
 	other_relation: Option<Relation>,
 	other_key: Option<[u8; 32]>,
-	other_nonce: Option<[u8; 12]>,
+	// both sides advertised support for compressed frames
+	compression: bool,
+	// both sides advertised support for the binary codec
+	binary: bool,
+	// frames declaring a longer length close the link
+	max_frame_len: usize,
 }
 
 
@@ -195,8 +371,6 @@ impl LinkBuilder{
 	pub fn from_stream(own_relation: SelfRelation, stream: TcpStream) -> Self{
 
 		let own_key = ChaCha20Poly1305::generate_key(&mut OsRng).into();
-		let mut own_nonce = [0u8; 12];
-		OsRng.fill_bytes(&mut own_nonce);
 
 		Self{
 			stream,
@@ -204,11 +378,12 @@ impl LinkBuilder{
 
 			own_relation,
 			own_key,
-			own_nonce,
 
 			other_relation: None,
 			other_key: None,
-			other_nonce: None,
+			compression: false,
+			binary: false,
+			max_frame_len: DEFAULT_MAX_FRAME_LEN,
 		}
 	}
 
@@ -216,11 +391,12 @@ impl LinkBuilder{
 		self.other_relation = relation;
 	}
 
-	async fn send_stream_config(&mut self){
+	async fn send_stream_config(&mut self) -> io::Result<()>{
 		//println!("sending stream config");
 		let mut raw_data = Vec::new();
 		raw_data.extend_from_slice(&self.own_key);
-		raw_data.extend_from_slice(&self.own_nonce);
+		// peers that predate capabilities ignore everything after the key
+		raw_data.push(CAPABILITIES);
 
 		let other_relation = self.other_relation.as_ref().expect("stream config can only be sent after setting other_relation");
 		let data = {
@@ -232,17 +408,19 @@ impl LinkBuilder{
 			// build and send frame
 			enc_data
 		};
-		self.write_frame(data).await;
+		self.write_frame(data).await
 		// println!("sent stream config");
 	}
 
-	async fn read_stream_config(&mut self, enable_key_request: &Option<String>) -> bool{
+	/// Read the other side's stream configuration. Returns true if the
+	/// frame was a key request, which has been responded to.
+	async fn read_stream_config(&mut self, enable_key_request: &Option<String>) -> Result<bool, LinkError>{
 		//println!("reading stream config");
 		let enc_data = if let Some(enc_data) = self.read_frame().await {
 			enc_data
 		} else {
 			self.stream.shutdown().await;
-			return true;
+			return Err(LinkError::Handshake);
 		};
 		
 		// if key is requested and enabled, respond with that instead.
@@ -250,79 +428,107 @@ impl LinkBuilder{
 		if enc_data == b"KEY_REQUEST"{
 			match enable_key_request {
 				Some(name) => {
-					self.respond_key_request(name.clone()).await;
+					if let Err(e) = self.respond_key_request(name.clone()).await {
+						warn!("Failed to respond to key request: {}", e);
+					}
 				},
 				None => {},
 			}
 			self.stream.shutdown().await;
-			return true;
+			return Ok(true);
 		}
 
 		let dec_data = { // ensure that encryption items are not held across await
 			// decrypt data with our key
 			let padding = rsa::PaddingScheme::new_pkcs1v15_encrypt();
-			self.own_relation.private_key().decrypt(padding, &enc_data).expect("Failed to decrypt")
+			self.own_relation.private_key().decrypt(padding, &enc_data)
+		};
+		let stream_key: [u8; 32] = match &dec_data {
+			Ok(dec_data) if dec_data.len() >= 32 => dec_data[0..32].try_into().expect("wrong length"),
+			_ => {
+				self.stream.shutdown().await;
+				return Err(LinkError::Crypto);
+			}
 		};
-		let stream_key: [u8; 32] = dec_data[0..32].try_into().expect("wrong length");
-		let stream_nonce: [u8; 12] = dec_data[32..(32+12)].try_into().expect("wrong length");
 		self.other_key = Some(stream_key);
-		self.other_nonce = Some(stream_nonce);
+		let other_capabilities = match &dec_data {
+			Ok(dec_data) => dec_data.get(32).copied().unwrap_or(0),
+			Err(_) => 0,
+		};
+		self.compression = CAPABILITIES & other_capabilities & CAP_COMPRESSION != 0;
+		self.binary = CAPABILITIES & other_capabilities & BincodeCodec::CAPABILITY != 0;
 		// println!("saved stream config");
-		return false;
+		Ok(false)
 	}
 
-	async fn respond_key_request(&mut self, name: String) {
+	async fn respond_key_request(&mut self, name: String) -> io::Result<()> {
 		let request = KeyRequest{
 			key: self.own_relation.relation.id.clone(),
 			name
 		};
 		let data = serde_json::ser::to_vec(&request).expect("request should serialize");
-		self.write_frame(data).await;
+		self.write_frame(data).await
 	}
 
-	async fn send_introduction(&mut self){
+	async fn send_introduction(&mut self) -> io::Result<()>{
 		//println!("sending introduction");
 		// serialize introduction message (stream encryption)
 		let intro = Protocol::Introduction {
 			id: self.own_relation.relation.id.clone(),
 			role: self.own_relation.relation.role,
+			version: PROTOCOL_VERSION,
 		};
 		let raw_data = serde_json::ser::to_vec(&intro).expect("Failed to serialize struct");
 
 		let data = self.own_encrypt(&raw_data);
 
-		self.write_frame(data).await;
+		self.write_frame(data).await
 	}
 
-	async fn read_introduction(&mut self){
+	async fn read_introduction(&mut self) -> Result<(), LinkError>{
 		//println!("reading introduction");
 		// read packet data
 		let enc_data = if let Some(enc_data) = self.read_frame().await {
 			enc_data
 		} else {
-			eprintln!("Failed to read frame!");
+			warn!("Failed to read introduction frame");
 			self.stream.shutdown().await;
-			return;
+			return Err(LinkError::Handshake);
 		};
 
-		let dec_data = self.other_decrypt(&enc_data);
+		let dec_data = match self.other_decrypt(&enc_data) {
+			Ok(dec_data) => dec_data,
+			Err(_) => {
+				self.stream.shutdown().await;
+				return Err(LinkError::Crypto);
+			}
+		};
 
 		// deserialize into introduction message
-		let prot: Protocol = serde_json::de::from_slice(&dec_data).expect("Failed to deserialize introduction");
+		let prot = serde_json::de::from_slice(&dec_data);
 		// create Relation
-		if let Protocol::Introduction { id, role} = prot {
+		if let Ok(Protocol::Introduction { id, role, version }) = prot {
+			if version < MIN_PROTOCOL_VERSION {
+				error!("Rejected link with protocol version {}", version);
+				self.stream.shutdown().await;
+				return Err(LinkError::IncompatibleVersion(version));
+			}
+			if version > PROTOCOL_VERSION {
+				// the newer side is responsible for speaking the older protocol
+				info!("Other side of link has newer protocol version {}", version);
+			}
 			let other_rel = Relation{
 				id,
 				role,
 			};
 			match &self.other_relation {
 				Some(existing_other) => {
-					println!("other relation exists: {:?}", other_rel);
+					debug!("other relation exists: {:?}", other_rel);
 					if *existing_other != other_rel {
-						println!("other relation differs from current self");
+						warn!("other relation differs from current self");
 						// error has occured, this is not who we expected to connect to, close
 						self.stream.shutdown().await;
-						return;
+						return Err(LinkError::IdentityMismatch);
 					}else{
 						// println!("other relation equals current self");
 					}
@@ -334,115 +540,215 @@ impl LinkBuilder{
 			}
 		}else{
 			self.stream.shutdown().await;
-			return;
+			return Err(LinkError::Handshake);
 		}
+		Ok(())
 	}
 
 	async fn read_frame(&mut self) -> Option<Vec<u8>> {
-		loop{
-			// Attempt to deserialize from the buffer
-			let mut deserializer = Deserializer::from_slice(self.buffer.as_slice()).into_iter();
-			
-			match deserializer.next() {
-				Some(result) => {
-					match result{
-						Ok(msg) => {
-							let msg: Frame = msg;
-							self.buffer = self.buffer[deserializer.byte_offset()..].to_vec();
-							break Some(msg.data);
-						},
-						Err(ref e) if e.classify() == Category::Eof => {
-							// if we have encountered an EOF, more information may arrive later
-							// procede to read section
-						},
-						Err(e) => {
-							error!("Encountered deserialization error: {}\n\tDeserialization buffer: {:?}", e, String::from_utf8(self.buffer.clone()).unwrap());
-							break None;
-						},
+		read_frame_limited(&mut self.stream, &mut self.buffer, self.max_frame_len).await
+	}
+
+	async fn write_frame(&mut self, data: Vec<u8>) -> io::Result<()>{
+		self.stream.write_all(&encode_frame(&data)).await
+	}
+
+	async fn write_protocol(&mut self, protocol: &Protocol) -> io::Result<()>{
+		let raw_data = self.encode(protocol);
+		let raw_data = self.compress(raw_data);
+		// encrypt using stream cypher here
+		let encrypted_data = self.own_encrypt(&raw_data);
+		self.write_frame(encrypted_data).await
+	}
+
+	/// Serialize the protocol with the negotiated [Codec].
+	fn encode(&self, protocol: &Protocol) -> Vec<u8> {
+		let data = if self.binary {
+			BincodeCodec::encode(protocol)
+		} else {
+			JsonCodec::encode(protocol)
+		};
+		data.expect("Failed to serialize struct")
+	}
+
+	/// Deserialize a protocol with the negotiated [Codec].
+	fn decode(&self, data: &[u8]) -> Option<Protocol> {
+		if self.binary {
+			BincodeCodec::decode(data)
+		} else {
+			JsonCodec::decode(data)
+		}
+	}
+
+	/// If compression was negotiated, prefix the data with a flag byte,
+	/// compressing it first if it is large enough to benefit.
+	fn compress(&self, data: Vec<u8>) -> Vec<u8> {
+		if !self.compression {
+			return data;
+		}
+		if data.len() >= COMPRESSION_THRESHOLD {
+			let mut encoder = DeflateEncoder::new(vec![FRAME_COMPRESSED], Compression::fast());
+			if encoder.write_all(&data).is_ok() {
+				if let Ok(compressed) = encoder.finish() {
+					if compressed.len() < data.len() {
+						return compressed;
 					}
-				},
-				None => {
-					// if there is no next element, more may arrive after read
-				},
-			}
-			
-			
-			// if there is insufficient data to deserialize, read some more
-			let mut read_buffer = [0; 1024];
-			match self.stream.read(&mut read_buffer).await {
-				Ok(0) => {
-					return None;
-				}, 
-				Ok(len) => { // Append data to buffer
-					self.buffer.extend_from_slice(&read_buffer[..len]);
-				},
-				Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-					continue; // try to read again
-				},
-				Err(e) =>{
-					error!("Encountered error reading from connection: {}", e);
-					// probably should terminate connection here, depending on error
-					return None;
 				}
 			}
 		}
-
+		let mut plain = Vec::with_capacity(data.len() + 1);
+		plain.push(FRAME_PLAIN);
+		plain.extend(data);
+		plain
 	}
 
-	async fn write_frame(&mut self, data: Vec<u8>){
-		let frame = Frame{data};
-		let bytes = serde_json::ser::to_vec(&frame).expect("failed to serialize frame");
-		self.stream.write_all(&bytes).await;
+	/// Reverse [LinkBuilder::compress], returning None if the data is
+	/// malformed or decompresses to more than the max_frame_len, the most
+	/// the other side could have sent without compression.
+	fn decompress(&self, data: Vec<u8>) -> Option<Vec<u8>> {
+		if !self.compression {
+			return Some(data);
+		}
+		match data.split_first()? {
+			(&FRAME_PLAIN, rest) => Some(rest.to_vec()),
+			(&FRAME_COMPRESSED, rest) => {
+				let limit = self.max_frame_len as u64;
+				let mut decompressed = Vec::new();
+				let mut decoder = DeflateDecoder::new(rest).take(limit + 1);
+				decoder.read_to_end(&mut decompressed).ok()?;
+				if decompressed.len() as u64 > limit {
+					return None;
+				}
+				Some(decompressed)
+			},
+			_ => None,
+		}
 	}
 
+	/// Encrypt the data with a fresh nonce, which is prepended to the
+	/// cyphertext.
 	fn own_encrypt(&self, data: &[u8]) -> Vec<u8> {
 		let own_key = Key::from(self.own_key);
-		let own_nonce = Nonce::from(self.own_nonce);
-		
+		let mut nonce = [0u8; NONCE_LEN];
+		OsRng.fill_bytes(&mut nonce);
+
 		let cypher = ChaCha20Poly1305::new(&own_key);
 
-		cypher.encrypt(&own_nonce, data).unwrap()
+		let mut encrypted = nonce.to_vec();
+		// only fails for data far larger than a frame can hold
+		encrypted.extend(cypher.encrypt(&Nonce::from(nonce), data).expect("Failed to encrypt frame"));
+		encrypted
 	}
 
-	fn other_decrypt(&self, data: &[u8]) -> Vec<u8>{
-		let other_key = Key::from(self.other_key.unwrap());
-		let other_nonce = Nonce::from(self.other_nonce.unwrap());
+	/// Decrypt data produced by the other side's [LinkBuilder::own_encrypt],
+	/// reading the nonce from the front of the data. Fails if the data is
+	/// too short, was not encrypted with the other side's key, or has been
+	/// tampered with.
+	fn other_decrypt(&self, data: &[u8]) -> Result<Vec<u8>, aead::Error>{
+		let other_key = Key::from(self.other_key.ok_or(aead::Error)?);
+		if data.len() < NONCE_LEN {
+			return Err(aead::Error);
+		}
+		let (nonce, cyphertext) = data.split_at(NONCE_LEN);
 
 		let cypher = ChaCha20Poly1305::new(&other_key);
 
-		cypher.decrypt(&other_nonce, data).unwrap()
+		cypher.decrypt(Nonce::from_slice(nonce), cyphertext)
 	}
 
-	pub async fn process(mut self) -> Link{
+	pub async fn process(mut self, interval: Duration, timeout: Duration) -> Link{
 		let own_relation = self.own_relation.clone();
 		let other_relation = self.other_relation.clone().unwrap();
 
 		let (out_tx, mut out_rx) = channel(50); 
 		let (in_tx, in_rx) = channel(50);
 
-		let notify_exit = Arc::new(Notify::new());
-		let notify_exit_copy = notify_exit.clone();
+		let (exit_tx, mut exit_rx) = oneshot::channel();
+		let goodbye = Arc::new(OnceLock::new());
+		let goodbye_copy = goodbye.clone();
+
+		let connected = Arc::new(AtomicBool::new(true));
+		let notify_disconnect = Arc::new(Notify::new());
+		let guard = DisconnectGuard{
+			connected: connected.clone(),
+			notify_disconnect: notify_disconnect.clone(),
+		};
 
 		let handle = tokio::spawn(async move{
+			let _guard = guard;
+			let mut ping_interval = interval_at(Instant::now() + interval, interval);
+			// when a ping is outstanding, the time by which its pong must arrive
+			let mut pong_deadline: Option<Instant> = None;
 			loop{
 				select! {
-					_ = notify_exit.notified() => {
+					reason = &mut exit_rx => {
+						if let Ok(reason) = reason {
+							// closing either way, so a failed goodbye does not matter
+							let _ = self.write_protocol(&Protocol::Goodbye { reason }).await;
+						}
 						break; // exit the loop to stop the processor
 					}
+					_ = ping_interval.tick() => {
+						if pong_deadline.is_none() {
+							pong_deadline = Some(Instant::now() + timeout);
+							if let Err(e) = self.write_protocol(&Protocol::Ping).await {
+								info!("Failed to write to link, closing: {}", e);
+								break;
+							}
+						}
+					}
+					_ = sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+						info!("Link keepalive timed out");
+						break; // no pong recieved, the other side is gone
+					}
 					data = self.read_frame() => {
 						match data{
 							Some(data) => {
 								// must also decrypt here
-								let decrypted_data = self.other_decrypt(&data);
+								let decrypted_data = match self.other_decrypt(&data) {
+									Ok(data) => data,
+									Err(_) => {
+										error!("Failed to decrypt frame, closing link");
+										break;
+									},
+								};
+								let decrypted_data = match self.decompress(decrypted_data) {
+									Some(data) => data,
+									None => {
+										error!("Failed to decompress frame");
+										break;
+									},
+								};
 								// deserialize frame data
-								let proto: Protocol = serde_json::from_slice(&decrypted_data).unwrap();
+								let proto: Protocol = match self.decode(&decrypted_data) {
+									Some(proto) => proto,
+									None => {
+										error!("Failed to decode frame, closing link");
+										break;
+									},
+								};
 								match proto {
-									Protocol::Introduction { id, role} => {
-										panic!("it is an error to send a second introduction");
+									Protocol::Introduction { .. } => {
+										error!("Recieved a second introduction, closing link");
+										break;
 									},
 									Protocol::Message(msg) => {
 										in_tx.send(msg).await;
 									},
+									Protocol::Ping => {
+										if let Err(e) = self.write_protocol(&Protocol::Pong).await {
+											info!("Failed to write to link, closing: {}", e);
+											break;
+										}
+									},
+									Protocol::Pong => {
+										pong_deadline = None;
+									},
+									Protocol::Goodbye { reason } => {
+										info!("Link closed by other end: {:?}", reason);
+										let _ = goodbye_copy.set(reason);
+										break;
+									},
 								}
 							},
 							None => {
@@ -458,10 +764,10 @@ impl LinkBuilder{
 							break; // connection closed, shutdown
 						};
 						let protocol = Protocol::Message(msg);
-						let raw_data = serde_json::ser::to_vec(&protocol).expect("Failed to serialize struct");
-						// encrypt using stream cypher here
-						let encrypted_data = self.own_encrypt(&raw_data);
-						self.write_frame(encrypted_data).await;
+						if let Err(e) = self.write_protocol(&protocol).await {
+							info!("Failed to write to link, closing: {}", e);
+							break;
+						}
 					} 
 				}
 			}
@@ -474,11 +780,224 @@ impl LinkBuilder{
 			other_relation,
 			out_tx,
 			in_rx: Some(in_rx),
-			notify_exit: notify_exit_copy,
+			exit_tx,
+			goodbye,
+			connected,
+			notify_disconnect,
 			handle,
 		}
 	}
 }
 
+/// Prefix the data with the frame header
+fn encode_frame(data: &[u8]) -> Vec<u8>{
+	let len = u32::try_from(data.len()).expect("frame should fit in u32 length");
+	let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + data.len());
+	bytes.push(FRAME_VERSION);
+	bytes.extend_from_slice(&len.to_be_bytes());
+	bytes.extend_from_slice(data);
+	bytes
+}
 
+/// Accept connections from the listener until it fails, completing the
+/// handshake for each and sending the resulting Links through tx.
+async fn accept_links(listener: TcpListener, own_relation: SelfRelation, tx: Sender<Link>, kr: Arc<Mutex<Option<String>>>, max_frame_len: usize){
+	loop{
+		let stream = if let Ok((stream, _)) = listener.accept().await{
+			stream
+		}else{
+			return;
+		};
 
+		let local_tx = tx.clone();
+		let local_own_relation = own_relation.clone();
+		let local_kr = kr.clone();
+		tokio::spawn(async move{
+			let mut lb = LinkBuilder::from_stream(local_own_relation, stream);
+			lb.max_frame_len = max_frame_len;
+			let done = lb.read_stream_config(&&local_kr.lock().await).await;
+			if !matches!(done, Ok(false)) {
+				return;
+			}
+			if lb.read_introduction().await.is_err() {
+				return;
+			}
+			if lb.send_stream_config().await.is_err() || lb.send_introduction().await.is_err() {
+				return;
+			}
+			// process stream,
+			let link = lb.process(DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIMEOUT).await;
+			// emit Link on channel,
+			local_tx.send(link).await;
+		});
+	}
+}
+
+/// Read a single frame from the stream, failing if the frame header
+/// declares a payload longer than max_len, before the payload is read.
+/// Bytes are accumulated in the buffer between calls, so this is safe to
+/// use in a select.
+async fn read_frame_limited(stream: &mut TcpStream, buffer: &mut Vec<u8>, max_len: usize) -> Option<Vec<u8>>{
+	loop{
+		// Attempt to take a complete frame from the buffer
+		if buffer.len() >= FRAME_HEADER_LEN {
+			if buffer[0] != FRAME_VERSION {
+				error!("Encountered frame with unknown version: {:#x}", buffer[0]);
+				return None;
+			}
+			let len = u32::from_be_bytes(buffer[1..FRAME_HEADER_LEN].try_into().expect("header length is fixed")) as usize;
+			if len > max_len {
+				error!("Encountered frame of {} bytes, over the limit of {}", len, max_len);
+				return None;
+			}
+			if buffer.len() >= FRAME_HEADER_LEN + len {
+				let data = buffer[FRAME_HEADER_LEN..(FRAME_HEADER_LEN + len)].to_vec();
+				buffer.drain(..(FRAME_HEADER_LEN + len));
+				return Some(data);
+			}
+		}
+
+		// if there is insufficient data for a frame, read some more
+		let mut read_buffer = [0; 1024];
+		match stream.read(&mut read_buffer).await {
+			Ok(0) => {
+				return None;
+			},
+			Ok(len) => { // Append data to buffer
+				buffer.extend_from_slice(&read_buffer[..len]);
+			},
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+				continue; // try to read again
+			},
+			Err(e) =>{
+				error!("Encountered error reading from connection: {}", e);
+				// probably should terminate connection here, depending on error
+				return None;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{io::Write, time::Duration};
+
+	use chacha20poly1305::{aead::{Aead, KeyInit}, ChaCha20Poly1305, Key, Nonce};
+	use flate2::{write::DeflateEncoder, Compression};
+	use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}, time::timeout};
+
+	use crate::{message::{Message, Protocol}, Relation, Role, SelfRelation};
+
+	use super::{encode_frame, LinkBuilder, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIMEOUT, FRAME_COMPRESSED, NONCE_LEN};
+
+	const KEY: [u8; 32] = [7; 32];
+	const MAX_FRAME_LEN: usize = 1024;
+
+	fn encrypt(data: &[u8]) -> Vec<u8> {
+		let cypher = ChaCha20Poly1305::new(&Key::from(KEY));
+		let nonce = [0u8; NONCE_LEN];
+		let mut encrypted = nonce.to_vec();
+		encrypted.extend(cypher.encrypt(&Nonce::from(nonce), data).unwrap());
+		encrypted
+	}
+
+	/// Process a link, send it the frame, and check that it closes cleanly.
+	async fn frame_closes_link(own: &SelfRelation, other: &Relation, frame: Vec<u8>, compression: bool) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let mut attacker = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+		let (stream, _) = listener.accept().await.unwrap();
+
+		let mut builder = LinkBuilder::from_stream(own.clone(), stream);
+		builder.set_other_relation(Some(other.clone()));
+		builder.other_key = Some(KEY);
+		builder.max_frame_len = MAX_FRAME_LEN;
+		builder.compression = compression;
+		let mut link = builder.process(DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIMEOUT).await;
+
+		attacker.write_all(&encode_frame(&frame)).await.unwrap();
+		let msg = timeout(Duration::from_secs(10), link.recv()).await.expect("link did not close");
+		assert!(msg.is_none());
+		// the processor exited without panicking
+		(&mut link.handle).await.expect("link processor panicked");
+		assert!(!link.is_connected());
+	}
+
+	#[tokio::test]
+	async fn bad_frames_close_link() {
+		let own = SelfRelation::generate_key(Role::Peer);
+		let other = SelfRelation::generate_key(Role::Peer).relation;
+		let intro = Protocol::Introduction { id: other.id.clone(), role: other.role, version: 1 };
+		let frames = vec![
+			// too short to hold a nonce
+			b"short".to_vec(),
+			// not encrypted with the other side's key
+			b"this is not a valid cyphertext".to_vec(),
+			// encrypted, but not a protocol message
+			encrypt(b"garbage"),
+			// a second introduction
+			encrypt(&serde_json::to_vec(&intro).unwrap()),
+		];
+
+		for frame in frames {
+			frame_closes_link(&own, &other, frame, false).await;
+		}
+	}
+
+	#[tokio::test]
+	async fn tampered_frame_closes_link() {
+		let own = SelfRelation::generate_key(Role::Peer);
+		let other = SelfRelation::generate_key(Role::Peer).relation;
+		let msg = Protocol::Message(Message::Error("hello".into()));
+		let mut frame = encrypt(&serde_json::to_vec(&msg).unwrap());
+		// flip a bit of the cyphertext, after the nonce
+		frame[NONCE_LEN + 2] ^= 0x01;
+		frame_closes_link(&own, &other, frame, false).await;
+	}
+
+	#[tokio::test]
+	async fn oversized_frame_closes_link() {
+		let own = SelfRelation::generate_key(Role::Peer);
+		let other = SelfRelation::generate_key(Role::Peer).relation;
+		let msg = Protocol::Message(Message::Error("x".repeat(MAX_FRAME_LEN).into()));
+		let frame = encrypt(&serde_json::to_vec(&msg).unwrap());
+		assert!(frame.len() > MAX_FRAME_LEN);
+		frame_closes_link(&own, &other, frame, false).await;
+	}
+
+	#[tokio::test]
+	async fn oversized_decompression_closes_link() {
+		let own = SelfRelation::generate_key(Role::Peer);
+		let other = SelfRelation::generate_key(Role::Peer).relation;
+		let msg = Protocol::Message(Message::Error("x".repeat(MAX_FRAME_LEN).into()));
+		let mut encoder = DeflateEncoder::new(vec![FRAME_COMPRESSED], Compression::best());
+		encoder.write_all(&serde_json::to_vec(&msg).unwrap()).unwrap();
+		let frame = encrypt(&encoder.finish().unwrap());
+		// the frame fits, but what it decompresses to would not have
+		assert!(frame.len() < MAX_FRAME_LEN);
+		frame_closes_link(&own, &other, frame, true).await;
+	}
+
+	#[tokio::test]
+	async fn silent_peer_closes_link() {
+		let own = SelfRelation::generate_key(Role::Peer);
+		let other = SelfRelation::generate_key(Role::Peer).relation;
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		// the peer stays connected, but never answers a ping
+		let _silent = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+		let (stream, _) = listener.accept().await.unwrap();
+
+		let mut builder = LinkBuilder::from_stream(own, stream);
+		builder.set_other_relation(Some(other));
+		builder.other_key = Some(KEY);
+		let start = tokio::time::Instant::now();
+		let mut link = builder.process(Duration::from_millis(100), Duration::from_millis(200)).await;
+		assert!(link.is_connected());
+
+		let msg = timeout(Duration::from_secs(10), link.recv()).await.expect("link did not close");
+		assert!(msg.is_none());
+		// closed by the keepalive, after one interval and one timeout
+		assert!(start.elapsed() >= Duration::from_millis(300));
+		(&mut link.handle).await.expect("link processor panicked");
+		assert!(!link.is_connected());
+	}
+}
@@ -0,0 +1,83 @@
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Number, Value};
+
+use super::DatasetData;
+
+impl DatasetData {
+    /// Convert any serializable value into a [DatasetData], such as a struct
+    /// that a peripheral stores in a dataset. Structs become
+    /// [DatasetData::Map]s, and sequences become [DatasetData::Array]s.
+    /// Numbers are converted as described for the conversion from a JSON
+    /// value, so integers outside of the i32 range lose precision.
+    pub fn from_serialize<T: Serialize>(value: &T) -> Result<Self, serde_json::Error> {
+        serde_json::to_value(value).map(DatasetData::from)
+    }
+
+    /// Convert this DatasetData back into a deserializable value, such as a
+    /// struct that was stored with [DatasetData::from_serialize].
+    /// Returns an error if the shape of the data does not match the type.
+    pub fn to_deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(Value::from(self.clone()))
+    }
+}
+
+/// Convert a JSON value into a [DatasetData].
+///
+/// Integers from 0 to 255 become [DatasetData::Byte], other integers within
+/// the range of an i32 become [DatasetData::Int], and all other numbers
+/// become [DatasetData::Float]. This conversion is lossy for integers
+/// outside of the i32 range and for floats that need more precision than
+/// an f32 provides.
+impl From<Value> for DatasetData {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => DatasetData::Null,
+            Value::Bool(b) => DatasetData::Bool(b),
+            Value::Number(n) => number_to_data(&n),
+            Value::String(s) => DatasetData::String(s),
+            Value::Array(arr) => DatasetData::Array(arr.into_iter().map(DatasetData::from).collect()),
+            Value::Object(map) => {
+                DatasetData::Map(map.into_iter().map(|(k, v)| (k, DatasetData::from(v))).collect())
+            }
+        }
+    }
+}
+
+/// Convert a [DatasetData] into a JSON value.
+///
+/// [DatasetData::Timestamp] becomes its number of milliseconds, so it will
+/// not convert back into a Timestamp. A [DatasetData::Float] that is NaN or
+/// infinite has no JSON representation and becomes null.
+impl From<DatasetData> for Value {
+    fn from(data: DatasetData) -> Self {
+        match data {
+            DatasetData::Null => Value::Null,
+            DatasetData::Byte(b) => Value::from(b),
+            DatasetData::Int(i) => Value::from(i),
+            DatasetData::Float(f) => match Number::from_f64(f as f64) {
+                Some(n) => Value::Number(n),
+                None => Value::Null,
+            },
+            DatasetData::String(s) => Value::String(s),
+            DatasetData::Bool(b) => Value::Bool(b),
+            DatasetData::Timestamp(t) => Value::from(t),
+            DatasetData::Array(arr) => Value::Array(arr.into_iter().map(Value::from).collect()),
+            DatasetData::Map(map) => {
+                Value::Object(map.into_iter().map(|(k, v)| (k, Value::from(v))).collect::<Map<_, _>>())
+            }
+        }
+    }
+}
+
+fn number_to_data(n: &Number) -> DatasetData {
+    if let Some(i) = n.as_i64() {
+        if let Ok(b) = u8::try_from(i) {
+            return DatasetData::Byte(b);
+        }
+        if let Ok(i) = i32::try_from(i) {
+            return DatasetData::Int(i);
+        }
+    }
+    // too large for an Int, or not an integer
+    DatasetData::Float(n.as_f64().unwrap_or(f64::NAN) as f32)
+}
@@ -1,9 +1,14 @@
 use std::collections::HashMap;
 
+use chrono::{DateTime, SecondsFormat};
 use serde::{Deserialize, Serialize};
 
-use crate::SpiderId2048;
+use crate::{Relation, SpiderId2048};
 
+mod schema;
+pub use schema::{DatasetSchema, DatasetKind};
+
+mod json;
 
 
 // ========== Absolute Path ==========
@@ -126,7 +131,7 @@ impl DatasetPath{
 
 // ========== Dataset Data and Message ===========
 /// A DatasetData represents an entry in a dataset.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DatasetData{
     /// A null value
     Null,
@@ -138,6 +143,10 @@ pub enum DatasetData{
     Float(f32),
     /// A String
     String(String),
+    /// A boolean
+    Bool(bool),
+    /// A point in time, in milliseconds since the unix epoch
+    Timestamp(i64),
 
     /// An array of DatasetData
     Array(Vec<DatasetData>),
@@ -157,11 +166,16 @@ pub enum DatasetMessage{
     },
     
     /// Append a [DatasetData] to the dataset described by the [DatasetPath]
+    /// The base responds with a [DatasetMessage::AppendResult].
     Append{
         /// The [DatasetPath] to the dataset to which to append.
         path: DatasetPath,
         /// The [DatasetData] to append to the dataset.
-        data: DatasetData
+        data: DatasetData,
+        /// An optional token, echoed in the [DatasetMessage::AppendResult]
+        /// so that the result can be matched to this request.
+        #[serde(default)]
+        token: Option<String>,
     },
 
     /// Append a Vec<[DatasetData]> to the dataset described by
@@ -176,7 +190,7 @@ pub enum DatasetMessage{
     /// Set the element at the given id to the given [DatasetData], in the
     /// dataset described by the [DatasetPath]. If the id refers to a position
     /// after the end of the dataset, the dataset will be padded with
-    /// [DatasetData::Null].
+    /// [DatasetData::Null], by at most [DatasetOp::MAX_PADDING] elements.
     SetElement{
         /// The [DatasetPath] to the dataset to modify.
         path: DatasetPath,
@@ -189,7 +203,7 @@ pub enum DatasetMessage{
     /// Set the elements after the given id to the given Vec<[DatasetData]>,
     /// in the dataset described by the [DatasetPath]. If the id refers to a
     /// position after the end of the dataset, the dataset will be padded with
-    /// [DatasetData::Null].
+    /// [DatasetData::Null], by at most [DatasetOp::MAX_PADDING] elements.
     SetElements{
         /// The [DatasetPath] to the dataset to modify.
         path: DatasetPath,
@@ -214,6 +228,57 @@ pub enum DatasetMessage{
         path: DatasetPath
     },
 
+    /// Apply several messages to a single dataset at once. The changes are
+    /// written together, so that either all or none of them are made, and
+    /// subscribers are informed once afterwards. Only
+    /// [DatasetMessage::Append], [DatasetMessage::Extend],
+    /// [DatasetMessage::SetElement], [DatasetMessage::SetElements],
+    /// [DatasetMessage::DeleteElement], and [DatasetMessage::Empty] may be
+    /// batched, and all of them must refer to the same [DatasetPath].
+    /// Otherwise the whole batch is rejected with a
+    /// [Message::Error](crate::message::Message::Error).
+    Batch(Vec<DatasetMessage>),
+
+    /// Set the [DatasetSchema] for the dataset described by the
+    /// [DatasetPath]. Afterwards, any [DatasetMessage::Append],
+    /// [DatasetMessage::Extend], [DatasetMessage::SetElement], or
+    /// [DatasetMessage::SetElements] with data that does not conform to the
+    /// schema will be rejected with a [Message::Error](crate::message::Message::Error).
+    SetSchema{
+        /// The [DatasetPath] to the dataset.
+        path: DatasetPath,
+        /// The [DatasetSchema] that elements must conform to.
+        schema: DatasetSchema,
+    },
+
+    /// Set which relations may modify the public dataset described by the
+    /// [DatasetPath]. A dataset without an owner may only be claimed by the
+    /// base or an owner UI, which then becomes the owner of the dataset.
+    /// Afterwards only the owner or the base may change it.
+    /// Setting [DatasetAccess::Open] releases ownership. A relation that is
+    /// not permitted is sent a [Message::Error](crate::message::Message::Error).
+    SetAccess{
+        /// The [DatasetPath] to the public dataset.
+        path: DatasetPath,
+        /// The [DatasetAccess] that modifications must satisfy.
+        access: DatasetAccess,
+    },
+
+    /// Request a slice of the dataset described by the [DatasetPath],
+    /// without subscribing to it. The base responds with a
+    /// [DatasetMessage::QueryResult].
+    Query{
+        /// The [DatasetPath] to the dataset to query.
+        path: DatasetPath,
+        /// The number of matching elements to skip.
+        offset: usize,
+        /// The maximum number of elements to return.
+        limit: usize,
+        /// If present, only elements matching the [DatasetFilter] are
+        /// returned.
+        filter: Option<DatasetFilter>,
+    },
+
     // Dataset Response
     /// The current state of the dataset described by [DatasetPath].
     /// Sent after subscribing to a dataset to synchronize the state on both
@@ -224,6 +289,189 @@ pub enum DatasetMessage{
         /// The [DatasetData] in the dataset.
         data: Vec<DatasetData>
     },
+
+    /// A change to the dataset described by [DatasetPath], sent to
+    /// subscribers after a [DatasetMessage::Dataset] has synchronized their
+    /// copy. Applying the [DatasetOp]s in order with [DatasetOp::apply]
+    /// brings the copy up to date. The base periodically sends a full
+    /// [DatasetMessage::Dataset] in place of a Delta to resynchronize.
+    Delta{
+        /// The [DatasetPath] to the dataset.
+        path: DatasetPath,
+        /// The [DatasetOp]s that were applied to the dataset, in order.
+        ops: Vec<DatasetOp>,
+    },
+
+    /// The response to a [DatasetMessage::Append], sent only to the
+    /// appending relation.
+    AppendResult{
+        /// The [DatasetPath] to the dataset.
+        path: DatasetPath,
+        /// The index at which the element was inserted.
+        id: usize,
+        /// The token provided in the [DatasetMessage::Append].
+        token: Option<String>,
+    },
+
+    /// The response to a [DatasetMessage::Query].
+    QueryResult{
+        /// The [DatasetPath] to the dataset.
+        path: DatasetPath,
+        /// The offset that was requested in the query.
+        offset: usize,
+        /// The [DatasetData] that matched the query.
+        data: Vec<DatasetData>
+    },
+}
+
+impl DatasetMessage{
+    /// Update a local copy of a subscribed dataset from a
+    /// [DatasetMessage::Dataset] or [DatasetMessage::Delta]. Returns false,
+    /// leaving the copy unchanged, for any other message. Also returns false
+    /// if an op in a Delta could not be applied, in which case the ops
+    /// before it have been applied and the copy should be replaced by
+    /// subscribing again.
+    pub fn apply(&self, dataset: &mut Vec<DatasetData>) -> bool{
+        match self{
+            DatasetMessage::Dataset { data, .. } => {
+                dataset.clone_from(data);
+                true
+            },
+            DatasetMessage::Delta { ops, .. } => {
+                ops.iter().all(|op| op.apply(dataset))
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A DatasetAccess describes which relations may modify a public dataset.
+/// Any relation may read a public dataset, and private datasets may only
+/// be modified by the peripheral they belong to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum DatasetAccess{
+    /// Any relation may modify the dataset.
+    #[default]
+    Open,
+    /// Only the owner of the dataset may modify it.
+    OwnerOnly,
+    /// The owner of the dataset and the listed relations may modify it.
+    Allowed(Vec<Relation>),
+}
+
+/// A DatasetOp is a single change to a dataset, mirroring the
+/// [DatasetMessage] that caused it. These are sent to subscribers in a
+/// [DatasetMessage::Delta].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DatasetOp{
+    /// The [DatasetData] was appended to the dataset.
+    Append(DatasetData),
+    /// The Vec<[DatasetData]> was appended to the dataset.
+    Extend(Vec<DatasetData>),
+    /// The element at the id was set to the [DatasetData], padding the
+    /// dataset with [DatasetData::Null] if needed.
+    SetElement{
+        /// The index of the element.
+        id: usize,
+        /// The new value of the element.
+        data: DatasetData,
+    },
+    /// The elements starting at the id were set to the Vec<[DatasetData]>,
+    /// padding the dataset with [DatasetData::Null] if needed.
+    SetElements{
+        /// The index of the first element.
+        id: usize,
+        /// The new values of the elements.
+        data: Vec<DatasetData>,
+    },
+    /// The element at the id was removed, shifting all succeding elements
+    /// back by one.
+    DeleteElement{
+        /// The index of the removed element.
+        id: usize,
+    },
+    /// All data was removed from the dataset.
+    Empty,
+}
+
+impl DatasetOp{
+    /// The most [DatasetData::Null] elements that a
+    /// [DatasetOp::SetElement] or [DatasetOp::SetElements] may pad a
+    /// dataset with, an op whose id is further past the end is invalid.
+    pub const MAX_PADDING: usize = 1024;
+
+    /// Returns true if this operation can be applied to a dataset of the
+    /// given length. Ops that would pad the dataset by more than
+    /// [DatasetOp::MAX_PADDING] elements, or whose indices overflow, cannot.
+    pub fn fits(&self, len: usize) -> bool{
+        let (id, count) = match self{
+            DatasetOp::SetElement { id, .. } => (*id, 1),
+            DatasetOp::SetElements { id, data } => (*id, data.len()),
+            _ => return true,
+        };
+        id.checked_add(count).is_some() && id <= len.saturating_add(Self::MAX_PADDING)
+    }
+
+    /// Apply this operation to a local copy of a dataset, in the same way
+    /// that the base applied it to its own copy. Returns false, leaving the
+    /// dataset unchanged, if the op does not [fit](DatasetOp::fits).
+    pub fn apply(&self, dataset: &mut Vec<DatasetData>) -> bool{
+        if !self.fits(dataset.len()){
+            return false;
+        }
+        match self{
+            DatasetOp::Append(data) => dataset.push(data.clone()),
+            DatasetOp::Extend(data) => dataset.extend_from_slice(data),
+            DatasetOp::SetElement { id, data } => {
+                if dataset.len() <= *id {
+                    dataset.resize(id + 1, DatasetData::Null);
+                }
+                dataset[*id] = data.clone();
+            },
+            DatasetOp::SetElements { id, data } => {
+                let end = id + data.len();
+                if dataset.len() < end {
+                    dataset.resize(end, DatasetData::Null);
+                }
+                dataset[*id..end].clone_from_slice(data);
+            },
+            DatasetOp::DeleteElement { id } => {
+                if *id < dataset.len() {
+                    dataset.remove(*id);
+                }
+            },
+            DatasetOp::Empty => dataset.clear(),
+        }
+        true
+    }
+}
+
+/// A DatasetFilter selects which elements of a dataset are returned by a
+/// [DatasetMessage::Query].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DatasetFilter{
+    /// Matches [DatasetData::Map] elements where the given key is equal to
+    /// the given [DatasetData].
+    KeyEquals{
+        /// The key in the map to compare.
+        key: String,
+        /// The value that the key must be equal to.
+        value: DatasetData,
+    },
+}
+
+impl DatasetFilter{
+    /// Returns true if the [DatasetData] matches this filter.
+    pub fn matches(&self, data: &DatasetData) -> bool{
+        match self{
+            DatasetFilter::KeyEquals { key, value } => {
+                match data {
+                    DatasetData::Map(map) => map.get(key) == Some(value),
+                    _ => false,
+                }
+            },
+        }
+    }
 }
 
 impl DatasetData{
@@ -239,6 +487,8 @@ impl DatasetData{
             DatasetData::Int(_) => &DatasetData::Null,
             DatasetData::Float(_) => &DatasetData::Null,
             DatasetData::String(_) => &DatasetData::Null,
+            DatasetData::Bool(_) => &DatasetData::Null,
+            DatasetData::Timestamp(_) => &DatasetData::Null,
             DatasetData::Array(arr) => {
                 match property.parse::<usize>(){
                     Ok(index) => {
@@ -261,7 +511,73 @@ impl DatasetData{
         }
     }
 
+    /// Get a nested entry by following each part of the path in turn with
+    /// [DatasetData::get_property]. Returns [DatasetData::Null] if any part
+    /// of the path is missing.
+    pub fn get_path(&self, parts: &[&str]) -> &Self{
+        let mut data = self;
+        for part in parts{
+            data = data.get_property(&part.to_string());
+        }
+        data
+    }
+
+    /// Get the value as an i64 if it is a [DatasetData::Byte],
+    /// [DatasetData::Int], or [DatasetData::Timestamp].
+    pub fn as_i64(&self) -> Option<i64>{
+        match self{
+            DatasetData::Byte(b) => Some(*b as i64),
+            DatasetData::Int(i) => Some(*i as i64),
+            DatasetData::Timestamp(t) => Some(*t),
+            _ => None,
+        }
+    }
+
+    /// Get the value as an f64 if it is a [DatasetData::Byte],
+    /// [DatasetData::Int], or [DatasetData::Float].
+    pub fn as_f64(&self) -> Option<f64>{
+        match self{
+            DatasetData::Byte(b) => Some(*b as f64),
+            DatasetData::Int(i) => Some(*i as f64),
+            DatasetData::Float(f) => Some(*f as f64),
+            _ => None,
+        }
+    }
+
+    /// Get the value if it is a [DatasetData::String].
+    pub fn as_str(&self) -> Option<&str>{
+        match self{
+            DatasetData::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Get the value if it is a [DatasetData::Bool].
+    pub fn as_bool(&self) -> Option<bool>{
+        match self{
+            DatasetData::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Get the elements if this is a [DatasetData::Array].
+    pub fn as_array(&self) -> Option<&Vec<DatasetData>>{
+        match self{
+            DatasetData::Array(arr) => Some(arr),
+            _ => None,
+        }
+    }
+
+    /// Get the entries if this is a [DatasetData::Map].
+    pub fn as_map(&self) -> Option<&HashMap<String, DatasetData>>{
+        match self{
+            DatasetData::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
     /// Convert this DatasetData to a string representation.
+    /// Timestamps are represented in ISO-8601 format.
     pub fn to_string(&self) -> String{
         match self{
             DatasetData::Null => "<null>".to_owned(),
@@ -269,6 +585,11 @@ impl DatasetData{
             DatasetData::Int(i) => i.to_string(),
             DatasetData::Float(f) => f.to_string(),
             DatasetData::String(s) => s.to_string(),
+            DatasetData::Bool(b) => b.to_string(),
+            DatasetData::Timestamp(t) => match DateTime::from_timestamp_millis(*t) {
+                Some(time) => time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                None => t.to_string(),
+            },
             DatasetData::Array(a) => format!("{:?}", a),
             DatasetData::Map(m) => format!("{:?}", m),
         }
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::DatasetData;
+
+/// A DatasetSchema describes the expected shape of each element in a
+/// dataset. Once set, elements that do not conform to the schema are
+/// rejected by the base.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DatasetSchema{
+    /// Any [DatasetData] is accepted.
+    Any,
+    /// Only [DatasetData] of the given [DatasetKind] is accepted.
+    Kind(DatasetKind),
+    /// A [DatasetData::Array] where every element conforms to the inner
+    /// schema.
+    Array(Box<DatasetSchema>),
+    /// A [DatasetData::Map] where each named field is present and conforms to
+    /// its schema. Fields not named in the schema are allowed.
+    Map(HashMap<String, DatasetSchema>),
+}
+
+impl DatasetSchema{
+    /// Returns true if the [DatasetData] conforms to this schema.
+    pub fn validate(&self, data: &DatasetData) -> bool{
+        match self{
+            DatasetSchema::Any => true,
+            DatasetSchema::Kind(kind) => *kind == DatasetKind::of(data),
+            DatasetSchema::Array(schema) => match data {
+                DatasetData::Array(arr) => arr.iter().all(|elem| schema.validate(elem)),
+                _ => false,
+            },
+            DatasetSchema::Map(fields) => match data {
+                DatasetData::Map(map) => fields.iter().all(|(key, schema)|{
+                    match map.get(key) {
+                        Some(elem) => schema.validate(elem),
+                        None => false,
+                    }
+                }),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// A DatasetKind names one of the variants of [DatasetData].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DatasetKind{
+    /// [DatasetData::Null]
+    Null,
+    /// [DatasetData::Byte]
+    Byte,
+    /// [DatasetData::Int]
+    Int,
+    /// [DatasetData::Float]
+    Float,
+    /// [DatasetData::String]
+    String,
+    /// [DatasetData::Bool]
+    Bool,
+    /// [DatasetData::Timestamp]
+    Timestamp,
+    /// [DatasetData::Array]
+    Array,
+    /// [DatasetData::Map]
+    Map,
+}
+
+impl DatasetKind{
+    /// Returns the DatasetKind of the [DatasetData].
+    pub fn of(data: &DatasetData) -> Self{
+        match data{
+            DatasetData::Null => DatasetKind::Null,
+            DatasetData::Byte(_) => DatasetKind::Byte,
+            DatasetData::Int(_) => DatasetKind::Int,
+            DatasetData::Float(_) => DatasetKind::Float,
+            DatasetData::String(_) => DatasetKind::String,
+            DatasetData::Bool(_) => DatasetKind::Bool,
+            DatasetData::Timestamp(_) => DatasetKind::Timestamp,
+            DatasetData::Array(_) => DatasetKind::Array,
+            DatasetData::Map(_) => DatasetKind::Map,
+        }
+    }
+}
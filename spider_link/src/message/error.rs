@@ -0,0 +1,89 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The kind of problem described by an [ErrorMessage], so that the receiver
+/// can react to it without parsing the detail text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The sender is not permitted to make the request.
+    Unauthorized,
+    /// The request named a path, dataset, or other item that is not valid.
+    InvalidPath,
+    /// The request was malformed or had an invalid argument.
+    InvalidRequest,
+    /// The sender is sending messages too quickly.
+    RateLimited,
+    /// The data does not conform to the schema of its dataset.
+    SchemaViolation,
+    /// The base failed to store the result of the request.
+    StorageFailure,
+    /// Any other error, described only by its detail.
+    Other,
+}
+
+/// An ErrorMessage describes why a request could not be completed.
+/// The [ErrorCode] identifies the kind of error, the detail is a human
+/// readable description, and the context optionally names what the
+/// error concerns, such as the path of a dataset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    code: ErrorCode,
+    detail: String,
+    context: Option<String>,
+}
+
+impl ErrorMessage {
+    /// Create a new ErrorMessage with the given code and detail.
+    pub fn new<S: Into<String>>(code: ErrorCode, detail: S) -> Self {
+        Self {
+            code,
+            detail: detail.into(),
+            context: None,
+        }
+    }
+
+    /// Add a context to this ErrorMessage, naming what the error concerns.
+    pub fn with_context<S: Into<String>>(mut self, context: S) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Get the [ErrorCode] of this ErrorMessage.
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// Get the human readable detail of this ErrorMessage.
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+
+    /// Get the context of this ErrorMessage, if it has one.
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+}
+
+impl fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.context {
+            Some(context) => write!(f, "{}: {}", context, self.detail),
+            None => write!(f, "{}", self.detail),
+        }
+    }
+}
+
+/// Convert free text into an ErrorMessage with the [ErrorCode::Other] code.
+impl From<String> for ErrorMessage {
+    fn from(detail: String) -> Self {
+        Self::new(ErrorCode::Other, detail)
+    }
+}
+
+/// Convert free text into an ErrorMessage with the [ErrorCode::Other] code.
+impl From<&str> for ErrorMessage {
+    fn from(detail: &str) -> Self {
+        Self::new(ErrorCode::Other, detail)
+    }
+}
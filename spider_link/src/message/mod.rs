@@ -18,8 +18,12 @@ pub use crate::message::ui::{
 	UiMessage,
 	UiPage,
 	UiPageManager,
+	SyncError,
 	UiPageList,
 	UiPath,
+	UiPathParseError,
+	UiPageStyle,
+	UiPageTheme,
 
 	UiElement,
 	UiElementKind,
@@ -27,6 +31,7 @@ pub use crate::message::ui::{
     UiElementChange,
     UiElementContent,
     UiElementContentPart,
+    UiElementValidation,
     UiChildOperations,
     UpdateSummary,
 
@@ -36,24 +41,31 @@ pub use crate::message::ui::{
 mod dataset;
 pub use dataset::{
     DatasetMessage,
+    DatasetOp,
+    DatasetAccess,
     AbsoluteDatasetScope,
     AbsoluteDatasetPath,
     DatasetScope,
     DatasetPath,
     DatasetData,
+    DatasetFilter,
+    DatasetSchema,
+    DatasetKind,
+};
+
+mod error;
+pub use error::{
+    ErrorMessage,
+    ErrorCode,
 };
 
 mod router;
 pub use router::{
     RouterMessage,
     DirectoryEntry,
+    ConnectionInfo,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct Frame {
-    pub data: Vec<u8>,
-}
-
 /// The key request is used by a peripheral to get the id and
 /// name of the listening base. This struct contains the
 /// response to that request.
@@ -67,8 +79,31 @@ pub struct KeyRequest{
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum Protocol {
-    Introduction { id: SpiderId2048, role: Role },
+    Introduction {
+        id: SpiderId2048,
+        role: Role,
+        // peers from before versioning do not send a version
+        #[serde(default)]
+        version: u16,
+    },
     Message(Message),
+    Ping,
+    Pong,
+    Goodbye { reason: GoodbyeReason },
+}
+
+/// The reason given by one end of a Link for deliberately closing it.
+/// This allows the other end to distinguish a deliberate disconnection
+/// from a network failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GoodbyeReason {
+    /// The Link was closed without a more specific reason.
+    Closed,
+    /// The other end has removed this member from its directory, and
+    /// will not accept it again without being paired.
+    Unpaired,
+    /// The other end is shutting down.
+    Shutdown,
 }
 
 /// A Message sent to or from a member of the spider network.
@@ -83,6 +118,7 @@ pub enum Message {
     /// The message is a [RouterMessage].
     /// Used to route arbitrairy data to members of the network
     Router(RouterMessage),
-    /// The message is an error
-    Error(String),
+    /// The message is an [ErrorMessage].
+    /// Used to report why a request could not be completed
+    Error(ErrorMessage),
 }
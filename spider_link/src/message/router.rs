@@ -19,6 +19,8 @@ use super::DatasetData;
 /// Chord messages allow peripherals to get a list of addresses in the base's
 /// chord in order for those peripherals to be able to use the chord to find
 /// the base.
+/// Connection messages allow peripherals to see which members of the
+/// network are currently connected to the base.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RouterMessage {
     // Authorization messages
@@ -45,6 +47,30 @@ pub enum RouterMessage {
     Subscribe(String),
     /// Stop receiving messages of a particular type routed by the base.
     Unsubscribe(String),
+    /// Subscribe to messages of a particular type, like
+    /// [RouterMessage::Subscribe], and immediately receive up to the given
+    /// number of the most recent messages of that type, oldest first.
+    SubscribeReplay(String, usize),
+    /// Request to receive messages with types matching a pattern. A pattern
+    /// ending in `*` matches every type beginning with the rest of the
+    /// pattern, such as `sensor.*`, otherwise it must match exactly.
+    SubscribePattern(String),
+    /// Stop receiving messages matching a pattern previously subscribed to
+    /// with [RouterMessage::SubscribePattern].
+    UnsubscribePattern(String),
+    /// Request that the base subscribe to messages of a particular type from
+    /// the given peer base. Events received from that peer are routed to the
+    /// base's subscribers.
+    SubscribePeer(Relation, String),
+    /// Request that the base stop receiving messages of a particular type
+    /// from the given peer base.
+    UnsubscribePeer(Relation, String),
+    /// Sent from one base to another to receive messages of a particular
+    /// type that originate from the other base's peripherals.
+    PeerSubscribe(String),
+    /// Sent from one base to another to stop receiving messages of a
+    /// particular type.
+    PeerUnsubscribe(String),
 
     // Directory messages
     /// Request to receive notifications of changes to the directory.
@@ -59,6 +85,15 @@ pub enum RouterMessage {
     /// Indicate to the other member of this connection to update this member's
     /// identity properties.
     SetIdentityProperty(String, String),
+    /// Request the entries in the directory whose nickname or name contains
+    /// the query, ignoring case. An empty query matches every entry.
+    /// The base responds with a [RouterMessage::SearchDirResult].
+    SearchDir {
+        /// The text to search for.
+        query: String,
+    },
+    /// The entries in the directory matching a [RouterMessage::SearchDir].
+    SearchDirResult(Vec<DirectoryEntry>),
 
     // Chord messages
     /// Request to receive the n most recent addresses in the base's chord in
@@ -69,6 +104,15 @@ pub enum RouterMessage {
     UnsubscribeChord,
     /// The n most recent chord addresses.
     ChordAddrs(Vec<String>),
+
+    // Connection messages
+    /// Request the list of members currently connected to the base.
+    /// Only peripherals may make this request, it is ignored from peers.
+    /// The base responds with a [RouterMessage::Connections].
+    ListConnections,
+    /// The members connected to the base, in response to a
+    /// [RouterMessage::ListConnections].
+    Connections(Vec<ConnectionInfo>),
 }
 
 /// A DirectoryEntry holds details about some other member of the
@@ -103,3 +147,49 @@ impl DirectoryEntry{
         self.properties.insert(key, value);
     }
 }
+
+/// A ConnectionInfo describes a member of the spider network that is
+/// currently connected to the base, along with the number of messages sent
+/// over its link.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo{
+    relation: Relation,
+    nickname: Option<String>,
+    messages_sent: u64,
+    messages_received: u64,
+}
+
+impl ConnectionInfo{
+    /// Create a new ConnectionInfo for the provided [Relation].
+    pub fn new(relation: Relation, nickname: Option<String>, messages_sent: u64, messages_received: u64) -> Self{
+        Self{
+            relation,
+            nickname,
+            messages_sent,
+            messages_received,
+        }
+    }
+
+    /// Get the [Relation] of the connected member, which includes its role.
+    pub fn relation(&self)-> &Relation {
+        &self.relation
+    }
+
+    /// Get the nickname of the connected member from the base's directory,
+    /// if it has one.
+    pub fn nickname(&self)-> Option<&String> {
+        self.nickname.as_ref()
+    }
+
+    /// Get the number of messages the base has sent to this member since
+    /// it connected.
+    pub fn messages_sent(&self)-> u64 {
+        self.messages_sent
+    }
+
+    /// Get the number of messages the base has received from this member
+    /// since it connected.
+    pub fn messages_received(&self)-> u64 {
+        self.messages_received
+    }
+}
@@ -38,13 +38,34 @@ impl UiElementContent{
         }
     }
 
+    /// Create a new UiElementContent from a sequence of parts, which are
+    /// rendered in order. Text and data parts can be mixed freely, e.g. to
+    /// render "Temp: <temp>°C".
+    pub fn from_parts(parts: Vec<UiElementContentPart>) -> Self{
+        Self {
+            parts
+        }
+    }
+
+    /// Get the sequence of [UiElementContentPart]s in this content.
+    pub fn parts(&self) -> &Vec<UiElementContentPart>{
+        &self.parts
+    }
+
+    /// Consume the content, returning its sequence of
+    /// [UiElementContentPart]s.
+    pub fn into_parts(self) -> Vec<UiElementContentPart>{
+        self.parts
+    }
+
     /// Add a new [UiElementContentPart] to the end of the sequence.
     pub fn add_part(&mut self, part: UiElementContentPart){
         self.parts.push(part);
     }
 
     /// Return a String of the content, with the data references resolved to
-    /// the data in the provided [DatasetData].
+    /// the data in the provided [DatasetData]. The text and resolved data
+    /// parts are concatenated in order.
     pub fn resolve(&self, data: &DatasetData) -> String {
         let mut collect = Vec::with_capacity(self.parts.len());
         for part in &self.parts{
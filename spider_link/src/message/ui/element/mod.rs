@@ -1,4 +1,4 @@
-use std::{collections::HashMap, iter::FusedIterator};
+use std::{collections::HashMap, iter::FusedIterator, sync::OnceLock};
 
 use serde::{Deserialize, Serialize};
 
@@ -24,6 +24,9 @@ pub use update_summary::UpdateSummary;
 mod reference;
 pub use reference::UiElementRef;
 
+mod validation;
+pub use validation::UiElementValidation;
+
 use crate::message::{AbsoluteDatasetPath, DatasetData};
 
 /// A UiElement is a portion of a UiPage, they are arranged as nodes in a tree
@@ -36,6 +39,10 @@ pub struct UiElement {
 
     content: UiElementContent,
     alt_text: UiElementContent,
+    #[serde(default)]
+    validation: Option<UiElementValidation>,
+    #[serde(default)]
+    visible_when: Option<(UiElementContentPart, DatasetData)>,
 
     dataset: Option<AbsoluteDatasetPath>,
 
@@ -47,7 +54,7 @@ pub struct UiElement {
 
 /// A [UiElement] can be one of several variants, to represent different kinds
 /// of element that can be layed out on the page.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum UiElementKind {
     /// Do not render anything
     None,
@@ -62,8 +69,10 @@ pub enum UiElementKind {
     /// The children of this [UiElement] will be arranged horizontally next to
     /// each other.
     Rows,
-    /// Unimplemented, would have arranged elements into a fixed grid of the
-    /// specified dimensions
+    /// The children of this [UiElement] will be arranged into a fixed grid
+    /// with the specified number of rows and columns, filling each row in
+    /// turn. Missing cells are filled with [UiElementKind::None], and excess
+    /// children are not displayed.
     Grid(u8, u8),
 
     // Output
@@ -71,12 +80,28 @@ pub enum UiElementKind {
     Header,
     /// Standard text
     Text,
+    /// An image, the content of the element resolves to the URL of the
+    /// image, or to a base64 data URI containing the image.
+    Image,
 
     // Input
     /// A text box used for recieving a text input from the user
     TextEntry,
     /// A button used for recieving a click input from the user
     Button,
+    /// A checkbox used for recieving a boolean input from the user. The
+    /// content of the element is used as its label.
+    Checkbox,
+    /// A slider used for recieving a numeric input from the user, between
+    /// min and max in increments of step.
+    Slider{
+        /// The smallest value that can be selected
+        min: f32,
+        /// The largest value that can be selected
+        max: f32,
+        /// The increment between selectable values
+        step: f32,
+    },
 
     // Misc
     /// The kind of this element is determined by the text in the resolved
@@ -95,14 +120,19 @@ impl UiElementKind{
             UiElementKind::Grid(_, _) => false,
             UiElementKind::Header => false,
             UiElementKind::Text => false,
+            UiElementKind::Image => false,
             UiElementKind::TextEntry => true,
             UiElementKind::Button => true,
+            UiElementKind::Checkbox => true,
+            UiElementKind::Slider { .. } => true,
             UiElementKind::Variable(_) =>false ,
         }
     }
 
     /// Resolve references in this UiElement's [UiElementContent] using the
-    /// provided [DatasetData]
+    /// provided [DatasetData]. A grid is resolved from text beginning with
+    /// "grid" followed by the rows and columns, such as "grid 2x3". A slider
+    /// reads its bounds from the "min", "max", and "step" fields of the data.
     pub fn resolve(self, datum: &Option<&DatasetData>) -> UiElementKind{
         match datum{
             Some(datum) => {
@@ -114,11 +144,24 @@ impl UiElementKind{
                         "spacer" => UiElementKind::Spacer,
                         "columns" => UiElementKind::Columns,
                         "rows" => UiElementKind::Rows,
+                        grid if grid.starts_with("grid") => {
+                            match parse_grid_dimensions(&grid[4..]) {
+                                Some((rows, columns)) => UiElementKind::Grid(rows, columns),
+                                None => self,
+                            }
+                        },
 
                         "header" => UiElementKind::Header,
                         "text" => UiElementKind::Text,
+                        "image" => UiElementKind::Image,
                         "textentry" => UiElementKind::TextEntry,
                         "button" => UiElementKind::Button,
+                        "checkbox" => UiElementKind::Checkbox,
+                        "slider" => UiElementKind::Slider {
+                            min: slider_field(datum, "min", 0.0),
+                            max: slider_field(datum, "max", 100.0),
+                            step: slider_field(datum, "step", 1.0),
+                        },
                         _ => self
                     }
                 }else{
@@ -130,6 +173,27 @@ impl UiElementKind{
     }
 }
 
+/// Parse the first two numbers from the text as the rows and columns of a grid
+fn parse_grid_dimensions(text: &str) -> Option<(u8, u8)>{
+    let mut numbers = text
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse::<u8>());
+    let rows = numbers.next()?.ok()?;
+    let columns = numbers.next()?.ok()?;
+    Some((rows, columns))
+}
+
+/// Read a numeric field from the data for a slider, or use the default
+fn slider_field(datum: &DatasetData, field: &str, default: f32) -> f32{
+    match datum.get_property(&field.to_string()){
+        DatasetData::Byte(b) => *b as f32,
+        DatasetData::Int(i) => *i as f32,
+        DatasetData::Float(f) => *f,
+        _ => default,
+    }
+}
+
 impl UiElement {
     /// Create a new UiElement of the specified kind.
     pub fn new(kind: UiElementKind) -> Self {
@@ -141,6 +205,8 @@ impl UiElement {
 
             content: UiElementContent::new(),
             alt_text: UiElementContent::new(),
+            validation: None,
+            visible_when: None,
 
             dataset: None,
 
@@ -162,6 +228,8 @@ impl UiElement {
 
             content: UiElementContent::new_text(string.into()),
             alt_text: UiElementContent::new(),
+            validation: None,
+            visible_when: None,
 
             dataset: None,
 
@@ -230,6 +298,50 @@ impl UiElement {
         self.dataset = dataset;
     }
 
+    /// Get a reference to the [UiElementValidation] of this UiElement.
+    pub fn validation(&self) -> &Option<UiElementValidation>{
+        &self.validation
+    }
+    /// Set the constraints on input to this UiElement.
+    pub fn set_validation(&mut self, validation: Option<UiElementValidation>){
+        self.validation = validation;
+    }
+
+    /// Get a reference to the condition for this UiElement to be visible.
+    pub fn visible_when(&self) -> &Option<(UiElementContentPart, DatasetData)>{
+        &self.visible_when
+    }
+    /// Set the condition for this UiElement to be visible. When iterating
+    /// with [UiElement::children_dataset], the child is only yielded if the
+    /// part resolves to data equal to the given [DatasetData]. If None, the
+    /// UiElement is always visible.
+    pub fn set_visible_when(&mut self, condition: Option<(UiElementContentPart, DatasetData)>){
+        self.visible_when = condition;
+    }
+    /// Returns true if this UiElement should be rendered with the given
+    /// data, according to its visibility condition. Missing data is
+    /// treated as [DatasetData::Null].
+    pub fn is_visible(&self, data: Option<&DatasetData>) -> bool{
+        match &self.visible_when {
+            Some((part, expected)) => {
+                let data = data.unwrap_or(&DatasetData::Null);
+                match part {
+                    UiElementContentPart::Text(text) => {
+                        matches!(expected, DatasetData::String(s) if s == text)
+                    },
+                    UiElementContentPart::Data(path) => {
+                        let mut data = data;
+                        for property in path{
+                            data = data.get_property(property);
+                        }
+                        data == expected
+                    },
+                }
+            },
+            None => true,
+        }
+    }
+
     // Content operations
     /// Return a String of the content of this UiElement, resolving any
     /// references to data with the provided [DatasetData]
@@ -293,7 +405,8 @@ impl UiElement {
     /// This iterator yields a triplet of
     /// (Option<usize>, &'a UiElement, Option<&'a [DatasetData]>).
     /// This allows iteration over elements, while providing the correct
-    /// [DatasetData] to resolve the UiElement.
+    /// [DatasetData] to resolve the UiElement. Children that are not
+    /// visible with their [DatasetData] are skipped.
     pub fn children_dataset<'a>(&'a self, data: &'a Option<&DatasetData>, data_map: &'a HashMap<AbsoluteDatasetPath, Vec<DatasetData>>) -> UiElementDatasetIterator{
         UiElementDatasetIterator::new(&self, data, data_map)
     }
@@ -330,6 +443,27 @@ impl UiElement {
         }
     }
 
+    /// Returns true if this UiElement has the same properties as the other,
+    /// not including their children.
+    pub(crate) fn same_properties(&self, other: &UiElement) -> bool{
+        self.kind == other.kind
+            && self.id == other.id
+            && self.selectable == other.selectable
+            && self.content == other.content
+            && self.alt_text == other.alt_text
+            && self.validation == other.validation
+            && self.visible_when == other.visible_when
+            && self.dataset == other.dataset
+    }
+
+    /// Returns true if this UiElement and all of its descendants have the
+    /// same properties as the other.
+    pub(crate) fn same_tree(&self, other: &UiElement) -> bool{
+        self.same_properties(other)
+            && self.children().len() == other.children().len()
+            && self.children().zip(other.children()).all(|(a, b)| a.same_tree(b))
+    }
+
     // Change management operations
     /// Take all the accrued changes as a [UiElementChangeSet], leaving the
     /// UiElement with no recorded changes.
@@ -340,6 +474,9 @@ impl UiElement {
     /// Apply a [UiElementChangeSet] to this UiElement. A mutable reference to
     /// an [UpdateSummary] must be provided, and will contain the net changes
     /// to dataset subscriptions.
+    /// Child operations with an index out of range are recorded in the
+    /// summary. Inserts and move destinations past the end are clamped to
+    /// the end, and deletes and moves of missing children are skipped.
     pub fn apply_update(&mut self, mut update: UiElementUpdate, summary: &mut UpdateSummary){
         // if node was changed, assign values to self
         if let Some(node_changes) = update.take_element(){
@@ -347,9 +484,13 @@ impl UiElement {
             // assign from change to self
             self.kind = node_changes.kind;
             self.id = node_changes.id;
+            self.selectable = node_changes.selectable;
         
             self.content = node_changes.content;
             self.alt_text = node_changes.alt_text;
+            self.validation = node_changes.validation;
+            self.visible_when = node_changes.visible_when;
+            self.dataset = node_changes.dataset;
         }
 
         // apply changes to children
@@ -358,16 +499,32 @@ impl UiElement {
 
                 for operation in child_changes{
                     match operation{
-                        change::UiChildOperations::Insert(index, element) => {
+                        change::UiChildOperations::Insert(mut index, element) => {
+                            if index > children.len() {
+                                summary.invalid();
+                                index = children.len();
+                            }
                             summary.add(&element);
                             children.insert(index, element);
                         },
                         change::UiChildOperations::Delete(index) => {
+                            if index >= children.len() {
+                                summary.invalid();
+                                continue;
+                            }
                             let removed = children.remove(index);
                             summary.remove(&removed);
                         },
-                        change::UiChildOperations::MoveTo { from, to } => {
+                        change::UiChildOperations::MoveTo { from, mut to } => {
+                            if from >= children.len() {
+                                summary.invalid();
+                                continue;
+                            }
                             let element = children.remove(from);
+                            if to > children.len() {
+                                summary.invalid();
+                                to = children.len();
+                            }
                             summary.move_to(&element);
                             children.insert(to, element);
                         },
@@ -384,6 +541,7 @@ impl UiElement {
 
 
 
+#[derive(Clone)]
 pub struct UiElementDatasetIterator<'a>{
     // data references
     elem: &'a UiElement,
@@ -395,6 +553,17 @@ pub struct UiElementDatasetIterator<'a>{
     // back iterator
     back_dataset: isize,
     back_child: isize,
+    // grid layout: the positions of the next cells, and the number of cells
+    // that are filled by children rather than padding
+    grid: Option<(usize, usize, usize)>,
+    // some children have visibility conditions, so they may be skipped
+    filtered: bool,
+}
+
+/// The element yielded for empty grid cells
+fn grid_padding() -> &'static UiElement{
+    static PADDING: OnceLock<UiElement> = OnceLock::new();
+    PADDING.get_or_init(|| UiElement::new(UiElementKind::None))
 }
 
 impl<'a> UiElementDatasetIterator<'a>{
@@ -412,7 +581,7 @@ impl<'a> UiElementDatasetIterator<'a>{
             Some(children) => (children.len() as isize) - 1,
             None => 0,
         };
-        Self{
+        let mut iter = Self{
             // data references
             elem,
             data,
@@ -423,7 +592,19 @@ impl<'a> UiElementDatasetIterator<'a>{
             // back iterator
             back_dataset,
             back_child,
+            grid: None,
+            filtered: elem.children().any(|child| child.visible_when.is_some()),
+        };
+        if let UiElementKind::Grid(rows, columns) = elem.kind {
+            let cells = rows as usize * columns as usize;
+            let children = iter.size_hint_inner().0;
+            // drop children that do not fit in the grid
+            for _ in cells..children {
+                iter.next_back_inner();
+            }
+            iter.grid = Some((0, cells, children.min(cells)));
         }
+        iter
     }
 
     fn is_done(&self) -> bool{
@@ -460,6 +641,86 @@ impl<'a> Iterator for UiElementDatasetIterator<'a>{
     type Item = (Option<usize>, &'a UiElement, Option<&'a DatasetData>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.grid {
+            Some((front, back, filled)) => {
+                if front >= back {
+                    return None;
+                }
+                let position = *front;
+                *front += 1;
+                if position < *filled {
+                    self.next_inner()
+                } else {
+                    Some((None, grid_padding(), None))
+                }
+            },
+            None => self.next_inner(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.grid {
+            Some((front, back, _)) => {
+                let l = back.saturating_sub(*front);
+                (l, Some(l))
+            },
+            None => self.size_hint_inner(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for UiElementDatasetIterator<'a>{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match &mut self.grid {
+            Some((front, back, filled)) => {
+                if front >= back {
+                    return None;
+                }
+                *back -= 1;
+                if *back < *filled {
+                    self.next_back_inner()
+                } else {
+                    Some((None, grid_padding(), None))
+                }
+            },
+            None => self.next_back_inner(),
+        }
+    }
+}
+
+impl<'a> UiElementDatasetIterator<'a>{
+    fn next_inner(&mut self) -> Option<(Option<usize>, &'a UiElement, Option<&'a DatasetData>)> {
+        loop {
+            let item = self.next_raw()?;
+            if item.1.is_visible(item.2) {
+                return Some(item);
+            }
+        }
+    }
+
+    fn next_back_inner(&mut self) -> Option<(Option<usize>, &'a UiElement, Option<&'a DatasetData>)> {
+        loop {
+            let item = self.next_back_raw()?;
+            if item.1.is_visible(item.2) {
+                return Some(item);
+            }
+        }
+    }
+
+    fn size_hint_inner(&self) -> (usize, Option<usize>) {
+        if !self.filtered {
+            return self.size_hint_raw();
+        }
+        // the remaining children must be checked to find how many are visible
+        let mut remaining = self.clone();
+        let mut count = 0;
+        while remaining.next_inner().is_some() {
+            count += 1;
+        }
+        (count, Some(count))
+    }
+
+    fn next_raw(&mut self) -> Option<(Option<usize>, &'a UiElement, Option<&'a DatasetData>)> {
         match &self.elem.children{
             Some(children) => {
                 // if the elem has a dataset, iterate that
@@ -526,7 +787,7 @@ impl<'a> Iterator for UiElementDatasetIterator<'a>{
         }
     }
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
+    fn size_hint_raw(&self) -> (usize, Option<usize>) {
         match &self.elem.dataset{
             Some(path) => {
                 // there is a dataset path, add uniterated dataset sizes to total
@@ -553,10 +814,8 @@ impl<'a> Iterator for UiElementDatasetIterator<'a>{
             },
         }
     }
-}
 
-impl<'a> DoubleEndedIterator for UiElementDatasetIterator<'a>{
-    fn next_back(&mut self) -> Option<Self::Item> {
+    fn next_back_raw(&mut self) -> Option<(Option<usize>, &'a UiElement, Option<&'a DatasetData>)> {
         match &self.elem.children{
             Some(children) => {
                 // if the elem has a dataset, iterate that
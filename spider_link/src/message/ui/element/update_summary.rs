@@ -11,8 +11,10 @@ use super::UiElement;
 /// UiPage or a UiElement within that page. It records if a change actually
 /// took place, and the change in how many UiElements subscribe to each
 /// dataset.
+#[derive(Debug)]
 pub struct UpdateSummary{
     changed: bool,
+    invalid_operations: usize,
     dataset_subscriptions: HashMap<AbsoluteDatasetPath, isize>,
 }
 
@@ -21,6 +23,7 @@ impl UpdateSummary{
     pub fn new() -> Self{
         Self { 
             changed: false,
+            invalid_operations: 0,
             dataset_subscriptions: HashMap::new(),
         }
     }
@@ -31,6 +34,13 @@ impl UpdateSummary{
         self.changed
     }
 
+    /// The number of child operations in this update that referred to an
+    /// index out of range. These were either clamped to the valid range
+    /// or skipped.
+    pub fn invalid_operations(&self) -> usize {
+        self.invalid_operations
+    }
+
     /// Returns a map from an [AbsoluteDatasetPath] to the net change in
     /// subscriptions to that dataset. This might not be a comprehensive list
     /// of all subscribed datasets, only of ones where some change occured.
@@ -69,9 +79,13 @@ impl UpdateSummary{
         // Changed:
         self.changed |= old.kind != new.kind;
         self.changed |= old.id != new.id;
+        self.changed |= old.selectable != new.selectable;
+        self.changed |= old.dataset != new.dataset;
     
         self.changed |= old.content != new.content;
         self.changed |= old.alt_text != new.alt_text;
+        self.changed |= old.validation != new.validation;
+        self.changed |= old.visible_when != new.visible_when;
 
         // Dataset Changes:
         match &old.dataset{
@@ -144,4 +158,9 @@ impl UpdateSummary{
         // Dataset Changes: no changes
     }
 
+    /// Record that a child operation referred to an index out of range.
+    pub fn invalid(&mut self){
+        self.invalid_operations += 1;
+    }
+
 }
@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// A UiElementValidation describes constraints on the input that a
+/// [UiElementKind::TextEntry](super::UiElementKind::TextEntry) will accept.
+/// These constraints are not enforced by the base, but are provided so that
+/// the UI peripheral can validate the input before sending it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UiElementValidation {
+    /// The maximum number of characters that may be entered.
+    pub max_len: Option<usize>,
+    /// If the input must not be empty.
+    pub required: bool,
+    /// A regular expression that the input must match.
+    pub pattern: Option<String>,
+}
@@ -7,4 +7,8 @@ pub enum UiInput {
     Click,
     /// The user has entered text in a textbox
     Text(String),
+    /// The user has toggled a checkbox to the given state
+    Bool(bool),
+    /// The user has selected a value on a slider
+    Number(f32),
 }
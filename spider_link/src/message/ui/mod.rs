@@ -6,8 +6,12 @@ mod page;
 pub use page::{
 	UiPage,
     UiPageManager,
+    SyncError,
 	UiPageList,
     UiPath,
+    UiPathParseError,
+    UiPageStyle,
+    UiPageTheme,
 };
 
 mod element;
@@ -18,6 +22,7 @@ pub use element::{
     UiElementChange,
     UiElementContent,
     UiElementContentPart,
+    UiElementValidation,
     
     UiChildOperations,
 
@@ -51,10 +56,20 @@ pub enum UiMessage {
     /// A Vec<[UiElementUpdate]> to be applied to the [UiPage] identified by
     /// the [SpiderId2048]
     UpdateElementsFor(SpiderId2048, Vec<UiElementUpdate>),
+    /// The [UiPage] identified by the [SpiderId2048] has been removed.
+    RemovePage(SpiderId2048),
     /// An updated dataset that a [UiPage] depends on.
     Dataset(AbsoluteDatasetPath, Vec<DatasetData>),
     /// The user has provided input for a [UiPage] for some peripheral.
     InputFor(SpiderId2048, String, Vec<usize>, UiInput),
+    /// Subscribe to a dataset directly, rather than through a [UiPage].
+    /// The dataset and any changes to it are sent as [UiMessage::Dataset].
+    /// Public datasets are available to any UI Peripheral, but the private
+    /// datasets of other peripherals are only available to UIs that the
+    /// owner of the base has authorized, such as an owner's dashboard.
+    SubscribeDataset(AbsoluteDatasetPath),
+    /// Stop a subscription made with [UiMessage::SubscribeDataset].
+    UnsubscribeDataset(AbsoluteDatasetPath),
 
     //Peripheral page <---> Base
     /// This peripheral is setting its [UiPage]
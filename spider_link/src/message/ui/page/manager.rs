@@ -1,11 +1,11 @@
 use std::{
     collections::{BTreeSet, HashMap},
-    mem,
+    fmt, mem,
 };
 
 use crate::{
     message::{
-        ui::element::{UiElementChangeSet, UiElementRef, UiElementUpdate, UpdateSummary},
+        ui::element::{UiChildOperations, UiElementChangeSet, UiElementRef, UiElementUpdate, UpdateSummary},
         UiElement,
     },
     SpiderId2048,
@@ -84,7 +84,7 @@ impl UiPageManager {
     /// Get a reference to a [UiElement] in the wrapped [UiPage] using the
     /// provided [UiPath] to determine which element.
     pub fn get_element(&self, path: &UiPath) -> Option<&UiElement> {
-        let mut cursor = &self.page.root;
+        let mut cursor: &UiElement = &self.page.root;
         for child_index in path.iter() {
             cursor = match cursor.get_child(*child_index) {
                 Some(child) => child,
@@ -99,7 +99,7 @@ impl UiPageManager {
     /// Making changes to a [UiElement] through a [UiElementRef] allows
     /// changes to be captured by the UiPageManager.
     pub fn get_element_mut(&mut self, path: &UiPath) -> Option<UiElementRef> {
-        let mut cursor = &mut self.page.root;
+        let mut cursor: &mut UiElement = &mut self.page.root;
         for child_index in path.iter() {
             cursor = match cursor.get_child_mut(*child_index) {
                 Some(child) => child,
@@ -115,7 +115,7 @@ impl UiPageManager {
     /// The UiPageManager will not be able to track elements
     /// referenced in this way.
     pub fn get_element_raw(&mut self, path: &UiPath) -> Option<&mut UiElement> {
-        let mut cursor = &mut self.page.root;
+        let mut cursor: &mut UiElement = &mut self.page.root;
         for child_index in path.iter() {
             cursor = match cursor.get_child_mut(*child_index) {
                 Some(child) => child,
@@ -206,11 +206,72 @@ impl UiPageManager {
         ret
     }
 
+    /// Calculate a Vec<[UiElementUpdate]> that would change the wrapped
+    /// [UiPage] into the new one. This allows a peripheral that builds a
+    /// whole new page to send only what changed.
+    /// Children that are unchanged at the start and end of each list of
+    /// children are kept, and the rest are compared in position, with any
+    /// extra children inserted or deleted. The name and style of the page
+    /// are not included.
+    pub fn diff_to(&self, new: &UiPage) -> Vec<UiElementUpdate> {
+        let mut updates = Vec::new();
+        Self::diff_node(&mut updates, UiPath::root(), &self.page.root, &new.root);
+        updates
+    }
+    fn diff_node(updates: &mut Vec<UiElementUpdate>, path: UiPath, old: &UiElement, new: &UiElement) {
+        let old_children: Vec<&UiElement> = old.children().collect();
+        let new_children: Vec<&UiElement> = new.children().collect();
+
+        // skip the unchanged children at either end
+        let prefix = old_children
+            .iter()
+            .zip(new_children.iter())
+            .take_while(|(o, n)| o.same_tree(n))
+            .count();
+        let suffix = old_children[prefix..]
+            .iter()
+            .rev()
+            .zip(new_children[prefix..].iter().rev())
+            .take_while(|(o, n)| o.same_tree(n))
+            .count();
+        let old_middle = &old_children[prefix..old_children.len() - suffix];
+        let new_middle = &new_children[prefix..new_children.len() - suffix];
+
+        // children in the same position are compared, the rest are
+        // inserted or deleted after them
+        let paired = old_middle.len().min(new_middle.len());
+        let mut operations = Vec::new();
+        for _ in paired..old_middle.len() {
+            operations.push(UiChildOperations::Delete(prefix + paired));
+        }
+        for (offset, child) in new_middle.iter().enumerate().skip(paired) {
+            operations.push(UiChildOperations::Insert(prefix + offset, (*child).clone()));
+        }
+
+        let changed = !old.same_properties(new);
+        if changed && !operations.is_empty() {
+            updates.push(UiElementUpdate::update_element_children(path.clone(), new.clone(), operations));
+        } else if changed {
+            updates.push(UiElementUpdate::update_element(path.clone(), new.clone()));
+        } else if !operations.is_empty() {
+            updates.push(UiElementUpdate::update_children(path.clone(), operations));
+        }
+
+        for index in 0..paired {
+            let mut child_path = path.clone();
+            child_path.append_child(prefix + index);
+            Self::diff_node(updates, child_path, old_middle[index], new_middle[index]);
+        }
+    }
+
     /// Apply a Vec<[UiElementUpdate]> to the [UiPage]. If manual changes were
     /// made to this UiPage, and the UiPage to which this is being synchronized
     /// differ, those changes are ignored by the update and could cause 
     /// errors to occur.
-    pub fn apply_changes(&mut self, changes: Vec<UiElementUpdate>) -> UpdateSummary {
+    /// If an update refers to an element that does not exist, the pages are
+    /// out of sync, and a [SyncError] is returned. The updates before it
+    /// have already been applied, and their summary is in the error.
+    pub fn apply_changes(&mut self, changes: Vec<UiElementUpdate>) -> Result<UpdateSummary, SyncError> {
         let mut ret = UpdateSummary::new();
 
         self.consolidate_changes();
@@ -224,11 +285,44 @@ impl UiPageManager {
                 }
                 None => {
                     // could not find element to update, need to resync
-                    return ret;
+                    let path = change.path().clone();
+                    self.recalculate_ids();
+                    return Err(SyncError { path, summary: ret });
                 }
             }
         }
         self.recalculate_ids(); // Could change this to only update ids that have changed per the new updates
-        return ret;
+        Ok(ret)
     }
 }
+
+/// A SyncError is returned when a [UiElementUpdate] could not be applied
+/// because the element it refers to does not exist. This means that the
+/// two copies of the [UiPage] are out of sync, and the whole page should
+/// be sent again.
+#[derive(Debug)]
+pub struct SyncError {
+    path: UiPath,
+    summary: UpdateSummary,
+}
+
+impl SyncError {
+    /// The [UiPath] of the element that could not be found.
+    pub fn path(&self) -> &UiPath {
+        &self.path
+    }
+
+    /// Take the [UpdateSummary] of the updates that were applied before
+    /// the missing element was encountered.
+    pub fn into_summary(self) -> UpdateSummary {
+        self.summary
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not find element at {} to update", self.path)
+    }
+}
+
+impl std::error::Error for SyncError {}
@@ -5,13 +5,16 @@ use crate::SpiderId2048;
 use super::UiElement;
 
 mod path;
-pub use path::UiPath;
+pub use path::{UiPath, UiPathParseError};
 
 mod page_list;
 pub use page_list::UiPageList;
 
 mod manager;
-pub use manager::UiPageManager;
+pub use manager::{SyncError, UiPageManager};
+
+mod style;
+pub use style::{UiPageStyle, UiPageTheme};
 
 /// A UiPage represents a page in the Ui that a peripheral has registered to
 /// display its state and accept inputs.
@@ -19,8 +22,11 @@ pub use manager::UiPageManager;
 pub struct UiPage {
     id: SpiderId2048,
     name: String,
+    #[serde(default)]
+    style: Option<UiPageStyle>,
 
-    root: UiElement,
+    // boxed, since pages are carried inline in several message enums
+    root: Box<UiElement>,
 }
 
 impl UiPage {
@@ -32,7 +38,8 @@ impl UiPage {
         Self {
             id,
             name: name.into(),
-            root: UiElement::from_string("<new page>"),
+            style: None,
+            root: Box::new(UiElement::from_string("<new page>")),
         }
     }
 
@@ -50,6 +57,15 @@ impl UiPage {
         self.id = id;
     }
 
+    /// Get the [UiPageStyle] of this UiPage
+    pub fn style(&self) -> &Option<UiPageStyle> {
+        &self.style
+    }
+    /// Set the [UiPageStyle] of this UiPage
+    pub fn set_style(&mut self, style: Option<UiPageStyle>) {
+        self.style = style;
+    }
+
     /// Get the root [UiElement] of this UiPage
     pub fn root(&self) -> &UiElement {
         &self.root
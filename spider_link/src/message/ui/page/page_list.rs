@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::SpiderId2048;
 
@@ -6,10 +6,12 @@ use super::{UiPage, UiPageManager};
 
 /// A UiPageList holds a set of [UiPageManager]s in a particular order.
 /// The [UiPageManager]s are stored in a tree, and the order is maintained
-/// in a Vec.
+/// in a Vec. Pages can also be looked up by name, if several pages share a
+/// name, the most recently upserted one is returned.
 pub struct UiPageList {
     order: Vec<SpiderId2048>,
     pages: BTreeMap<SpiderId2048, UiPageManager>,
+    names: HashMap<String, SpiderId2048>,
     selected_page: usize,
 }
 
@@ -19,6 +21,7 @@ impl UiPageList {
         Self {
             order: Vec::new(),
             pages: BTreeMap::new(),
+            names: HashMap::new(),
             selected_page: 0,
         }
     }
@@ -27,6 +30,7 @@ impl UiPageList {
     pub fn clear(&mut self) {
         self.order.clear();
         self.pages.clear();
+        self.names.clear();
         self.selected_page = 0;
     }
 
@@ -41,11 +45,17 @@ impl UiPageList {
 
 	/// Update a [UiPageManager] in the list without changing its position.
 	/// If the [UiPageManager] is not in the list, it is added to the end.
+	/// The page becomes the one returned when looking up its name.
     pub fn upsert_page(&mut self, page: UiPage) -> Option<UiPage> {
+        self.names.insert(page.name.clone(), page.id.clone());
         match self.pages.get_mut(&page.id) {
             Some(p) => {
                 // page exists, replace it
-                Some(p.set_page(page))
+                let old = p.set_page(page);
+                if old.name != p.get_page().name {
+                    self.unindex_name(&old.name, &old.id);
+                }
+                Some(old)
             }
             None => {
                 // new page, add at end
@@ -58,6 +68,42 @@ impl UiPageList {
         }
     }
 
+	/// Remove a [UiPageManager] from the list, returning it if it was
+	/// present. The selection remains on the same page if possible.
+    pub fn remove_page(&mut self, id: &SpiderId2048) -> Option<UiPageManager> {
+        let page = self.pages.remove(id)?;
+        if let Some(index) = self.order.iter().position(|other| other == id) {
+            self.order.remove(index);
+            if index < self.selected_page {
+                self.selected_page -= 1;
+            }
+        }
+        if self.selected_page >= self.order.len() {
+            self.selected_page = self.order.len().saturating_sub(1);
+        }
+        self.unindex_name(&page.get_page().name, id);
+        Some(page)
+    }
+
+	/// Move a [UiPageManager] to a new position in the list. If the new
+	/// index is past the end of the list, the page is moved to the end.
+	/// The selection remains on the same page.
+    pub fn move_page(&mut self, id: &SpiderId2048, new_index: usize) {
+        let index = match self.order.iter().position(|other| other == id) {
+            Some(index) => index,
+            None => return,
+        };
+        let selected = self.order.get(self.selected_page).cloned();
+        let key = self.order.remove(index);
+        let new_index = new_index.min(self.order.len());
+        self.order.insert(new_index, key);
+        if let Some(selected) = selected {
+            if let Some(position) = self.order.iter().position(|other| *other == selected) {
+                self.selected_page = position;
+            }
+        }
+    }
+
 	/// Get a [UiPageManager] from the list
     pub fn get_page(&self, id: &SpiderId2048) -> Option<&UiPageManager> {
         self.pages.get(&id)
@@ -68,6 +114,41 @@ impl UiPageList {
         self.pages.get_mut(&id)
     }
 
+	/// Get a [UiPageManager] from the list by the name of its page. If
+	/// several pages share the name, the most recently upserted one is
+	/// returned.
+    pub fn get_page_by_name(&self, name: &str) -> Option<&UiPageManager> {
+        let id = self.names.get(name)?;
+        self.pages.get(id)
+    }
+
+	/// Mutably get a [UiPageManager] from the list by the name of its page.
+	/// If several pages share the name, the most recently upserted one is
+	/// returned.
+    pub fn get_page_by_name_mut(&mut self, name: &str) -> Option<&mut UiPageManager> {
+        let id = self.names.get(name)?;
+        self.pages.get_mut(id)
+    }
+
+	/// Remove the name of a page from the index, if the name referred to that
+	/// page. Another page with the same name, if any, takes its place.
+    fn unindex_name(&mut self, name: &str, id: &SpiderId2048) {
+        if self.names.get(name) != Some(id) {
+            return;
+        }
+        self.names.remove(name);
+        let replacement = self.order.iter().rev().find(|other| {
+            *other != id
+                && self
+                    .pages
+                    .get(*other)
+                    .is_some_and(|page| page.get_page().name == name)
+        });
+        if let Some(other) = replacement {
+            self.names.insert(name.to_string(), other.clone());
+        }
+    }
+
 	/// Get a Vec of [UiPage]s in this list
     pub fn get_page_vec(&self) -> Vec<&UiPage> {
         let mut list = Vec::new();
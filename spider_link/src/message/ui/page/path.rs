@@ -1,9 +1,11 @@
 
-use std::slice::Iter;
+use std::{fmt, slice::Iter, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-/// A UiPath refers to a UiElement within a UiPage
+/// A UiPath refers to a UiElement within a UiPage.
+/// As text, it is written as the child indices separated by slashes,
+/// e.g. "/0/2/1". The root element is written as "/".
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct UiPath{
     pub(super) path: Vec<usize>
@@ -44,6 +46,49 @@ impl UiPath {
     }
 }
 
+impl fmt::Display for UiPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            return write!(f, "/");
+        }
+        for index in &self.path {
+            write!(f, "/{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UiPath {
+    type Err = UiPathParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || UiPathParseError { input: s.to_string() };
+        let rest = s.strip_prefix('/').ok_or_else(err)?;
+        if rest.is_empty() {
+            return Ok(Self::root());
+        }
+        let path = rest
+            .split('/')
+            .map(|part| part.parse::<usize>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { path })
+    }
+}
+
+/// The error returned when a string can not be parsed as a [UiPath].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiPathParseError {
+    input: String,
+}
+
+impl fmt::Display for UiPathParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid UiPath: {:?}", self.input)
+    }
+}
+
+impl std::error::Error for UiPathParseError {}
+
 impl PartialOrd for UiPath {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         let comparison = match self.path.len().cmp(&other.path.len()) {
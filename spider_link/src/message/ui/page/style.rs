@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// A UiPageStyle holds hints about how a [UiPage](super::UiPage) should be
+/// presented. Each hint is optional, and UI peripherals may ignore any that
+/// they do not support.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiPageStyle {
+    /// The color of the page's title, as a CSS color string. E.g. "#ff8800"
+    pub title_color: Option<String>,
+    /// The preferred color scheme for the page.
+    pub theme: Option<UiPageTheme>,
+    /// The icon of the page, either a URL or a base64 data URI.
+    pub icon: Option<String>,
+}
+
+/// The color scheme preferred by a [UiPage](super::UiPage).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UiPageTheme {
+    /// Dark text on a light background
+    Light,
+    /// Light text on a dark background
+    Dark,
+}
@@ -0,0 +1,84 @@
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{Keyfile, Relation, Role};
+
+/// The first byte of an encoded [PairingPayload], changed if the layout of
+/// the payload ever changes.
+const PAIRING_VERSION: u8 = 1;
+
+/// A PairingPayload bundles everything a peripheral needs to pair with a
+/// base into one compact string, small enough to be shown as a QR code.
+/// It holds the base's [Relation], an optional permission code that lets
+/// the peripheral be approved automatically, and an optional address where
+/// the base may be found. Like a [Keyfile], it only needs to be used once.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PairingPayload {
+    /// The relation of the base to pair with.
+    pub relation: Relation,
+    /// The permission code used to approve the peripheral.
+    pub permission_code: Option<String>,
+    /// An address where the base was listening when the payload was made.
+    pub addr_hint: Option<String>,
+}
+
+impl PairingPayload {
+    /// Creates a new PairingPayload from its parts.
+    pub fn new(relation: Relation, permission_code: Option<String>, addr_hint: Option<String>) -> Self {
+        Self {
+            relation,
+            permission_code,
+            addr_hint,
+        }
+    }
+
+    /// Creates a PairingPayload from the contents of a [Keyfile], with an
+    /// optional address hint.
+    pub fn from_keyfile(keyfile: Keyfile, addr_hint: Option<String>) -> Self {
+        Self {
+            relation: Relation { role: Role::Peer, id: keyfile.id },
+            permission_code: keyfile.permission_code,
+            addr_hint,
+        }
+    }
+
+    /// Returns a base 64 encoded representation of this payload.
+    pub fn to_base64(&self) -> String {
+        let mut bytes = vec![PAIRING_VERSION];
+        bytes.extend(bincode::serialize(self).expect("Failed to serialize pairing payload"));
+        general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Optionally returns a PairingPayload from a base64 encoded string,
+    /// such as the contents of a scanned QR code.
+    pub fn from_base64<S: Into<String>>(s: S) -> Option<Self> {
+        let bytes = general_purpose::URL_SAFE_NO_PAD.decode(s.into().trim()).ok()?;
+        let (version, bytes) = bytes.split_first()?;
+        if *version != PAIRING_VERSION {
+            return None;
+        }
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PairingPayload;
+    use crate::{Relation, Role, SpiderId2048};
+
+    #[test]
+    fn round_trip() {
+        let relation = Relation { role: Role::Peer, id: SpiderId2048::from_bytes([3; 294]) };
+        let payload = PairingPayload::new(relation, Some("abcdefghij".into()), Some("192.168.1.5:1930".into()));
+        let encoded = payload.to_base64();
+        assert_eq!(PairingPayload::from_base64(encoded.clone()), Some(payload));
+        println!("pairing payload: {} characters", encoded.len());
+
+        let relation = Relation { role: Role::Peer, id: SpiderId2048::from_bytes([4; 294]) };
+        let payload = PairingPayload::new(relation, None, None);
+        assert_eq!(PairingPayload::from_base64(payload.to_base64()), Some(payload));
+
+        assert_eq!(PairingPayload::from_base64("not a payload"), None);
+        assert_eq!(PairingPayload::from_base64(""), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use spider_link::message::{DatasetData, DatasetMessage, DatasetOp, DatasetPath};
+
+
+#[test]
+fn json_to_dataset(){
+    let value = json!({
+        "name": "sensor",
+        "active": true,
+        "missing": null,
+        "small": 12,
+        "medium": 1000,
+        "negative": -5,
+        "large": 5_000_000_000u64,
+        "ratio": 0.5,
+        "readings": [1, 2.5, "three"],
+    });
+    let data = DatasetData::from(value);
+
+    let mut expected = HashMap::new();
+    expected.insert("name".to_string(), DatasetData::String("sensor".into()));
+    expected.insert("active".to_string(), DatasetData::Bool(true));
+    expected.insert("missing".to_string(), DatasetData::Null);
+    expected.insert("small".to_string(), DatasetData::Byte(12));
+    expected.insert("medium".to_string(), DatasetData::Int(1000));
+    expected.insert("negative".to_string(), DatasetData::Int(-5));
+    // out of the range of an Int, so it becomes a lossy Float
+    expected.insert("large".to_string(), DatasetData::Float(5_000_000_000.0));
+    expected.insert("ratio".to_string(), DatasetData::Float(0.5));
+    expected.insert("readings".to_string(), DatasetData::Array(vec![
+        DatasetData::Byte(1),
+        DatasetData::Float(2.5),
+        DatasetData::String("three".into()),
+    ]));
+    assert_eq!(data, DatasetData::Map(expected));
+}
+
+#[test]
+fn json_round_trip(){
+    let value = json!({
+        "title": "Living Room",
+        "on": false,
+        "level": 200,
+        "offset": -40000,
+        "temperature": 21.5,
+        "history": [[1, 2], {"nested": null}],
+    });
+    let data = DatasetData::from(value.clone());
+    assert_eq!(Value::from(data.clone()), value);
+    assert_eq!(DatasetData::from(Value::from(data.clone())), data);
+}
+
+#[test]
+fn dataset_to_json_lossy(){
+    // timestamps become plain numbers, and come back as numbers
+    let time = DatasetData::Timestamp(1_700_000_000_000);
+    assert_eq!(Value::from(time), json!(1_700_000_000_000i64));
+
+    // non finite floats have no json representation
+    assert_eq!(Value::from(DatasetData::Float(f32::NAN)), Value::Null);
+    assert_eq!(Value::from(DatasetData::Float(f32::INFINITY)), Value::Null);
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Reading{
+    sensor: String,
+    value: f32,
+    count: u32,
+    tags: Vec<String>,
+    location: Option<(i32, i32)>,
+}
+
+#[test]
+fn serialize_round_trip(){
+    let reading = Reading{
+        sensor: "thermometer".into(),
+        value: 21.5,
+        count: 70000,
+        tags: vec!["indoor".into()],
+        location: Some((3, -4)),
+    };
+    let data = DatasetData::from_serialize(&reading).unwrap();
+    assert_eq!(data.get_property(&"sensor".to_string()), &DatasetData::String("thermometer".into()));
+    assert_eq!(data.get_property(&"count".to_string()), &DatasetData::Int(70000));
+
+    let back: Reading = data.to_deserialize().unwrap();
+    assert_eq!(back, reading);
+}
+
+#[test]
+fn deserialize_shape_mismatch(){
+    // not a map
+    assert!(DatasetData::String("reading".into()).to_deserialize::<Reading>().is_err());
+
+    // missing a field
+    let mut map = HashMap::new();
+    map.insert("sensor".to_string(), DatasetData::String("thermometer".into()));
+    assert!(DatasetData::Map(map.clone()).to_deserialize::<Reading>().is_err());
+
+    // a field of the wrong type
+    map.insert("value".to_string(), DatasetData::Float(1.0));
+    map.insert("count".to_string(), DatasetData::String("many".into()));
+    map.insert("tags".to_string(), DatasetData::Array(vec![]));
+    map.insert("location".to_string(), DatasetData::Null);
+    assert!(DatasetData::Map(map.clone()).to_deserialize::<Reading>().is_err());
+
+    // and once corrected, it deserializes
+    map.insert("count".to_string(), DatasetData::Byte(3));
+    let reading: Reading = DatasetData::Map(map).to_deserialize().unwrap();
+    assert_eq!(reading.count, 3);
+    assert_eq!(reading.location, None);
+}
+
+#[test]
+fn numeric_accessors(){
+    assert_eq!(DatasetData::Byte(7).as_i64(), Some(7));
+    assert_eq!(DatasetData::Int(-70000).as_i64(), Some(-70000));
+    assert_eq!(DatasetData::Timestamp(1_700_000_000_000).as_i64(), Some(1_700_000_000_000));
+    assert_eq!(DatasetData::Float(1.5).as_i64(), None);
+    assert_eq!(DatasetData::String("7".into()).as_i64(), None);
+
+    assert_eq!(DatasetData::Byte(7).as_f64(), Some(7.0));
+    assert_eq!(DatasetData::Int(-3).as_f64(), Some(-3.0));
+    assert_eq!(DatasetData::Float(1.5).as_f64(), Some(1.5));
+    assert_eq!(DatasetData::Bool(true).as_f64(), None);
+}
+
+#[test]
+fn other_accessors(){
+    assert_eq!(DatasetData::String("text".into()).as_str(), Some("text"));
+    assert_eq!(DatasetData::Int(1).as_str(), None);
+
+    assert_eq!(DatasetData::Bool(false).as_bool(), Some(false));
+    assert_eq!(DatasetData::Null.as_bool(), None);
+
+    let arr = DatasetData::Array(vec![DatasetData::Null]);
+    assert_eq!(arr.as_array().map(|a| a.len()), Some(1));
+    assert!(arr.as_map().is_none());
+
+    let map = DatasetData::Map(HashMap::from([("key".to_string(), DatasetData::Byte(1))]));
+    assert_eq!(map.as_map().and_then(|m| m.get("key")), Some(&DatasetData::Byte(1)));
+    assert!(map.as_array().is_none());
+}
+
+#[test]
+fn nested_path(){
+    let data = DatasetData::from(json!({
+        "a": {
+            "b": {"c": "found"},
+            "list": [10, {"name": "second"}],
+        },
+    }));
+    assert_eq!(data.get_path(&["a", "b", "c"]), &DatasetData::String("found".into()));
+    assert_eq!(data.get_path(&["a", "list", "0"]), &DatasetData::Byte(10));
+    assert_eq!(data.get_path(&["a", "list", "1", "name"]), &DatasetData::String("second".into()));
+    assert_eq!(data.get_path(&[]), &data);
+
+    // any miss along the way is null
+    assert_eq!(data.get_path(&["a", "x", "c"]), &DatasetData::Null);
+    assert_eq!(data.get_path(&["a", "list", "5"]), &DatasetData::Null);
+    assert_eq!(data.get_path(&["a", "b", "c", "d"]), &DatasetData::Null);
+}
+
+#[test]
+fn apply_ops(){
+    let mut dataset = Vec::new();
+    DatasetOp::Append(DatasetData::Byte(0)).apply(&mut dataset);
+    DatasetOp::Extend(vec![DatasetData::Byte(1), DatasetData::Byte(2)]).apply(&mut dataset);
+    assert_eq!(dataset, vec![DatasetData::Byte(0), DatasetData::Byte(1), DatasetData::Byte(2)]);
+
+    // setting past the end pads with null
+    DatasetOp::SetElement { id: 4, data: DatasetData::Byte(4) }.apply(&mut dataset);
+    assert_eq!(dataset[3], DatasetData::Null);
+    assert_eq!(dataset[4], DatasetData::Byte(4));
+    DatasetOp::SetElements { id: 5, data: vec![DatasetData::Byte(5), DatasetData::Byte(6)] }.apply(&mut dataset);
+    assert_eq!(dataset.len(), 7);
+
+    DatasetOp::DeleteElement { id: 3 }.apply(&mut dataset);
+    assert_eq!(dataset[3], DatasetData::Byte(4));
+    // deleting past the end does nothing
+    DatasetOp::DeleteElement { id: 10 }.apply(&mut dataset);
+    assert_eq!(dataset.len(), 6);
+
+    DatasetOp::Empty.apply(&mut dataset);
+    assert!(dataset.is_empty());
+}
+
+#[test]
+fn apply_messages(){
+    let path = DatasetPath::new_public(vec!["data".into()]);
+    let mut dataset = vec![DatasetData::Null];
+
+    let full = DatasetMessage::Dataset { path: path.clone(), data: vec![DatasetData::Byte(1)] };
+    assert!(full.apply(&mut dataset));
+    assert_eq!(dataset, vec![DatasetData::Byte(1)]);
+
+    let delta = DatasetMessage::Delta {
+        path: path.clone(),
+        ops: vec![
+            DatasetOp::Append(DatasetData::Byte(2)),
+            DatasetOp::DeleteElement { id: 0 },
+        ],
+    };
+    assert!(delta.apply(&mut dataset));
+    assert_eq!(dataset, vec![DatasetData::Byte(2)]);
+
+    let other = DatasetMessage::Subscribe { path };
+    assert!(!other.apply(&mut dataset));
+    assert_eq!(dataset, vec![DatasetData::Byte(2)]);
+}
+
+#[test]
+fn apply_out_of_range(){
+    let mut dataset = vec![DatasetData::Byte(0)];
+    let op = DatasetOp::SetElement { id: usize::MAX, data: DatasetData::Null };
+    assert!(!op.fits(dataset.len()));
+    assert!(!op.apply(&mut dataset));
+    let op = DatasetOp::SetElements { id: usize::MAX - 1, data: vec![DatasetData::Null; 2] };
+    assert!(!op.apply(&mut dataset));
+    let op = DatasetOp::SetElement { id: 1 + DatasetOp::MAX_PADDING + 1, data: DatasetData::Null };
+    assert!(!op.apply(&mut dataset));
+    assert_eq!(dataset, vec![DatasetData::Byte(0)]);
+
+    // padding up to the limit is allowed
+    let op = DatasetOp::SetElement { id: 1 + DatasetOp::MAX_PADDING, data: DatasetData::Null };
+    assert!(op.apply(&mut dataset));
+    assert_eq!(dataset.len(), DatasetOp::MAX_PADDING + 2);
+
+    // a delta stops at the first op that does not fit
+    let delta = DatasetMessage::Delta {
+        path: DatasetPath::new_public(vec!["data".into()]),
+        ops: vec![DatasetOp::Empty, DatasetOp::SetElement { id: usize::MAX, data: DatasetData::Null }],
+    };
+    assert!(!delta.apply(&mut dataset));
+    assert!(dataset.is_empty());
+}
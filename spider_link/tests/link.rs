@@ -10,7 +10,7 @@
 use std::collections::HashMap;
 
 use rsa::RsaPrivateKey;
-use spider_link::{link::Link, SelfRelation, Role, message::{Message, DatasetData, UiElement, UiElementKind, AbsoluteDatasetPath, DatasetPath}, id::SpiderId};
+use spider_link::{link::{Link, DEFAULT_MAX_FRAME_LEN}, SelfRelation, Role, message::{Message, GoodbyeReason, DatasetData, DatasetMessage, UiElement, UiElementKind, UiElementContentPart, UiElementContent, AbsoluteDatasetPath, DatasetPath, UiMessage, UiPage, UiPageManager, UiPageList, UiPageStyle, UiPageTheme, UiPath, UiElementUpdate, UiChildOperations, UpdateSummary}, id::SpiderId};
 
 
 
@@ -51,6 +51,145 @@ async fn send_message(){
 }
 
 
+#[tokio::test]
+async fn send_many_messages(){
+    // setup base listener
+    let mut rng = rand::thread_rng();
+    let priv_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let base_relation = SelfRelation::from_key(priv_key, Role::Peer);
+    let (mut listener, _) = Link::listen(base_relation.clone(), "127.0.0.1:1931");
+    // allow the listener to bind
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // setup peripheral link
+    let peripheral_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let peripheral_relation = SelfRelation::from_key(peripheral_key, Role::Peripheral);
+    let to_host = Link::connect(peripheral_relation, "127.0.0.1:1931", base_relation.relation).await.expect("failed to connect to base");
+
+    // get link from base listener
+    let mut to_peripheral = listener.recv().await.expect("failed to get new link");
+
+    // each message is encrypted with its own nonce, all must decrypt
+    let sender = tokio::spawn(async move {
+        for i in 0..1000 {
+            to_host.send(Message::Error(format!("message {}", i).into())).await.expect("link closed");
+        }
+        to_host
+    });
+    for i in 0..1000 {
+        match to_peripheral.recv().await.expect("link closed") {
+            Message::Error(text) => assert_eq!(text.detail(), format!("message {}", i)),
+            msg => panic!("incorrect message recieved: {:?}", msg),
+        }
+    }
+    sender.await.expect("sender should finish");
+}
+
+
+#[tokio::test]
+async fn terminate_sends_goodbye(){
+    // setup base listener
+    let mut rng = rand::thread_rng();
+    let priv_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let base_relation = SelfRelation::from_key(priv_key, Role::Peer);
+    let (mut listener, _) = Link::listen(base_relation.clone(), "127.0.0.1:1932");
+    // allow the listener to bind
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // setup peripheral link
+    let peripheral_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let peripheral_relation = SelfRelation::from_key(peripheral_key, Role::Peripheral);
+    let mut to_host = Link::connect(peripheral_relation, "127.0.0.1:1932", base_relation.relation).await.expect("failed to connect to base");
+
+    // get link from base listener
+    let to_peripheral = listener.recv().await.expect("failed to get new link");
+
+    // the peripheral sees the reason the base closed the link
+    to_peripheral.terminate_with(GoodbyeReason::Unpaired).await;
+    assert!(to_host.recv().await.is_none());
+    assert_eq!(to_host.goodbye(), Some(GoodbyeReason::Unpaired));
+}
+
+#[tokio::test]
+async fn listen_all_skips_failed_binds(){
+    // setup base listener, the second bind of the same address fails
+    let mut rng = rand::thread_rng();
+    let priv_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let base_relation = SelfRelation::from_key(priv_key, Role::Peer);
+    let addrs = vec![String::from("127.0.0.1:1936"), String::from("127.0.0.1:1936"), String::from("not an address")];
+    let (mut listener, _) = Link::listen_all(base_relation.clone(), addrs, DEFAULT_MAX_FRAME_LEN);
+    // allow the listener to bind
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // setup peripheral link
+    let peripheral_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let peripheral_relation = SelfRelation::from_key(peripheral_key, Role::Peripheral);
+    let _to_host = Link::connect(peripheral_relation, "127.0.0.1:1936", base_relation.relation.clone()).await.expect("failed to connect to base");
+    listener.recv().await.expect("failed to get new link");
+
+    // if no address can be bound, the listener closes
+    let (mut listener, _) = Link::listen_all(base_relation, vec![String::from("not an address")], DEFAULT_MAX_FRAME_LEN);
+    assert!(listener.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn large_dataset_round_trip(){
+    // setup base listener
+    let mut rng = rand::thread_rng();
+    let priv_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let base_relation = SelfRelation::from_key(priv_key, Role::Peer);
+    let (mut listener, _) = Link::listen(base_relation.clone(), "127.0.0.1:1935");
+    // allow the listener to bind
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // setup peripheral link
+    let peripheral_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate key");
+    let peripheral_relation = SelfRelation::from_key(peripheral_key, Role::Peripheral);
+    let to_host = Link::connect(peripheral_relation, "127.0.0.1:1935", base_relation.relation).await.expect("failed to connect to base");
+
+    // get link from base listener
+    let mut to_peripheral = listener.recv().await.expect("failed to get new link");
+
+    // about 1MB of data, which is compressed in transit
+    let data: Vec<DatasetData> = (0..1024)
+        .map(|i| DatasetData::String(format!("{:04}", i).repeat(256)))
+        .collect();
+    let path = DatasetPath::new_public(vec!["large".into()]);
+    let msg = Message::Dataset(DatasetMessage::Extend { path, data: data.clone() });
+    to_host.send(msg).await.expect("link closed");
+
+    match to_peripheral.recv().await.expect("link closed") {
+        Message::Dataset(DatasetMessage::Extend { data: recv_data, .. }) => {
+            assert_eq!(recv_data, data);
+        },
+        msg => panic!("incorrect message recieved: {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn key_request_gives_up(){
+    // a server that accepts connections but never responds
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:1933").await.expect("failed to bind");
+    let held = tokio::spawn(async move {
+        let (sock, _) = silent.accept().await.expect("failed to accept");
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        drop(sock);
+    });
+    let start = std::time::Instant::now();
+    let key = Link::key_request_with_timeout("127.0.0.1:1933", std::time::Duration::from_millis(200)).await;
+    assert!(key.is_none());
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    held.abort();
+
+    // a server that closes the connection without responding
+    let closing = tokio::net::TcpListener::bind("127.0.0.1:1934").await.expect("failed to bind");
+    tokio::spawn(async move {
+        let (sock, _) = closing.accept().await.expect("failed to accept");
+        drop(sock);
+    });
+    assert!(Link::key_request("127.0.0.1:1934").await.is_none());
+}
+
 #[test]
 fn test_ui_element_dataset_iterator(){
     let mut data_map: HashMap<AbsoluteDatasetPath, Vec<DatasetData>> = HashMap::new();
@@ -109,6 +248,121 @@ fn test_ui_element_dataset_iterator(){
 
 }
 
+#[test]
+fn test_grid_child_ordering(){
+    let data_map: HashMap<AbsoluteDatasetPath, Vec<DatasetData>> = HashMap::new();
+
+    let mut grid = UiElement::new(UiElementKind::Grid(2, 3));
+    for i in 0..4 {
+        grid.append_child(UiElement::from_string(format!("Child {}", i)));
+    }
+
+    // children fill each row in turn, empty cells are padded
+    let cells: Vec<String> = grid.children_dataset(&None, &data_map).map(|(_, child, _)| {
+        match child.kind() {
+            UiElementKind::None => String::from("none"),
+            _ => child.text(),
+        }
+    }).collect();
+    assert_eq!(cells, vec!["Child 0", "Child 1", "Child 2", "Child 3", "none", "none"]);
+
+    // the same cells are produced in reverse
+    let reversed: Vec<String> = grid.children_dataset(&None, &data_map).rev().map(|(_, child, _)| child.text()).collect();
+    assert_eq!(reversed, vec!["", "", "Child 3", "Child 2", "Child 1", "Child 0"]);
+
+    // excess children are not included
+    for i in 4..8 {
+        grid.append_child(UiElement::from_string(format!("Child {}", i)));
+    }
+    let mut iter = grid.children_dataset(&None, &data_map);
+    assert_eq!(iter.len(), 6);
+    assert_eq!(iter.next_back().map(|(_, child, _)| child.text()), Some(String::from("Child 5")));
+
+    // grids can be resolved from data
+    let kind = UiElementKind::Variable(UiElementContentPart::Data(vec!["kind".into()]));
+    let mut map = HashMap::new();
+    map.insert(String::from("kind"), DatasetData::String("Grid 2x3".into()));
+    let datum = DatasetData::Map(map);
+    assert_eq!(kind.resolve(&Some(&datum)), UiElementKind::Grid(2, 3));
+}
+
+#[test]
+fn test_page_style_round_trip(){
+    let id = SpiderId::from_bytes([1; 294]);
+    let style = UiPageStyle {
+        title_color: Some("#ff8800".into()),
+        theme: Some(UiPageTheme::Dark),
+        icon: None,
+    };
+    let mut page = UiPage::new(id.clone(), "Styled");
+    page.set_style(Some(style.clone()));
+
+    let mut mgr = UiPageManager::from_page(UiPage::new(id, "Plain"));
+    let old = mgr.set_page(page);
+    assert_eq!(old.style(), &None);
+    assert_eq!(mgr.get_page().style(), &Some(style.clone()));
+
+    let msg = Message::Ui(UiMessage::Page(mgr.get_page().clone()));
+    let json = serde_json::to_string(&msg).expect("failed to serialize");
+    match serde_json::from_str(&json).expect("failed to deserialize") {
+        Message::Ui(UiMessage::Page(page)) => assert_eq!(page.style(), &Some(style)),
+        msg => panic!("incorrect message recieved: {:?}", msg),
+    }
+}
+
+#[test]
+fn test_page_list_by_name(){
+    let first = SpiderId::from_bytes([1; 294]);
+    let second = SpiderId::from_bytes([2; 294]);
+    let mut list = UiPageList::new();
+    list.upsert_page(UiPage::new(first.clone(), "Sensors"));
+    list.upsert_page(UiPage::new(second.clone(), "Sensors"));
+
+    // most recently upserted page wins
+    let found = list.get_page_by_name("Sensors").expect("page should exist");
+    assert_eq!(found.get_page().id(), &second);
+
+    // renaming the winner exposes the other page
+    list.upsert_page(UiPage::new(second.clone(), "Lights"));
+    let found = list.get_page_by_name("Sensors").expect("page should exist");
+    assert_eq!(found.get_page().id(), &first);
+    let found = list.get_page_by_name("Lights").expect("page should exist");
+    assert_eq!(found.get_page().id(), &second);
+
+    list.clear();
+    assert!(list.get_page_by_name("Sensors").is_none());
+}
+
+#[test]
+fn test_page_list_remove_and_move(){
+    let ids: Vec<_> = (0..4).map(|i| SpiderId::from_bytes([i; 294])).collect();
+    let mut list = UiPageList::new();
+    for (i, id) in ids.iter().enumerate() {
+        list.upsert_page(UiPage::new(id.clone(), format!("Page {}", i)));
+    }
+    list.select_next_page();
+    list.select_next_page();
+    assert_eq!(list.selected_page().unwrap().get_page().id(), &ids[2]);
+
+    // removing an earlier page keeps the same page selected
+    assert!(list.remove_page(&ids[0]).is_some());
+    assert!(list.remove_page(&ids[0]).is_none());
+    assert_eq!(list.selected_page().unwrap().get_page().id(), &ids[2]);
+    assert!(list.get_page_by_name("Page 0").is_none());
+
+    // moving pages keeps the same page selected
+    list.move_page(&ids[3], 0);
+    list.move_page(&ids[1], 10);
+    let order: Vec<_> = list.get_page_vec().into_iter().map(|page| page.id().clone()).collect();
+    assert_eq!(order, vec![ids[3].clone(), ids[2].clone(), ids[1].clone()]);
+    assert_eq!(list.selected_page().unwrap().get_page().id(), &ids[2]);
+
+    // removing the selected last page selects the new last page
+    list.move_page(&ids[2], 2);
+    list.remove_page(&ids[2]);
+    assert_eq!(list.selected_page().unwrap().get_page().id(), &ids[1]);
+}
+
 #[test]
 fn test_iter(){
     let v = vec![0, 1, 2, 3];
@@ -118,3 +372,181 @@ fn test_iter(){
         println!("val: {:?}", val);
     }
 }
+
+#[test]
+fn test_spider_id_json(){
+    let id = SpiderId::<294>::from_bytes([42; 294]);
+
+    // human readable formats use the base64 string
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(json, format!("\"{}\"", id.to_base64()));
+    assert_eq!(serde_json::from_str::<SpiderId<294>>(&json).unwrap(), id);
+
+    // ids stored before as an array of bytes can still be read
+    let legacy = serde_json::to_string(&vec![42u8; 294]).unwrap();
+    assert_eq!(serde_json::from_str::<SpiderId<294>>(&legacy).unwrap(), id);
+
+    assert!(serde_json::from_str::<SpiderId<294>>("\"not an id\"").is_err());
+}
+
+#[test]
+fn test_content_from_parts(){
+    let content = UiElementContent::from_parts(vec![
+        UiElementContentPart::Text("Temp: ".into()),
+        UiElementContentPart::Data(vec!["temp".into()]),
+        UiElementContentPart::Text("°C, Humidity: ".into()),
+        UiElementContentPart::Data(vec!["humidity".into(), "percent".into()]),
+        UiElementContentPart::Text("%".into()),
+    ]);
+    assert_eq!(content.parts().len(), 5);
+    assert_eq!(content.parts()[1], UiElementContentPart::Data(vec!["temp".into()]));
+    assert_eq!(content.to_string(), "Temp: <temp>°C, Humidity: <humidity.percent>%");
+
+    let mut humidity = HashMap::new();
+    humidity.insert("percent".to_string(), DatasetData::Byte(40));
+    let mut data = HashMap::new();
+    data.insert("temp".to_string(), DatasetData::Int(21));
+    data.insert("humidity".to_string(), DatasetData::Map(humidity));
+    assert_eq!(content.resolve(&DatasetData::Map(data)), "Temp: 21°C, Humidity: 40%");
+
+    assert_eq!(content.into_parts().len(), 5);
+}
+
+#[test]
+fn test_visible_when_filters_rows(){
+    let mut data_map: HashMap<AbsoluteDatasetPath, Vec<DatasetData>> = HashMap::new();
+    let path = AbsoluteDatasetPath::new_public(vec!["alarms".into()]);
+    let dataset = ["ok", "error", "ok", "error"].iter().enumerate().map(|(i, status)| {
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), DatasetData::Byte(i as u8));
+        row.insert("status".to_string(), DatasetData::String(status.to_string()));
+        DatasetData::Map(row)
+    }).collect();
+    data_map.insert(path.clone(), dataset);
+
+    // only show the alarm row when the status is an error
+    let mut row = UiElement::new(UiElementKind::Text);
+    row.set_content(UiElementContent::from_parts(vec![
+        UiElementContentPart::Text("Alarm ".into()),
+        UiElementContentPart::Data(vec!["id".into()]),
+    ]));
+    row.set_visible_when(Some((UiElementContentPart::Data(vec!["status".into()]), DatasetData::String("error".into()))));
+    let mut elem = UiElement::new(UiElementKind::Rows);
+    elem.append_child(row);
+    elem.set_dataset(Some(path));
+
+    let iter = elem.children_dataset(&None, &data_map);
+    assert_eq!(iter.len(), 2);
+    let rows: Vec<(Option<usize>, String)> = iter.map(|(index, child, datum)| (index, child.render_content_opt(&datum))).collect();
+    assert_eq!(rows, vec![(Some(1), "Alarm 1".to_string()), (Some(3), "Alarm 3".to_string())]);
+
+    let reversed: Vec<Option<usize>> = elem.children_dataset(&None, &data_map).rev().map(|(index, _, _)| index).collect();
+    assert_eq!(reversed, vec![Some(3), Some(1)]);
+}
+
+#[test]
+fn test_ui_path_string(){
+    let mut path = UiPath::root();
+    assert_eq!(path.to_string(), "/");
+    assert_eq!("/".parse::<UiPath>().unwrap(), path);
+
+    path.append_child(0);
+    path.append_child(2);
+    path.append_child(1);
+    assert_eq!(path.to_string(), "/0/2/1");
+    assert_eq!("/0/2/1".parse::<UiPath>().unwrap(), path);
+    assert_eq!(path.to_string().parse::<UiPath>().unwrap(), path);
+
+    for malformed in ["", "0/2", "/0/", "//0", "/a/1", "/-1", "/0 /1"]{
+        assert!(malformed.parse::<UiPath>().is_err(), "parsed {:?}", malformed);
+    }
+}
+
+#[test]
+fn test_out_of_range_child_operations(){
+    let mut elem = UiElement::new(UiElementKind::Rows);
+    elem.append_child(UiElement::from_string("first"));
+    elem.append_child(UiElement::from_string("second"));
+
+    // an insert past the end is clamped to the end
+    let update = UiElementUpdate::update_children(UiPath::root(), vec![
+        UiChildOperations::Insert(7, UiElement::from_string("third")),
+    ]);
+    let mut summary = UpdateSummary::new();
+    elem.apply_update(update, &mut summary);
+    assert_eq!(summary.invalid_operations(), 1);
+    let texts: Vec<String> = elem.children().map(|child| child.text()).collect();
+    assert_eq!(texts, vec!["first", "second", "third"]);
+
+    // moves from missing children are skipped, and to past the end are clamped
+    let update = UiElementUpdate::update_children(UiPath::root(), vec![
+        UiChildOperations::MoveTo { from: 9, to: 0 },
+        UiChildOperations::MoveTo { from: 0, to: 9 },
+    ]);
+    let mut summary = UpdateSummary::new();
+    elem.apply_update(update, &mut summary);
+    assert_eq!(summary.invalid_operations(), 2);
+    let texts: Vec<String> = elem.children().map(|child| child.text()).collect();
+    assert_eq!(texts, vec!["second", "third", "first"]);
+
+    // deletes from an empty element are skipped
+    let mut empty = UiElement::new(UiElementKind::Rows);
+    let update = UiElementUpdate::update_children(UiPath::root(), vec![
+        UiChildOperations::Delete(0),
+        UiChildOperations::Delete(3),
+    ]);
+    let mut summary = UpdateSummary::new();
+    empty.apply_update(update, &mut summary);
+    assert_eq!(summary.invalid_operations(), 2);
+    assert!(!summary.changed());
+    assert_eq!(empty.children().len(), 0);
+}
+
+#[test]
+fn test_page_diff(){
+    let id = SpiderId::from_bytes([5;294]);
+    let mut old = UiPageManager::new(id, "Diff");
+    let root = old.get_element_raw(&UiPath::root()).unwrap();
+    root.set_kind(UiElementKind::Rows);
+    root.append_child(UiElement::from_string("first"));
+    root.append_child(UiElement::from_string("second"));
+    root.append_child(UiElement::from_string("third"));
+    let old_page = old.get_page().clone();
+
+    // changing a leaf gives a single update for that element
+    let mut new = UiPageManager::from_page(old_page.clone());
+    new.get_element_raw(&"/1".parse().unwrap()).unwrap().set_text("changed");
+    let updates = old.diff_to(new.get_page());
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].path(), &"/1".parse::<UiPath>().unwrap());
+    let mut applied = UiPageManager::from_page(old_page.clone());
+    applied.apply_changes(updates).unwrap();
+    assert_eq!(serde_json::to_value(applied.get_page()).unwrap(), serde_json::to_value(new.get_page()).unwrap());
+
+    // inserting a child gives a single insert operation
+    let mut new = UiPageManager::from_page(old_page.clone());
+    new.get_element_raw(&UiPath::root()).unwrap().insert_child(1, UiElement::from_string("inserted"));
+    let updates = old.diff_to(new.get_page());
+    assert_eq!(updates.len(), 1);
+    match updates[0].children() {
+        Some(ops) => assert!(matches!(ops.as_slice(), [UiChildOperations::Insert(1, _)])),
+        None => panic!("expected child operations"),
+    }
+    let mut applied = UiPageManager::from_page(old_page.clone());
+    applied.apply_changes(updates).unwrap();
+    assert_eq!(serde_json::to_value(applied.get_page()).unwrap(), serde_json::to_value(new.get_page()).unwrap());
+
+    // removing children and changing another
+    let mut new = UiPageManager::from_page(old_page.clone());
+    let root = new.get_element_raw(&UiPath::root()).unwrap();
+    root.delete_child(0);
+    root.delete_child(0);
+    root.get_child_mut(0).unwrap().set_text("last");
+    let updates = old.diff_to(new.get_page());
+    let mut applied = UiPageManager::from_page(old_page.clone());
+    applied.apply_changes(updates).unwrap();
+    assert_eq!(serde_json::to_value(applied.get_page()).unwrap(), serde_json::to_value(new.get_page()).unwrap());
+
+    // identical pages need no updates
+    assert!(old.diff_to(&old_page).is_empty());
+}